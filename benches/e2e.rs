@@ -0,0 +1,289 @@
+//! End-to-end benchmarks of the server loop.
+//!
+//! Each stack is served on a loopback port and driven by keep-alive client
+//! connections for a fixed time, printing throughput and latency in the
+//! style of `wrk`. Run with `cargo bench --bench e2e`, optionally setting
+//! `IRON_BENCH_SECS` (default 5), `IRON_BENCH_CONNECTIONS` (default 8) and
+//! `IRON_BENCH_STACK` to run a single stack.
+
+#[macro_use]
+extern crate iron;
+extern crate rustc_serialize;
+
+use std::env;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::path::PathBuf;
+use std::process;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use iron::prelude::*;
+use iron::{status, AfterMiddleware, BeforeMiddleware, Handler};
+use iron::typemap::Key;
+use rustc_serialize::json::{Json, ToJson};
+
+struct Stack {
+    name: &'static str,
+    request: String,
+    handler: Box<Handler>
+}
+
+fn main() {
+    let secs = var("IRON_BENCH_SECS", 5);
+    let connections = var("IRON_BENCH_CONNECTIONS", 8);
+    let only = env::var("IRON_BENCH_STACK").ok();
+    let mut servers = vec![];
+
+    for stack in stacks() {
+        if only.as_ref().map_or(false, |only| only != stack.name) { continue }
+
+        let listening = Iron::new(stack.handler).http("127.0.0.1:0").unwrap();
+        let report = run(listening.socket, &stack.request, connections,
+                         Duration::from_secs(secs as u64));
+        report.print(stack.name, listening.socket, connections);
+        servers.push(listening);
+    }
+
+    // Dropping a hyper 0.9 `Listening` waits for the server to stop, which
+    // it never does, so exit with the servers still running.
+    process::exit(0);
+}
+
+fn var(name: &str, default: usize) -> usize {
+    env::var(name).ok().and_then(|v| v.parse().ok()).unwrap_or(default)
+}
+
+fn stacks() -> Vec<Stack> {
+    vec![
+        Stack {
+            name: "empty",
+            request: get("/"),
+            handler: Box::new(|_: &mut Request| Ok(Response::with((status::Ok, "Hello world!"))))
+        },
+        Stack {
+            name: "logger+router+static",
+            request: get("/static/index.html"),
+            handler: Box::new(static_stack())
+        },
+        Stack {
+            name: "json api",
+            request: post_json("/items", r#"{"name":"widget","count":3}"#),
+            handler: Box::new(json_stack())
+        }
+    ]
+}
+
+fn get(path: &str) -> String {
+    format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path)
+}
+
+fn post_json(path: &str, body: &str) -> String {
+    format!("POST {} HTTP/1.1\r\nHost: localhost\r\nContent-Type: application/json\r\n\
+             Content-Length: {}\r\n\r\n{}", path, body.len(), body)
+}
+
+// A logger which times each request, a router matching on the first path
+// segment, and a handler serving a file from disk.
+struct Logger;
+
+struct StartTime;
+impl Key for StartTime { type Value = Instant; }
+
+impl BeforeMiddleware for Logger {
+    fn before(&self, req: &mut Request) -> IronResult<()> {
+        req.extensions.insert::<StartTime>(Instant::now());
+        Ok(())
+    }
+}
+
+impl AfterMiddleware for Logger {
+    fn after(&self, req: &mut Request, res: Response) -> IronResult<Response> {
+        let elapsed = req.extensions.get::<StartTime>().map(|start| start.elapsed());
+        let line = format!("{} {} {:?} {:?}", req.method, req.url, res.status, elapsed);
+        io::sink().write_all(line.as_bytes()).unwrap();
+        Ok(res)
+    }
+}
+
+fn static_stack() -> Chain {
+    let root = env::temp_dir().join("iron-bench-static");
+    std::fs::create_dir_all(&root).unwrap();
+    File::create(root.join("index.html")).unwrap()
+        .write_all(&[b'x'; 4096]).unwrap();
+
+    let mut chain = Chain::new(move |req: &mut Request| {
+        let path = req.url.path();
+        match path.first() {
+            Some(&"static") => {
+                let file: PathBuf = path[1..].iter().fold(root.clone(), |p, s| p.join(s));
+                Ok(Response::with((status::Ok, file)))
+            },
+            Some(&"health") => Ok(Response::with((status::Ok, "ok"))),
+            _ => Ok(Response::with(status::NotFound))
+        }
+    });
+    chain.link_before(Logger);
+    chain.link_after(Logger);
+    chain
+}
+
+// Parse a JSON object, update a field and send it back.
+fn json_stack() -> Chain {
+    Chain::new(|req: &mut Request| {
+        let body = itry!(req.body_string(64 * 1024), status::BadRequest);
+        let mut item = match itry!(Json::from_str(&body), status::BadRequest) {
+            Json::Object(item) => item,
+            _ => return Ok(Response::with(status::BadRequest))
+        };
+        let count = item.get("count").and_then(Json::as_u64).unwrap_or(0);
+        item.insert("count".into(), (count + 1).to_json());
+        Ok(Response::with((status::Created, Json::Object(item).to_string())))
+    })
+}
+
+struct Report {
+    elapsed: Duration,
+    latencies: Vec<Duration>,
+    errors: usize
+}
+
+fn run(addr: SocketAddr, request: &str, connections: usize, duration: Duration) -> Report {
+    let results = Arc::new(Mutex::new((vec![], 0)));
+    let start = Instant::now();
+
+    let clients: Vec<_> = (0..connections).map(|_| {
+        let request = request.to_owned();
+        let results = results.clone();
+        thread::spawn(move || {
+            let (latencies, errors) = client(addr, request.as_bytes(), start + duration);
+            let mut results = results.lock().unwrap();
+            results.0.extend(latencies);
+            results.1 += errors;
+        })
+    }).collect();
+
+    for client in clients { client.join().unwrap() }
+
+    let (mut latencies, errors) = Arc::try_unwrap(results).ok().unwrap().into_inner().unwrap();
+    latencies.sort();
+    Report { elapsed: start.elapsed(), latencies: latencies, errors: errors }
+}
+
+// Send `request` repeatedly over one connection until `deadline`,
+// reconnecting after errors.
+fn client(addr: SocketAddr, request: &[u8], deadline: Instant) -> (Vec<Duration>, usize) {
+    let mut latencies = vec![];
+    let mut errors = 0;
+
+    while Instant::now() < deadline {
+        let stream = match TcpStream::connect(addr) {
+            Ok(stream) => stream,
+            Err(_) => { errors += 1; continue }
+        };
+        stream.set_nodelay(true).unwrap();
+        let mut writer = stream.try_clone().unwrap();
+        let mut reader = BufReader::new(stream);
+
+        while Instant::now() < deadline {
+            let sent = Instant::now();
+            let ok = writer.write_all(request).and_then(|_| read_response(&mut reader));
+            match ok {
+                Ok(true) => latencies.push(sent.elapsed()),
+                Ok(false) => { errors += 1; break },
+                Err(_) => { errors += 1; break }
+            }
+        }
+    }
+
+    (latencies, errors)
+}
+
+// Read one response, returning whether it was a success and the connection
+// can be reused.
+fn read_response<R: BufRead>(reader: &mut R) -> io::Result<bool> {
+    let mut line = String::new();
+    try!(reader.read_line(&mut line));
+    let ok = line.split(' ').nth(1).map_or(false, |code| code.starts_with('2'));
+
+    let mut length = 0;
+    let mut chunked = false;
+    let mut close = false;
+    loop {
+        line.clear();
+        if try!(reader.read_line(&mut line)) == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed"))
+        }
+        let header = line.trim_right().to_ascii_lowercase();
+        if header.is_empty() { break }
+        if header.starts_with("content-length:") {
+            length = header[15..].trim().parse().unwrap_or(0);
+        } else if header.starts_with("transfer-encoding:") {
+            chunked = header.contains("chunked");
+        } else if header.starts_with("connection:") {
+            close = header.contains("close");
+        }
+    }
+
+    if chunked {
+        loop {
+            line.clear();
+            try!(reader.read_line(&mut line));
+            let size = usize::from_str_radix(line.trim(), 16).unwrap_or(0);
+            try!(skip(reader, size + 2));
+            if size == 0 { break }
+        }
+    } else {
+        try!(skip(reader, length));
+    }
+
+    Ok(ok && !close)
+}
+
+fn skip<R: Read>(reader: &mut R, bytes: usize) -> io::Result<()> {
+    let copied = try!(io::copy(&mut reader.take(bytes as u64), &mut io::sink()));
+    if copied < bytes as u64 {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "truncated body"))
+    }
+    Ok(())
+}
+
+impl Report {
+    fn print(&self, name: &str, addr: SocketAddr, connections: usize) {
+        let secs = self.elapsed.as_secs() as f64 + self.elapsed.subsec_nanos() as f64 / 1e9;
+        let requests = self.latencies.len();
+
+        println!("Running {:.0}s test @ http://{}/ ({})", secs, addr, name);
+        println!("  {} connections", connections);
+        println!("  Latency    avg {}  p50 {}  p90 {}  p99 {}  max {}",
+                 micros(self.average()), micros(self.percentile(50.0)),
+                 micros(self.percentile(90.0)), micros(self.percentile(99.0)),
+                 micros(self.latencies.last().cloned().unwrap_or_default()));
+        println!("  {} requests in {:.2}s, {} errors", requests, secs, self.errors);
+        println!("Requests/sec: {:>10.2}", requests as f64 / secs);
+        println!();
+    }
+
+    fn average(&self) -> Duration {
+        if self.latencies.is_empty() { return Duration::from_secs(0) }
+        let total: Duration = self.latencies.iter().sum();
+        total / self.latencies.len() as u32
+    }
+
+    fn percentile(&self, p: f64) -> Duration {
+        if self.latencies.is_empty() { return Duration::from_secs(0) }
+        let index = ((self.latencies.len() - 1) as f64 * p / 100.0).round() as usize;
+        self.latencies[index]
+    }
+}
+
+fn micros(d: Duration) -> String {
+    let micros = d.as_secs() * 1_000_000 + d.subsec_nanos() as u64 / 1_000;
+    if micros >= 10_000 {
+        format!("{:.2}ms", micros as f64 / 1000.0)
+    } else {
+        format!("{}us", micros)
+    }
+}
@@ -0,0 +1,20 @@
+//! Feed arbitrary bytes to the request parser.
+//!
+//! Run with `cargo fuzz run request_from_bytes` from the repository root.
+
+#![no_main]
+
+#[macro_use]
+extern crate libfuzzer_sys;
+extern crate iron;
+
+use std::io::Read;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(mut req) = iron::parse::request_from_bytes(data) {
+        // Everything a handler might look at must be usable without panicking.
+        let _ = req.url.path();
+        let _ = req.url.query().map(|query| iron::url::query_pairs(query).count());
+        let _ = req.body.read_to_end(&mut vec![]);
+    }
+});
@@ -0,0 +1,161 @@
+//! Counting the memory allocations made while handling requests.
+//!
+//! Available with the `count-allocations` feature. The counts come from
+//! `CountingAllocator`, which the application installs as its global
+//! allocator; each thread keeps its own count, and since a request is
+//! handled on a single thread, the difference between two readings on that
+//! thread is the number of allocations made in between:
+//!
+//! ```ignore
+//! #[global_allocator]
+//! static ALLOCATOR: CountingAllocator = CountingAllocator;
+//!
+//! let counter = Arc::new(CountAllocations);
+//! chain.link_before(counter.clone());
+//! chain.link_after(counter);
+//! ```
+//!
+//! Without the allocator installed, every count is zero.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+
+use typemap::Key;
+
+use {BeforeMiddleware, AfterMiddleware, Request, Response, IronResult, IronError};
+
+thread_local! {
+    static COUNT: Cell<u64> = const { Cell::new(0) };
+}
+
+/// A global allocator which allocates from the system allocator and counts
+/// the allocations made by each thread.
+pub struct CountingAllocator;
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        count();
+        System.alloc(layout)
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        count();
+        System.alloc_zeroed(layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        count();
+        System.realloc(ptr, layout, new_size)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+fn count() {
+    // The count is unavailable while the thread is being torn down.
+    let _ = COUNT.try_with(|count| count.set(count.get() + 1));
+}
+
+/// The number of allocations, including reallocations, made so far by the
+/// current thread.
+pub fn allocations() -> u64 {
+    COUNT.try_with(Cell::get).unwrap_or(0)
+}
+
+/// Middleware which counts the allocations made while handling a request.
+///
+/// Link the same `CountAllocations` as both `BeforeMiddleware` and
+/// `AfterMiddleware`, usually first and last, through an `Arc`. The count
+/// for the middleware in between is then available from the request's
+/// extensions under `Allocations`.
+pub struct CountAllocations;
+
+/// The number of allocations made while handling a request, as counted by
+/// `CountAllocations`.
+pub struct Allocations;
+
+impl Key for Allocations { type Value = u64; }
+
+// The count when `CountAllocations` last saw the request.
+struct Started;
+
+impl Key for Started { type Value = u64; }
+
+impl CountAllocations {
+    fn finish(&self, req: &mut Request) {
+        let end = allocations();
+        if let Some(start) = req.extensions.remove::<Started>() {
+            req.extensions.insert::<Allocations>(end - start);
+        }
+    }
+}
+
+impl BeforeMiddleware for CountAllocations {
+    fn before(&self, req: &mut Request) -> IronResult<()> {
+        // Start counting once the extension is inserted, so that inserting
+        // it is not counted.
+        req.extensions.insert::<Started>(0);
+        if let Some(start) = req.extensions.get_mut::<Started>() {
+            *start = allocations();
+        }
+        Ok(())
+    }
+}
+
+impl AfterMiddleware for CountAllocations {
+    fn after(&self, req: &mut Request, res: Response) -> IronResult<Response> {
+        self.finish(req);
+        Ok(res)
+    }
+
+    fn catch(&self, req: &mut Request, err: IronError) -> IronResult<Response> {
+        self.finish(req);
+        Err(err)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::hint;
+    use std::sync::Arc;
+
+    use method::Method;
+    use test::StubRequest;
+    use {Chain, Handler, Request, Response, IronResult};
+    use super::{allocations, Allocations, CountAllocations, CountingAllocator};
+
+    #[global_allocator]
+    static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+    fn noop(_: &mut Request) -> IronResult<Response> {
+        Ok(Response::new())
+    }
+
+    #[test]
+    fn test_noop_chain_does_not_allocate() {
+        let chain = Chain::new(noop);
+        let mut req = StubRequest::new(Method::Get, "http://localhost/").build();
+
+        let start = allocations();
+        let res = chain.handle(&mut req);
+        assert_eq!(allocations() - start, 0);
+        drop(res);
+    }
+
+    #[test]
+    fn test_count_allocations() {
+        let counter = Arc::new(CountAllocations);
+        let mut chain = Chain::new(|_: &mut Request| {
+            hint::black_box(vec![0u8; 16]);
+            Ok(Response::new())
+        });
+        chain.link_before(counter.clone());
+        chain.link_after(counter);
+
+        let mut req = StubRequest::new(Method::Get, "http://localhost/").build();
+        chain.handle(&mut req).unwrap();
+        assert_eq!(req.extensions.get::<Allocations>(), Some(&1));
+    }
+}
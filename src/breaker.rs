@@ -0,0 +1,440 @@
+//! Circuit breakers, to fail fast while a downstream service is down.
+//!
+//! A `Breaker` wraps calls to one service. After a number of consecutive
+//! failures it opens, and calls fail immediately with `BreakerError::Open`
+//! instead of waiting on the service. Once a cooldown has passed it lets a
+//! single trial call through: if that succeeds the breaker closes again,
+//! and otherwise it stays open for another cooldown. A trial which is not
+//! reported within a cooldown is abandoned, and another is let through.
+//!
+//! `Breakers` holds named breakers sharing one configuration, and as
+//! `BeforeMiddleware` makes them available to handlers through
+//! `Breakers::of`:
+//!
+//! ```ignore
+//! chain.link_before(Breakers::new(5, Duration::from_secs(30)));
+//!
+//! // In a handler:
+//! let breaker = Breakers::of(req).unwrap().get("billing");
+//! match breaker.call(|| client.get("http://billing.internal/balance")) {
+//!     Ok(res) => ...,
+//!     Err(BreakerError::Open) => return Ok(Response::with(status::ServiceUnavailable)),
+//!     Err(BreakerError::Failed(e)) => return Err(IronError::new(e, status::BadGateway))
+//! }
+//! ```
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, SystemTime};
+
+use typemap::Key;
+
+use clock::{Clock, SystemClock};
+use {BeforeMiddleware, Request, IronResult};
+
+/// The state of a `Breaker`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BreakerState {
+    /// Calls go through, and failures are counted.
+    Closed,
+
+    /// Calls fail immediately until the cooldown has passed.
+    Open,
+
+    /// The cooldown has passed, and the next call is a trial.
+    HalfOpen
+}
+
+/// A circuit breaker for calls to one service.
+///
+/// Clones share the same state.
+#[derive(Clone)]
+pub struct Breaker {
+    inner: Arc<Mutex<Inner>>,
+    threshold: u32,
+    cooldown: Duration,
+    clock: Arc<Clock>
+}
+
+struct Inner {
+    state: State,
+    // Advanced whenever the breaker opens, or a trial is abandoned, so that
+    // the outcomes of calls which began before then can be ignored.
+    epoch: u64
+}
+
+enum State {
+    Closed { failures: u32 },
+    Open { until: SystemTime },
+    // When the trial call was let through, if it is under way.
+    HalfOpen { trial: Option<SystemTime> }
+}
+
+impl Breaker {
+    /// Create a closed breaker, which opens after `threshold` consecutive
+    /// failures and stays open for `cooldown`.
+    pub fn new(threshold: u32, cooldown: Duration) -> Breaker {
+        Breaker::with_clock(threshold, cooldown, Arc::new(SystemClock))
+    }
+
+    fn with_clock(threshold: u32, cooldown: Duration, clock: Arc<Clock>) -> Breaker {
+        Breaker {
+            inner: Arc::new(Mutex::new(Inner { state: State::Closed { failures: 0 }, epoch: 0 })),
+            threshold: threshold,
+            cooldown: cooldown,
+            clock: clock
+        }
+    }
+
+    /// Use `clock` to time the cooldown, instead of the system clock.
+    pub fn clock<C: Clock>(&mut self, clock: C) -> &mut Breaker {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// The current state.
+    pub fn state(&self) -> BreakerState {
+        let mut inner = self.lock();
+        self.expire(&mut inner);
+        match inner.state {
+            State::Closed { .. } => BreakerState::Closed,
+            State::Open { .. } => BreakerState::Open,
+            State::HalfOpen { .. } => BreakerState::HalfOpen
+        }
+    }
+
+    /// Call `f` unless the breaker is open, recording whether it failed.
+    ///
+    /// A call which panics is recorded as a failure.
+    pub fn call<T, E, F>(&self, f: F) -> Result<T, BreakerError<E>>
+    where F: FnOnce() -> Result<T, E> {
+        let permit = match self.allow() {
+            Some(permit) => permit,
+            None => return Err(BreakerError::Open)
+        };
+
+        match f() {
+            Ok(value) => {
+                permit.succeeded();
+                Ok(value)
+            },
+            Err(e) => {
+                permit.failed();
+                Err(BreakerError::Failed(e))
+            }
+        }
+    }
+
+    /// A permit to make a call now, unless the breaker is open or its
+    /// trial call is under way. The outcome of the call is reported
+    /// through the permit.
+    ///
+    /// `call` does this itself; permits are for calls which cannot be
+    /// wrapped in a closure.
+    pub fn allow(&self) -> Option<Permit> {
+        let mut inner = self.lock();
+        self.expire(&mut inner);
+        let now = self.clock.now();
+        let allowed = match inner.state {
+            State::Closed { .. } => true,
+            State::Open { .. } => false,
+            State::HalfOpen { trial: Some(_) } => false,
+            State::HalfOpen { ref mut trial } => {
+                *trial = Some(now);
+                true
+            }
+        };
+
+        if !allowed { return None }
+        Some(Permit { breaker: self.clone(), epoch: inner.epoch, reported: false })
+    }
+
+    // Record the outcome of a call made with a permit from `epoch`.
+    fn report(&self, epoch: u64, succeeded: bool) {
+        let mut inner = self.lock();
+        // The breaker has opened since the call began, so its outcome says
+        // nothing about the service now.
+        if epoch != inner.epoch { return }
+
+        if succeeded {
+            inner.state = State::Closed { failures: 0 };
+            return
+        }
+
+        let open = match inner.state {
+            State::Closed { ref mut failures } => {
+                *failures += 1;
+                *failures >= self.threshold
+            },
+            State::Open { .. } => false,
+            State::HalfOpen { .. } => true
+        };
+        if open {
+            warn!("Circuit breaker opened for {}s", self.cooldown.as_secs());
+            inner.state = State::Open { until: self.clock.now() + self.cooldown };
+            inner.epoch += 1;
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<Inner> {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    // Let a trial through once the cooldown has passed, including when an
+    // earlier trial has not been reported within a cooldown.
+    fn expire(&self, inner: &mut Inner) {
+        let now = self.clock.now();
+        let expired = match inner.state {
+            State::Open { until } => now >= until,
+            State::HalfOpen { trial: Some(started) } => now >= started + self.cooldown,
+            _ => false
+        };
+        if expired {
+            if let State::HalfOpen { .. } = inner.state { inner.epoch += 1 }
+            inner.state = State::HalfOpen { trial: None };
+        }
+    }
+}
+
+/// Permission from a `Breaker` to make one call, through which its outcome
+/// is reported.
+///
+/// A permit dropped without a report, as when the call panics, counts as a
+/// failure. Outcomes of calls which began before the breaker last opened
+/// are ignored.
+pub struct Permit {
+    breaker: Breaker,
+    epoch: u64,
+    reported: bool
+}
+
+impl Permit {
+    /// Report a successful call, closing the breaker.
+    pub fn succeeded(mut self) {
+        self.reported = true;
+        self.breaker.report(self.epoch, true);
+    }
+
+    /// Report a failed call, opening the breaker if this was the trial call
+    /// or the failures have reached the threshold.
+    pub fn failed(mut self) {
+        self.reported = true;
+        self.breaker.report(self.epoch, false);
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        if !self.reported { self.breaker.report(self.epoch, false) }
+    }
+}
+
+impl fmt::Debug for Permit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Permit {{ epoch: {} }}", self.epoch)
+    }
+}
+
+impl fmt::Debug for Breaker {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Breaker {{ state: {:?} }}", self.state())
+    }
+}
+
+/// The error from a call through a `Breaker`.
+#[derive(Debug)]
+pub enum BreakerError<E> {
+    /// The breaker is open, so the call was not made.
+    Open,
+
+    /// The call was made, and failed.
+    Failed(E)
+}
+
+impl<E: fmt::Display> fmt::Display for BreakerError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            BreakerError::Open => f.write_str("Circuit breaker is open"),
+            BreakerError::Failed(ref e) => fmt::Display::fmt(e, f)
+        }
+    }
+}
+
+impl<E: Error> Error for BreakerError<E> {
+    fn description(&self) -> &str {
+        match *self {
+            BreakerError::Open => "Circuit breaker is open",
+            BreakerError::Failed(ref e) => e.description()
+        }
+    }
+
+    fn cause(&self) -> Option<&Error> {
+        match *self {
+            BreakerError::Open => None,
+            BreakerError::Failed(ref e) => Some(e)
+        }
+    }
+}
+
+/// Named breakers sharing one configuration, created on first use.
+///
+/// Clones share the same breakers.
+#[derive(Clone)]
+pub struct Breakers {
+    breakers: Arc<Mutex<HashMap<String, Breaker>>>,
+    threshold: u32,
+    cooldown: Duration,
+    clock: Arc<Clock>
+}
+
+impl Key for Breakers { type Value = Breakers; }
+
+impl Breakers {
+    /// Create breakers which open after `threshold` consecutive failures
+    /// and stay open for `cooldown`.
+    pub fn new(threshold: u32, cooldown: Duration) -> Breakers {
+        Breakers {
+            breakers: Arc::new(Mutex::new(HashMap::new())),
+            threshold: threshold,
+            cooldown: cooldown,
+            clock: Arc::new(SystemClock)
+        }
+    }
+
+    /// Use `clock` to time cooldowns, instead of the system clock.
+    pub fn clock<C: Clock>(&mut self, clock: C) -> &mut Breakers {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// The breakers shared with `req`, if they have been linked before
+    /// the current middleware.
+    pub fn of(req: &Request) -> Option<Breakers> {
+        req.extensions.get::<Breakers>().cloned()
+    }
+
+    /// The breaker named `name`.
+    pub fn get(&self, name: &str) -> Breaker {
+        let mut breakers = self.breakers.lock().unwrap_or_else(|e| e.into_inner());
+        breakers.entry(name.into())
+            .or_insert_with(|| Breaker::with_clock(self.threshold, self.cooldown,
+                                                   self.clock.clone()))
+            .clone()
+    }
+
+    /// The state of each breaker, by name.
+    pub fn states(&self) -> HashMap<String, BreakerState> {
+        let breakers = self.breakers.lock().unwrap_or_else(|e| e.into_inner());
+        breakers.iter().map(|(name, breaker)| (name.clone(), breaker.state())).collect()
+    }
+}
+
+impl BeforeMiddleware for Breakers {
+    fn before(&self, req: &mut Request) -> IronResult<()> {
+        req.extensions.insert::<Breakers>(self.clone());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::panic::{self, AssertUnwindSafe};
+    use std::time::Duration;
+
+    use clock::ManualClock;
+    use super::{Breaker, BreakerError, BreakerState, Breakers};
+
+    fn fail(breaker: &Breaker) -> Result<(), BreakerError<()>> {
+        breaker.call(|| Err(()))
+    }
+
+    #[test]
+    fn test_breaker() {
+        let clock = ManualClock::new();
+        let mut breaker = Breaker::new(2, Duration::from_secs(10));
+        breaker.clock(clock.clone());
+
+        assert!(match fail(&breaker) { Err(BreakerError::Failed(())) => true, _ => false });
+        assert_eq!(breaker.call(|| Ok::<_, ()>(1)).ok(), Some(1));
+        fail(&breaker).unwrap_err();
+        assert_eq!(breaker.state(), BreakerState::Closed);
+        fail(&breaker).unwrap_err();
+        assert_eq!(breaker.state(), BreakerState::Open);
+
+        let mut called = false;
+        let result = breaker.call(|| {
+            called = true;
+            Ok::<(), ()>(())
+        });
+        assert!(match result {
+            Err(BreakerError::Open) => true,
+            _ => false
+        });
+        assert!(!called);
+
+        // A failed trial opens the breaker again.
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(breaker.state(), BreakerState::HalfOpen);
+        fail(&breaker).unwrap_err();
+        assert_eq!(breaker.state(), BreakerState::Open);
+
+        // Only one trial is let through, and its success closes the breaker.
+        clock.advance(Duration::from_secs(10));
+        let trial = breaker.allow().unwrap();
+        assert!(breaker.allow().is_none());
+        trial.succeeded();
+        assert_eq!(breaker.state(), BreakerState::Closed);
+    }
+
+    #[test]
+    fn test_panics_fail() {
+        let breaker = Breaker::new(1, Duration::from_secs(10));
+        let panicked = panic::catch_unwind(AssertUnwindSafe(|| {
+            breaker.call(|| -> Result<(), ()> { panic!("client bug") })
+        }));
+        assert!(panicked.is_err());
+        assert_eq!(breaker.state(), BreakerState::Open);
+    }
+
+    #[test]
+    fn test_abandoned_trial() {
+        let clock = ManualClock::new();
+        let mut breaker = Breaker::new(1, Duration::from_secs(10));
+        breaker.clock(clock.clone());
+        fail(&breaker).unwrap_err();
+
+        clock.advance(Duration::from_secs(10));
+        let stuck = breaker.allow().unwrap();
+        clock.advance(Duration::from_secs(9));
+        assert!(breaker.allow().is_none());
+        clock.advance(Duration::from_secs(1));
+        let trial = breaker.allow().unwrap();
+
+        // The abandoned trial's late outcome is ignored.
+        stuck.failed();
+        assert_eq!(breaker.state(), BreakerState::HalfOpen);
+        trial.succeeded();
+        assert_eq!(breaker.state(), BreakerState::Closed);
+    }
+
+    #[test]
+    fn test_stale_successes() {
+        let breaker = Breaker::new(1, Duration::from_secs(10));
+        let slow = breaker.allow().unwrap();
+        fail(&breaker).unwrap_err();
+
+        slow.succeeded();
+        assert_eq!(breaker.state(), BreakerState::Open);
+    }
+
+    #[test]
+    fn test_breakers() {
+        let breakers = Breakers::new(1, Duration::from_secs(10));
+        fail(&breakers.get("a")).unwrap_err();
+        assert_eq!(breakers.get("a").state(), BreakerState::Open);
+        assert_eq!(breakers.clone().get("b").state(), BreakerState::Closed);
+        assert_eq!(breakers.states().len(), 2);
+    }
+}
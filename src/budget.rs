@@ -0,0 +1,219 @@
+//! Budgets for the downstream calls made while handling a request.
+//!
+//! A handler retrying a failing service can multiply the load on it just
+//! when it is struggling. `CallBudget` gives each request a `Budget` of
+//! downstream calls and time, which `Client::send_within` spends. Once the
+//! budget is spent, further calls fail without being made, and if the
+//! handler then fails, the response is `504 Gateway Timeout`:
+//!
+//! ```ignore
+//! let budget = CallBudget::new(3, Duration::from_secs(2));
+//! let mut chain = Chain::new(handler);
+//! chain.link_before(budget);
+//! chain.link_after(budget);
+//!
+//! // In a handler:
+//! let budget = Budget::of(req).unwrap();
+//! let res = itry!(client.send_within(&budget, Method::Get, url, Headers::new(), &[]));
+//! ```
+//!
+//! The time is checked before each call; a call under way is only bounded
+//! by the client's own timeout. A response whose `Retry-After` asks the
+//! caller to wait longer than the time left spends the whole budget, as no
+//! retry could succeed in time.
+
+use std::error::Error;
+use std::fmt;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use typemap::Key;
+
+use {status, BeforeMiddleware, AfterMiddleware, Request, Response, IronResult, IronError};
+
+/// The downstream calls and time left to one request.
+///
+/// Clones share the same budget.
+#[derive(Clone)]
+pub struct Budget {
+    inner: Arc<Mutex<Inner>>
+}
+
+struct Inner {
+    calls: u32,
+    deadline: Instant,
+    exceeded: bool
+}
+
+impl Key for Budget { type Value = Budget; }
+
+impl Budget {
+    /// A budget of `calls` calls, to be made within `time` from now.
+    pub fn new(calls: u32, time: Duration) -> Budget {
+        Budget {
+            inner: Arc::new(Mutex::new(Inner {
+                calls: calls,
+                deadline: Instant::now() + time,
+                exceeded: false
+            }))
+        }
+    }
+
+    /// The budget given to `req` by `CallBudget`, if any.
+    pub fn of(req: &Request) -> Option<Budget> {
+        req.extensions.get::<Budget>().cloned()
+    }
+
+    /// Spend one call, failing if no calls or time are left.
+    pub fn charge(&self) -> Result<(), BudgetExceeded> {
+        let mut inner = self.lock();
+        if inner.exceeded || inner.calls == 0 || Instant::now() >= inner.deadline {
+            inner.exceeded = true;
+            return Err(BudgetExceeded)
+        }
+        inner.calls -= 1;
+        Ok(())
+    }
+
+    /// Spend the whole budget, so that further calls fail.
+    pub fn exhaust(&self) {
+        self.lock().exceeded = true;
+    }
+
+    /// The number of calls left.
+    pub fn calls_left(&self) -> u32 {
+        let inner = self.lock();
+        if inner.exceeded { 0 } else { inner.calls }
+    }
+
+    /// The time left.
+    pub fn time_left(&self) -> Duration {
+        let inner = self.lock();
+        if inner.exceeded { return Duration::from_secs(0) }
+        let now = Instant::now();
+        if now < inner.deadline { inner.deadline - now } else { Duration::from_secs(0) }
+    }
+
+    /// Whether a call has been refused, or the budget exhausted.
+    pub fn is_exceeded(&self) -> bool {
+        self.lock().exceeded
+    }
+
+    fn lock(&self) -> MutexGuard<Inner> {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl fmt::Debug for Budget {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Budget {{ calls_left: {}, time_left: {:?} }}",
+               self.calls_left(), self.time_left())
+    }
+}
+
+/// Middleware giving each request a `Budget`.
+///
+/// As `AfterMiddleware`, it answers with `504 Gateway Timeout` when the
+/// handler fails after its budget was exceeded.
+#[derive(Debug, Clone, Copy)]
+pub struct CallBudget {
+    calls: u32,
+    time: Duration
+}
+
+impl CallBudget {
+    /// Allow each request `calls` downstream calls within `time` of the
+    /// middleware running.
+    pub fn new(calls: u32, time: Duration) -> CallBudget {
+        CallBudget { calls: calls, time: time }
+    }
+}
+
+impl BeforeMiddleware for CallBudget {
+    fn before(&self, req: &mut Request) -> IronResult<()> {
+        req.extensions.insert::<Budget>(Budget::new(self.calls, self.time));
+        Ok(())
+    }
+}
+
+impl AfterMiddleware for CallBudget {
+    fn after(&self, _: &mut Request, res: Response) -> IronResult<Response> {
+        Ok(res)
+    }
+
+    fn catch(&self, req: &mut Request, mut err: IronError) -> IronResult<Response> {
+        if Budget::of(req).map_or(false, |budget| budget.is_exceeded()) {
+            err.response.status = Some(status::GatewayTimeout);
+        }
+        Err(err)
+    }
+}
+
+/// The error for a call refused because its request's budget is spent.
+#[derive(Debug)]
+pub struct BudgetExceeded;
+
+impl fmt::Display for BudgetExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.description())
+    }
+}
+
+impl Error for BudgetExceeded {
+    fn description(&self) -> &str {
+        "Downstream call budget exceeded"
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use method::Method;
+    use status;
+    use test::{MiddlewareHarness, StubRequest};
+    use {Chain, IronError, Request, Response};
+    use super::{Budget, CallBudget};
+
+    #[test]
+    fn test_budget() {
+        let budget = Budget::new(2, Duration::from_secs(60));
+        assert!(budget.charge().is_ok());
+        assert!(budget.clone().charge().is_ok());
+        assert!(!budget.is_exceeded());
+        assert!(budget.charge().is_err());
+        assert!(budget.is_exceeded());
+        assert_eq!(budget.calls_left(), 0);
+
+        let budget = Budget::new(2, Duration::from_secs(0));
+        assert!(budget.charge().is_err());
+
+        let budget = Budget::new(2, Duration::from_secs(60));
+        budget.exhaust();
+        assert!(budget.charge().is_err());
+        assert_eq!(budget.time_left(), Duration::from_secs(0));
+    }
+
+    #[test]
+    fn test_gateway_timeout() {
+        let mut chain = Chain::new(|req: &mut Request| {
+            let budget = Budget::of(req).unwrap();
+            let calls = req.url.path()[0].parse().unwrap();
+            for _ in 0..calls {
+                try!(budget.charge().map_err(|e| IronError::new(e, status::BadGateway)));
+            }
+            Ok(Response::with(status::Ok))
+        });
+        let budget = CallBudget::new(2, Duration::from_secs(60));
+        chain.link_before(budget);
+        chain.link_after(budget);
+        let harness = MiddlewareHarness::new(chain);
+
+        let status = |calls: u32| {
+            let url = format!("http://localhost/{}", calls);
+            harness.handle(StubRequest::new(Method::Get, &url)).status()
+        };
+        assert_eq!(status(2), Some(status::Ok));
+        assert_eq!(status(3), Some(status::GatewayTimeout));
+    }
+}
@@ -0,0 +1,271 @@
+//! A key-value cache interface shared by session stores, rate limiters and
+//! response caches.
+//!
+//! `Cache` is the interface backends implement, and `MemoryCache` is an
+//! in-process implementation which evicts the least recently used entries.
+//! To make one cache available to every request, link a `SharedCache` as
+//! `BeforeMiddleware` and retrieve it with `SharedCache::of`:
+//!
+//! ```ignore
+//! let cache = SharedCache::new(MemoryCache::new(10_000));
+//!
+//! let mut chain = Chain::new(handler);
+//! chain.link_before(cache.clone());
+//! chain.link_around(HttpCache::new(cache));
+//!
+//! // In a handler:
+//! let cache = SharedCache::of(req).unwrap();
+//! cache.set("greeting", b"hello".to_vec(), Some(Duration::from_secs(60)));
+//! ```
+
+use std::collections::{HashMap, BTreeMap};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, SystemTime};
+
+use typemap::Key;
+
+use clock::{Clock, SystemClock};
+
+use {BeforeMiddleware, Request, IronResult};
+
+/// A store of byte values under string keys, with optional expiry.
+///
+/// Implementations may evict entries before they expire, so a cache must
+/// never be the only copy of data.
+pub trait Cache: Send + Sync + 'static {
+    /// The value stored under `key`, unless it is missing or has expired.
+    fn get(&self, key: &str) -> Option<Vec<u8>>;
+
+    /// Store `value` under `key`, replacing any previous value, to expire
+    /// after `ttl` if one is given.
+    fn set(&self, key: &str, value: Vec<u8>, ttl: Option<Duration>);
+
+    /// Remove the value stored under `key`, returning whether there was one.
+    fn delete(&self, key: &str) -> bool;
+}
+
+/// An in-memory `Cache` holding at most a fixed number of entries.
+///
+/// When full, storing a new entry evicts the least recently used one.
+/// Expired entries are removed when they are next looked up.
+pub struct MemoryCache {
+    capacity: usize,
+    clock: Box<Clock>,
+    inner: Mutex<Lru>
+}
+
+struct Lru {
+    entries: HashMap<String, Entry>,
+    // Keys by the tick at which they were last used.
+    recency: BTreeMap<u64, String>,
+    tick: u64
+}
+
+struct Entry {
+    value: Vec<u8>,
+    expires: Option<SystemTime>,
+    used: u64
+}
+
+impl MemoryCache {
+    /// Create an empty cache holding at most `capacity` entries.
+    ///
+    /// ## Panics
+    ///
+    /// Panics if `capacity` is zero.
+    pub fn new(capacity: usize) -> MemoryCache {
+        MemoryCache::with_clock(capacity, SystemClock)
+    }
+
+    /// Create an empty cache holding at most `capacity` entries, which uses
+    /// `clock` to expire them.
+    ///
+    /// ## Panics
+    ///
+    /// Panics if `capacity` is zero.
+    pub fn with_clock<C: Clock>(capacity: usize, clock: C) -> MemoryCache {
+        assert!(capacity > 0, "MemoryCache capacity must be positive");
+
+        MemoryCache {
+            capacity: capacity,
+            clock: Box::new(clock),
+            inner: Mutex::new(Lru {
+                entries: HashMap::new(),
+                recency: BTreeMap::new(),
+                tick: 0
+            })
+        }
+    }
+
+    /// The number of entries in the cache, including any which have expired
+    /// but not yet been removed.
+    pub fn len(&self) -> usize {
+        self.lock().entries.len()
+    }
+
+    /// Whether the cache has no entries.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn lock(&self) -> MutexGuard<Lru> {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Lru {
+    fn next_tick(&mut self) -> u64 {
+        self.tick += 1;
+        self.tick
+    }
+
+    fn remove(&mut self, key: &str) -> Option<Entry> {
+        let entry = self.entries.remove(key);
+        if let Some(ref entry) = entry { self.recency.remove(&entry.used); }
+        entry
+    }
+}
+
+impl Cache for MemoryCache {
+    fn get(&self, key: &str) -> Option<Vec<u8>> {
+        let now = self.clock.now();
+        let mut lru = self.lock();
+        let tick = lru.next_tick();
+
+        let (expired, used) = match lru.entries.get(key) {
+            Some(entry) => (entry.expires.map_or(false, |at| at <= now), entry.used),
+            None => return None
+        };
+
+        if expired {
+            lru.remove(key);
+            return None;
+        }
+
+        lru.recency.remove(&used);
+        lru.recency.insert(tick, key.to_owned());
+
+        let entry = lru.entries.get_mut(key).unwrap();
+        entry.used = tick;
+        Some(entry.value.clone())
+    }
+
+    fn set(&self, key: &str, value: Vec<u8>, ttl: Option<Duration>) {
+        let mut lru = self.lock();
+        let tick = lru.next_tick();
+
+        lru.remove(key);
+
+        while lru.entries.len() >= self.capacity {
+            let oldest = match lru.recency.iter().next() {
+                Some((_, key)) => key.clone(),
+                None => break
+            };
+            lru.remove(&oldest);
+        }
+
+        lru.recency.insert(tick, key.to_owned());
+        lru.entries.insert(key.to_owned(), Entry {
+            value: value,
+            expires: ttl.map(|ttl| self.clock.now() + ttl),
+            used: tick
+        });
+    }
+
+    fn delete(&self, key: &str) -> bool {
+        self.lock().remove(key).is_some()
+    }
+}
+
+/// A handle to a cache shared between middleware and handlers.
+///
+/// As `BeforeMiddleware`, it makes the cache available to later middleware
+/// and handlers through `SharedCache::of`. It also implements `Cache`
+/// itself, so it can be given to middleware which use a cache directly.
+#[derive(Clone)]
+pub struct SharedCache(Arc<Cache>);
+
+impl Key for SharedCache { type Value = Arc<Cache>; }
+
+impl SharedCache {
+    /// Share `cache`.
+    pub fn new<C: Cache>(cache: C) -> SharedCache {
+        SharedCache(Arc::new(cache))
+    }
+
+    /// The cache shared with `req`, if a `SharedCache` has been linked
+    /// before the current middleware.
+    pub fn of(req: &Request) -> Option<Arc<Cache>> {
+        req.extensions.get::<SharedCache>().cloned()
+    }
+}
+
+impl Cache for SharedCache {
+    fn get(&self, key: &str) -> Option<Vec<u8>> { self.0.get(key) }
+
+    fn set(&self, key: &str, value: Vec<u8>, ttl: Option<Duration>) {
+        self.0.set(key, value, ttl)
+    }
+
+    fn delete(&self, key: &str) -> bool { self.0.delete(key) }
+}
+
+impl BeforeMiddleware for SharedCache {
+    fn before(&self, req: &mut Request) -> IronResult<()> {
+        req.extensions.insert::<SharedCache>(self.0.clone());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use clock::ManualClock;
+    use super::{Cache, MemoryCache};
+
+    #[test]
+    fn test_get_set_delete() {
+        let cache = MemoryCache::new(10);
+        assert_eq!(cache.get("a"), None);
+
+        cache.set("a", b"1".to_vec(), None);
+        assert_eq!(cache.get("a"), Some(b"1".to_vec()));
+
+        cache.set("a", b"2".to_vec(), None);
+        assert_eq!(cache.get("a"), Some(b"2".to_vec()));
+        assert_eq!(cache.len(), 1);
+
+        assert!(cache.delete("a"));
+        assert!(!cache.delete("a"));
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn test_evicts_least_recently_used() {
+        let cache = MemoryCache::new(2);
+        cache.set("a", b"1".to_vec(), None);
+        cache.set("b", b"2".to_vec(), None);
+
+        // Using "a" makes "b" the least recently used.
+        cache.get("a");
+        cache.set("c", b"3".to_vec(), None);
+
+        assert_eq!(cache.get("b"), None);
+        assert_eq!(cache.get("a"), Some(b"1".to_vec()));
+        assert_eq!(cache.get("c"), Some(b"3".to_vec()));
+    }
+
+    #[test]
+    fn test_expiry() {
+        let clock = ManualClock::new();
+        let cache = MemoryCache::with_clock(2, clock.clone());
+        cache.set("a", b"1".to_vec(), Some(Duration::from_secs(10)));
+        cache.set("b", b"2".to_vec(), Some(Duration::from_secs(60)));
+
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(cache.get("a"), None);
+        assert_eq!(cache.get("b"), Some(b"2".to_vec()));
+        assert_eq!(cache.len(), 1);
+    }
+}
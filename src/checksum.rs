@@ -0,0 +1,257 @@
+//! Integrity checksums for request and response bodies.
+//!
+//! `Checksum` computes a digest of each response body and sends it in a
+//! `Content-MD5` or `Digest` header, depending on the chosen `Algorithm`.
+//! It can also check the `Content-MD5` header of incoming requests, rejecting
+//! bodies which do not match it:
+//!
+//! ```ignore
+//! let mut checksum = Checksum::new(Algorithm::Sha256);
+//! checksum.validate_uploads(10 * 1024 * 1024);
+//!
+//! let mut chain = Chain::new(handler);
+//! chain.link_before(checksum.clone());
+//! chain.link_after(checksum);
+//! ```
+//!
+//! The digest covers the body as it is sent, so `Checksum` should be linked
+//! after middleware which transforms the body, such as `Compression`.
+
+use std::error::Error;
+use std::fmt;
+use std::io::{self, Read};
+
+use md5;
+use rustc_serialize::base64::{ToBase64, FromBase64, STANDARD};
+use sha2::{Sha256, Digest};
+
+use response::ResponseBody;
+use {status, BeforeMiddleware, AfterMiddleware, Request, Response, IronResult, IronError};
+
+// Request bodies up to this size are checked in memory; larger ones are
+// buffered in a temporary file.
+const IN_MEMORY_LIMIT: usize = 64 * 1024;
+
+/// A digest algorithm for response bodies.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Algorithm {
+    /// MD5, sent in a `Content-MD5` header.
+    Md5,
+
+    /// SHA-256, sent in a `Digest` header.
+    Sha256
+}
+
+impl Algorithm {
+    /// The digest of `bytes`.
+    pub fn digest(&self, bytes: &[u8]) -> Vec<u8> {
+        match *self {
+            Algorithm::Md5 => md5::compute(bytes).0.to_vec(),
+            Algorithm::Sha256 => Sha256::digest(bytes).to_vec()
+        }
+    }
+}
+
+/// The HMAC-SHA256 of `message` under `key`, for signing messages with a
+/// shared secret.
+pub fn hmac_sha256(key: &[u8], message: &[u8]) -> Vec<u8> {
+    const BLOCK_SIZE: usize = 64;
+
+    let mut block = [0; BLOCK_SIZE];
+    if key.len() > BLOCK_SIZE {
+        block[..32].copy_from_slice(&Sha256::digest(key));
+    } else {
+        block[..key.len()].copy_from_slice(key);
+    }
+
+    let pad = |byte: u8| block.iter().map(|b| b ^ byte).collect::<Vec<u8>>();
+    let inner = Sha256::new().chain(pad(0x36)).chain(message).finalize();
+    Sha256::new().chain(pad(0x5c)).chain(inner).finalize().to_vec()
+}
+
+/// Whether `a` and `b` are equal, taking the same time wherever they
+/// differ, for comparing signatures without revealing how much of a forged
+/// one is correct.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Middleware which adds checksums to responses and optionally verifies
+/// those of requests.
+///
+/// As an `AfterMiddleware` it buffers each response body in memory to
+/// compute its digest. As a `BeforeMiddleware` it does nothing unless
+/// `validate_uploads` has been called.
+#[derive(Debug, Clone)]
+pub struct Checksum {
+    algorithm: Algorithm,
+    validate: Option<u64>
+}
+
+impl Checksum {
+    /// Add checksums computed with `algorithm` to responses.
+    pub fn new(algorithm: Algorithm) -> Checksum {
+        Checksum { algorithm: algorithm, validate: None }
+    }
+
+    /// Verify the `Content-MD5` header of requests which have one, accepting
+    /// bodies of up to `limit` bytes.
+    ///
+    /// Requests whose body does not match are rejected with a 400, and those
+    /// whose body is too long with a 413. The body is buffered, and rewound
+    /// for the handler.
+    pub fn validate_uploads(&mut self, limit: u64) -> &mut Checksum {
+        self.validate = Some(limit);
+        self
+    }
+
+    // Buffer the body of `res` and set the header carrying its digest.
+    fn sign(&self, res: &mut Response) -> io::Result<()> {
+        if res.status == Some(status::NoContent) || res.status == Some(status::NotModified) {
+            return Ok(());
+        }
+
+        let mut body = match res.body.take() {
+            Some(body) => body,
+            None => return Ok(())
+        };
+
+        let mut bytes = Vec::new();
+        try!(body.write_body(&mut ResponseBody::new(&mut bytes)));
+
+        let digest = self.algorithm.digest(&bytes).to_base64(STANDARD);
+
+        match self.algorithm {
+            Algorithm::Md5 => res.headers.set_raw("Content-MD5", vec![digest.into_bytes()]),
+            Algorithm::Sha256 => {
+                res.headers.set_raw("Digest", vec![format!("SHA-256={}", digest).into_bytes()])
+            }
+        }
+
+        res.body = Some(Box::new(bytes));
+        Ok(())
+    }
+}
+
+impl BeforeMiddleware for Checksum {
+    fn before(&self, req: &mut Request) -> IronResult<()> {
+        let limit = match self.validate {
+            Some(limit) => limit,
+            None => return Ok(())
+        };
+
+        let expected = match req.headers.get_raw("Content-MD5") {
+            Some(raw) if raw.len() == 1 => raw[0].from_base64().ok(),
+            Some(_) => None,
+            None => return Ok(())
+        };
+
+        let expected = match expected {
+            Some(expected) => expected,
+            None => return Err(IronError::new(ChecksumMismatch, status::BadRequest))
+        };
+
+        if let Err(e) = req.body.buffer_spilling(IN_MEMORY_LIMIT, limit) {
+            return Err(match e.kind() {
+                io::ErrorKind::InvalidData => IronError::new(e, status::PayloadTooLarge),
+                _ => IronError::new(e, status::BadRequest)
+            });
+        }
+
+        let mut context = md5::Context::new();
+        let mut buf = [0; 8192];
+
+        loop {
+            match req.body.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => context.consume(&buf[..n]),
+                Err(e) => return Err(IronError::new(e, status::BadRequest))
+            }
+        }
+
+        if let Err(e) = req.body.rewind() {
+            return Err(IronError::new(e, status::InternalServerError));
+        }
+
+        if context.compute().0[..] != expected[..] {
+            return Err(IronError::new(ChecksumMismatch, status::BadRequest));
+        }
+
+        Ok(())
+    }
+}
+
+impl AfterMiddleware for Checksum {
+    fn after(&self, _: &mut Request, mut res: Response) -> IronResult<Response> {
+        match self.sign(&mut res) {
+            Ok(()) => Ok(res),
+            Err(e) => Err(IronError::new(e, status::InternalServerError))
+        }
+    }
+}
+
+/// The error used when a request body does not match its `Content-MD5`
+/// header, or the header is malformed.
+#[derive(Debug)]
+pub struct ChecksumMismatch;
+
+impl fmt::Display for ChecksumMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.description())
+    }
+}
+
+impl Error for ChecksumMismatch {
+    fn description(&self) -> &str {
+        "Request body does not match its Content-MD5 header"
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rustc_serialize::base64::{ToBase64, STANDARD};
+    use rustc_serialize::hex::ToHex;
+
+    use super::{hmac_sha256, Algorithm, Checksum};
+    use Response;
+
+    #[test]
+    fn test_digests() {
+        assert_eq!(Algorithm::Md5.digest(b"").to_hex(), "d41d8cd98f00b204e9800998ecf8427e");
+        assert_eq!(Algorithm::Sha256.digest(b"abc").to_hex(),
+                   "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+    }
+
+    #[test]
+    fn test_hmac_sha256() {
+        // RFC 4231, test cases 2 and 6.
+        assert_eq!(hmac_sha256(b"Jefe", b"what do ya want for nothing?").to_hex(),
+                   "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843");
+        assert_eq!(hmac_sha256(&[0xaa; 131],
+                               b"Test Using Larger Than Block-Size Key - Hash Key First")
+                       .to_hex(),
+                   "60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54");
+    }
+
+    #[test]
+    fn test_response_headers() {
+        let mut res = Response::with("hello");
+        Checksum::new(Algorithm::Md5).sign(&mut res).unwrap();
+        assert_eq!(res.headers.get_raw("Content-MD5").unwrap(),
+                   &[Algorithm::Md5.digest(b"hello").to_base64(STANDARD).into_bytes()]);
+
+        let mut res = Response::with("hello");
+        Checksum::new(Algorithm::Sha256).sign(&mut res).unwrap();
+        let expected = format!("SHA-256={}",
+                               Algorithm::Sha256.digest(b"hello").to_base64(STANDARD));
+        assert_eq!(res.headers.get_raw("Digest").unwrap(), &[expected.into_bytes()]);
+        assert!(res.body.is_some());
+    }
+
+    #[test]
+    fn test_empty_responses_untouched() {
+        let mut res = Response::new();
+        Checksum::new(Algorithm::Md5).sign(&mut res).unwrap();
+        assert!(res.headers.get_raw("Content-MD5").is_none());
+    }
+}
@@ -0,0 +1,89 @@
+//! Describing a server's setup, for deployment checks.
+//!
+//! `describe` prints the settings `Iron::listen` will use and the
+//! middleware of the server's `Chain`, in the order requests pass through
+//! them. It is meant to be wired to a flag in the application's binary:
+//!
+//! ```ignore
+//! let iron = Iron::from_env(chain).unwrap();
+//! if env::args().any(|arg| arg == "--describe") {
+//!     iron::cli::describe(&iron);
+//! } else {
+//!     iron.listen().unwrap();
+//! }
+//! ```
+
+use std::fmt::Write;
+use std::time::Duration;
+
+use {Chain, Iron};
+
+/// Print the description of `iron` to standard output.
+pub fn describe(iron: &Iron<Chain>) {
+    print!("{}", description(iron));
+}
+
+/// Describe the settings and middleware of `iron`.
+pub fn description(iron: &Iron<Chain>) -> String {
+    let config = iron.config();
+    let chain = &iron.handler;
+    let mut out = String::new();
+
+    let _ = writeln!(out, "Listen address: {} (http, {} threads)", config.addr, config.threads);
+    let _ = writeln!(out, "Timeouts: keep-alive {}, read {}, write {}",
+                     seconds(config.timeouts.keep_alive), seconds(config.timeouts.read),
+                     seconds(config.timeouts.write));
+
+    let limits = &config.limits;
+    let _ = writeln!(out, "Limits: URI {} bytes, {} headers of {} bytes, {} header bytes in total",
+                     limits.max_uri_length, limits.max_headers, limits.max_header_size,
+                     limits.max_header_bytes);
+
+    let _ = writeln!(out, "Chain:");
+    for (i, name) in chain.before_names().iter().enumerate() {
+        let _ = writeln!(out, "  before  {}. {}", i + 1, name);
+    }
+    for name in chain.around_names().iter().rev() {
+        let _ = writeln!(out, "  around     {}", name);
+    }
+    let _ = writeln!(out, "  handler    {}", chain.handler_name());
+    for (i, name) in chain.after_names().iter().enumerate() {
+        let _ = writeln!(out, "  after   {}. {}", i + 1, name);
+    }
+
+    out
+}
+
+fn seconds(timeout: Option<Duration>) -> String {
+    match timeout {
+        Some(timeout) => format!("{}s", timeout.as_secs()),
+        None => "none".to_owned()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use middleware::DevErrorPage;
+    use {status, BeforeMiddleware, Chain, Iron, IronResult, Request, Response};
+    use super::description;
+
+    struct Auth;
+
+    impl BeforeMiddleware for Auth {
+        fn before(&self, _: &mut Request) -> IronResult<()> { Ok(()) }
+    }
+
+    #[test]
+    fn test_description() {
+        let mut chain = Chain::new(|_: &mut Request| Ok(Response::with(status::Ok)));
+        chain.link_before(Auth);
+        chain.link_around(DevErrorPage::new(true));
+        let iron = Iron::new(chain);
+
+        let text = description(&iron);
+        assert!(text.contains("Listen address: 0.0.0.0:3000"), "{}", text);
+        assert!(text.contains("keep-alive 5s, read 30s, write 1s"), "{}", text);
+        assert!(text.contains("before  1. iron::cli::test::Auth\n"), "{}", text);
+        assert!(text.contains("around     iron::middleware::dev::DevErrorPage\n"), "{}", text);
+    }
+}
@@ -0,0 +1,316 @@
+//! A minimal HTTP client, for middleware which talks to other servers.
+//!
+//! `Client` keeps connections alive in a pool shared by all the requests it
+//! sends, applies timeouts, follows redirects up to a limit, and reads each
+//! response body into memory, up to a limit:
+//!
+//! ```ignore
+//! let mut client = Client::new();
+//! client.timeout(Some(Duration::from_secs(2)));
+//!
+//! let res = try!(client.get("http://localhost:8080/health"));
+//! if res.status != status::Ok {
+//!     warn!("Backend unhealthy: {}", res.status);
+//! }
+//! ```
+//!
+//! `https` URLs are supported when Iron is built with the `ssl` feature.
+
+use std::error::Error;
+use std::fmt;
+use std::io::Read;
+use std::time::Duration;
+
+use hyper;
+use hyper::client::RedirectPolicy;
+use hyper::client::pool;
+use rust_url;
+
+use budget::Budget;
+use {headers, status, Headers, Url};
+use method::Method;
+
+/// A pooling HTTP client.
+///
+/// A `Client` is `Send` and `Sync`, and is meant to be shared, for instance
+/// in an `Arc`, so that its connections are reused.
+pub struct Client {
+    inner: hyper::Client,
+    redirects: u32,
+    limit: usize
+}
+
+/// A response received by a `Client`.
+#[derive(Debug)]
+pub struct ClientResponse {
+    /// The status of the response.
+    pub status: status::Status,
+
+    /// The headers of the response.
+    pub headers: Headers,
+
+    /// The URL the response came from, after any redirects.
+    pub url: Url,
+
+    /// The whole body of the response.
+    pub body: Vec<u8>
+}
+
+/// An error sending a request or receiving its response.
+#[derive(Debug)]
+pub enum ClientError {
+    /// The URL was invalid, the connection failed, or the response was
+    /// malformed.
+    Http(hyper::Error),
+
+    /// More redirects were received than the client follows.
+    TooManyRedirects,
+
+    /// The response body was longer than the client's limit.
+    TooLarge,
+
+    /// The request's call budget was spent, so no request was sent.
+    BudgetExceeded
+}
+
+impl Client {
+    /// Create a client keeping up to five idle connections per host open,
+    /// with a timeout of thirty seconds, following up to five redirects,
+    /// and reading bodies of up to 10 MiB.
+    pub fn new() -> Client {
+        Client::with_max_idle(5)
+    }
+
+    /// Create a client keeping up to `max_idle` idle connections per host
+    /// open.
+    pub fn with_max_idle(max_idle: usize) -> Client {
+        let mut inner = hyper::Client::with_pool_config(pool::Config { max_idle: max_idle });
+        inner.set_redirect_policy(RedirectPolicy::FollowNone);
+        let mut client = Client { inner: inner, redirects: 5, limit: 10 * 1024 * 1024 };
+        client.timeout(Some(Duration::from_secs(30)));
+        client
+    }
+
+    /// Set the timeout for each read and write on a connection, or `None`
+    /// to wait indefinitely.
+    pub fn timeout(&mut self, timeout: Option<Duration>) -> &mut Client {
+        self.inner.set_read_timeout(timeout);
+        self.inner.set_write_timeout(timeout);
+        self
+    }
+
+    /// Set how many redirects to follow for a request. With `0`, redirect
+    /// responses are returned as they are.
+    pub fn redirects(&mut self, redirects: u32) -> &mut Client {
+        self.redirects = redirects;
+        self
+    }
+
+    /// Fail with `ClientError::TooLarge` for response bodies longer than
+    /// `limit` bytes.
+    pub fn limit(&mut self, limit: usize) -> &mut Client {
+        self.limit = limit;
+        self
+    }
+
+    /// Send a `GET` request to `url`.
+    pub fn get(&self, url: &str) -> Result<ClientResponse, ClientError> {
+        self.send(Method::Get, url, Headers::new(), &[])
+    }
+
+    /// Send a request, with a body unless it is empty, and read the
+    /// response.
+    ///
+    /// A `303 See Other` response, or a `301` or `302` response to a `POST`,
+    /// is followed with a `GET` and no body; other redirects repeat the
+    /// request. `Authorization` and `Cookie` headers are dropped when a
+    /// redirect leads to another host.
+    pub fn send(&self, mut method: Method, url: &str, mut headers: Headers, mut body: &[u8])
+                -> Result<ClientResponse, ClientError> {
+        let mut url = try!(rust_url::Url::parse(url).map_err(hyper::Error::from));
+        let mut redirects = 0;
+
+        loop {
+            let mut req = self.inner.request(method.clone(), url.clone())
+                .headers(headers.clone());
+            if !body.is_empty() { req = req.body(body) }
+            let mut res = try!(req.send());
+
+            let location = match res.headers.get::<headers::Location>() {
+                Some(location) if res.status.is_redirection() => url.join(location).ok(),
+                _ => None
+            };
+            let next = match location {
+                Some(next) if self.redirects > 0 => next,
+                _ => return self.read(res)
+            };
+
+            if redirects == self.redirects { return Err(ClientError::TooManyRedirects) }
+            redirects += 1;
+
+            let see_other = res.status == status::SeeOther ||
+                (method == Method::Post &&
+                 (res.status == status::MovedPermanently || res.status == status::Found));
+            if see_other {
+                method = Method::Get;
+                body = &[];
+            }
+            if next.host_str() != url.host_str() || next.port() != url.port() {
+                headers.remove::<headers::Authorization<String>>();
+                headers.remove::<headers::Cookie>();
+            }
+
+            // Drain the body so that the connection goes back to the pool.
+            let _ = res.by_ref().take(self.limit as u64).read_to_end(&mut Vec::new());
+            url = next;
+        }
+    }
+
+    /// Send a request as `send` does, spending one call from `budget`.
+    ///
+    /// Fails with `ClientError::BudgetExceeded`, without sending anything,
+    /// if the budget is spent. A response with a `Retry-After` of more
+    /// seconds than the budget has left exhausts it.
+    pub fn send_within(&self, budget: &Budget, method: Method, url: &str, headers: Headers,
+                       body: &[u8]) -> Result<ClientResponse, ClientError> {
+        try!(budget.charge().map_err(|_| ClientError::BudgetExceeded));
+        let res = try!(self.send(method, url, headers, body));
+
+        let retry_after = res.headers.get_raw("Retry-After")
+            .and_then(|values| String::from_utf8(values[0].clone()).ok())
+            .and_then(|value| value.trim().parse::<u64>().ok());
+        if let Some(seconds) = retry_after {
+            if Duration::from_secs(seconds) > budget.time_left() { budget.exhaust() }
+        }
+        Ok(res)
+    }
+
+    fn read(&self, mut res: hyper::client::Response) -> Result<ClientResponse, ClientError> {
+        let mut body = Vec::new();
+        try!(res.by_ref().take((self.limit as u64).saturating_add(1)).read_to_end(&mut body)
+             .map_err(hyper::Error::from));
+        if body.len() > self.limit { return Err(ClientError::TooLarge) }
+
+        Ok(ClientResponse {
+            status: res.status,
+            headers: res.headers.clone(),
+            url: Url::from_generic_url(res.url.clone()).unwrap(),
+            body: body
+        })
+    }
+}
+
+impl fmt::Debug for Client {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Client {{ redirects: {}, limit: {} }}", self.redirects, self.limit)
+    }
+}
+
+impl From<hyper::Error> for ClientError {
+    fn from(err: hyper::Error) -> ClientError {
+        ClientError::Http(err)
+    }
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ClientError::Http(ref err) => fmt::Display::fmt(err, f),
+            _ => f.write_str(self.description())
+        }
+    }
+}
+
+impl Error for ClientError {
+    fn description(&self) -> &str {
+        match *self {
+            ClientError::Http(ref err) => err.description(),
+            ClientError::TooManyRedirects => "Too many redirects",
+            ClientError::TooLarge => "Response body too large",
+            ClientError::BudgetExceeded => "Downstream call budget exceeded"
+        }
+    }
+
+    fn cause(&self) -> Option<&Error> {
+        match *self {
+            ClientError::Http(ref err) => Some(err),
+            _ => None
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use budget::Budget;
+    use headers::{Authorization, Location};
+    use method::Method;
+    use status;
+    use {Headers, Iron, IronError, Request, Response};
+    use super::{Client, ClientError};
+
+    #[test]
+    fn test_client() {
+        let mut listening = Iron::new(|req: &mut Request| {
+            // The server does not skip unread bodies before the next request.
+            try!(req.body_string(1024).map_err(IronError::from));
+            let res = match req.url.path()[0] {
+                "moved" => Response::with(status::SeeOther)
+                    .header(Location("/target".into())),
+                "loop" => Response::with(status::TemporaryRedirect)
+                    .header(Location("/loop".into())),
+                "target" => {
+                    let auth = req.headers.get::<Authorization<String>>().map(|a| a.0.clone());
+                    Response::with((status::Ok, format!("{} {:?}", req.method, auth)))
+                },
+                "busy" => {
+                    let mut res = Response::with(status::ServiceUnavailable);
+                    res.headers.set_raw("Retry-After", vec![b"120".to_vec()]);
+                    res
+                },
+                _ => Response::with((status::NotFound, vec![b'x'; 100]))
+            };
+            Ok(res)
+        }).http("127.0.0.1:0").unwrap();
+        let base = format!("http://{}", listening.socket);
+        // Detach the server threads, so that a failure does not wait for them.
+        listening.close().unwrap();
+
+        let mut client = Client::new();
+        let mut headers = Headers::new();
+        headers.set(Authorization("token".to_owned()));
+        let res = client.send(Method::Post, &format!("{}/moved", base), headers, b"data")
+            .unwrap();
+        assert_eq!(res.status, status::Ok);
+        assert_eq!(res.url.path(), vec!["target"]);
+        assert_eq!(res.body, b"GET Some(\"token\")".to_vec());
+
+        match client.get(&format!("{}/loop", base)) {
+            Err(ClientError::TooManyRedirects) => (),
+            other => panic!("Expected too many redirects, got {:?}", other)
+        }
+
+        client.redirects(0).limit(100);
+        assert_eq!(client.get(&format!("{}/loop", base)).unwrap().status,
+                   status::TemporaryRedirect);
+        assert_eq!(client.get(&format!("{}/missing", base)).unwrap().body.len(), 100);
+        client.limit(99);
+        match client.get(&format!("{}/missing", base)) {
+            Err(ClientError::TooLarge) => (),
+            other => panic!("Expected too large, got {:?}", other)
+        }
+        client.limit(usize::MAX);
+        assert_eq!(client.get(&format!("{}/missing", base)).unwrap().body.len(), 100);
+
+        // Asking to wait longer than the budget allows spends it.
+        let budget = Budget::new(5, Duration::from_secs(60));
+        let busy = format!("{}/busy", base);
+        let res = client.send_within(&budget, Method::Get, &busy, Headers::new(), &[]);
+        assert_eq!(res.unwrap().status, status::ServiceUnavailable);
+        match client.send_within(&budget, Method::Get, &busy, Headers::new(), &[]) {
+            Err(ClientError::BudgetExceeded) => (),
+            other => panic!("Expected the budget to be exceeded, got {:?}", other)
+        }
+    }
+}
@@ -0,0 +1,137 @@
+//! A source of the current time, which can be replaced in tests.
+//!
+//! Middleware whose behaviour depends on time, such as caches, take a
+//! `Clock` rather than reading the system time directly. Tests can then use
+//! a `ManualClock` to move time forward deterministically:
+//!
+//! ```
+//! # use std::time::Duration;
+//! # use iron::cache::{Cache, MemoryCache};
+//! # use iron::clock::ManualClock;
+//! let clock = ManualClock::new();
+//! let cache = MemoryCache::with_clock(100, clock.clone());
+//!
+//! cache.set("key", b"value".to_vec(), Some(Duration::from_secs(60)));
+//! clock.advance(Duration::from_secs(61));
+//! assert_eq!(cache.get("key"), None);
+//! ```
+//!
+//! Linking a `SharedClock` as `BeforeMiddleware` makes a clock available to
+//! handlers through `SharedClock::of`.
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use typemap::Key;
+
+use {BeforeMiddleware, Request, IronResult};
+
+/// A source of the current time.
+pub trait Clock: Send + Sync + 'static {
+    /// The current time.
+    fn now(&self) -> SystemTime;
+}
+
+/// The system's real-time clock.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime { SystemTime::now() }
+}
+
+/// A clock which only moves when told to.
+///
+/// Clones share the same time, so a test can keep one clone and give
+/// another to the middleware under test.
+#[derive(Debug, Clone)]
+pub struct ManualClock {
+    now: Arc<Mutex<SystemTime>>
+}
+
+impl ManualClock {
+    /// Create a clock set to an arbitrary fixed time.
+    pub fn new() -> ManualClock {
+        ManualClock::at(UNIX_EPOCH + Duration::from_secs(1_000_000_000))
+    }
+
+    /// Create a clock set to `now`.
+    pub fn at(now: SystemTime) -> ManualClock {
+        ManualClock { now: Arc::new(Mutex::new(now)) }
+    }
+
+    /// Move the clock forward by `duration`.
+    pub fn advance(&self, duration: Duration) {
+        *self.now.lock().unwrap() += duration;
+    }
+
+    /// Set the clock to `now`.
+    pub fn set(&self, now: SystemTime) {
+        *self.now.lock().unwrap() = now;
+    }
+}
+
+impl Default for ManualClock {
+    fn default() -> ManualClock { ManualClock::new() }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> SystemTime { *self.now.lock().unwrap() }
+}
+
+/// A handle to a clock shared between middleware and handlers.
+///
+/// As `BeforeMiddleware`, it makes the clock available to later middleware
+/// and handlers through `SharedClock::of`.
+#[derive(Clone)]
+pub struct SharedClock(Arc<Clock>);
+
+impl Key for SharedClock { type Value = Arc<Clock>; }
+
+impl SharedClock {
+    /// Share `clock`.
+    pub fn new<C: Clock>(clock: C) -> SharedClock {
+        SharedClock(Arc::new(clock))
+    }
+
+    /// The clock shared with `req`, or the system clock if no `SharedClock`
+    /// has been linked before the current middleware.
+    pub fn of(req: &Request) -> Arc<Clock> {
+        req.extensions.get::<SharedClock>().cloned().unwrap_or_else(|| Arc::new(SystemClock))
+    }
+}
+
+impl Clock for SharedClock {
+    fn now(&self) -> SystemTime { self.0.now() }
+}
+
+impl BeforeMiddleware for SharedClock {
+    fn before(&self, req: &mut Request) -> IronResult<()> {
+        req.extensions.insert::<SharedClock>(self.0.clone());
+        Ok(())
+    }
+}
+
+/// Seconds since the Unix epoch according to `clock`, or zero for times
+/// before it.
+pub fn unix_seconds(clock: &Clock) -> u64 {
+    clock.now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::{Clock, ManualClock, unix_seconds};
+
+    #[test]
+    fn test_manual_clock() {
+        let clock = ManualClock::new();
+        let shared = clock.clone();
+        let start = clock.now();
+
+        shared.advance(Duration::from_secs(5));
+        assert_eq!(clock.now().duration_since(start).unwrap(), Duration::from_secs(5));
+        assert_eq!(unix_seconds(&clock), 1_000_000_005);
+    }
+}
@@ -0,0 +1,471 @@
+//! Compression of response bodies, and decompression of request bodies.
+//!
+//! `Compression` is an `AfterMiddleware` which encodes response bodies with
+//! the best content coding the client accepts, as chosen by
+//! `negotiation::negotiate_encoding`. Content codings are provided by
+//! `Encoder`s; gzip and deflate are available by default, and brotli with
+//! the `brotli` feature.
+//!
+//! `Decompression` is a `BeforeMiddleware` which decodes request bodies
+//! sent with a gzip or deflate `Content-Encoding`, so that the middleware
+//! and handler after it see the original body.
+//!
+//! ```ignore
+//! let mut chain = Chain::new(handler);
+//! chain.link_before(Decompression::new(10 * 1024 * 1024));
+//! chain.link_after(Compression::new());
+//! ```
+
+use std::error::Error;
+use std::fmt;
+use std::io::{self, Read};
+use std::sync::Arc;
+
+use flate2;
+use flate2::read::{GzDecoder, DeflateDecoder, ZlibDecoder};
+use flate2::write::{GzEncoder, DeflateEncoder};
+
+use headers::{self, Encoding};
+use negotiation::negotiate_encoding;
+use request::Body;
+use response::{WriteBody, ResponseBody};
+use {status, AfterMiddleware, BeforeMiddleware, Request, Response, IronResult, IronError};
+
+/// A content coding which can be applied to response bodies.
+pub trait Encoder: Send + Sync + 'static {
+    /// The content coding this encoder produces, as named in
+    /// `Accept-Encoding` and `Content-Encoding`.
+    fn encoding(&self) -> Encoding;
+
+    /// Write `body` to `res`, encoded.
+    ///
+    /// Implementations must finish the encoded stream before returning.
+    fn encode(&self, body: &mut WriteBody, res: &mut ResponseBody) -> io::Result<()>;
+}
+
+/// The gzip content coding.
+pub struct Gzip;
+
+impl Encoder for Gzip {
+    fn encoding(&self) -> Encoding { Encoding::Gzip }
+
+    fn encode(&self, body: &mut WriteBody, res: &mut ResponseBody) -> io::Result<()> {
+        let mut encoder = GzEncoder::new(res, flate2::Compression::default());
+        try!(body.write_body(&mut ResponseBody::new(&mut encoder)));
+        encoder.finish().map(|_| ())
+    }
+}
+
+/// The deflate content coding.
+pub struct Deflate;
+
+impl Encoder for Deflate {
+    fn encoding(&self) -> Encoding { Encoding::Deflate }
+
+    fn encode(&self, body: &mut WriteBody, res: &mut ResponseBody) -> io::Result<()> {
+        let mut encoder = DeflateEncoder::new(res, flate2::Compression::default());
+        try!(body.write_body(&mut ResponseBody::new(&mut encoder)));
+        encoder.finish().map(|_| ())
+    }
+}
+
+/// The brotli content coding.
+#[cfg(feature = "brotli")]
+pub struct Brotli;
+
+#[cfg(feature = "brotli")]
+impl Encoder for Brotli {
+    fn encoding(&self) -> Encoding { Encoding::EncodingExt("br".to_owned()) }
+
+    fn encode(&self, body: &mut WriteBody, res: &mut ResponseBody) -> io::Result<()> {
+        use std::io::Write;
+        use brotli::CompressorWriter;
+
+        let mut encoder = CompressorWriter::new(res, 4096, 5, 22);
+        try!(body.write_body(&mut ResponseBody::new(&mut encoder)));
+        try!(encoder.flush());
+        // Dropping the writer finishes the stream.
+        encoder.into_inner();
+        Ok(())
+    }
+}
+
+/// Middleware which compresses response bodies.
+///
+/// Responses without a body, responses which already have a
+/// `Content-Encoding`, responses whose `Content-Length` is below the
+/// minimum size, and 204 and 304 responses are left untouched.
+#[derive(Clone)]
+pub struct Compression {
+    encoders: Vec<Arc<Encoder>>,
+    min_size: u64
+}
+
+impl Compression {
+    /// Compress with the default encoders: brotli if the feature is enabled,
+    /// then gzip, then deflate, in order of preference.
+    pub fn new() -> Compression {
+        let mut compression = Compression::empty();
+
+        #[cfg(feature = "brotli")]
+        compression.encoder(Brotli);
+
+        compression.encoder(Gzip).encoder(Deflate);
+        compression
+    }
+
+    /// Create a `Compression` with no encoders, to be added with `encoder`.
+    pub fn empty() -> Compression {
+        Compression { encoders: vec![], min_size: 256 }
+    }
+
+    /// Leave bodies shorter than `bytes` uncompressed, as the encoding
+    /// would save little or even make them longer. Defaults to 256 bytes.
+    ///
+    /// Only bodies with a `Content-Length` are known to be short; others
+    /// are always compressed.
+    pub fn min_size(&mut self, bytes: u64) -> &mut Compression {
+        self.min_size = bytes;
+        self
+    }
+
+    /// Add an encoder, less preferred than those added before it.
+    pub fn encoder<E: Encoder>(&mut self, encoder: E) -> &mut Compression {
+        self.encoders.push(Arc::new(encoder));
+        self
+    }
+}
+
+impl Default for Compression {
+    fn default() -> Compression { Compression::new() }
+}
+
+impl AfterMiddleware for Compression {
+    fn after(&self, req: &mut Request, mut res: Response) -> IronResult<Response> {
+        if res.body.is_none() || res.headers.has::<headers::ContentEncoding>() ||
+           res.status == Some(status::NoContent) || res.status == Some(status::NotModified) {
+            return Ok(res);
+        }
+        if res.headers.get::<headers::ContentLength>().map_or(false, |l| l.0 < self.min_size) {
+            return Ok(res);
+        }
+
+        vary_on_accept_encoding(&mut res);
+
+        let mut available = self.encoders.iter().map(|e| e.encoding()).collect::<Vec<_>>();
+        available.push(Encoding::Identity);
+
+        let chosen = match negotiate_encoding(req, &available) {
+            Some(Encoding::Identity) | None => return Ok(res),
+            Some(encoding) => encoding
+        };
+
+        // Safe, since only encodings from `self.encoders` besides identity
+        // can be chosen.
+        let encoder = self.encoders.iter().find(|e| e.encoding() == chosen).unwrap().clone();
+
+        res.body = Some(Box::new(Compressed { body: res.body.take().unwrap(), encoder: encoder }));
+        res.headers.remove::<headers::ContentLength>();
+        res.headers.set(headers::ContentEncoding(vec![chosen]));
+        Ok(res)
+    }
+}
+
+fn vary_on_accept_encoding(res: &mut Response) {
+    use unicase::UniCase;
+
+    let accept_encoding = UniCase("Accept-Encoding".to_owned());
+
+    match res.headers.get_mut::<headers::Vary>() {
+        Some(&mut headers::Vary::Items(ref mut items)) => {
+            if !items.contains(&accept_encoding) { items.push(accept_encoding) }
+            return;
+        },
+        Some(&mut headers::Vary::Any) => return,
+        None => {}
+    }
+
+    res.headers.set(headers::Vary::Items(vec![accept_encoding]));
+}
+
+struct Compressed {
+    body: Box<WriteBody>,
+    encoder: Arc<Encoder>
+}
+
+impl WriteBody for Compressed {
+    fn write_body(&mut self, res: &mut ResponseBody) -> io::Result<()> {
+        self.encoder.encode(&mut *self.body, res)
+    }
+}
+
+/// Middleware which decompresses request bodies.
+///
+/// Bodies with a `Content-Encoding` of gzip or deflate, or a list of them,
+/// are decoded into memory, and the request's `Content-Encoding` header is
+/// replaced by a `Content-Length` for the decoded body. Since a small body
+/// can decompress to a huge one, bodies which would decompress to more than
+/// a limit are rejected with `413 Payload Too Large`. Bodies which cannot be
+/// decoded are rejected with `400 Bad Request`, and other content codings
+/// with `415 Unsupported Media Type`, leaving the body unread.
+///
+/// Requests without a `Content-Encoding` are passed on untouched.
+pub struct Decompression {
+    limit: usize
+}
+
+impl Decompression {
+    /// Decompress request bodies of up to `limit` bytes once decoded.
+    pub fn new(limit: usize) -> Decompression {
+        Decompression { limit: limit }
+    }
+}
+
+impl BeforeMiddleware for Decompression {
+    fn before(&self, req: &mut Request) -> IronResult<()> {
+        let codings = match req.headers.get::<headers::ContentEncoding>() {
+            Some(&headers::ContentEncoding(ref codings)) => codings.clone(),
+            None => return Ok(())
+        };
+
+        let supported = [Encoding::Identity, Encoding::Gzip, Encoding::Deflate];
+        if let Some(coding) = codings.iter().find(|c| !supported.contains(c)) {
+            let err = UnsupportedEncoding(coding.to_string());
+            return Err(IronError::new(err, status::UnsupportedMediaType));
+        }
+
+        let mut body = Vec::new();
+        try!(read_limited(&mut req.body, self.limit, &mut body));
+
+        // Codings are listed in the order they were applied.
+        for coding in codings.iter().rev() {
+            let mut decoded = Vec::new();
+            match *coding {
+                Encoding::Gzip => {
+                    try!(read_limited(&mut GzDecoder::new(&body[..]), self.limit, &mut decoded))
+                },
+                // The deflate coding is meant to be zlib-wrapped, but some
+                // clients send a raw deflate stream instead.
+                Encoding::Deflate if is_zlib(&body) => {
+                    try!(read_limited(&mut ZlibDecoder::new(&body[..]), self.limit, &mut decoded))
+                },
+                Encoding::Deflate => {
+                    try!(read_limited(&mut DeflateDecoder::new(&body[..]), self.limit,
+                                      &mut decoded))
+                },
+                _ => continue
+            }
+            body = decoded;
+        }
+
+        req.headers.remove::<headers::ContentEncoding>();
+        req.headers.set(headers::ContentLength(body.len() as u64));
+        req.body = Body::from_bytes(body);
+        Ok(())
+    }
+}
+
+// Read all of `reader` into `out`, failing if it is longer than `limit`.
+fn read_limited<R: Read>(reader: &mut R, limit: usize, out: &mut Vec<u8>) -> IronResult<()> {
+    if let Err(e) = reader.take((limit as u64).saturating_add(1)).read_to_end(out) {
+        return Err(IronError::new(e, status::BadRequest));
+    }
+    if out.len() > limit {
+        let err = io::Error::new(io::ErrorKind::InvalidData,
+                                 format!("Request body exceeds limit of {} bytes", limit));
+        return Err(IronError::new(err, status::PayloadTooLarge));
+    }
+    Ok(())
+}
+
+// Whether `body` starts with a zlib header.
+fn is_zlib(body: &[u8]) -> bool {
+    body.len() >= 2 && body[0] & 0x0F == 8 && (body[0] as u16 * 256 + body[1] as u16) % 31 == 0
+}
+
+/// The error used when a request body has a `Content-Encoding` which
+/// `Decompression` cannot decode.
+#[derive(Debug)]
+pub struct UnsupportedEncoding(pub String);
+
+impl fmt::Display for UnsupportedEncoding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Unsupported Content-Encoding: {}", self.0)
+    }
+}
+
+impl Error for UnsupportedEncoding {
+    fn description(&self) -> &str { "Unsupported Content-Encoding" }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Read, Write};
+
+    use flate2;
+    use flate2::read::GzDecoder;
+    use flate2::write::ZlibEncoder;
+
+    use flate2::read::DeflateDecoder;
+    use unicase::UniCase;
+
+    use super::{Compression, Decompression, Encoder, Deflate, Gzip};
+    use headers::{ContentEncoding, ContentLength, Encoding, Vary};
+    use method::Method;
+    use response::ResponseBody;
+    use test::StubRequest;
+    use {status, AfterMiddleware, BeforeMiddleware, Request, Response};
+
+    #[test]
+    fn test_gzip_round_trip() {
+        let mut encoded = Vec::new();
+        Gzip.encode(&mut "hello, hello, hello".to_owned(),
+                    &mut ResponseBody::new(&mut encoded)).unwrap();
+
+        let mut decoded = String::new();
+        GzDecoder::new(&encoded[..]).read_to_string(&mut decoded).unwrap();
+        assert_eq!(decoded, "hello, hello, hello");
+    }
+
+    fn compress(compression: &Compression, accept: Option<&str>, res: Response) -> Response {
+        let mut req = StubRequest::new(Method::Get, "http://localhost/");
+        if let Some(accept) = accept { req = req.raw_header("Accept-Encoding", accept) }
+        compression.after(&mut req.build(), res).unwrap()
+    }
+
+    fn body(res: &mut Response) -> Vec<u8> {
+        let mut body = Vec::new();
+        res.body.take().unwrap().write_body(&mut ResponseBody::new(&mut body)).unwrap();
+        body
+    }
+
+    fn vary(names: &[&str]) -> Option<Vary> {
+        Some(Vary::Items(names.iter().map(|&name| UniCase(name.to_owned())).collect()))
+    }
+
+    #[test]
+    fn test_compression() {
+        let compression = Compression::new();
+        let text = "compressible ".repeat(100);
+
+        let mut res = compress(&compression, Some("deflate, gzip;q=0.5"),
+                               Response::with(text.clone()));
+        assert_eq!(res.headers.get::<ContentEncoding>(),
+                   Some(&ContentEncoding(vec![Encoding::Deflate])));
+        assert!(!res.headers.has::<ContentLength>());
+        assert_eq!(res.headers.get::<Vary>().cloned(), vary(&["Accept-Encoding"]));
+        let mut decoded = String::new();
+        DeflateDecoder::new(&body(&mut res)[..]).read_to_string(&mut decoded).unwrap();
+        assert_eq!(decoded, text);
+
+        let mut original = Response::with(text.clone());
+        original.headers.set(vary(&["Origin"]).unwrap());
+        let mut res = compress(&compression, Some("gzip"), original);
+        assert_eq!(res.headers.get::<Vary>().cloned(), vary(&["Origin", "Accept-Encoding"]));
+        let mut decoded = String::new();
+        GzDecoder::new(&body(&mut res)[..]).read_to_string(&mut decoded).unwrap();
+        assert_eq!(decoded, text);
+
+        // Clients accepting no coding get the body as it is.
+        for accept in vec![None, Some("identity"), Some("gzip;q=0, deflate;q=0")] {
+            let res = compress(&compression, accept, Response::with(text.clone()));
+            assert!(!res.headers.has::<ContentEncoding>(), "{:?}", accept);
+            assert_eq!(res.headers.get::<Vary>().cloned(), vary(&["Accept-Encoding"]));
+        }
+    }
+
+    #[test]
+    fn test_compression_skipped() {
+        let compression = Compression::new();
+
+        let mut encoded = Response::with(encoded(&Gzip, &"x".repeat(1000)));
+        encoded.headers.set(ContentEncoding(vec![Encoding::Gzip]));
+        let res = compress(&compression, Some("deflate"), encoded);
+        assert_eq!(res.headers.get::<ContentEncoding>(),
+                   Some(&ContentEncoding(vec![Encoding::Gzip])));
+        assert!(!res.headers.has::<Vary>());
+
+        let mut res = compress(&compression, Some("gzip"), Response::with("tiny"));
+        assert!(!res.headers.has::<ContentEncoding>());
+        assert_eq!(res.headers.get::<ContentLength>(), Some(&ContentLength(4)));
+        assert_eq!(body(&mut res), b"tiny".to_vec());
+
+        let mut always = Compression::new();
+        always.min_size(0);
+        let res = compress(&always, Some("gzip"), Response::with("tiny"));
+        assert_eq!(res.headers.get::<ContentEncoding>(),
+                   Some(&ContentEncoding(vec![Encoding::Gzip])));
+
+        let res = compress(&compression, Some("gzip"), Response::with(status::NoContent));
+        assert!(!res.headers.has::<ContentEncoding>());
+    }
+
+    #[cfg(feature = "brotli")]
+    #[test]
+    fn test_brotli() {
+        use brotli::Decompressor;
+
+        let text = "compressible ".repeat(100);
+        let res = Response::with(text.clone());
+        let mut res = compress(&Compression::new(), Some("gzip, br"), res);
+        assert_eq!(res.headers.get::<ContentEncoding>(),
+                   Some(&ContentEncoding(vec![Encoding::EncodingExt("br".into())])));
+        let mut decoded = String::new();
+        Decompressor::new(&body(&mut res)[..], 4096).read_to_string(&mut decoded).unwrap();
+        assert_eq!(decoded, text);
+    }
+
+    fn encoded(encoder: &Encoder, text: &str) -> Vec<u8> {
+        let mut encoded = Vec::new();
+        encoder.encode(&mut text.to_owned(), &mut ResponseBody::new(&mut encoded)).unwrap();
+        encoded
+    }
+
+    fn request<'a, 'b>(codings: Vec<Encoding>, body: Vec<u8>) -> Request<'a, 'b> {
+        StubRequest::new(Method::Post, "http://localhost/")
+            .header(ContentEncoding(codings))
+            .body(body)
+            .build()
+    }
+
+    #[test]
+    fn test_decompression() {
+        let decompression = Decompression::new(100);
+
+        let mut req = request(vec![Encoding::Gzip], encoded(&Gzip, "hello, hello"));
+        decompression.before(&mut req).unwrap();
+        assert!(!req.headers.has::<ContentEncoding>());
+        assert_eq!(req.headers.get::<ContentLength>(), Some(&ContentLength(12)));
+        assert_eq!(req.body_string(100).unwrap(), "hello, hello");
+
+        let mut zlib = ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+        zlib.write_all(b"zlib").unwrap();
+        for body in vec![zlib.finish().unwrap(), encoded(&Deflate, "zlib")] {
+            let mut req = request(vec![Encoding::Deflate], body);
+            decompression.before(&mut req).unwrap();
+            assert_eq!(req.body_string(100).unwrap(), "zlib");
+        }
+
+        // Deflated, then gzipped.
+        let mut twice = Vec::new();
+        Gzip.encode(&mut encoded(&Deflate, "twice"), &mut ResponseBody::new(&mut twice)).unwrap();
+        let mut req = request(vec![Encoding::Deflate, Encoding::Gzip], twice);
+        decompression.before(&mut req).unwrap();
+        assert_eq!(req.body_string(100).unwrap(), "twice");
+    }
+
+    #[test]
+    fn test_decompression_errors() {
+        let decompression = Decompression::new(10);
+        let status = |codings, body| {
+            decompression.before(&mut request(codings, body)).err().and_then(|e| e.response.status)
+        };
+
+        assert_eq!(status(vec![Encoding::Gzip], encoded(&Gzip, &"a".repeat(11))),
+                   Some(status::PayloadTooLarge));
+        assert_eq!(status(vec![Encoding::Gzip], b"not gzip".to_vec()), Some(status::BadRequest));
+        assert_eq!(status(vec![Encoding::EncodingExt("br".into())], vec![]),
+                   Some(status::UnsupportedMediaType));
+        assert_eq!(status(vec![Encoding::Identity], b"plain".to_vec()), None);
+    }
+}
@@ -0,0 +1,225 @@
+//! Limits on how many requests are handled at once.
+//!
+//! `ConcurrencyLimit` is `AroundMiddleware` which counts the requests its
+//! handler is working on, grouped by a key such as the client's address,
+//! and turns away requests beyond a maximum instead of queueing them. It
+//! protects expensive endpoints, such as report exports, from tying up
+//! every worker thread. Combined with `OnPrefix` it applies to one route:
+//!
+//! ```ignore
+//! // At most two exports per client, and eight in all.
+//! chain.link_around(OnPrefix::new("/reports/export", ConcurrencyLimit::per_ip(2)));
+//! chain.link_around(OnPrefix::new("/reports/export", ConcurrencyLimit::global(8)
+//!     .status(status::ServiceUnavailable)));
+//! ```
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+use {status, AroundMiddleware, Handler, Request, Response, IronResult, IronError};
+use status::Status;
+
+/// Middleware limiting the requests handled at once for each key.
+///
+/// Requests over the limit are answered with `429 Too Many Requests`,
+/// unless changed with `status`, and a `Retry-After` header, without
+/// reaching the handler.
+pub struct ConcurrencyLimit {
+    max: usize,
+    key: Box<Fn(&Request) -> Option<String> + Send + Sync>,
+    status: Status,
+    retry_after: u32
+}
+
+impl ConcurrencyLimit {
+    /// Handle at most `max` requests at once in all.
+    pub fn global(max: usize) -> ConcurrencyLimit {
+        ConcurrencyLimit::per_key(max, |_: &Request| Some(String::new()))
+    }
+
+    /// Handle at most `max` requests at once from each client IP address.
+    pub fn per_ip(max: usize) -> ConcurrencyLimit {
+        ConcurrencyLimit::per_key(max, |req: &Request| Some(req.remote_addr.ip().to_string()))
+    }
+
+    /// Handle at most `max` requests at once for each key returned by
+    /// `key`. Requests for which it returns `None` are not limited.
+    pub fn per_key<F>(max: usize, key: F) -> ConcurrencyLimit
+    where F: Fn(&Request) -> Option<String> + Send + Sync + 'static {
+        ConcurrencyLimit {
+            max: max,
+            key: Box::new(key),
+            status: status::TooManyRequests,
+            retry_after: 1
+        }
+    }
+
+    /// Answer requests over the limit with `status`, such as
+    /// `503 Service Unavailable` for a limit protecting the server rather
+    /// than rationing clients.
+    pub fn status(mut self, status: Status) -> ConcurrencyLimit {
+        self.status = status;
+        self
+    }
+
+    /// Suggest clients retry after `seconds`. Defaults to one.
+    pub fn retry_after(mut self, seconds: u32) -> ConcurrencyLimit {
+        self.retry_after = seconds;
+        self
+    }
+}
+
+impl AroundMiddleware for ConcurrencyLimit {
+    fn around(self, handler: Box<Handler>) -> Box<Handler> {
+        Box::new(Limited {
+            limit: self,
+            in_flight: Arc::new(Mutex::new(HashMap::new())),
+            handler: handler
+        })
+    }
+}
+
+/// The error for requests turned away by `ConcurrencyLimit`.
+#[derive(Debug)]
+pub struct TooManyInFlight;
+
+impl fmt::Display for TooManyInFlight {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.description())
+    }
+}
+
+impl Error for TooManyInFlight {
+    fn description(&self) -> &str {
+        "Too many requests in flight"
+    }
+}
+
+struct Limited {
+    limit: ConcurrencyLimit,
+    in_flight: Arc<Mutex<HashMap<String, usize>>>,
+    handler: Box<Handler>
+}
+
+// Counts a request as in flight until dropped, even if the handler panics.
+struct InFlight<'a> {
+    in_flight: &'a Mutex<HashMap<String, usize>>,
+    key: String
+}
+
+impl<'a> Drop for InFlight<'a> {
+    fn drop(&mut self) {
+        let mut in_flight = self.in_flight.lock().unwrap_or_else(|e| e.into_inner());
+        let remove = match in_flight.get_mut(&self.key) {
+            Some(count) => {
+                *count -= 1;
+                *count == 0
+            },
+            None => false
+        };
+        if remove { in_flight.remove(&self.key); }
+    }
+}
+
+impl Handler for Limited {
+    fn handle(&self, req: &mut Request) -> IronResult<Response> {
+        let key = match (self.limit.key)(req) {
+            Some(key) => key,
+            None => return self.handler.handle(req)
+        };
+
+        let _in_flight = {
+            let mut in_flight = self.in_flight.lock().unwrap_or_else(|e| e.into_inner());
+            // Checked before inserting, so that rejected keys are not kept.
+            if in_flight.get(&key).map_or(0, |&count| count) >= self.limit.max {
+                let retry_after = self.limit.retry_after.to_string().into_bytes();
+                let mut err = IronError::new(TooManyInFlight, self.limit.status);
+                err.response.headers.set_raw("Retry-After", vec![retry_after]);
+                return Err(err)
+            }
+            *in_flight.entry(key.clone()).or_insert(0) += 1;
+            InFlight { in_flight: &self.in_flight, key: key }
+        };
+
+        self.handler.handle(req)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use std::sync::{Arc, Barrier, Mutex};
+    use std::thread;
+
+    use method::Method;
+    use status;
+    use test::{MiddlewareHarness, StubRequest};
+    use {AroundMiddleware, Handler, Request, Response};
+    use super::{ConcurrencyLimit, Limited};
+
+    fn get(harness: &MiddlewareHarness<Box<Handler>>, path: &str, client: Option<&str>)
+           -> (Option<status::Status>, Option<Vec<u8>>) {
+        let mut req = StubRequest::new(Method::Get, &format!("http://localhost/{}", path));
+        if let Some(client) = client { req = req.raw_header("X-Client", client) }
+        let run = harness.handle(req);
+        let retry_after = run.response().headers.get_raw("Retry-After")
+            .map(|values| values[0].clone());
+        (run.status(), retry_after)
+    }
+
+    #[test]
+    fn test_limit() {
+        // Requests to /wait stay in flight until the test joins the barrier.
+        let barrier = Arc::new(Barrier::new(2));
+        let waiting = barrier.clone();
+        let handler: Box<Handler> = Box::new(move |req: &mut Request| {
+            if req.url.path() == vec!["wait"] { waiting.wait(); }
+            Ok(Response::with(status::Ok))
+        });
+        let limit = ConcurrencyLimit::per_key(1, |req: &Request| {
+            req.headers.get_raw("X-Client")
+                .map(|values| String::from_utf8_lossy(&values[0]).into_owned())
+        });
+        let harness = Arc::new(MiddlewareHarness::new(limit.around(handler)));
+
+        let in_flight = {
+            let harness = harness.clone();
+            // Retry if turned away while one of the probes below is in flight.
+            thread::spawn(move || loop {
+                let result = get(&harness, "wait", Some("a"));
+                if result.0 != Some(status::TooManyRequests) { return result }
+            })
+        };
+        // Wait for the waiting request to be counted.
+        while get(&harness, "", Some("a")).0 == Some(status::Ok) {
+            thread::yield_now();
+        }
+
+        assert_eq!(get(&harness, "", Some("a")),
+                   (Some(status::TooManyRequests), Some(b"1".to_vec())));
+        assert_eq!(get(&harness, "", Some("b")), (Some(status::Ok), None));
+        assert_eq!(get(&harness, "", None), (Some(status::Ok), None));
+
+        barrier.wait();
+        assert_eq!(in_flight.join().unwrap().0, Some(status::Ok));
+        assert_eq!(get(&harness, "", Some("a")), (Some(status::Ok), None));
+    }
+
+    #[test]
+    fn test_rejected_keys_are_not_kept() {
+        let limited = Limited {
+            limit: ConcurrencyLimit::per_key(0, |req: &Request| Some(req.url.path().join("/"))),
+            in_flight: Arc::new(Mutex::new(HashMap::new())),
+            handler: Box::new(|_: &mut Request| Ok(Response::with(status::Ok)))
+        };
+        let harness = MiddlewareHarness::new(limited);
+
+        for path in &["a", "b", "c"] {
+            let req = StubRequest::new(Method::Get, &format!("http://localhost/{}", path));
+            assert_eq!(harness.handle(req).status(), Some(status::TooManyRequests));
+        }
+        assert!(harness.middleware().in_flight.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,324 @@
+//! Evaluation of conditional requests, as specified by RFC 7232.
+//!
+//! Handlers which know the validators of the representation they are about
+//! to send, its entity tag and modification date, can use `evaluate` to
+//! honour `If-Match`, `If-None-Match`, `If-Modified-Since` and
+//! `If-Unmodified-Since` with the precedence the RFC requires:
+//!
+//! ```ignore
+//! let etag = EntityTag::strong(hash_of(&file));
+//! match conditional::evaluate(req, Some(&etag), Some(modified)) {
+//!     Outcome::Proceed => Ok(Response::with((status::Ok, file, Header(ETag(etag))))),
+//!     outcome => Ok(Response::with(outcome.status().unwrap()))
+//! }
+//! ```
+//!
+//! `if_range` decides whether a `Range` header should be honoured.
+//!
+//! APIs can use `strong_etag` and `check_if_match` for optimistic locking,
+//! refusing to apply a change made to a representation the client has not
+//! seen:
+//!
+//! ```ignore
+//! let current = conditional::strong_etag(&json::encode(&item).unwrap().into_bytes());
+//! try!(conditional::check_if_match(req, &current, true));
+//! // Apply the update.
+//! ```
+
+use std::error::Error;
+use std::fmt;
+
+use rustc_serialize::base64::{ToBase64, URL_SAFE};
+use sha2::{Sha256, Digest};
+
+use headers::{Headers, EntityTag, HttpDate, IfMatch, IfNoneMatch, IfModifiedSince,
+              IfUnmodifiedSince, IfRange, Range};
+use method::Method;
+use status::Status;
+use {status, Request, IronResult, IronError};
+
+/// The result of evaluating the preconditions of a request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    /// All preconditions hold; handle the request normally.
+    Proceed,
+
+    /// The client's cached representation is current; respond with a 304.
+    NotModified,
+
+    /// A precondition failed; respond with a 412.
+    PreconditionFailed
+}
+
+impl Outcome {
+    /// The status to respond with instead of handling the request, if any.
+    pub fn status(&self) -> Option<Status> {
+        match *self {
+            Outcome::Proceed => None,
+            Outcome::NotModified => Some(status::NotModified),
+            Outcome::PreconditionFailed => Some(status::PreconditionFailed)
+        }
+    }
+}
+
+/// Evaluate the preconditions of `req` against the current validators of
+/// the target resource.
+///
+/// `etag` and `last_modified` are those of the representation which would
+/// be selected for the request; a missing validator means conditions based
+/// on it are ignored, as the RFC allows.
+pub fn evaluate(req: &Request, etag: Option<&EntityTag>, last_modified: Option<HttpDate>)
+                -> Outcome {
+    evaluate_headers(&req.method, &req.headers, etag, last_modified)
+}
+
+/// Evaluate preconditions given a request's method and headers, as `evaluate`.
+pub fn evaluate_headers(method: &Method, headers: &Headers, etag: Option<&EntityTag>,
+                        last_modified: Option<HttpDate>) -> Outcome {
+    let safe = *method == Method::Get || *method == Method::Head;
+
+    // Step 1 and 2: If-Match, or failing that If-Unmodified-Since.
+    if let Some(if_match) = headers.get::<IfMatch>() {
+        let matched = match *if_match {
+            IfMatch::Any => true,
+            IfMatch::Items(ref tags) => etag.map_or(false, |etag| {
+                tags.iter().any(|tag| tag.strong_eq(etag))
+            })
+        };
+
+        if !matched { return Outcome::PreconditionFailed }
+    } else if let Some(&IfUnmodifiedSince(date)) = headers.get::<IfUnmodifiedSince>() {
+        if let Some(modified) = last_modified {
+            if seconds(modified) > seconds(date) { return Outcome::PreconditionFailed }
+        }
+    }
+
+    // Step 3 and 4: If-None-Match, or failing that If-Modified-Since.
+    if let Some(if_none_match) = headers.get::<IfNoneMatch>() {
+        let matched = match *if_none_match {
+            IfNoneMatch::Any => true,
+            IfNoneMatch::Items(ref tags) => etag.map_or(false, |etag| {
+                tags.iter().any(|tag| tag.weak_eq(etag))
+            })
+        };
+
+        if matched {
+            return if safe { Outcome::NotModified } else { Outcome::PreconditionFailed };
+        }
+    } else if safe {
+        if let Some(&IfModifiedSince(date)) = headers.get::<IfModifiedSince>() {
+            if let Some(modified) = last_modified {
+                if seconds(modified) <= seconds(date) { return Outcome::NotModified }
+            }
+        }
+    }
+
+    Outcome::Proceed
+}
+
+/// Whether the `Range` header of a GET request should be honoured, given its
+/// `If-Range` header and the current validators of the resource.
+///
+/// Returns `false` if the request has no `Range` header or is not a GET,
+/// since the range must then be ignored, and `true` if there is a range
+/// but no `If-Range`. Otherwise the `If-Range` validator must match
+/// exactly: entity tags are compared strongly and dates must be identical.
+pub fn if_range(method: &Method, headers: &Headers, etag: Option<&EntityTag>,
+                last_modified: Option<HttpDate>) -> bool {
+    if *method != Method::Get || !headers.has::<Range>() { return false }
+
+    match headers.get::<IfRange>() {
+        None => true,
+        Some(&IfRange::EntityTag(ref tag)) => etag.map_or(false, |etag| tag.strong_eq(etag)),
+        Some(&IfRange::Date(date)) => last_modified.map_or(false, |modified| {
+            seconds(modified) == seconds(date)
+        })
+    }
+}
+
+/// A strong entity tag for a representation, derived from its bytes.
+///
+/// Equal representations get equal tags, so the tag can be computed afresh
+/// for each request rather than stored with the resource.
+pub fn strong_etag(representation: &[u8]) -> EntityTag {
+    EntityTag::strong(Sha256::digest(representation)[..16].to_base64(URL_SAFE))
+}
+
+/// Check the `If-Match` header of a mutating request against `current`,
+/// the entity tag of the resource as it is now.
+///
+/// Fails with `412 Precondition Failed` if the tags do not match, and, when
+/// `required` is set, with `428 Precondition Required` if the request has
+/// no `If-Match` header. Safe requests always pass.
+pub fn check_if_match(req: &Request, current: &EntityTag, required: bool)
+                      -> IronResult<()> {
+    if req.method == Method::Get || req.method == Method::Head { return Ok(()) }
+
+    let matched = match req.headers.get::<IfMatch>() {
+        Some(&IfMatch::Any) => true,
+        Some(&IfMatch::Items(ref tags)) => tags.iter().any(|tag| tag.strong_eq(current)),
+        None if required => {
+            return Err(IronError::new(IfMatchFailed::Missing, status::PreconditionRequired))
+        },
+        None => true
+    };
+
+    if matched {
+        Ok(())
+    } else {
+        Err(IronError::new(IfMatchFailed::Mismatch, status::PreconditionFailed))
+    }
+}
+
+/// The error for a request refused by `check_if_match`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IfMatchFailed {
+    /// The request had no `If-Match` header, but one was required.
+    Missing,
+
+    /// The resource has changed since the client last saw it.
+    Mismatch
+}
+
+impl fmt::Display for IfMatchFailed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.description())
+    }
+}
+
+impl Error for IfMatchFailed {
+    fn description(&self) -> &str {
+        match *self {
+            IfMatchFailed::Missing => "If-Match header required",
+            IfMatchFailed::Mismatch => "If-Match precondition failed"
+        }
+    }
+}
+
+// HTTP dates have a resolution of one second, so compare at that resolution
+// to avoid sub-second modification times defeating the comparison.
+fn seconds(date: HttpDate) -> i64 {
+    date.0.to_timespec().sec
+}
+
+#[cfg(test)]
+mod test {
+    use headers::{Headers, EntityTag, HttpDate, IfMatch, IfNoneMatch, IfModifiedSince,
+                  IfUnmodifiedSince, IfRange, Range};
+    use method::Method;
+    use status;
+    use test::StubRequest;
+
+    use super::{check_if_match, evaluate_headers, if_range, strong_etag, Outcome};
+
+    fn date(s: &str) -> HttpDate {
+        s.parse().unwrap()
+    }
+
+    fn etag() -> EntityTag {
+        EntityTag::strong("abc".to_owned())
+    }
+
+    const EARLY: &'static str = "Sun, 06 Nov 1994 08:49:37 GMT";
+    const LATE: &'static str = "Mon, 07 Nov 1994 08:49:37 GMT";
+
+    #[test]
+    fn test_no_conditions() {
+        let outcome = evaluate_headers(&Method::Get, &Headers::new(), Some(&etag()), None);
+        assert_eq!(outcome, Outcome::Proceed);
+    }
+
+    #[test]
+    fn test_if_match() {
+        let mut headers = Headers::new();
+        headers.set(IfMatch::Items(vec![EntityTag::strong("other".to_owned())]));
+        assert_eq!(evaluate_headers(&Method::Put, &headers, Some(&etag()), None),
+                   Outcome::PreconditionFailed);
+
+        headers.set(IfMatch::Items(vec![etag()]));
+        assert_eq!(evaluate_headers(&Method::Put, &headers, Some(&etag()), None),
+                   Outcome::Proceed);
+
+        // Weak tags never match strongly.
+        headers.set(IfMatch::Items(vec![EntityTag::weak("abc".to_owned())]));
+        assert_eq!(evaluate_headers(&Method::Put, &headers, Some(&etag()), None),
+                   Outcome::PreconditionFailed);
+    }
+
+    #[test]
+    fn test_if_match_overrides_if_unmodified_since() {
+        let mut headers = Headers::new();
+        headers.set(IfMatch::Any);
+        headers.set(IfUnmodifiedSince(date(EARLY)));
+        assert_eq!(evaluate_headers(&Method::Put, &headers, None, Some(date(LATE))),
+                   Outcome::Proceed);
+
+        headers.remove::<IfMatch>();
+        assert_eq!(evaluate_headers(&Method::Put, &headers, None, Some(date(LATE))),
+                   Outcome::PreconditionFailed);
+    }
+
+    #[test]
+    fn test_if_none_match() {
+        let mut headers = Headers::new();
+        headers.set(IfNoneMatch::Items(vec![EntityTag::weak("abc".to_owned())]));
+        assert_eq!(evaluate_headers(&Method::Get, &headers, Some(&etag()), None),
+                   Outcome::NotModified);
+        assert_eq!(evaluate_headers(&Method::Post, &headers, Some(&etag()), None),
+                   Outcome::PreconditionFailed);
+    }
+
+    #[test]
+    fn test_if_none_match_overrides_if_modified_since() {
+        let mut headers = Headers::new();
+        headers.set(IfNoneMatch::Items(vec![EntityTag::strong("other".to_owned())]));
+        headers.set(IfModifiedSince(date(LATE)));
+        assert_eq!(evaluate_headers(&Method::Get, &headers, Some(&etag()), Some(date(EARLY))),
+                   Outcome::Proceed);
+
+        headers.remove::<IfNoneMatch>();
+        assert_eq!(evaluate_headers(&Method::Get, &headers, Some(&etag()), Some(date(EARLY))),
+                   Outcome::NotModified);
+        assert_eq!(evaluate_headers(&Method::Get, &headers, Some(&etag()), Some(date(LATE))),
+                   Outcome::NotModified);
+    }
+
+    #[test]
+    fn test_if_range() {
+        let mut headers = Headers::new();
+        assert!(!if_range(&Method::Get, &headers, Some(&etag()), None));
+
+        headers.set(Range::bytes(0, 10));
+        assert!(if_range(&Method::Get, &headers, Some(&etag()), None));
+
+        headers.set(IfRange::EntityTag(etag()));
+        assert!(if_range(&Method::Get, &headers, Some(&etag()), None));
+
+        headers.set(IfRange::Date(date(EARLY)));
+        assert!(!if_range(&Method::Get, &headers, Some(&etag()), Some(date(LATE))));
+        assert!(if_range(&Method::Get, &headers, Some(&etag()), Some(date(EARLY))));
+    }
+
+    #[test]
+    fn test_check_if_match() {
+        let current = strong_etag(b"{\"name\":\"ann\"}");
+        assert_eq!(current, strong_etag(b"{\"name\":\"ann\"}"));
+        assert!(current != strong_etag(b"{\"name\":\"bob\"}"));
+        assert!(!current.weak);
+
+        let check = |method: Method, if_match: Option<String>, required: bool| {
+            let mut req = StubRequest::new(method, "http://localhost/");
+            if let Some(ref tag) = if_match { req = req.raw_header("If-Match", tag) }
+            check_if_match(&req.build(), &current, required)
+                .map_err(|err| err.response.status.unwrap())
+        };
+
+        assert_eq!(check(Method::Put, Some(current.to_string()), true), Ok(()));
+        assert_eq!(check(Method::Put, Some("*".into()), true), Ok(()));
+        assert_eq!(check(Method::Put, Some("\"stale\"".into()), false),
+                   Err(status::PreconditionFailed));
+        assert_eq!(check(Method::Delete, None, true), Err(status::PreconditionRequired));
+        assert_eq!(check(Method::Delete, None, false), Ok(()));
+        assert_eq!(check(Method::Get, Some("\"stale\"".into()), true), Ok(()));
+    }
+}
@@ -0,0 +1,387 @@
+//! Application configuration loaded from TOML files.
+//!
+//! A configuration file holds the default settings at the top level, and
+//! settings for particular environments in tables under `env`, which are
+//! merged over the defaults when the `IRON_ENV` environment variable names
+//! that environment:
+//!
+//! ```toml
+//! [session]
+//! secret = "development secret"
+//!
+//! [env.production.session]
+//! secret = "something better"
+//! ```
+//!
+//! Individual settings can also be overridden by environment variables named
+//! after their path, in upper case, with `__` separating tables: for instance
+//! `IRON_SESSION__SECRET` overrides `session.secret`.
+//!
+//! As `BeforeMiddleware`, a `Config` makes itself available to handlers
+//! through `Config::of`:
+//!
+//! ```ignore
+//! let config = Config::load("config.toml").unwrap();
+//! chain.link_before(config.clone());
+//!
+//! // In a handler:
+//! let secret = Config::of(req).and_then(|c| c.get_str("session.secret"));
+//! ```
+
+use std::env;
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+use std::sync::Arc;
+
+use rustc_serialize::Decodable;
+use toml::{self, Parser, Table};
+use typemap::Key;
+
+use {BeforeMiddleware, Request, IronResult};
+
+pub use toml::Value;
+
+/// The environment used when `IRON_ENV` is not set.
+pub const DEFAULT_ENV: &'static str = "development";
+
+// Environment variables overriding settings start with this prefix.
+const OVERRIDE_PREFIX: &'static str = "IRON_";
+
+/// A loaded configuration.
+///
+/// Cheap to clone.
+#[derive(Debug, Clone)]
+pub struct Config {
+    table: Arc<Table>,
+    env: String
+}
+
+impl Key for Config { type Value = Config; }
+
+impl Config {
+    /// Load the configuration file at `path` for the environment named by
+    /// `IRON_ENV`, applying overrides from environment variables.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Config, ConfigError> {
+        let mut source = String::new();
+        try!(File::open(path).and_then(|mut file| file.read_to_string(&mut source)));
+
+        let env = env::var("IRON_ENV").unwrap_or_else(|_| DEFAULT_ENV.to_owned());
+        let mut config = try!(Config::parse(&source, &env));
+        config.override_with(env::vars());
+        Ok(config)
+    }
+
+    /// Parse configuration from a string for the environment `env`, without
+    /// applying any overrides.
+    pub fn parse(source: &str, env: &str) -> Result<Config, ConfigError> {
+        let mut parser = Parser::new(source);
+
+        let mut table = match parser.parse() {
+            Some(table) => table,
+            None => {
+                let messages = parser.errors.iter().map(|e| {
+                    let (line, col) = parser.to_linecol(e.lo);
+                    format!("{}:{}: {}", line + 1, col + 1, e.desc)
+                }).collect::<Vec<_>>();
+                return Err(ConfigError::Parse(messages.join("; ")));
+            }
+        };
+
+        let overlay = match table.remove("env") {
+            Some(Value::Table(mut envs)) => envs.remove(env),
+            Some(_) => return Err(ConfigError::Parse("`env` must be a table".to_owned())),
+            None => None
+        };
+
+        match overlay {
+            Some(Value::Table(overlay)) => merge(&mut table, overlay),
+            Some(_) => {
+                return Err(ConfigError::Parse(format!("`env.{}` must be a table", env)))
+            },
+            None => {}
+        }
+
+        Ok(Config { table: Arc::new(table), env: env.to_owned() })
+    }
+
+    /// Apply overrides from `vars`, a list of environment variables.
+    ///
+    /// Variables whose names start with `IRON_` and contain `__` override
+    /// the setting at the corresponding path. Values are parsed according to
+    /// the type of the setting they replace, and as strings for new settings.
+    pub fn override_with<I: IntoIterator<Item=(String, String)>>(&mut self, vars: I) {
+        for (name, value) in vars {
+            if !name.starts_with(OVERRIDE_PREFIX) || !name.contains("__") { continue }
+
+            let path = name[OVERRIDE_PREFIX.len()..].split("__")
+                .map(|part| part.to_lowercase())
+                .collect::<Vec<_>>();
+
+            let value = match parse_like(self.lookup(&path), &value) {
+                Some(value) => value,
+                None => {
+                    warn!("Ignoring {}: {:?} is not a valid value", name, value);
+                    continue;
+                }
+            };
+
+            set(Arc::make_mut(&mut self.table), &path, value);
+        }
+    }
+
+    /// The environment this configuration was loaded for.
+    pub fn environment(&self) -> &str {
+        &self.env
+    }
+
+    /// The setting at `path`, a list of keys separated by dots.
+    pub fn get(&self, path: &str) -> Option<&Value> {
+        self.lookup(&path.split('.').map(|part| part.to_owned()).collect::<Vec<_>>())
+    }
+
+    /// The string setting at `path`.
+    pub fn get_str(&self, path: &str) -> Option<&str> {
+        self.get(path).and_then(|value| value.as_str())
+    }
+
+    /// The integer setting at `path`.
+    pub fn get_integer(&self, path: &str) -> Option<i64> {
+        self.get(path).and_then(|value| value.as_integer())
+    }
+
+    /// The boolean setting at `path`.
+    pub fn get_bool(&self, path: &str) -> Option<bool> {
+        self.get(path).and_then(|value| value.as_bool())
+    }
+
+    /// Decode the setting at `path`, usually a table, into a `T`.
+    ///
+    /// An empty `path` decodes the whole configuration.
+    pub fn decode<T: Decodable>(&self, path: &str) -> Result<T, ConfigError> {
+        let value = if path.is_empty() {
+            Value::Table((*self.table).clone())
+        } else {
+            match self.get(path) {
+                Some(value) => value.clone(),
+                None => return Err(ConfigError::Missing(path.to_owned()))
+            }
+        };
+
+        T::decode(&mut toml::Decoder::new(value))
+            .map_err(|e| ConfigError::Decode(format!("{}: {}", path, e)))
+    }
+
+    /// The configuration made available to `req` by a `Config` linked as
+    /// `BeforeMiddleware`, if any.
+    pub fn of<'a>(req: &'a Request) -> Option<&'a Config> {
+        req.extensions.get::<Config>()
+    }
+
+    fn lookup(&self, path: &[String]) -> Option<&Value> {
+        let (last, tables) = match path.split_last() {
+            Some(split) => split,
+            None => return None
+        };
+
+        let mut table = &*self.table;
+        for key in tables {
+            match table.get(key) {
+                Some(&Value::Table(ref inner)) => table = inner,
+                _ => return None
+            }
+        }
+
+        table.get(last)
+    }
+}
+
+impl BeforeMiddleware for Config {
+    fn before(&self, req: &mut Request) -> IronResult<()> {
+        req.extensions.insert::<Config>(self.clone());
+        Ok(())
+    }
+}
+
+// Merge `overlay` into `table`, recursively merging tables present in both.
+fn merge(table: &mut Table, overlay: Table) {
+    for (key, value) in overlay {
+        match (table.get_mut(&key), value) {
+            (Some(&mut Value::Table(ref mut inner)), Value::Table(overlay)) => {
+                merge(inner, overlay);
+            },
+            (Some(existing), value) => *existing = value,
+            (None, value) => { table.insert(key, value); }
+        }
+    }
+}
+
+// Set the value at `path`, creating tables on the way and replacing
+// non-table values in the way.
+fn set(table: &mut Table, path: &[String], value: Value) {
+    match path.split_first() {
+        Some((key, [])) => { table.insert(key.clone(), value); },
+        Some((key, rest)) => {
+            let entry = table.entry(key.clone()).or_insert_with(|| Value::Table(Table::new()));
+            if entry.as_table().is_none() { *entry = Value::Table(Table::new()) }
+
+            if let Value::Table(ref mut inner) = *entry { set(inner, rest, value) }
+        },
+        None => {}
+    }
+}
+
+// Parse `raw` as the same type as `existing`, or as a string if there is no
+// existing value.
+fn parse_like(existing: Option<&Value>, raw: &str) -> Option<Value> {
+    match existing {
+        Some(&Value::Integer(_)) => raw.parse().ok().map(Value::Integer),
+        Some(&Value::Float(_)) => raw.parse().ok().map(Value::Float),
+        Some(&Value::Boolean(_)) => raw.parse().ok().map(Value::Boolean),
+        Some(&Value::Array(_)) => {
+            let items = raw.split(',').map(|item| Value::String(item.trim().to_owned()));
+            Some(Value::Array(items.collect()))
+        },
+        Some(&Value::Table(_)) => None,
+        _ => Some(Value::String(raw.to_owned()))
+    }
+}
+
+/// An error loading or reading configuration.
+#[derive(Debug)]
+pub enum ConfigError {
+    /// The configuration file could not be read.
+    Io(io::Error),
+
+    /// The configuration file is not valid TOML, or is badly structured.
+    Parse(String),
+
+    /// A setting requested with `decode` does not exist.
+    Missing(String),
+
+    /// A setting could not be decoded into the requested type.
+    Decode(String)
+}
+
+impl From<io::Error> for ConfigError {
+    fn from(err: io::Error) -> ConfigError {
+        ConfigError::Io(err)
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ConfigError::Io(ref e) => write!(f, "Could not read configuration: {}", e),
+            ConfigError::Parse(ref e) => write!(f, "Invalid configuration: {}", e),
+            ConfigError::Missing(ref path) => write!(f, "Missing setting `{}`", path),
+            ConfigError::Decode(ref e) => write!(f, "Invalid setting {}", e)
+        }
+    }
+}
+
+impl Error for ConfigError {
+    fn description(&self) -> &str {
+        match *self {
+            ConfigError::Io(_) => "Could not read configuration",
+            ConfigError::Parse(_) => "Invalid configuration",
+            ConfigError::Missing(_) => "Missing setting",
+            ConfigError::Decode(_) => "Invalid setting"
+        }
+    }
+
+    fn cause(&self) -> Option<&Error> {
+        match *self {
+            ConfigError::Io(ref e) => Some(e),
+            _ => None
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rustc_serialize::{Decodable, Decoder};
+
+    use super::{Config, ConfigError};
+
+    const SOURCE: &'static str = r#"
+        [server]
+        port = 3000
+        debug = true
+
+        [session]
+        secret = "dev"
+
+        [env.production.server]
+        port = 80
+        debug = false
+    "#;
+
+    #[test]
+    fn test_environments() {
+        let dev = Config::parse(SOURCE, "development").unwrap();
+        assert_eq!(dev.get_integer("server.port"), Some(3000));
+        assert_eq!(dev.get_bool("server.debug"), Some(true));
+        assert!(dev.get("env").is_none());
+
+        let prod = Config::parse(SOURCE, "production").unwrap();
+        assert_eq!(prod.environment(), "production");
+        assert_eq!(prod.get_integer("server.port"), Some(80));
+        assert_eq!(prod.get_bool("server.debug"), Some(false));
+        assert_eq!(prod.get_str("session.secret"), Some("dev"));
+    }
+
+    #[test]
+    fn test_overrides() {
+        let mut config = Config::parse(SOURCE, "development").unwrap();
+        config.override_with(vec![
+            ("IRON_SERVER__PORT".to_owned(), "8080".to_owned()),
+            ("IRON_SERVER__DEBUG".to_owned(), "not a bool".to_owned()),
+            ("IRON_SESSION__SECRET".to_owned(), "from env".to_owned()),
+            ("IRON_CACHE__DIR".to_owned(), "/tmp".to_owned()),
+            ("IRON_THREADS".to_owned(), "4".to_owned())
+        ]);
+
+        assert_eq!(config.get_integer("server.port"), Some(8080));
+        assert_eq!(config.get_bool("server.debug"), Some(true));
+        assert_eq!(config.get_str("session.secret"), Some("from env"));
+        assert_eq!(config.get_str("cache.dir"), Some("/tmp"));
+        assert!(config.get("threads").is_none());
+    }
+
+    #[test]
+    fn test_decode() {
+        #[derive(Debug, PartialEq)]
+        struct Server { port: u16, debug: bool }
+
+        impl Decodable for Server {
+            fn decode<D: Decoder>(d: &mut D) -> Result<Server, D::Error> {
+                d.read_struct("Server", 2, |d| {
+                    Ok(Server {
+                        port: try!(d.read_struct_field("port", 0, Decodable::decode)),
+                        debug: try!(d.read_struct_field("debug", 1, Decodable::decode))
+                    })
+                })
+            }
+        }
+
+        let config = Config::parse(SOURCE, "development").unwrap();
+        assert_eq!(config.decode::<Server>("server").unwrap(), Server { port: 3000, debug: true });
+
+        match config.decode::<Server>("nope") {
+            Err(ConfigError::Missing(_)) => {},
+            other => panic!("unexpected {:?}", other)
+        }
+        assert!(config.decode::<Server>("session").is_err());
+    }
+
+    #[test]
+    fn test_parse_error() {
+        match Config::parse("[server\nport = ", "development") {
+            Err(ConfigError::Parse(_)) => {},
+            other => panic!("unexpected {:?}", other)
+        }
+    }
+}
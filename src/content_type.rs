@@ -0,0 +1,270 @@
+//! Checking that bodies are what their `Content-Type` says they are.
+//!
+//! `ContentTypeGuard` rejects requests whose body does not parse as its
+//! declared type before any handler sees them, so a handler decoding a form
+//! never has to cope with JSON sent by mistake, and makes every response
+//! declare its type and forbid browsers from guessing another one:
+//!
+//! ```ignore
+//! let guard = ContentTypeGuard::new(1024 * 1024);
+//! chain.link_before(guard.clone());
+//! chain.link_after(guard);
+//! ```
+//!
+//! Responses without a body are left without a `Content-Type`.
+
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+use mime::Mime;
+
+use json_depth::{self, JsonError, DEFAULT_MAX_DEPTH};
+use request::BodyError;
+use url::{query_pairs, DecodeError};
+use {headers, status, BeforeMiddleware, AfterMiddleware, Request, Response, IronResult,
+     IronError};
+
+/// Middleware validating request bodies against their `Content-Type`, and
+/// giving responses an explicit one.
+///
+/// As `BeforeMiddleware`, bodies declared as JSON (`application/json` or
+/// any `+json` type) must parse as JSON, and bodies declared as
+/// `application/x-www-form-urlencoded` must decode as a form. Bodies which
+/// do not, or JSON nested more deeply than the maximum depth, are rejected
+/// with `415 Unsupported Media Type`, and bodies longer than the limit with
+/// `413 Payload Too Large`. Empty bodies and other
+/// types are passed on; checked bodies are rewound afterwards.
+///
+/// As `AfterMiddleware`, including for errors, it sets the `Content-Type`
+/// of responses which have a body but no type, and adds
+/// `X-Content-Type-Options: nosniff` to every response.
+#[derive(Clone)]
+pub struct ContentTypeGuard {
+    limit: usize,
+    max_depth: usize,
+    default_type: Mime
+}
+
+impl ContentTypeGuard {
+    /// Validate request bodies of up to `limit` bytes, with JSON nested up
+    /// to `json_depth::DEFAULT_MAX_DEPTH` deep.
+    ///
+    /// Untyped responses are given `application/octet-stream`.
+    pub fn new(limit: usize) -> ContentTypeGuard {
+        ContentTypeGuard {
+            limit: limit,
+            max_depth: DEFAULT_MAX_DEPTH,
+            default_type: "application/octet-stream".parse().unwrap()
+        }
+    }
+
+    /// Reject JSON bodies whose arrays and objects are nested more than
+    /// `depth` deep.
+    pub fn max_depth(mut self, depth: usize) -> ContentTypeGuard {
+        self.max_depth = depth;
+        self
+    }
+
+    /// Give responses with a body but no `Content-Type` `mime` instead.
+    pub fn default_type(mut self, mime: Mime) -> ContentTypeGuard {
+        self.default_type = mime;
+        self
+    }
+
+    fn secure(&self, res: &mut Response) {
+        if res.body.is_some() && !res.headers.has::<headers::ContentType>() {
+            res.headers.set(headers::ContentType(self.default_type.clone()));
+        }
+        res.headers.set_raw("X-Content-Type-Options", vec![b"nosniff".to_vec()]);
+    }
+}
+
+impl BeforeMiddleware for ContentTypeGuard {
+    fn before(&self, req: &mut Request) -> IronResult<()> {
+        let kind = match req.headers.get::<headers::ContentType>() {
+            Some(&headers::ContentType(ref mime)) => kind(mime),
+            None => None
+        };
+        let kind = match kind {
+            Some(kind) => kind,
+            None => return Ok(())
+        };
+
+        let body = match req.body_string(self.limit) {
+            Ok(body) => body,
+            Err(e) => {
+                let status = match e {
+                    BodyError::Io(ref e) if e.kind() == io::ErrorKind::InvalidData => {
+                        status::PayloadTooLarge
+                    },
+                    BodyError::Io(_) => status::BadRequest,
+                    _ => status::UnsupportedMediaType
+                };
+                return Err(IronError::new(e, status))
+            }
+        };
+        if let Err(e) = req.body.rewind() {
+            return Err(IronError::new(e, status::InternalServerError));
+        }
+        if body.is_empty() { return Ok(()) }
+
+        // The body may have been buffered earlier with a larger limit.
+        if body.len() > self.limit {
+            let e = io::Error::new(io::ErrorKind::InvalidData, "Request body too large");
+            return Err(IronError::new(e, status::PayloadTooLarge))
+        }
+
+        let checked = match kind {
+            Kind::Json => json_depth::parse(&body, self.max_depth).map(|_| ())
+                .map_err(ContentMismatch::Json),
+            Kind::Form => query_pairs(&body).map(|pair| pair.map(|_| ()))
+                .collect::<Result<(), _>>().map_err(ContentMismatch::Form)
+        };
+        checked.map_err(|e| IronError::new(e, status::UnsupportedMediaType))
+    }
+}
+
+impl AfterMiddleware for ContentTypeGuard {
+    fn after(&self, _: &mut Request, mut res: Response) -> IronResult<Response> {
+        self.secure(&mut res);
+        Ok(res)
+    }
+
+    fn catch(&self, _: &mut Request, mut err: IronError) -> IronResult<Response> {
+        self.secure(&mut err.response);
+        Err(err)
+    }
+}
+
+/// The error for a request body which does not match its `Content-Type`.
+#[derive(Debug)]
+pub enum ContentMismatch {
+    /// The body was declared as JSON, but is not valid JSON, or is nested
+    /// too deeply.
+    Json(JsonError),
+
+    /// The body was declared as a form, but could not be decoded as one.
+    Form(DecodeError)
+}
+
+impl fmt::Display for ContentMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ContentMismatch::Json(ref e) => write!(f, "Request body is not valid JSON: {}", e),
+            ContentMismatch::Form(ref e) => write!(f, "Request body is not a valid form: {}", e)
+        }
+    }
+}
+
+impl Error for ContentMismatch {
+    fn description(&self) -> &str {
+        match *self {
+            ContentMismatch::Json(_) => "Request body is not valid JSON",
+            ContentMismatch::Form(_) => "Request body is not a valid form"
+        }
+    }
+
+    fn cause(&self) -> Option<&Error> {
+        match *self {
+            ContentMismatch::Json(ref e) => Some(e),
+            ContentMismatch::Form(ref e) => Some(e)
+        }
+    }
+}
+
+enum Kind {
+    Json,
+    Form
+}
+
+fn kind(mime: &Mime) -> Option<Kind> {
+    let Mime(ref top, ref sub, _) = *mime;
+    match (top.as_str(), sub.as_str()) {
+        ("application", "x-www-form-urlencoded") => Some(Kind::Form),
+        ("application", "json") => Some(Kind::Json),
+        (_, sub) if sub.ends_with("+json") => Some(Kind::Json),
+        _ => None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Read;
+
+    use method::Method;
+    use status;
+    use test::{MiddlewareHarness, StubRequest};
+    use {Chain, Request, Response};
+    use super::ContentTypeGuard;
+
+    fn harness() -> MiddlewareHarness<Chain> {
+        let mut chain = Chain::new(|req: &mut Request| {
+            let mut body = String::new();
+            req.body.read_to_string(&mut body).unwrap();
+            Ok(Response::with((status::Ok, body)))
+        });
+        let guard = ContentTypeGuard::new(16);
+        chain.link_before(guard.clone());
+        chain.link_after(guard);
+        MiddlewareHarness::new(chain)
+    }
+
+    fn post(harness: &MiddlewareHarness<Chain>, content_type: &str, body: &str)
+            -> Option<status::Status> {
+        let req = StubRequest::new(Method::Post, "http://localhost/")
+            .raw_header("Content-Type", content_type)
+            .body(body);
+        harness.handle(req).status()
+    }
+
+    #[test]
+    fn test_requests() {
+        let harness = harness();
+        assert_eq!(post(&harness, "application/json", "{\"a\": 1}"), Some(status::Ok));
+        assert_eq!(post(&harness, "application/json", "a=1"),
+                   Some(status::UnsupportedMediaType));
+        assert_eq!(post(&harness, "application/problem+json", "{"),
+                   Some(status::UnsupportedMediaType));
+        assert_eq!(post(&harness, "application/json", ""), Some(status::Ok));
+        assert_eq!(post(&harness, "application/json", "[1, 2, 3, 4, 5, 6, 7]"),
+                   Some(status::PayloadTooLarge));
+
+        let shallow = MiddlewareHarness::new(ContentTypeGuard::new(1024).max_depth(3));
+        let nested = |depth| format!("{}{}", "[".repeat(depth), "]".repeat(depth));
+        let before = |body: String| {
+            shallow.before(StubRequest::new(Method::Post, "http://localhost/")
+                .raw_header("Content-Type", "application/json")
+                .body(body)).status()
+        };
+        assert_eq!(before(nested(3)), None);
+        assert_eq!(before(nested(4)), Some(status::UnsupportedMediaType));
+        assert_eq!(before("[".repeat(1000)), Some(status::UnsupportedMediaType));
+
+        assert_eq!(post(&harness, "application/x-www-form-urlencoded", "a=1&b=%20"),
+                   Some(status::Ok));
+        assert_eq!(post(&harness, "application/x-www-form-urlencoded", "a=%zz"),
+                   Some(status::UnsupportedMediaType));
+        assert_eq!(post(&harness, "text/plain", "{"), Some(status::Ok));
+    }
+
+    #[test]
+    fn test_responses() {
+        let harness = harness();
+        let mut run = harness.handle(StubRequest::new(Method::Post, "http://localhost/")
+            .raw_header("Content-Type", "application/json")
+            .body("{}"));
+        assert_eq!(run.response().headers.get_raw("Content-Type"),
+                   Some(&[b"application/octet-stream".to_vec()][..]));
+        assert_eq!(run.response().headers.get_raw("X-Content-Type-Options"),
+                   Some(&[b"nosniff".to_vec()][..]));
+        assert_eq!(run.take_body(), b"{}");
+
+        let run = harness.handle(StubRequest::new(Method::Post, "http://localhost/")
+            .raw_header("Content-Type", "application/json")
+            .body("{"));
+        assert_eq!(run.status(), Some(status::UnsupportedMediaType));
+        assert!(!run.response().headers.has::<::headers::ContentType>());
+        assert!(run.response().headers.get_raw("X-Content-Type-Options").is_some());
+    }
+}
@@ -0,0 +1,259 @@
+//! Cookies which clients cannot tamper with.
+//!
+//! A `CookieJar` holds a server secret and uses it to sign cookie values,
+//! so that changes made by clients are detected, or to encrypt them, so
+//! that clients cannot read them either. It is meant to be shared by the
+//! middleware keeping state in cookies, such as sessions, CSRF protection
+//! and flash messages, and as `BeforeMiddleware` makes itself available
+//! to them through `CookieJar::of`:
+//!
+//! ```ignore
+//! let mut jar = CookieJar::new(b"a long, random server secret");
+//! jar.previous_key(b"the secret it replaces");
+//! chain.link_before(jar);
+//!
+//! // In a handler:
+//! let jar = CookieJar::of(req).unwrap();
+//! let visits = jar.get_signed(req, "visits").and_then(|v| v.parse().ok()).unwrap_or(0);
+//! let value = jar.sign("visits", &(visits + 1).to_string());
+//! Ok(Response::with((status::Ok, "Welcome back")).cookie(format!("visits={}; HttpOnly", value)))
+//! ```
+//!
+//! Values are bound to their cookie's name, so a value cannot be moved from
+//! one cookie to another. Encrypted values are deterministic: equal values
+//! of the same cookie encrypt to the same string, which reveals that they
+//! are equal but nothing more.
+
+use std::sync::Arc;
+
+use rustc_serialize::base64::{FromBase64, ToBase64, URL_SAFE};
+use typemap::Key;
+
+use checksum::{constant_time_eq, hmac_sha256};
+use url::{percent_encode, percent_decode};
+use {headers, BeforeMiddleware, Request, IronResult};
+
+/// Signs and encrypts cookie values with a server secret.
+///
+/// Clones share the same keys.
+#[derive(Clone)]
+pub struct CookieJar {
+    keys: Arc<Vec<Keys>>
+}
+
+// The keys derived from one secret.
+#[derive(Clone)]
+struct Keys {
+    signing: Vec<u8>,
+    encryption: Vec<u8>,
+    // Keys the synthetic IVs of encrypted values, which are sent in the
+    // clear and so must not double as signatures.
+    siv: Vec<u8>
+}
+
+impl Keys {
+    fn derive(secret: &[u8]) -> Keys {
+        Keys {
+            signing: hmac_sha256(secret, b"iron cookie signing"),
+            encryption: hmac_sha256(secret, b"iron cookie encryption"),
+            siv: hmac_sha256(secret, b"iron cookie siv")
+        }
+    }
+
+    fn mac(&self, name: &str, value: &[u8]) -> Vec<u8> {
+        hmac_sha256(&self.signing, &named(name, value))
+    }
+
+    fn siv(&self, name: &str, value: &[u8]) -> Vec<u8> {
+        hmac_sha256(&self.siv, &named(name, value))
+    }
+
+    // XOR `data` with a keystream of HMAC-SHA256 blocks keyed by the
+    // encryption key, over `iv` and a block counter. Applying it twice
+    // gives back the original.
+    fn apply_keystream(&self, iv: &[u8], data: &mut [u8]) {
+        for (counter, chunk) in data.chunks_mut(32).enumerate() {
+            let mut input = iv.to_vec();
+            input.extend_from_slice(&(counter as u64).to_be_bytes());
+            for (byte, key) in chunk.iter_mut().zip(hmac_sha256(&self.encryption, &input)) {
+                *byte ^= key;
+            }
+        }
+    }
+}
+
+// The message authenticated for `value` of the cookie `name`.
+fn named(name: &str, value: &[u8]) -> Vec<u8> {
+    let mut message = Vec::with_capacity(name.len() + 1 + value.len());
+    message.extend_from_slice(name.as_bytes());
+    message.push(b'=');
+    message.extend_from_slice(value);
+    message
+}
+
+impl Key for CookieJar { type Value = CookieJar; }
+
+impl CookieJar {
+    /// Create a jar keyed by `secret`, which should be long and random.
+    pub fn new(secret: &[u8]) -> CookieJar {
+        CookieJar { keys: Arc::new(vec![Keys::derive(secret)]) }
+    }
+
+    /// Also accept values signed or encrypted under `secret`, a secret
+    /// being rotated out. New values always use the secret given to `new`.
+    pub fn previous_key(&mut self, secret: &[u8]) -> &mut CookieJar {
+        Arc::make_mut(&mut self.keys).push(Keys::derive(secret));
+        self
+    }
+
+    /// The jar shared with `req`, if one has been linked before the
+    /// current middleware.
+    pub fn of(req: &Request) -> Option<CookieJar> {
+        req.extensions.get::<CookieJar>().cloned()
+    }
+
+    /// The value of the cookie `name` sent with `req`, as it was sent.
+    pub fn get(req: &Request, name: &str) -> Option<String> {
+        req.headers.get::<headers::Cookie>().and_then(|cookies| {
+            cookies.iter().find(|cookie| cookie.name == name).map(|cookie| cookie.value.clone())
+        })
+    }
+
+    /// Sign `value` for the cookie `name`, giving the value to send.
+    ///
+    /// The value stays readable by clients.
+    pub fn sign(&self, name: &str, value: &str) -> String {
+        let encoded = percent_encode(value);
+        let mac = self.keys[0].mac(name, encoded.as_bytes());
+        format!("{}.{}", encoded, mac.to_base64(URL_SAFE))
+    }
+
+    /// Check a value produced by `sign` for the cookie `name`, returning the
+    /// original value unless it has been tampered with.
+    pub fn verify(&self, name: &str, signed: &str) -> Option<String> {
+        let mut parts = signed.rsplitn(2, '.');
+        let (mac, encoded) = match (parts.next(), parts.next()) {
+            (Some(mac), Some(encoded)) => (mac, encoded),
+            _ => return None
+        };
+        let mac = match mac.from_base64() {
+            Ok(mac) => mac,
+            Err(_) => return None
+        };
+
+        let valid = self.keys.iter()
+            .any(|keys| constant_time_eq(&keys.mac(name, encoded.as_bytes()), &mac));
+        if valid { percent_decode(encoded).ok() } else { None }
+    }
+
+    /// Encrypt `value` for the cookie `name`, giving the value to send.
+    pub fn encrypt(&self, name: &str, value: &str) -> String {
+        let keys = &self.keys[0];
+        let iv = keys.siv(name, value.as_bytes());
+        let mut data = value.as_bytes().to_vec();
+        keys.apply_keystream(&iv, &mut data);
+
+        let mut sealed = iv;
+        sealed.extend_from_slice(&data);
+        sealed.to_base64(URL_SAFE)
+    }
+
+    /// Decrypt a value produced by `encrypt` for the cookie `name`,
+    /// returning the original value unless it has been tampered with.
+    pub fn decrypt(&self, name: &str, sealed: &str) -> Option<String> {
+        let sealed = match sealed.from_base64() {
+            Ok(ref sealed) if sealed.len() >= 32 => sealed.clone(),
+            _ => return None
+        };
+        let (iv, data) = sealed.split_at(32);
+
+        self.keys.iter().filter_map(|keys| {
+            let mut value = data.to_vec();
+            keys.apply_keystream(iv, &mut value);
+            if constant_time_eq(&keys.siv(name, &value), iv) { Some(value) } else { None }
+        }).next().and_then(|value| String::from_utf8(value).ok())
+    }
+
+    /// The verified value of the signed cookie `name` sent with `req`.
+    pub fn get_signed(&self, req: &Request, name: &str) -> Option<String> {
+        CookieJar::get(req, name).and_then(|signed| self.verify(name, &signed))
+    }
+
+    /// The decrypted value of the encrypted cookie `name` sent with `req`.
+    pub fn get_encrypted(&self, req: &Request, name: &str) -> Option<String> {
+        CookieJar::get(req, name).and_then(|sealed| self.decrypt(name, &sealed))
+    }
+}
+
+impl BeforeMiddleware for CookieJar {
+    fn before(&self, req: &mut Request) -> IronResult<()> {
+        req.extensions.insert::<CookieJar>(self.clone());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rustc_serialize::base64::{FromBase64, ToBase64, URL_SAFE};
+
+    use method::Method;
+    use test::StubRequest;
+    use super::CookieJar;
+
+    #[test]
+    fn test_signing() {
+        let jar = CookieJar::new(b"secret");
+        let signed = jar.sign("user", "ann; admin=1");
+        assert!(signed.starts_with("ann%3B%20admin%3D1."));
+        assert_eq!(jar.verify("user", &signed), Some("ann; admin=1".into()));
+
+        assert_eq!(jar.verify("other", &signed), None);
+        assert_eq!(jar.verify("user", &signed.replace("ann", "bob")), None);
+        assert_eq!(CookieJar::new(b"other").verify("user", &signed), None);
+    }
+
+    #[test]
+    fn test_encryption() {
+        let jar = CookieJar::new(b"secret");
+        let long = "x".repeat(100);
+        for value in &["", "ann", &long] {
+            let sealed = jar.encrypt("session", value);
+            assert!(!sealed.contains("ann"));
+            assert_eq!(jar.decrypt("session", &sealed), Some(value.to_string()));
+            assert_eq!(jar.decrypt("other", &sealed), None);
+        }
+
+        let mut sealed = jar.encrypt("session", "ann").into_bytes();
+        let last = sealed.len() - 1;
+        sealed[last] = if sealed[last] == b'A' { b'B' } else { b'A' };
+        assert_eq!(jar.decrypt("session", &String::from_utf8(sealed).unwrap()), None);
+
+        // The IV of an encrypted value is not a signature of it.
+        let sealed = jar.encrypt("user", "admin").from_base64().unwrap();
+        let forged = format!("admin.{}", sealed[..32].to_base64(URL_SAFE));
+        assert_eq!(jar.verify("user", &forged), None);
+    }
+
+    #[test]
+    fn test_rotation_and_requests() {
+        let old = CookieJar::new(b"old");
+        let mut jar = CookieJar::new(b"new");
+        jar.previous_key(b"old");
+
+        let signed = old.sign("a", "1");
+        let sealed = old.encrypt("b", "2");
+        assert_eq!(jar.verify("a", &signed), Some("1".into()));
+        assert_eq!(old.verify("a", &jar.sign("a", "1")), None);
+
+        let cookies = format!("a={}; b={}; c=3", signed, sealed);
+        let mut req = StubRequest::new(Method::Get, "http://localhost/")
+            .raw_header("Cookie", &cookies)
+            .build();
+        assert_eq!(jar.get_signed(&req, "a"), Some("1".into()));
+        assert_eq!(jar.get_encrypted(&req, "b"), Some("2".into()));
+        assert_eq!(jar.get_signed(&req, "c"), None);
+        assert_eq!(CookieJar::get(&req, "c"), Some("3".into()));
+        req.headers.remove_raw("Cookie");
+        assert_eq!(CookieJar::get(&req, "c"), None);
+    }
+}
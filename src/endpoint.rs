@@ -0,0 +1,250 @@
+//! A single JSON endpoint for query languages.
+//!
+//! `SingleEndpoint` is an `AroundMiddleware` which hands the JSON bodies of
+//! `POST`s to one path to an executor function and sends back the JSON
+//! value it returns. It deals with the HTTP side, content types, errors
+//! and batches, leaving the executor to interpret the body, so it is a
+//! neutral place to plug in GraphQL or a similar query layer:
+//!
+//! ```ignore
+//! chain.link_around(SingleEndpoint::new("/graphql", move |query: Json, req: &mut Request| {
+//!     let context = req.extensions.get::<CurrentUser>().cloned();
+//!     Ok(schema.execute(&query, context))
+//! }));
+//! ```
+
+use rustc_serialize::json::{Json, Object, ToJson};
+
+use mime::Mime;
+use {headers, status, AroundMiddleware, Handler, Request, Response, IronResult, IronError, Set};
+use json_depth::{self, DEFAULT_MAX_DEPTH};
+use method::Method;
+use negotiation::negotiate_media_type;
+
+/// Middleware sending the JSON bodies of `POST`s to one path to an
+/// executor.
+///
+/// The executor's result is sent with `200 OK`. If it fails, the response
+/// has the status of its `IronError`, and unless the error carries its own
+/// body, a JSON body of the form `{"error": "<message>"}`.
+///
+/// When batching is enabled, as it is by default, a body which is a JSON
+/// array is treated as a batch: the executor is called for each element,
+/// and the response is an array of the results, where a failed element is
+/// replaced by an error object and does not fail the others.
+///
+/// Requests are rejected before reaching the executor if:
+///
+/// * they do not use `POST` (`405 Method Not Allowed`);
+/// * their `Content-Type` is set to something other than JSON (`415
+///   Unsupported Media Type`);
+/// * their `Accept` header excludes JSON (`406 Not Acceptable`);
+/// * their body is not valid JSON, is longer than the limit, or is nested
+///   more deeply than the maximum depth (`400 Bad Request`).
+pub struct SingleEndpoint<F> {
+    path: Vec<String>,
+    executor: F,
+    batching: bool,
+    limit: usize,
+    max_depth: usize
+}
+
+impl<F> SingleEndpoint<F>
+where F: Fn(Json, &mut Request) -> IronResult<Json> + Send + Sync + 'static {
+    /// Send bodies posted to `path` to `executor`, with a body limit of
+    /// 1 MiB and a maximum depth of `json_depth::DEFAULT_MAX_DEPTH`.
+    pub fn new(path: &str, executor: F) -> SingleEndpoint<F> {
+        SingleEndpoint {
+            path: path.split('/').filter(|s| !s.is_empty()).map(String::from).collect(),
+            executor: executor,
+            batching: true,
+            limit: 1024 * 1024,
+            max_depth: DEFAULT_MAX_DEPTH
+        }
+    }
+
+    /// Whether to treat arrays as batches. When disabled, arrays are given
+    /// to the executor whole.
+    pub fn batching(mut self, batching: bool) -> SingleEndpoint<F> {
+        self.batching = batching;
+        self
+    }
+
+    /// Reject request bodies longer than `limit` bytes.
+    pub fn limit(mut self, limit: usize) -> SingleEndpoint<F> {
+        self.limit = limit;
+        self
+    }
+
+    /// Reject request bodies whose arrays and objects are nested more than
+    /// `depth` deep.
+    pub fn max_depth(mut self, depth: usize) -> SingleEndpoint<F> {
+        self.max_depth = depth;
+        self
+    }
+
+    fn serve(&self, req: &mut Request) -> IronResult<Response> {
+        if req.method != Method::Post {
+            return Ok(Response::with(status::MethodNotAllowed)
+                .header(headers::Allow(vec![Method::Post])))
+        }
+
+        let json_body = match req.headers.get::<headers::ContentType>() {
+            Some(&headers::ContentType(ref mime)) => is_json(mime),
+            None => true
+        };
+        if !json_body { return Ok(error(status::UnsupportedMediaType, "Expected a JSON body")) }
+        if negotiate_media_type(req, &[json_type()]).is_none() {
+            return Ok(error(status::NotAcceptable, "JSON responses are not accepted"))
+        }
+
+        let body = try!(req.body_string(self.limit).map_err(IronError::from));
+        let query = match json_depth::parse(&body, self.max_depth) {
+            Ok(query) => query,
+            Err(e) => return Ok(error(status::BadRequest, &format!("Invalid JSON: {}", e)))
+        };
+
+        match query {
+            Json::Array(queries) if self.batching => {
+                let results = queries.into_iter().map(|query| {
+                    let result = (self.executor)(query, req);
+                    result.unwrap_or_else(|e| error_json(&e.error.to_string()))
+                }).collect();
+                Ok(json(status::Ok, Json::Array(results)))
+            },
+            query => match (self.executor)(query, req) {
+                Ok(result) => Ok(json(status::Ok, result)),
+                Err(mut e) => {
+                    if e.response.body.is_none() {
+                        let body = error_json(&e.error.to_string()).to_string();
+                        e.response.set_mut((body, json_type()));
+                    }
+                    Err(e)
+                }
+            }
+        }
+    }
+}
+
+fn is_json(mime: &Mime) -> bool {
+    let Mime(ref top, ref sub, _) = *mime;
+    top.as_str() == "application" && (sub.as_str() == "json" || sub.as_str().ends_with("+json"))
+}
+
+fn error_json(message: &str) -> Json {
+    let mut error = Object::new();
+    error.insert("error".into(), message.to_json());
+    Json::Object(error)
+}
+
+fn error(status: status::Status, message: &str) -> Response {
+    json(status, error_json(message))
+}
+
+fn json(status: status::Status, body: Json) -> Response {
+    Response::with((status, body.to_string(), json_type()))
+}
+
+fn json_type() -> Mime {
+    "application/json".parse().unwrap()
+}
+
+impl<F> AroundMiddleware for SingleEndpoint<F>
+where F: Fn(Json, &mut Request) -> IronResult<Json> + Send + Sync + 'static {
+    fn around(self, handler: Box<Handler>) -> Box<Handler> {
+        Box::new(Endpoint { endpoint: self, handler: handler })
+    }
+}
+
+struct Endpoint<F> {
+    endpoint: SingleEndpoint<F>,
+    handler: Box<Handler>
+}
+
+impl<F> Handler for Endpoint<F>
+where F: Fn(Json, &mut Request) -> IronResult<Json> + Send + Sync + 'static {
+    fn handle(&self, req: &mut Request) -> IronResult<Response> {
+        if req.url.path().iter().filter(|s| !s.is_empty()).eq(self.endpoint.path.iter()) {
+            self.endpoint.serve(req)
+        } else {
+            self.handler.handle(req)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fmt;
+
+    use rustc_serialize::json::Json;
+
+    use headers::{Accept, ContentType, qitem};
+    use method::Method;
+    use status;
+    use test::{MiddlewareHarness, StubRequest};
+    use {Chain, IronError, IronResult, Request, Response};
+    use super::SingleEndpoint;
+
+    // Echo the query, failing for `false`.
+    fn execute(query: Json, _: &mut Request) -> IronResult<Json> {
+        match query {
+            Json::Boolean(false) => Err(IronError::new(fmt::Error, status::UnprocessableEntity)),
+            query => Ok(query)
+        }
+    }
+
+    fn harness(batching: bool) -> MiddlewareHarness<Chain> {
+        let mut chain = Chain::new(|_: &mut Request| Ok(Response::with(status::NotFound)));
+        chain.link_around(SingleEndpoint::new("/query", execute).batching(batching));
+        MiddlewareHarness::new(chain)
+    }
+
+    fn post(body: &str) -> StubRequest {
+        StubRequest::new(Method::Post, "http://localhost/query").body(body)
+    }
+
+    fn reply(harness: &MiddlewareHarness<Chain>, req: StubRequest)
+             -> (Option<status::Status>, String) {
+        let mut run = harness.handle(req);
+        let body = String::from_utf8(run.take_body()).unwrap();
+        (run.status(), body)
+    }
+
+    #[test]
+    fn test_execute() {
+        let harness = harness(true);
+        assert_eq!(reply(&harness, post(r#"{"q":1}"#)), (Some(status::Ok), r#"{"q":1}"#.into()));
+        assert_eq!(reply(&harness, post("false")),
+                   (Some(status::UnprocessableEntity),
+                    r#"{"error":"an error occurred when formatting an argument"}"#.into()));
+        assert_eq!(reply(&harness, post("[1,false]")),
+                   (Some(status::Ok),
+                    r#"[1,{"error":"an error occurred when formatting an argument"}]"#.into()));
+
+        let unbatched = self::harness(false);
+        assert_eq!(reply(&unbatched, post("[1,2]")), (Some(status::Ok), "[1,2]".into()));
+    }
+
+    #[test]
+    fn test_rejections() {
+        let harness = harness(true);
+        let status = |req| reply(&harness, req).0;
+
+        assert_eq!(status(StubRequest::new(Method::Get, "http://localhost/query")),
+                   Some(status::MethodNotAllowed));
+        assert_eq!(status(post("{").header(ContentType("text/plain".parse().unwrap()))),
+                   Some(status::UnsupportedMediaType));
+        assert_eq!(status(post("{}").header(Accept(vec![qitem("text/html".parse().unwrap())]))),
+                   Some(status::NotAcceptable));
+        assert_eq!(status(post("{}").raw_header("Accept", "application/json;q=0, */*")),
+                   Some(status::NotAcceptable));
+        assert_eq!(status(post("{}").raw_header("Accept", "text/html, application/*;q=0.1")),
+                   Some(status::Ok));
+        assert_eq!(status(post("{")), Some(status::BadRequest));
+        assert_eq!(status(post(&"[".repeat(100_000))), Some(status::BadRequest));
+        assert_eq!(status(post("{}").header(ContentType("application/json".parse().unwrap()))),
+                   Some(status::Ok));
+        assert_eq!(status(StubRequest::new(Method::Post, "http://localhost/other")),
+                   Some(status::NotFound));
+    }
+}
@@ -1,8 +1,13 @@
 use std::error::Error as StdError;
 use std::fmt;
+use std::io;
 
 use modifier::Modifier;
 use {Response};
+use status::{self, Status};
+use parse::ParseError;
+use request::{BodyError, QueryError};
+use url::DecodeError;
 
 pub use err::Error;
 pub use hyper::Error as HttpError;
@@ -21,6 +26,14 @@ pub use hyper::error::Result as HttpResult;
 ///
 /// The `response` field provides a tangible action to be taken if this error
 /// is not otherwise handled.
+///
+/// Common errors convert into an `IronError` with a fitting status, so that
+/// `try!` can be used on them in handlers, and `context` adds a description
+/// of what was being done when an error occurred:
+///
+/// ```ignore
+/// let file = try!(File::open(&path).map_err(|e| IronError::from(e).context("opening report")));
+/// ```
 #[derive(Debug)]
 pub struct IronError {
     /// The underlying error
@@ -43,6 +56,99 @@ impl IronError {
             response: Response::with(m)
         }
     }
+
+    /// Layer a description of what was being done when this error occurred
+    /// over it, keeping its response.
+    pub fn context<C: Into<String>>(self, context: C) -> IronError {
+        IronError {
+            error: Box::new(Context { context: context.into(), cause: self.error }),
+            response: self.response
+        }
+    }
+
+    /// The contexts added to this error with `context`, outermost first.
+    pub fn contexts(&self) -> Vec<&str> {
+        let mut contexts = vec![];
+        let mut error = &*self.error;
+        while let Some(layer) = error.downcast::<Context>() {
+            contexts.push(&*layer.context);
+            error = &*layer.cause;
+        }
+        contexts
+    }
+
+    /// The status of the response for this error, which defaults to
+    /// `500 Internal Server Error` if none was set.
+    pub fn status(&self) -> Status {
+        self.response.status.unwrap_or(status::InternalServerError)
+    }
+}
+
+/// An error with a description of what was being done when it occurred,
+/// created by `IronError::context`.
+#[derive(Debug)]
+pub struct Context {
+    /// What was being done.
+    pub context: String,
+
+    /// The error which occurred.
+    pub cause: Box<Error + Send>
+}
+
+impl fmt::Display for Context {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.context, self.cause)
+    }
+}
+
+impl StdError for Context {
+    fn description(&self) -> &str {
+        &self.context
+    }
+
+    fn cause(&self) -> Option<&StdError> {
+        Some(&*self.cause)
+    }
+}
+
+impl From<io::Error> for IronError {
+    fn from(err: io::Error) -> IronError {
+        let status = match err.kind() {
+            io::ErrorKind::NotFound => status::NotFound,
+            io::ErrorKind::PermissionDenied => status::Forbidden,
+            _ => status::InternalServerError
+        };
+        IronError::new(err, status)
+    }
+}
+
+impl From<BodyError> for IronError {
+    fn from(err: BodyError) -> IronError {
+        let status = match err {
+            BodyError::UnsupportedCharset(_) => status::UnsupportedMediaType,
+            _ => status::BadRequest
+        };
+        IronError::new(err, status)
+    }
+}
+
+impl From<QueryError> for IronError {
+    fn from(err: QueryError) -> IronError {
+        IronError::new(err, status::BadRequest)
+    }
+}
+
+impl From<DecodeError> for IronError {
+    fn from(err: DecodeError) -> IronError {
+        IronError::new(err, status::BadRequest)
+    }
+}
+
+impl From<ParseError> for IronError {
+    fn from(err: ParseError) -> IronError {
+        let status = err.status;
+        IronError::new(err, status)
+    }
 }
 
 impl fmt::Display for IronError {
@@ -61,3 +167,33 @@ impl StdError for IronError {
     }
 }
 
+
+#[cfg(test)]
+mod test {
+    use std::io;
+
+    use status;
+    use request::QueryError;
+    use super::IronError;
+
+    #[test]
+    fn test_context() {
+        let err = IronError::from(io::Error::new(io::ErrorKind::NotFound, "no such file"))
+            .context("reading template")
+            .context("rendering page");
+
+        assert_eq!(err.status(), status::NotFound);
+        assert_eq!(err.contexts(), vec!["rendering page", "reading template"]);
+        assert_eq!(err.to_string(), "rendering page: reading template: no such file");
+    }
+
+    #[test]
+    fn test_from() {
+        let err = IronError::from(QueryError::Missing("page".into()));
+        assert_eq!(err.status(), status::BadRequest);
+        assert!(err.contexts().is_empty());
+
+        let err = IronError::from(io::Error::new(io::ErrorKind::Other, "disk on fire"));
+        assert_eq!(err.status(), status::InternalServerError);
+    }
+}
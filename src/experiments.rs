@@ -0,0 +1,399 @@
+//! A/B experiments, assigning each user a variant of each experiment.
+//!
+//! `Experiments` is middleware which assigns every request a variant of
+//! each experiment it runs, chosen by hashing the experiment's name with a
+//! key from the request, such as the user or session id, so the same user
+//! always gets the same variant. The assignments are kept in a cookie, so
+//! they stay put even when the key changes, for instance when a visitor
+//! signs in, and made available to handlers as `Assignments`:
+//!
+//! ```ignore
+//! let mut experiments = Experiments::new();
+//! experiments.add(Experiment::new("checkout").variant("control", 1).variant("one_page", 1))
+//!            .key(|req| session_id(req))
+//!            .report_to(&stats);
+//! chain.link_before(experiments.clone());
+//! chain.link_after(experiments);
+//!
+//! // In a handler:
+//! match Assignments::of(req).and_then(|a| a.variant("checkout")) {
+//!     Some("one_page") => ...,
+//!     _ => ...
+//! }
+//! ```
+//!
+//! Each new assignment is recorded with the `AssignmentSink`, which by
+//! default logs it, and counted, so that outcomes can later be compared
+//! between variants.
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+use rustc_serialize::json::ToJson;
+use sha2::{Sha256, Digest};
+use typemap::Key;
+
+use cookies::CookieJar;
+use stats::Stats;
+use url::{percent_decode, percent_encode};
+use {BeforeMiddleware, AfterMiddleware, Request, Response, IronResult, IronError};
+
+/// An experiment and its weighted variants.
+#[derive(Debug, Clone)]
+pub struct Experiment {
+    name: String,
+    variants: Vec<(String, u32)>
+}
+
+impl Experiment {
+    /// Create an experiment called `name`, with no variants yet.
+    pub fn new(name: &str) -> Experiment {
+        Experiment { name: name.into(), variants: Vec::new() }
+    }
+
+    /// Add the variant `name`, which gets a share of users proportional to
+    /// `weight`.
+    pub fn variant(mut self, name: &str, weight: u32) -> Experiment {
+        self.variants.push((name.into(), weight));
+        self
+    }
+
+    /// The experiment's name.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The variant for `key`, or `None` if the experiment has no variants
+    /// with a weight.
+    pub fn assign(&self, key: &str) -> Option<&str> {
+        let total = self.variants.iter().map(|&(_, weight)| weight as u64).sum::<u64>();
+        if total == 0 { return None }
+
+        let digest = Sha256::digest(format!("{}:{}", self.name, key).as_bytes());
+        let mut point = digest[..8].iter().fold(0u64, |n, &byte| n << 8 | byte as u64) % total;
+        for &(ref variant, weight) in &self.variants {
+            if point < weight as u64 { return Some(variant) }
+            point -= weight as u64;
+        }
+        None
+    }
+
+    fn has_variant(&self, variant: &str) -> bool {
+        self.variants.iter().any(|&(ref name, weight)| name == variant && weight > 0)
+    }
+}
+
+/// A new assignment of a user to a variant.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Assignment {
+    /// The experiment's name.
+    pub experiment: String,
+
+    /// The variant assigned.
+    pub variant: String,
+
+    /// The key the variant was chosen by.
+    pub key: String
+}
+
+/// Receives new assignments, for instance to forward them to an analytics
+/// pipeline.
+pub trait AssignmentSink: Send + Sync + 'static {
+    /// Record `assignment`.
+    fn record(&self, assignment: &Assignment);
+}
+
+/// Logs each assignment at `info` level.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LogSink;
+
+impl AssignmentSink for LogSink {
+    fn record(&self, assignment: &Assignment) {
+        info!("Experiment {}: assigned {} to {}",
+              assignment.experiment, assignment.key, assignment.variant);
+    }
+}
+
+/// Middleware assigning requests to the variants of experiments.
+///
+/// As `BeforeMiddleware`, it stores the request's `Assignments`. As
+/// `AfterMiddleware`, including for errors, it sends the cookie keeping
+/// them when they have changed. Requests for which the key function returns
+/// `None` are only assigned variants kept in their cookie.
+///
+/// Clones share the same counts.
+#[derive(Clone)]
+pub struct Experiments {
+    experiments: Arc<Vec<Experiment>>,
+    key: Arc<Fn(&Request) -> Option<String> + Send + Sync>,
+    sink: Arc<AssignmentSink>,
+    cookie: String,
+    max_age: u64,
+    counts: Arc<Mutex<BTreeMap<String, BTreeMap<String, u64>>>>
+}
+
+impl Experiments {
+    /// Create the middleware with no experiments, keying assignments by
+    /// the client's IP address and keeping them in the cookie
+    /// `experiments` for 90 days.
+    pub fn new() -> Experiments {
+        Experiments {
+            experiments: Arc::new(Vec::new()),
+            key: Arc::new(|req: &Request| Some(req.remote_addr.ip().to_string())),
+            sink: Arc::new(LogSink),
+            cookie: "experiments".into(),
+            max_age: 90 * 24 * 60 * 60,
+            counts: Arc::new(Mutex::new(BTreeMap::new()))
+        }
+    }
+
+    /// Run `experiment`.
+    pub fn add(&mut self, experiment: Experiment) -> &mut Experiments {
+        Arc::make_mut(&mut self.experiments).push(experiment);
+        self
+    }
+
+    /// Choose variants by the result of `key`, such as the user or session
+    /// id, instead of the client's IP address.
+    pub fn key<F>(&mut self, key: F) -> &mut Experiments
+    where F: Fn(&Request) -> Option<String> + Send + Sync + 'static {
+        self.key = Arc::new(key);
+        self
+    }
+
+    /// Record new assignments with `sink`, instead of logging them.
+    pub fn sink<S: AssignmentSink>(&mut self, sink: S) -> &mut Experiments {
+        self.sink = Arc::new(sink);
+        self
+    }
+
+    /// Keep assignments in the cookie `name`, for `max_age` seconds.
+    pub fn cookie(&mut self, name: &str, max_age: u64) -> &mut Experiments {
+        self.cookie = name.into();
+        self.max_age = max_age;
+        self
+    }
+
+    /// The number of new assignments to each variant, by experiment.
+    pub fn counts(&self) -> BTreeMap<String, BTreeMap<String, u64>> {
+        self.counts.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    /// Include the assignment counts in the snapshots of `stats`, under
+    /// `experiments`.
+    pub fn report_to(&mut self, stats: &Stats) -> &mut Experiments {
+        let experiments = self.clone();
+        stats.add_section("experiments", move || experiments.counts().to_json());
+        self
+    }
+
+    // The assignments kept in the request's cookie, if it has one.
+    fn kept(&self, req: &Request) -> BTreeMap<String, String> {
+        let cookie = match CookieJar::get(req, &self.cookie) {
+            Some(cookie) => cookie,
+            None => return BTreeMap::new()
+        };
+        cookie.split('|').filter_map(|pair| {
+            let mut parts = pair.splitn(2, ':');
+            match (parts.next().map(percent_decode), parts.next().map(percent_decode)) {
+                (Some(Ok(experiment)), Some(Ok(variant))) => Some((experiment, variant)),
+                _ => None
+            }
+        }).collect()
+    }
+
+    fn set_cookie(&self, req: &Request, res: &mut Response) {
+        let assignments = match Assignments::of(req) {
+            Some(assignments) if assignments.changed => assignments,
+            _ => return
+        };
+
+        let value = assignments.variants.iter()
+            .map(|(experiment, variant)| {
+                format!("{}:{}", percent_encode(experiment), percent_encode(variant))
+            })
+            .collect::<Vec<_>>()
+            .join("|");
+        let cookie = format!("{}={}; Path=/; Max-Age={}; HttpOnly", self.cookie, value,
+                             self.max_age);
+
+        let mut cookies = res.headers.get_raw("Set-Cookie").map(|v| v.to_vec())
+            .unwrap_or_default();
+        cookies.push(cookie.into_bytes());
+        res.headers.set_raw("Set-Cookie", cookies);
+    }
+}
+
+impl Default for Experiments {
+    fn default() -> Experiments {
+        Experiments::new()
+    }
+}
+
+impl BeforeMiddleware for Experiments {
+    fn before(&self, req: &mut Request) -> IronResult<()> {
+        let kept = self.kept(req);
+        let key = (self.key)(req);
+
+        let mut variants = BTreeMap::new();
+        let mut changed = false;
+        for experiment in self.experiments.iter() {
+            match kept.get(&experiment.name) {
+                Some(variant) if experiment.has_variant(variant) => {
+                    variants.insert(experiment.name.clone(), variant.clone());
+                    continue
+                },
+                _ => ()
+            }
+
+            let key = match key {
+                Some(ref key) => key,
+                None => continue
+            };
+            if let Some(variant) = experiment.assign(key) {
+                self.sink.record(&Assignment {
+                    experiment: experiment.name.clone(),
+                    variant: variant.into(),
+                    key: key.clone()
+                });
+                {
+                    let mut counts = self.counts.lock().unwrap_or_else(|e| e.into_inner());
+                    *counts.entry(experiment.name.clone()).or_insert_with(BTreeMap::new)
+                        .entry(variant.into()).or_insert(0) += 1;
+                }
+                variants.insert(experiment.name.clone(), variant.into());
+                changed = true;
+            }
+        }
+        // Forget experiments which have ended, or variants which were dropped.
+        changed = changed || kept != variants;
+
+        req.extensions.insert::<Assignments>(Assignments { variants: variants, changed: changed });
+        Ok(())
+    }
+}
+
+impl AfterMiddleware for Experiments {
+    fn after(&self, req: &mut Request, mut res: Response) -> IronResult<Response> {
+        self.set_cookie(req, &mut res);
+        Ok(res)
+    }
+
+    fn catch(&self, req: &mut Request, mut err: IronError) -> IronResult<Response> {
+        self.set_cookie(req, &mut err.response);
+        Err(err)
+    }
+}
+
+/// The variants a request is assigned to, by experiment.
+#[derive(Debug, Clone)]
+pub struct Assignments {
+    variants: BTreeMap<String, String>,
+    changed: bool
+}
+
+impl Key for Assignments { type Value = Assignments; }
+
+impl Assignments {
+    /// The assignments of `req`, if `Experiments` has been linked before
+    /// the current middleware.
+    pub fn of<'a>(req: &'a Request) -> Option<&'a Assignments> {
+        req.extensions.get::<Assignments>()
+    }
+
+    /// The variant of `experiment` assigned, if any.
+    pub fn variant(&self, experiment: &str) -> Option<&str> {
+        self.variants.get(experiment).map(|variant| &**variant)
+    }
+
+    /// Every assignment, by experiment.
+    pub fn variants(&self) -> &BTreeMap<String, String> {
+        &self.variants
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{Arc, Mutex};
+
+    use method::Method;
+    use status;
+    use test::{MiddlewareHarness, StubRequest};
+    use {Chain, Request, Response};
+    use super::{Assignment, AssignmentSink, Assignments, Experiment, Experiments};
+
+    #[test]
+    fn test_assign() {
+        let experiment = Experiment::new("a").variant("x", 1).variant("y", 3).variant("z", 0);
+        let counts = (0..1000).fold([0, 0], |mut counts, i| {
+            match experiment.assign(&i.to_string()) {
+                Some("x") => counts[0] += 1,
+                Some("y") => counts[1] += 1,
+                other => panic!("{:?}", other)
+            }
+            counts
+        });
+        assert!(counts[0] > 200 && counts[0] < 300, "{:?}", counts);
+        assert_eq!(experiment.assign("ann"), experiment.assign("ann"));
+        assert_eq!(Experiment::new("b").assign("ann"), None);
+    }
+
+    struct Recorder(Arc<Mutex<Vec<Assignment>>>);
+
+    impl AssignmentSink for Recorder {
+        fn record(&self, assignment: &Assignment) {
+            self.0.lock().unwrap().push(assignment.clone());
+        }
+    }
+
+    #[test]
+    fn test_middleware() {
+        let recorded = Arc::new(Mutex::new(Vec::new()));
+        let mut experiments = Experiments::new();
+        experiments.add(Experiment::new("checkout").variant("control", 1).variant("new", 1))
+            .key(|req: &Request| {
+                req.headers.get_raw("X-User").map(|v| String::from_utf8_lossy(&v[0]).into_owned())
+            })
+            .sink(Recorder(recorded.clone()));
+
+        let mut chain = Chain::new(|req: &mut Request| {
+            let variant = Assignments::of(req).unwrap().variant("checkout").unwrap_or("none");
+            Ok(Response::with((status::Ok, variant)))
+        });
+        chain.link_before(experiments.clone());
+        chain.link_after(experiments.clone());
+        let harness = MiddlewareHarness::new(chain);
+        let get = |user: Option<&str>, cookie: Option<&str>| {
+            let mut req = StubRequest::new(Method::Get, "http://localhost/");
+            if let Some(user) = user { req = req.raw_header("X-User", user) }
+            if let Some(cookie) = cookie { req = req.raw_header("Cookie", cookie) }
+            let mut run = harness.handle(req);
+            let set_cookie = run.response().headers.get_raw("Set-Cookie")
+                .map(|v| String::from_utf8(v[0].clone()).unwrap());
+            (String::from_utf8(run.take_body()).unwrap(), set_cookie)
+        };
+
+        let (variant, cookie) = get(Some("ann"), None);
+        assert!(variant == "control" || variant == "new");
+        let cookie = cookie.unwrap();
+        assert!(cookie.starts_with(&format!("experiments=checkout:{};", variant)), "{}", cookie);
+        assert_eq!(*recorded.lock().unwrap(), vec![Assignment {
+            experiment: "checkout".into(),
+            variant: variant.clone(),
+            key: "ann".into()
+        }]);
+        assert_eq!(experiments.counts()["checkout"][&variant], 1);
+
+        // A kept assignment wins over the key, and is not recorded again.
+        let other = if variant == "new" { "control" } else { "new" };
+        let kept = format!("experiments=checkout:{}", other);
+        assert_eq!(get(Some("ann"), Some(&kept)), (other.to_owned(), None));
+        assert_eq!(get(None, Some(&kept)), (other.to_owned(), None));
+        assert_eq!(recorded.lock().unwrap().len(), 1);
+
+        // Unknown variants and ended experiments are dropped from the cookie.
+        assert_eq!(get(None, Some("experiments=checkout:gone|old:x")),
+                   ("none".to_owned(), Some("experiments=; Path=/; Max-Age=7776000; HttpOnly"
+                                                .to_owned())));
+    }
+}
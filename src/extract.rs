@@ -0,0 +1,201 @@
+//! Handlers taking their inputs as typed arguments.
+//!
+//! Types implementing `FromRequest` can be extracted from a request, for
+//! instance from its extensions or query string. `handler` turns a function
+//! taking up to four such arguments, followed by the `&mut Response` to
+//! fill in, into a `Handler`, extracting each argument in order before the
+//! function is called:
+//!
+//! ```ignore
+//! struct AuthenticatedUser { name: String }
+//!
+//! impl FromRequest for AuthenticatedUser {
+//!     fn from_request(req: &mut Request) -> IronResult<AuthenticatedUser> {
+//!         match req.extensions.get::<CurrentUser>() {
+//!             Some(name) => Ok(AuthenticatedUser { name: name.clone() }),
+//!             None => Err(IronError::new(NotLoggedIn, status::Unauthorized))
+//!         }
+//!     }
+//! }
+//!
+//! fn greet(user: AuthenticatedUser, Query(page): Query<Page>, res: &mut Response)
+//!          -> IronResult<()> {
+//!     res.set_mut((status::Ok, format!("Hello {}, page {}", user.name, page.number)));
+//!     Ok(())
+//! }
+//!
+//! let chain = Chain::new(handler(greet));
+//! ```
+//!
+//! An argument which cannot be extracted fails the handler with the error
+//! from `from_request`, which enters the error flow of the `Chain` like any
+//! other handler error, so its status decides the response.
+
+use std::marker::PhantomData;
+
+use rustc_serialize::Decodable;
+
+use {Handler, Headers, Request, Response, IronResult, IronError, Url};
+use method::Method;
+
+/// A value which can be extracted from a request.
+pub trait FromRequest: Sized {
+    /// Extract the value, or fail with an error whose response, typically a
+    /// `400 Bad Request` or `401 Unauthorized`, is sent instead.
+    fn from_request(req: &mut Request) -> IronResult<Self>;
+}
+
+impl FromRequest for Url {
+    fn from_request(req: &mut Request) -> IronResult<Url> {
+        Ok(req.url.clone())
+    }
+}
+
+impl FromRequest for Method {
+    fn from_request(req: &mut Request) -> IronResult<Method> {
+        Ok(req.method.clone())
+    }
+}
+
+impl FromRequest for Headers {
+    fn from_request(req: &mut Request) -> IronResult<Headers> {
+        Ok(req.headers.clone())
+    }
+}
+
+/// Extracts a value if it can be, and `None` otherwise.
+impl<T: FromRequest> FromRequest for Option<T> {
+    fn from_request(req: &mut Request) -> IronResult<Option<T>> {
+        Ok(T::from_request(req).ok())
+    }
+}
+
+/// The query string decoded into a `T`, as by `Request::query_as`, failing
+/// with `400 Bad Request`.
+pub struct Query<T>(pub T);
+
+impl<T: Decodable> FromRequest for Query<T> {
+    fn from_request(req: &mut Request) -> IronResult<Query<T>> {
+        req.query_as().map(Query).map_err(IronError::from)
+    }
+}
+
+/// A function which can be called with arguments extracted from a request;
+/// see `handler`.
+///
+/// `Args` is the tuple of the function's argument types, before the
+/// `&mut Response`.
+pub trait HandlerFn<Args>: Send + Sync + 'static {
+    /// Extract the arguments from `req` and call the function.
+    fn call(&self, req: &mut Request, res: &mut Response) -> IronResult<()>;
+}
+
+macro_rules! handler_fn {
+    ($($arg:ident),*) => {
+        impl<F, $($arg),*> HandlerFn<($($arg,)*)> for F
+        where F: Fn($($arg,)* &mut Response) -> IronResult<()> + Send + Sync + 'static,
+              $($arg: FromRequest),* {
+            #[allow(non_snake_case, unused_variables)]
+            fn call(&self, req: &mut Request, res: &mut Response) -> IronResult<()> {
+                $(let $arg = try!($arg::from_request(req));)*
+                self($($arg,)* res)
+            }
+        }
+    }
+}
+
+handler_fn!();
+handler_fn!(A);
+handler_fn!(A, B);
+handler_fn!(A, B, C);
+handler_fn!(A, B, C, D);
+
+/// A `Handler` calling a function with arguments extracted from each
+/// request, created by `handler`.
+pub struct FnHandler<F, Args> {
+    function: F,
+    args: PhantomData<fn() -> Args>
+}
+
+/// Turn `function`, taking up to four `FromRequest` arguments and the
+/// `&mut Response` to fill in, into a `Handler`.
+///
+/// The response starts out empty, like `Response::new()`.
+pub fn handler<Args, F: HandlerFn<Args>>(function: F) -> FnHandler<F, Args> {
+    FnHandler { function: function, args: PhantomData }
+}
+
+impl<F: HandlerFn<Args>, Args: 'static> Handler for FnHandler<F, Args> {
+    fn handle(&self, req: &mut Request) -> IronResult<Response> {
+        let mut res = Response::new();
+        try!(self.function.call(req, &mut res));
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use std::fmt;
+
+    use method::Method;
+    use status;
+    use test::{MiddlewareHarness, StubRequest};
+    use {Chain, IronError, IronResult, Request, Response, Set, Url};
+    use super::{handler, FromRequest, Query};
+
+    struct User(String);
+
+    impl FromRequest for User {
+        fn from_request(req: &mut Request) -> IronResult<User> {
+            match req.headers.get_raw("X-User") {
+                Some(names) => Ok(User(String::from_utf8_lossy(&names[0]).into_owned())),
+                None => Err(IronError::new(fmt::Error, status::Unauthorized))
+            }
+        }
+    }
+
+    fn greet(User(name): User, Query(query): Query<HashMap<String, String>>, url: Url,
+             res: &mut Response) -> IronResult<()> {
+        let greeting = query.get("greeting").cloned().unwrap_or_else(|| "Hello".into());
+        res.set_mut((status::Ok, format!("{} {} at {}", greeting, name, url.path().join("/"))));
+        Ok(())
+    }
+
+    #[test]
+    fn test_handler() {
+        let harness = MiddlewareHarness::new(Chain::new(handler(greet)));
+
+        let req = StubRequest::new(Method::Get, "http://localhost/a?greeting=Hi")
+            .raw_header("X-User", "ann");
+        let mut run = harness.handle(req);
+        assert_eq!(run.take_body(), b"Hi ann at a".to_vec());
+
+        let run = harness.handle(StubRequest::new(Method::Get, "http://localhost/a"));
+        assert_eq!(run.status(), Some(status::Unauthorized));
+
+        let req = StubRequest::new(Method::Get, "http://localhost/a?greeting=%zz")
+            .raw_header("X-User", "ann");
+        assert_eq!(harness.handle(req).status(), Some(status::BadRequest));
+    }
+
+    #[test]
+    fn test_optional_and_no_arguments() {
+        fn who(user: Option<User>, res: &mut Response) -> IronResult<()> {
+            res.set_mut(user.map_or("nobody".to_owned(), |User(name)| name));
+            Ok(())
+        }
+        fn empty(res: &mut Response) -> IronResult<()> {
+            res.set_mut(status::NoContent);
+            Ok(())
+        }
+
+        let harness = MiddlewareHarness::new(handler(who));
+        let mut run = harness.handle(StubRequest::new(Method::Get, "http://localhost/"));
+        assert_eq!(run.take_body(), b"nobody".to_vec());
+
+        let harness = MiddlewareHarness::new(handler(empty));
+        let run = harness.handle(StubRequest::new(Method::Get, "http://localhost/"));
+        assert_eq!(run.status(), Some(status::NoContent));
+    }
+}
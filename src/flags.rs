@@ -0,0 +1,453 @@
+//! Feature flags, with percentage rollouts and runtime overrides.
+//!
+//! `FeatureFlags` holds a `Rollout` for each flag, usually loaded from the
+//! `features` table of the configuration, where a flag is `true`, `false`
+//! or the percentage of requests to enable it for:
+//!
+//! ```toml
+//! [features]
+//! new_checkout = 10
+//! dark_mode = true
+//! ```
+//!
+//! As `BeforeMiddleware`, it gives each request its `Features`, and
+//! `FlagsEndpoint` lets operators override flags while the server runs:
+//!
+//! ```ignore
+//! let mut flags = FeatureFlags::from_config(&config, "features").unwrap();
+//! flags.key(|req| Some(user_id(req)));
+//! chain.link_before(flags.clone());
+//! chain.link_around(FlagsEndpoint::new(flags, |req| is_operator(req)));
+//!
+//! // In a handler:
+//! if Features::of(req).map_or(false, |f| f.enabled("new_checkout")) { ... }
+//! ```
+//!
+//! Percentage rollouts hash the flag's name with a key taken from the
+//! request, by default the client's IP address, so a given user sees the
+//! same choice on every request, and raising the percentage only adds
+//! users. Requests without a key only see flags which are fully on.
+//!
+//! `FlagsEndpoint` answers `GET /__iron/flags` with every flag's rollout as
+//! JSON, `PUT /__iron/flags/<name>` with a body of `true`, `false` or a
+//! percentage by overriding the flag, and `DELETE /__iron/flags/<name>` by
+//! removing the override. Every request to it must pass the authorization
+//! predicate it is created with, or is answered with `403 Forbidden`.
+
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+use rustc_serialize::json::{Json, ToJson};
+use sha2::{Sha256, Digest};
+use typemap::Key;
+
+use config::{Config, ConfigError, Value};
+use {headers, method, status, AroundMiddleware, BeforeMiddleware, Handler, Request, Response,
+     IronResult, IronError};
+
+/// Who a flag is enabled for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rollout {
+    /// Nobody.
+    Off,
+
+    /// Everybody.
+    On,
+
+    /// This percentage of keys, from 0 to 100.
+    Percent(u8)
+}
+
+impl Rollout {
+    /// Whether the flag is enabled for `key`, given a flag called `name`.
+    pub fn enabled_for(&self, name: &str, key: Option<&str>) -> bool {
+        match (*self, key) {
+            (Rollout::Off, _) => false,
+            (Rollout::On, _) => true,
+            (Rollout::Percent(percent), _) if percent >= 100 => true,
+            (Rollout::Percent(_), None) => false,
+            (Rollout::Percent(percent), Some(key)) => bucket(name, key) < percent
+        }
+    }
+}
+
+impl FromStr for Rollout {
+    type Err = InvalidRollout;
+
+    fn from_str(s: &str) -> Result<Rollout, InvalidRollout> {
+        match s.trim() {
+            "true" => Ok(Rollout::On),
+            "false" => Ok(Rollout::Off),
+            s => match s.parse::<u8>() {
+                Ok(percent) if percent <= 100 => Ok(Rollout::Percent(percent)),
+                _ => Err(InvalidRollout)
+            }
+        }
+    }
+}
+
+impl ToJson for Rollout {
+    fn to_json(&self) -> Json {
+        match *self {
+            Rollout::Off => Json::Boolean(false),
+            Rollout::On => Json::Boolean(true),
+            Rollout::Percent(percent) => Json::U64(percent as u64)
+        }
+    }
+}
+
+// A number from 0 to 99 which is stable for each flag and key, and
+// independent between flags.
+fn bucket(name: &str, key: &str) -> u8 {
+    let digest = Sha256::digest(format!("{}:{}", name, key).as_bytes());
+    let n = (digest[0] as u32) << 24 | (digest[1] as u32) << 16 |
+            (digest[2] as u32) << 8 | digest[3] as u32;
+    (n % 100) as u8
+}
+
+/// The error for a rollout which is not `true`, `false` or a percentage.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InvalidRollout;
+
+impl fmt::Display for InvalidRollout {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.description())
+    }
+}
+
+impl Error for InvalidRollout {
+    fn description(&self) -> &str {
+        "Rollout must be true, false or a percentage from 0 to 100"
+    }
+}
+
+/// A set of feature flags.
+///
+/// Clones share the same flags and overrides.
+#[derive(Clone)]
+pub struct FeatureFlags {
+    inner: Arc<RwLock<Flags>>,
+    key: Arc<Fn(&Request) -> Option<String> + Send + Sync>
+}
+
+struct Flags {
+    configured: HashMap<String, Rollout>,
+    overrides: HashMap<String, Rollout>
+}
+
+impl FeatureFlags {
+    /// Create a set with no flags.
+    pub fn new() -> FeatureFlags {
+        FeatureFlags {
+            inner: Arc::new(RwLock::new(Flags {
+                configured: HashMap::new(),
+                overrides: HashMap::new()
+            })),
+            key: Arc::new(|req: &Request| Some(req.remote_addr.ip().to_string()))
+        }
+    }
+
+    /// Load the flags in the table at `path` of `config`, with no flags if
+    /// there is no such table.
+    pub fn from_config(config: &Config, path: &str) -> Result<FeatureFlags, ConfigError> {
+        let flags = FeatureFlags::new();
+        let table = match config.get(path) {
+            Some(&Value::Table(ref table)) => table,
+            None => return Ok(flags),
+            Some(_) => return Err(ConfigError::Decode(format!("{}: expected a table", path)))
+        };
+
+        for (name, value) in table {
+            let rollout = match *value {
+                Value::Boolean(true) => Ok(Rollout::On),
+                Value::Boolean(false) => Ok(Rollout::Off),
+                Value::Integer(percent) => percent.to_string().parse(),
+                _ => Err(InvalidRollout)
+            };
+            let rollout = try!(rollout.map_err(|e| {
+                ConfigError::Decode(format!("{}.{}: {}", path, name, e))
+            }));
+            flags.configure(name, rollout);
+        }
+        Ok(flags)
+    }
+
+    /// Take the key for percentage rollouts from `key`, such as the id of
+    /// the signed-in user, instead of the client's IP address.
+    pub fn key<F>(&mut self, key: F) -> &mut FeatureFlags
+    where F: Fn(&Request) -> Option<String> + Send + Sync + 'static {
+        self.key = Arc::new(key);
+        self
+    }
+
+    /// Set the configured rollout of the flag `name`.
+    pub fn configure(&self, name: &str, rollout: Rollout) {
+        self.write().configured.insert(name.into(), rollout);
+    }
+
+    /// Override the rollout of the flag `name`, whatever its configuration.
+    pub fn set(&self, name: &str, rollout: Rollout) {
+        info!("Feature flag {} overridden: {:?}", name, rollout);
+        self.write().overrides.insert(name.into(), rollout);
+    }
+
+    /// Remove any override of the flag `name`, returning whether there was
+    /// one.
+    pub fn reset(&self, name: &str) -> bool {
+        self.write().overrides.remove(name).is_some()
+    }
+
+    /// The rollout of the flag `name`, if it is configured or overridden.
+    pub fn rollout(&self, name: &str) -> Option<Rollout> {
+        let flags = self.read();
+        flags.overrides.get(name).or_else(|| flags.configured.get(name)).cloned()
+    }
+
+    /// The rollout of every flag, by name.
+    pub fn rollouts(&self) -> BTreeMap<String, Rollout> {
+        let flags = self.read();
+        let mut rollouts: BTreeMap<_, _> = flags.configured.iter()
+            .map(|(name, rollout)| (name.clone(), *rollout)).collect();
+        rollouts.extend(flags.overrides.iter().map(|(name, rollout)| (name.clone(), *rollout)));
+        rollouts
+    }
+
+    /// Whether the flag `name` is enabled for `key`. Unknown flags are
+    /// disabled.
+    pub fn enabled_for(&self, name: &str, key: Option<&str>) -> bool {
+        self.rollout(name).map_or(false, |rollout| rollout.enabled_for(name, key))
+    }
+
+    fn read(&self) -> RwLockReadGuard<Flags> {
+        self.inner.read().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn write(&self) -> RwLockWriteGuard<Flags> {
+        self.inner.write().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Default for FeatureFlags {
+    fn default() -> FeatureFlags {
+        FeatureFlags::new()
+    }
+}
+
+impl BeforeMiddleware for FeatureFlags {
+    fn before(&self, req: &mut Request) -> IronResult<()> {
+        let key = (self.key)(req);
+        req.extensions.insert::<Features>(Features { flags: self.clone(), key: key });
+        Ok(())
+    }
+}
+
+/// The feature flags as they apply to one request.
+#[derive(Clone)]
+pub struct Features {
+    flags: FeatureFlags,
+    key: Option<String>
+}
+
+impl Key for Features { type Value = Features; }
+
+impl Features {
+    /// The flags of `req`, if `FeatureFlags` has been linked before the
+    /// current middleware.
+    pub fn of<'a>(req: &'a Request) -> Option<&'a Features> {
+        req.extensions.get::<Features>()
+    }
+
+    /// Whether the flag `name` is enabled for this request.
+    ///
+    /// Overrides made while the request is handled apply at once.
+    pub fn enabled(&self, name: &str) -> bool {
+        self.flags.enabled_for(name, self.key.as_ref().map(|key| &**key))
+    }
+
+    /// The key used for percentage rollouts, if the request has one.
+    pub fn key(&self) -> Option<&str> {
+        self.key.as_ref().map(|key| &**key)
+    }
+}
+
+/// `AroundMiddleware` serving an endpoint to list and override flags,
+/// passing all other requests to the handler it wraps.
+///
+/// The path defaults to `/__iron/flags`.
+pub struct FlagsEndpoint {
+    flags: FeatureFlags,
+    path: Vec<String>,
+    authorize: Box<Fn(&Request) -> bool + Send + Sync>
+}
+
+impl FlagsEndpoint {
+    /// Serve `flags` at `/__iron/flags` to requests for which `authorize`
+    /// returns true, answering any others with `403 Forbidden`.
+    pub fn new<F>(flags: FeatureFlags, authorize: F) -> FlagsEndpoint
+    where F: Fn(&Request) -> bool + Send + Sync + 'static {
+        FlagsEndpoint {
+            flags: flags,
+            path: vec!["__iron".into(), "flags".into()],
+            authorize: Box::new(authorize)
+        }
+    }
+
+    /// Serve the flags at `path` instead.
+    pub fn path(mut self, path: &str) -> FlagsEndpoint {
+        self.path = path.split('/').filter(|s| !s.is_empty()).map(String::from).collect();
+        self
+    }
+}
+
+impl AroundMiddleware for FlagsEndpoint {
+    fn around(self, handler: Box<Handler>) -> Box<Handler> {
+        Box::new(Endpoint {
+            flags: self.flags,
+            path: self.path,
+            authorize: self.authorize,
+            handler: handler
+        })
+    }
+}
+
+struct Endpoint {
+    flags: FeatureFlags,
+    path: Vec<String>,
+    authorize: Box<Fn(&Request) -> bool + Send + Sync>,
+    handler: Box<Handler>
+}
+
+impl Endpoint {
+    fn json(&self, json: Json) -> Response {
+        let mut res = Response::with((status::Ok, json.to_string()));
+        res.headers.set(headers::ContentType("application/json".parse().unwrap()));
+        res.headers.set(headers::CacheControl(vec![headers::CacheDirective::NoStore]));
+        res
+    }
+}
+
+impl Handler for Endpoint {
+    fn handle(&self, req: &mut Request) -> IronResult<Response> {
+        let name = {
+            let path: Vec<&str> = req.url.path().into_iter().filter(|s| !s.is_empty()).collect();
+            if path.len() < self.path.len() || !path.iter().zip(&self.path).all(|(a, b)| a == b) {
+                return self.handler.handle(req)
+            }
+            match path.len() - self.path.len() {
+                0 => None,
+                1 => Some(path[self.path.len()].to_owned()),
+                _ => return self.handler.handle(req)
+            }
+        };
+
+        if !(self.authorize)(req) {
+            return Ok(Response::with(status::Forbidden))
+        }
+
+        match (&req.method, name) {
+            (&method::Get, None) => {
+                let rollouts = self.flags.rollouts().into_iter()
+                    .map(|(name, rollout)| (name, rollout.to_json())).collect();
+                Ok(self.json(Json::Object(rollouts)))
+            },
+            (&method::Get, Some(name)) => match self.flags.rollout(&name) {
+                Some(rollout) => Ok(self.json(rollout.to_json())),
+                None => Ok(Response::with(status::NotFound))
+            },
+            (&method::Put, Some(name)) => {
+                let rollout = match req.body_string(64) {
+                    Ok(body) => try!(body.parse::<Rollout>()
+                        .map_err(|e| IronError::new(e, status::BadRequest))),
+                    Err(e) => return Err(IronError::new(e, status::BadRequest))
+                };
+                self.flags.set(&name, rollout);
+                Ok(self.json(rollout.to_json()))
+            },
+            (&method::Delete, Some(name)) => {
+                self.flags.reset(&name);
+                Ok(Response::with(status::NoContent))
+            },
+            _ => Ok(Response::with(status::MethodNotAllowed))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use config::Config;
+    use method::Method;
+    use status;
+    use test::{MiddlewareHarness, StubRequest};
+    use {Chain, Request, Response};
+    use super::{FeatureFlags, Features, FlagsEndpoint, Rollout};
+
+    #[test]
+    fn test_rollouts() {
+        let config = Config::parse("[features]\na = true\nb = false\nc = 30\n", "test").unwrap();
+        let flags = FeatureFlags::from_config(&config, "features").unwrap();
+        assert!(flags.enabled_for("a", None));
+        assert!(!flags.enabled_for("b", Some("user")));
+        assert!(!flags.enabled_for("c", None));
+        assert!(!flags.enabled_for("unknown", Some("user")));
+
+        let enabled = (0..1000).filter(|i| flags.enabled_for("c", Some(&i.to_string()))).count();
+        assert!(enabled > 250 && enabled < 350, "{}", enabled);
+        assert_eq!(flags.enabled_for("c", Some("user")), flags.enabled_for("c", Some("user")));
+
+        // Raising the percentage only adds keys.
+        let before: Vec<_> = (0..100).map(|i| flags.enabled_for("c", Some(&i.to_string())))
+            .collect();
+        flags.set("c", Rollout::Percent(60));
+        assert!((0..100).all(|i| !before[i] || flags.enabled_for("c", Some(&i.to_string()))));
+        assert!(flags.reset("c"));
+        assert_eq!(flags.rollout("c"), Some(Rollout::Percent(30)));
+
+        let config = Config::parse("[features]\na = 101\n", "test").unwrap();
+        assert!(FeatureFlags::from_config(&config, "features").is_err());
+    }
+
+    #[test]
+    fn test_endpoint() {
+        let mut flags = FeatureFlags::new();
+        flags.configure("a", Rollout::Off);
+        flags.key(|req: &Request| {
+            req.headers.get_raw("X-User").map(|v| String::from_utf8_lossy(&v[0]).into_owned())
+        });
+
+        let mut chain = Chain::new(|req: &mut Request| {
+            let enabled = Features::of(req).unwrap().enabled("a");
+            Ok(Response::with((status::Ok, enabled.to_string())))
+        });
+        chain.link_before(flags.clone());
+        chain.link_around(FlagsEndpoint::new(flags.clone(), |req: &Request| {
+            req.headers.get_raw("X-User").map_or(false, |v| v[0] == b"ann")
+        }));
+        let harness = MiddlewareHarness::new(chain);
+        let as_user = |user: &str, method, path: &str, body: &str| {
+            let req = StubRequest::new(method, &format!("http://localhost{}", path))
+                .raw_header("X-User", user)
+                .body(body);
+            let mut run = harness.handle(req);
+            (run.status(), String::from_utf8(run.take_body()).unwrap())
+        };
+        let body = |method, path: &str, body: &str| as_user("ann", method, path, body);
+
+        assert_eq!(as_user("bob", Method::Put, "/__iron/flags/a", "true").0,
+                   Some(status::Forbidden));
+        assert_eq!(as_user("bob", Method::Get, "/__iron/flags", "").0, Some(status::Forbidden));
+        assert_eq!(as_user("bob", Method::Get, "/", ""), (Some(status::Ok), "false".into()));
+
+        assert_eq!(body(Method::Get, "/", ""), (Some(status::Ok), "false".into()));
+        assert_eq!(body(Method::Put, "/__iron/flags/a", "true"),
+                   (Some(status::Ok), "true".into()));
+        assert_eq!(body(Method::Get, "/", ""), (Some(status::Ok), "true".into()));
+        assert_eq!(body(Method::Get, "/__iron/flags", ""),
+                   (Some(status::Ok), r#"{"a":true}"#.into()));
+        assert_eq!(body(Method::Put, "/__iron/flags/a", "x").0, Some(status::BadRequest));
+        assert_eq!(body(Method::Delete, "/__iron/flags/a", "").0, Some(status::NoContent));
+        assert_eq!(body(Method::Get, "/__iron/flags/a", ""), (Some(status::Ok), "false".into()));
+    }
+}
@@ -0,0 +1,532 @@
+//! Caching of whole responses.
+//!
+//! `HttpCache` is an `AroundMiddleware` which stores cacheable responses to
+//! GET requests in a `Cache` and answers later requests for the same URL from
+//! it, without calling the handler, for as long as the response is fresh:
+//!
+//! ```ignore
+//! let mut chain = Chain::new(handler);
+//! chain.link_around(HttpCache::new(MemoryCache::new(1000)));
+//! ```
+//!
+//! Responses are cached according to their `Cache-Control` header, as a
+//! shared cache would: only responses with an `s-maxage` or `max-age` are
+//! stored, and `no-store`, `no-cache` and `private` responses never are.
+//! `Vary` is respected. Requests with `Cache-Control: no-cache` or
+//! `max-age=0` are always passed to the handler, and its response replaces
+//! the cached one; requests with `Cache-Control: no-store` or an
+//! `Authorization` header bypass the cache entirely.
+
+use std::collections::{BTreeMap, HashSet};
+use std::io;
+use std::str;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+use rustc_serialize::base64::{ToBase64, FromBase64, STANDARD};
+use rustc_serialize::json::Json;
+
+use cache::Cache;
+use clock::{Clock, SystemClock, unix_seconds};
+use headers::{self, CacheControl, CacheDirective, Vary};
+use method::Method;
+use response::ResponseBody;
+use status::Status;
+use {AroundMiddleware, Handler, Request, Response, IronResult};
+
+/// Middleware which caches responses to GET requests.
+pub struct HttpCache {
+    cache: Arc<Cache>,
+    clock: Arc<Clock>,
+    stale_while_revalidate: bool
+}
+
+impl HttpCache {
+    /// Cache responses in `cache`.
+    pub fn new<C: Cache>(cache: C) -> HttpCache {
+        HttpCache {
+            cache: Arc::new(cache),
+            clock: Arc::new(SystemClock),
+            stale_while_revalidate: false
+        }
+    }
+
+    /// Use `clock` to determine the age of cached responses, instead of the
+    /// system clock.
+    pub fn clock<C: Clock>(&mut self, clock: C) -> &mut HttpCache {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// Honour the `stale-while-revalidate` directive of cached responses.
+    ///
+    /// Once such a response is stale, but still within its
+    /// `stale-while-revalidate` window, one request at a time is passed to
+    /// the handler to refresh it, while concurrent requests are answered
+    /// with the stale response instead of waiting. Disabled by default.
+    pub fn stale_while_revalidate(&mut self, enabled: bool) -> &mut HttpCache {
+        self.stale_while_revalidate = enabled;
+        self
+    }
+}
+
+impl AroundMiddleware for HttpCache {
+    fn around(self, handler: Box<Handler>) -> Box<Handler> {
+        Box::new(CachingHandler {
+            handler: handler,
+            cache: self.cache,
+            clock: self.clock,
+            stale_while_revalidate: self.stale_while_revalidate,
+            refreshing: Mutex::new(HashSet::new())
+        })
+    }
+}
+
+struct CachingHandler {
+    handler: Box<Handler>,
+    cache: Arc<Cache>,
+    clock: Arc<Clock>,
+    stale_while_revalidate: bool,
+    // The keys of stale entries currently being refreshed.
+    refreshing: Mutex<HashSet<String>>
+}
+
+impl Handler for CachingHandler {
+    fn handle(&self, req: &mut Request) -> IronResult<Response> {
+        if req.method != Method::Get || req.headers.has::<headers::Authorization<String>>() {
+            return self.handler.handle(req);
+        }
+
+        let request_directives = req.headers.get::<CacheControl>()
+            .map(|cc| cc.0.clone()).unwrap_or_default();
+
+        if request_directives.contains(&CacheDirective::NoStore) {
+            return self.handler.handle(req);
+        }
+
+        let url = req.url.to_string();
+        let varied = self.cache.get(&vary_key(&url))
+            .and_then(|names| String::from_utf8(names).ok())
+            .map(|names| names.lines().map(|name| name.to_owned()).collect::<Vec<_>>())
+            .unwrap_or_default();
+        let key = variant_key(&url, &varied, req);
+
+        let revalidate = request_directives.contains(&CacheDirective::NoCache) ||
+                         request_directives.contains(&CacheDirective::MaxAge(0));
+
+        if !revalidate {
+            let entry = self.cache.get(&key).and_then(|bytes| Entry::decode(&bytes));
+
+            if let Some(entry) = entry {
+                let age = unix_seconds(&*self.clock).saturating_sub(entry.stored);
+
+                if age < entry.fresh {
+                    return Ok(entry.into_response(age));
+                }
+
+                if self.stale_while_revalidate && age < entry.fresh.saturating_add(entry.stale) {
+                    if !self.refreshing().insert(key.clone()) {
+                        return Ok(entry.into_response(age));
+                    }
+
+                    let _refreshing = Refreshing { handler: self, key: key };
+                    return self.handle_and_store(req, &url);
+                }
+            }
+        }
+
+        self.handle_and_store(req, &url)
+    }
+}
+
+impl CachingHandler {
+    fn refreshing(&self) -> MutexGuard<HashSet<String>> {
+        self.refreshing.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn handle_and_store(&self, req: &mut Request, url: &str) -> IronResult<Response> {
+        let mut res = try!(self.handler.handle(req));
+
+        let (fresh, stale) = match lifetime(&res, self.stale_while_revalidate) {
+            Some(lifetime) => lifetime,
+            None => return Ok(res)
+        };
+
+        let varied = match res.headers.get::<Vary>() {
+            Some(&Vary::Any) => return Ok(res),
+            Some(&Vary::Items(ref names)) => names.iter().map(|name| name.to_string()).collect(),
+            None => vec![]
+        };
+
+        let now = unix_seconds(&*self.clock);
+        let entry = match Entry::from_response(&mut res, now, fresh, stale) {
+            Ok(entry) => entry,
+            Err(e) => {
+                warn!("Could not cache response for {}: {}", url, e);
+                return Ok(res);
+            }
+        };
+
+        let ttl = Some(Duration::from_secs(fresh.saturating_add(stale)));
+        self.cache.set(&vary_key(url), varied.join("\n").into_bytes(), ttl);
+        self.cache.set(&variant_key(url, &varied, req), entry.encode(), ttl);
+        Ok(res)
+    }
+}
+
+// Marks a stale entry as no longer being refreshed once the refresh is over,
+// even if the handler panicked.
+struct Refreshing<'a> {
+    handler: &'a CachingHandler,
+    key: String
+}
+
+impl<'a> Drop for Refreshing<'a> {
+    fn drop(&mut self) {
+        self.handler.refreshing().remove(&self.key);
+    }
+}
+
+// For how many seconds `res` may be served from the cache, and for how many
+// more it may be served stale while being revalidated, if it is cacheable.
+fn lifetime(res: &Response, stale_while_revalidate: bool) -> Option<(u64, u64)> {
+    match res.status.map(|status| status.to_u16()) {
+        Some(200) | Some(203) | Some(301) | Some(404) | Some(410) => {},
+        _ => return None
+    }
+
+    if res.headers.has::<headers::SetCookie>() { return None }
+
+    let directives = match res.headers.get::<CacheControl>() {
+        Some(cc) => &cc.0,
+        None => return None
+    };
+
+    let mut s_maxage = None;
+    let mut max_age = None;
+    let mut stale = 0;
+
+    for directive in directives {
+        match *directive {
+            CacheDirective::NoStore | CacheDirective::NoCache | CacheDirective::Private => {
+                return None
+            },
+            CacheDirective::SMaxAge(secs) => s_maxage = Some(secs as u64),
+            CacheDirective::MaxAge(secs) => max_age = Some(secs as u64),
+            CacheDirective::Extension(ref name, Some(ref secs))
+                if name == "stale-while-revalidate" && stale_while_revalidate => {
+                // Bounded like `max-age`, which is a `u32`.
+                stale = secs.parse().unwrap_or(0).min(u32::MAX as u64);
+            },
+            _ => {}
+        }
+    }
+
+    match s_maxage.or(max_age) {
+        Some(0) | None => None,
+        Some(fresh) => Some((fresh, stale))
+    }
+}
+
+fn vary_key(url: &str) -> String {
+    format!("http-cache:vary:{}", url)
+}
+
+fn variant_key(url: &str, varied: &[String], req: &Request) -> String {
+    let mut key = format!("http-cache:{}", url);
+
+    for name in varied {
+        let value = req.headers.get_raw(name)
+            .map(|lines| {
+                lines.iter().map(|line| String::from_utf8_lossy(line).into_owned())
+                    .collect::<Vec<_>>().join(",")
+            })
+            .unwrap_or_default();
+        key.push_str(&format!("\n{}: {}", name.to_ascii_lowercase(), value));
+    }
+
+    key
+}
+
+// A response as stored in the cache.
+#[derive(Debug, PartialEq)]
+struct Entry {
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+    // When the response was stored, in seconds since the epoch.
+    stored: u64,
+    fresh: u64,
+    stale: u64
+}
+
+impl Entry {
+    // Buffer the body of `res` to store it, as of `now`.
+    fn from_response(res: &mut Response, now: u64, fresh: u64, stale: u64)
+                     -> io::Result<Entry> {
+        let mut body = Vec::new();
+
+        if let Some(mut writer) = res.body.take() {
+            try!(writer.write_body(&mut ResponseBody::new(&mut body)));
+            res.body = Some(Box::new(body.clone()));
+        }
+
+        Ok(Entry {
+            status: res.status.map(|status| status.to_u16()).unwrap_or(200),
+            headers: res.headers.iter()
+                .map(|header| (header.name().to_owned(), header.value_string()))
+                .collect(),
+            body: body,
+            stored: now,
+            fresh: fresh,
+            stale: stale
+        })
+    }
+
+    fn into_response(self, age: u64) -> Response {
+        let mut res = Response::new();
+        res.status = Some(Status::from_u16(self.status));
+
+        for (name, value) in self.headers {
+            res.headers.set_raw(name, vec![value.into_bytes()]);
+        }
+
+        res.headers.set_raw("Age", vec![age.to_string().into_bytes()]);
+        res.body = Some(Box::new(self.body));
+        res
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let headers = self.headers.iter()
+            .map(|&(ref name, ref value)| {
+                Json::Array(vec![Json::String(name.clone()), Json::String(value.clone())])
+            })
+            .collect();
+
+        let mut object = BTreeMap::new();
+        object.insert("status".to_owned(), Json::U64(self.status as u64));
+        object.insert("headers".to_owned(), Json::Array(headers));
+        object.insert("body".to_owned(), Json::String(self.body.to_base64(STANDARD)));
+        object.insert("stored".to_owned(), Json::U64(self.stored));
+        object.insert("fresh".to_owned(), Json::U64(self.fresh));
+        object.insert("stale".to_owned(), Json::U64(self.stale));
+        Json::Object(object).to_string().into_bytes()
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Entry> {
+        let json = match str::from_utf8(bytes).ok().and_then(|s| Json::from_str(s).ok()) {
+            Some(json) => json,
+            None => return None
+        };
+
+        let number = |field: &str| json.find(field).and_then(|value| value.as_u64());
+
+        let mut headers = vec![];
+        for header in json.find("headers").and_then(|h| h.as_array()).into_iter().flat_map(|h| h) {
+            match header.as_array().map(|pair| (pair.get(0), pair.get(1))) {
+                Some((Some(&Json::String(ref name)), Some(&Json::String(ref value)))) => {
+                    headers.push((name.clone(), value.clone()))
+                },
+                _ => return None
+            }
+        }
+
+        let body = json.find("body")
+            .and_then(|body| body.as_string())
+            .and_then(|body| body.from_base64().ok());
+
+        match (number("status"), body, number("stored")) {
+            (Some(status), Some(body), Some(stored)) => Some(Entry {
+                status: status as u16,
+                headers: headers,
+                body: body,
+                stored: stored,
+                fresh: number("fresh").unwrap_or(0),
+                stale: number("stale").unwrap_or(0)
+            }),
+            _ => None
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::panic::{self, AssertUnwindSafe};
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    use cache::MemoryCache;
+    use clock::ManualClock;
+    use headers::{CacheControl, CacheDirective};
+    use method::Method;
+    use test::{MiddlewareHarness, StubRequest};
+    use {status, Chain, Request, Response, Set};
+
+    use super::{lifetime, Entry, HttpCache};
+
+    fn with_cache_control(directives: Vec<CacheDirective>) -> Response {
+        Response::with((status::Ok, "body")).set(::modifiers::Header(CacheControl(directives)))
+    }
+
+    #[test]
+    fn test_lifetime() {
+        let res = with_cache_control(vec![CacheDirective::MaxAge(60)]);
+        assert_eq!(lifetime(&res, false), Some((60, 0)));
+
+        let res = with_cache_control(vec![CacheDirective::MaxAge(60),
+                                          CacheDirective::SMaxAge(10)]);
+        assert_eq!(lifetime(&res, false), Some((10, 0)));
+
+        let swr = CacheDirective::Extension("stale-while-revalidate".to_owned(),
+                                            Some("30".to_owned()));
+        let res = with_cache_control(vec![CacheDirective::MaxAge(60), swr]);
+        assert_eq!(lifetime(&res, false), Some((60, 0)));
+        assert_eq!(lifetime(&res, true), Some((60, 30)));
+
+        let swr = CacheDirective::Extension("stale-while-revalidate".to_owned(),
+                                            Some(u64::MAX.to_string()));
+        let res = with_cache_control(vec![CacheDirective::MaxAge(60), swr]);
+        assert_eq!(lifetime(&res, true), Some((60, u32::MAX as u64)));
+    }
+
+    #[test]
+    fn test_uncacheable() {
+        assert_eq!(lifetime(&Response::with((status::Ok, "body")), false), None);
+
+        let res = with_cache_control(vec![CacheDirective::MaxAge(60), CacheDirective::Private]);
+        assert_eq!(lifetime(&res, false), None);
+
+        let mut res = with_cache_control(vec![CacheDirective::MaxAge(60)]);
+        res.headers.set_raw("Set-Cookie", vec![b"a=b".to_vec()]);
+        assert_eq!(lifetime(&res, false), None);
+
+        let mut res = with_cache_control(vec![CacheDirective::MaxAge(60)]);
+        res.status = Some(status::InternalServerError);
+        assert_eq!(lifetime(&res, false), None);
+    }
+
+    #[test]
+    fn test_entry_round_trip() {
+        let mut res = with_cache_control(vec![CacheDirective::MaxAge(60)]);
+        let entry = Entry::from_response(&mut res, 1000, 60, 5).unwrap();
+        assert_eq!(entry.body, b"body");
+        assert!(res.body.is_some());
+
+        assert_eq!(Entry::decode(&entry.encode()), Some(entry));
+        assert_eq!(Entry::decode(b"not json"), None);
+    }
+
+    #[test]
+    fn test_into_response() {
+        let mut res = with_cache_control(vec![CacheDirective::MaxAge(60)]);
+        let res = Entry::from_response(&mut res, 1000, 60, 0).unwrap().into_response(7);
+
+        assert_eq!(res.status, Some(status::Ok));
+        assert_eq!(res.headers.get::<CacheControl>(),
+                   Some(&CacheControl(vec![CacheDirective::MaxAge(60)])));
+        assert_eq!(res.headers.get_raw("Age").unwrap(), &[b"7".to_vec()]);
+    }
+
+    struct Origin {
+        calls: Arc<AtomicUsize>,
+        panics: Arc<AtomicBool>
+    }
+
+    // A chain caching responses which say how many times the handler has
+    // been called, and vary by `Accept-Language`.
+    fn harness(clock: &ManualClock) -> (MiddlewareHarness<Chain>, Origin) {
+        let origin = Origin {
+            calls: Arc::new(AtomicUsize::new(0)),
+            panics: Arc::new(AtomicBool::new(false))
+        };
+        let (calls, panics) = (origin.calls.clone(), origin.panics.clone());
+
+        let mut chain = Chain::new(move |_: &mut Request| {
+            if panics.load(Ordering::SeqCst) { panic!("refresh failed") }
+            let call = calls.fetch_add(1, Ordering::SeqCst) + 1;
+            let swr = CacheDirective::Extension("stale-while-revalidate".to_owned(),
+                                                Some("30".to_owned()));
+            let mut res = with_cache_control(vec![CacheDirective::MaxAge(60), swr]);
+            res.body = Some(Box::new(call.to_string()));
+            res.headers.set_raw("Vary", vec![b"Accept-Language".to_vec()]);
+            Ok(res)
+        });
+        let mut cache = HttpCache::new(MemoryCache::new(16));
+        cache.clock(clock.clone()).stale_while_revalidate(true);
+        chain.link_around(cache);
+        (MiddlewareHarness::new(chain), origin)
+    }
+
+    fn get(harness: &MiddlewareHarness<Chain>, language: &str) -> String {
+        let req = StubRequest::new(Method::Get, "http://localhost/")
+            .raw_header("Accept-Language", language);
+        String::from_utf8(harness.handle(req).take_body()).unwrap()
+    }
+
+    #[test]
+    fn test_hit_and_vary() {
+        let clock = ManualClock::new();
+        let (harness, origin) = harness(&clock);
+
+        assert_eq!(get(&harness, "en"), "1");
+        clock.advance(Duration::from_secs(59));
+        assert_eq!(get(&harness, "en"), "1");
+        assert_eq!(origin.calls.load(Ordering::SeqCst), 1);
+
+        assert_eq!(get(&harness, "fr"), "2");
+        assert_eq!(get(&harness, "fr"), "2");
+        assert_eq!(get(&harness, "en"), "1");
+
+        let no_cache = StubRequest::new(Method::Get, "http://localhost/")
+            .raw_header("Accept-Language", "en")
+            .raw_header("Cache-Control", "no-cache");
+        assert_eq!(harness.handle(no_cache).take_body(), b"3");
+        assert_eq!(get(&harness, "en"), "3");
+    }
+
+    #[test]
+    fn test_stale_while_revalidate() {
+        let clock = ManualClock::new();
+        let (harness, origin) = harness(&clock);
+
+        assert_eq!(get(&harness, "en"), "1");
+        clock.advance(Duration::from_secs(70));
+
+        origin.panics.store(true, Ordering::SeqCst);
+        let refresh = panic::catch_unwind(AssertUnwindSafe(|| get(&harness, "en")));
+        assert!(refresh.is_err());
+        origin.panics.store(false, Ordering::SeqCst);
+
+        // The failed refresh does not leave the entry marked as refreshing.
+        assert_eq!(get(&harness, "en"), "2");
+        assert_eq!(get(&harness, "en"), "2");
+
+        clock.advance(Duration::from_secs(100));
+        assert_eq!(get(&harness, "en"), "3");
+    }
+
+    #[test]
+    fn test_huge_stale_window() {
+        let clock = ManualClock::new();
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = calls.clone();
+        let mut chain = Chain::new(move |_: &mut Request| {
+            let call = counter.fetch_add(1, Ordering::SeqCst) + 1;
+            let swr = CacheDirective::Extension("stale-while-revalidate".to_owned(),
+                                                Some(u64::MAX.to_string()));
+            let mut res = with_cache_control(vec![CacheDirective::MaxAge(60), swr]);
+            res.body = Some(Box::new(call.to_string()));
+            Ok(res)
+        });
+        let mut cache = HttpCache::new(MemoryCache::new(16));
+        cache.clock(clock.clone()).stale_while_revalidate(true);
+        chain.link_around(cache);
+        let harness = MiddlewareHarness::new(chain);
+
+        assert_eq!(get(&harness, "en"), "1");
+        clock.advance(Duration::from_secs(70));
+        assert_eq!(get(&harness, "en"), "2");
+        assert_eq!(get(&harness, "en"), "2");
+    }
+}
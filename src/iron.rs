@@ -1,8 +1,15 @@
 //! Exposes the `Iron` type, the main entrance point of the
 //! `Iron` library.
 
+use std::env;
+use std::error::Error;
+use std::fmt;
 use std::net::{ToSocketAddrs, SocketAddr};
-use std::time::Duration;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
 #[cfg(feature = "ssl")]
 use std::path::PathBuf;
 
@@ -14,9 +21,14 @@ use request::HttpRequest;
 use response::HttpResponse;
 
 use error::HttpResult;
+use schedule::{Schedule, Stop};
+use parse::{check_headers, check_uri};
+use stats::Stats;
+use middleware::{is_abort, PanicFlag};
 
-use {Request, Handler};
-use status;
+use {Request, Response, Handler};
+use {headers, status};
+use status::Status;
 
 /// The primary entrance point to `Iron`, a `struct` to instantiate a new server.
 ///
@@ -31,7 +43,25 @@ pub struct Iron<H> {
     addr: Option<SocketAddr>,
 
     /// Once listening, the protocol used to serve content.
-    protocol: Option<Protocol>
+    protocol: Option<Protocol>,
+
+    /// Periodic tasks, started once listening and stopped on shutdown.
+    schedule: Schedule,
+
+    /// The settings used by `listen`.
+    config: ServerConfig,
+
+    /// Counters updated while serving requests.
+    stats: Stats,
+
+    /// Set once a graceful shutdown has begun.
+    draining: Arc<AtomicBool>,
+
+    /// Answers requests the handler could not find a response for.
+    not_found: Option<Box<Handler>>,
+
+    /// Answers requests the handler failed or panicked on.
+    internal_error: Option<Box<Handler>>
 }
 
 /// A settings struct containing a set of timeouts which can be applied to a server.
@@ -65,6 +95,184 @@ impl Default for Timeouts {
     }
 }
 
+/// Limits on the requests a server accepts, checked before a `Request` is
+/// created so that hostile requests are rejected before any middleware runs.
+///
+/// Requests with a target longer than `max_uri_length` are answered with
+/// `414 URI Too Long`, and those exceeding a header limit with `431 Request
+/// Header Fields Too Large`. The connection is closed after either.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Limits {
+    /// The maximum length of the request target, in bytes.
+    ///
+    /// The default is `8192`.
+    pub max_uri_length: usize,
+
+    /// The maximum number of header lines.
+    ///
+    /// The default is `100`, which is also the most the parser can read.
+    pub max_headers: usize,
+
+    /// The maximum size of a single header line's name and value, in bytes.
+    ///
+    /// The default is `8192`.
+    pub max_header_size: usize,
+
+    /// The maximum total size of the header names and values, in bytes.
+    ///
+    /// The default is `16384`.
+    pub max_header_bytes: usize
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_uri_length: 8 * 1024,
+            max_headers: 100,
+            max_header_size: 8 * 1024,
+            max_header_bytes: 16 * 1024
+        }
+    }
+}
+
+/// Server settings for `Iron::listen`, usually read from environment
+/// variables by `Iron::from_env`.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct ServerConfig {
+    /// The address to listen on.
+    ///
+    /// Read from `IRON_ADDR`, a host or a host and port, and `IRON_PORT`,
+    /// which takes precedence over a port given in `IRON_ADDR`. The default
+    /// is `0.0.0.0:3000`.
+    pub addr: SocketAddr,
+
+    /// The number of threads handling requests.
+    ///
+    /// Read from `IRON_THREADS`. The default is `8 * num_cpus`.
+    pub threads: usize,
+
+    /// The server's timeouts.
+    ///
+    /// Read from `IRON_KEEP_ALIVE`, `IRON_READ_TIMEOUT` and
+    /// `IRON_WRITE_TIMEOUT`, in seconds, where `0` means no timeout. The
+    /// defaults are those of `Timeouts::default`.
+    pub timeouts: Timeouts,
+
+    /// Limits on the requests accepted.
+    ///
+    /// Read from `IRON_MAX_URI_LENGTH`, `IRON_MAX_HEADERS`,
+    /// `IRON_MAX_HEADER_SIZE` and `IRON_MAX_HEADER_BYTES`. The defaults are
+    /// those of `Limits::default`.
+    pub limits: Limits
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            addr: SocketAddr::from(([0, 0, 0, 0], 3000)),
+            threads: 8 * ::num_cpus::get(),
+            timeouts: Timeouts::default(),
+            limits: Limits::default()
+        }
+    }
+}
+
+impl ServerConfig {
+    /// Read settings from the process's environment variables, using the
+    /// default for each one which is not set.
+    pub fn from_env() -> Result<ServerConfig, EnvError> {
+        ServerConfig::from_vars(env::vars())
+    }
+
+    /// Read settings from a list of environment variables, as `from_env`.
+    pub fn from_vars<I>(vars: I) -> Result<ServerConfig, EnvError>
+    where I: IntoIterator<Item=(String, String)> {
+        let mut config = ServerConfig::default();
+        let mut host = None;
+        let mut port = None;
+
+        for (name, value) in vars {
+            let invalid = |reason| {
+                EnvError { var: name.clone(), value: value.clone(), reason: reason }
+            };
+            let timeout = || seconds(&value).ok_or_else(|| invalid("not a number of seconds"));
+            let positive = || match value.parse() {
+                Ok(0) | Err(_) => Err(invalid("not a positive number")),
+                Ok(n) => Ok(n)
+            };
+
+            match &*name {
+                "IRON_ADDR" => host = Some(value.clone()),
+                "IRON_PORT" => port = Some(try!(value.parse().map_err(|_| invalid("not a port")))),
+                "IRON_THREADS" => config.threads = try!(positive()),
+                "IRON_KEEP_ALIVE" => config.timeouts.keep_alive = try!(timeout()),
+                "IRON_READ_TIMEOUT" => config.timeouts.read = try!(timeout()),
+                "IRON_WRITE_TIMEOUT" => config.timeouts.write = try!(timeout()),
+                "IRON_MAX_URI_LENGTH" => config.limits.max_uri_length = try!(positive()),
+                "IRON_MAX_HEADERS" => config.limits.max_headers = try!(positive()),
+                "IRON_MAX_HEADER_SIZE" => config.limits.max_header_size = try!(positive()),
+                "IRON_MAX_HEADER_BYTES" => config.limits.max_header_bytes = try!(positive()),
+                _ => {}
+            }
+        }
+
+        if let Some(host) = host {
+            config.addr = try!(resolve(&host, config.addr.port()).ok_or_else(|| EnvError {
+                var: "IRON_ADDR".to_owned(),
+                value: host.clone(),
+                reason: "not a valid address"
+            }));
+        }
+
+        if let Some(port) = port { config.addr.set_port(port) }
+
+        Ok(config)
+    }
+}
+
+// Parse a timeout in seconds, where zero means none.
+fn seconds(value: &str) -> Option<Option<Duration>> {
+    match value.parse() {
+        Ok(0) => Some(None),
+        Ok(secs) => Some(Some(Duration::from_secs(secs))),
+        Err(_) => None
+    }
+}
+
+// Resolve `host`, which may include a port, using `default_port` otherwise.
+fn resolve(host: &str, default_port: u16) -> Option<SocketAddr> {
+    if let Ok(mut addrs) = host.to_socket_addrs() {
+        return addrs.next();
+    }
+
+    (host, default_port).to_socket_addrs().ok().and_then(|mut addrs| addrs.next())
+}
+
+/// An invalid environment variable read by `ServerConfig::from_env`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EnvError {
+    /// The name of the variable.
+    pub var: String,
+
+    /// Its value.
+    pub value: String,
+
+    /// What is wrong with it.
+    pub reason: &'static str
+}
+
+impl fmt::Display for EnvError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Invalid {}={:?}: {}", self.var, self.value, self.reason)
+    }
+}
+
+impl Error for EnvError {
+    fn description(&self) -> &str {
+        "Invalid environment variable"
+    }
+}
+
 /// Protocol used to serve content. Future versions of Iron may add new protocols
 /// to this enum. Thus you should not exhaustively match on its variants.
 #[derive(Clone)]
@@ -148,6 +356,8 @@ impl<H: Handler> Iron<H> {
 
         self.addr = Some(sock_addr);
         self.protocol = Some(protocol.clone());
+        self.schedule.start();
+        self.stats.set_threads(threads);
 
         match protocol {
             Protocol::Http => {
@@ -179,7 +389,101 @@ impl<H: Handler> Iron<H> {
     /// This will create a new `Iron`, the base unit of the server, using the
     /// passed in `Handler`.
     pub fn new(handler: H) -> Iron<H> {
-        Iron { handler: handler, addr: None, protocol: None }
+        Iron {
+            handler: handler,
+            addr: None,
+            protocol: None,
+            schedule: Schedule::default(),
+            config: ServerConfig::default(),
+            stats: Stats::new(),
+            draining: Arc::new(AtomicBool::new(false)),
+            not_found: None,
+            internal_error: None
+        }
+    }
+
+    /// Instantiate a new instance of `Iron` whose `listen` settings are read
+    /// from environment variables, as described by `ServerConfig`.
+    ///
+    /// This allows the same build to be deployed to different environments,
+    /// such as containers, without code changes.
+    pub fn from_env(handler: H) -> Result<Iron<H>, EnvError> {
+        let mut iron = Iron::new(handler);
+        iron.config = try!(ServerConfig::from_env());
+        Ok(iron)
+    }
+
+    /// Kick off the server process using the HTTP protocol, with the address,
+    /// threads and timeouts of this server's `ServerConfig`.
+    ///
+    /// These are the defaults given by `ServerConfig::default` unless the
+    /// server was created by `from_env`.
+    pub fn listen(self) -> HttpResult<Listening> {
+        let config = self.config;
+        self.listen_with(config.addr, config.threads, Protocol::Http, Some(config.timeouts))
+    }
+
+    /// The settings used by `listen`.
+    pub fn config(&self) -> &ServerConfig {
+        &self.config
+    }
+
+    /// A handle to this server's counters, which can be kept to read them
+    /// once the server is listening.
+    pub fn stats(&self) -> Stats {
+        self.stats.clone()
+    }
+
+    /// Set the limits on the requests this server accepts.
+    pub fn limits(&mut self, limits: Limits) -> &mut Iron<H> {
+        self.config.limits = limits;
+        self
+    }
+
+    /// A handle for shutting this server down gracefully, which can be kept
+    /// to use once the server is listening.
+    pub fn shutdown_handle(&self) -> Shutdown {
+        Shutdown {
+            draining: self.draining.clone(),
+            stats: self.stats.clone(),
+            schedule: self.schedule.stop_handle()
+        }
+    }
+
+    /// Answer requests for which the handler produced a `404 Not Found`, or
+    /// no status at all, without a body, with `handler` instead.
+    ///
+    /// This is the last fallback, applied to whatever the handler returns,
+    /// so it works however the handler is composed.
+    pub fn not_found<N: Handler>(&mut self, handler: N) -> &mut Iron<H> {
+        self.not_found = Some(Box::new(handler));
+        self
+    }
+
+    /// Answer requests on which the handler panicked, or returned an error
+    /// with a server error status, or no status, and no body, with `handler`
+    /// instead.
+    ///
+    /// Panics are only caught once this has been set; otherwise a panic
+    /// ends the connection.
+    pub fn internal_error<E: Handler>(&mut self, handler: E) -> &mut Iron<H> {
+        self.internal_error = Some(Box::new(handler));
+        self
+    }
+
+    /// Run `task` every `interval` while the server is running.
+    ///
+    /// Each task runs on its own thread, started when the server starts
+    /// listening and stopped once shutdown begins through its `Shutdown`
+    /// handle, or when the server is dropped, once its threads have stopped
+    /// serving requests. Note that `Listening::close` does not currently stop
+    /// those threads. Use this for housekeeping such as
+    /// expiring sessions or flushing metrics. A task which panics is run
+    /// again at its next interval.
+    pub fn schedule<F>(&mut self, interval: Duration, task: F) -> &mut Iron<H>
+    where F: FnMut() + Send + 'static {
+        self.schedule.add(interval, Box::new(task));
+        self
     }
 }
 
@@ -188,27 +492,187 @@ impl<H: Handler> ::hyper::server::Handler for Iron<H> {
         // Set some defaults in case request handler panics.
         // This should not be necessary anymore once stdlib's catch_panic becomes stable.
         *http_res.status_mut() = status::InternalServerError;
+        let mut in_flight = InFlight::start(&self.stats);
+
+        let limits = &self.config.limits;
+        let checked = check_uri(&http_req.uri, limits)
+            .and_then(|_| check_headers(&http_req.headers, limits));
+        if let Err((status, reason)) = checked {
+            error!("Rejecting request from {}: {}", http_req.remote_addr, reason);
+            in_flight.status = status;
+            return reject(http_res, status);
+        }
 
         // Create `Request` wrapper.
         match Request::from_http(http_req, self.addr.clone().unwrap(),
                                  self.protocol.as_ref().unwrap()) {
             Ok(mut req) => {
                 // Dispatch the request, write the response back to http_res
-                self.handler.handle(&mut req).unwrap_or_else(|e| {
-                    error!("Error handling:\n{:?}\nError was: {:?}", req, e.error);
-                    e.response
-                }).write_back(http_res)
+                let mut res = self.respond(&mut req);
+                self.finish(&mut in_flight, &mut res);
+                res.write_back(http_res, &req.method)
             },
             Err(e) => {
                 error!("Error creating request:\n    {}", e);
-                bad_request(http_res)
+                in_flight.status = status::BadRequest;
+                reject(http_res, status::BadRequest)
             }
         }
     }
+
+    fn on_connection_start(&self) {
+        self.stats.connection_started();
+    }
+
+    fn on_connection_end(&self) {
+        self.stats.connection_ended();
+    }
+}
+
+impl<H: Handler> Iron<H> {
+    // Dispatch a request to the handler, falling back to the `not_found` and
+    // `internal_error` handlers where they apply.
+    fn respond(&self, req: &mut Request) -> Response {
+        let result = match self.internal_error {
+            Some(_) => panic::catch_unwind(AssertUnwindSafe(|| self.handler.handle(req))),
+            None => Ok(self.handler.handle(req))
+        };
+
+        let res = match result {
+            Ok(Ok(res)) => res,
+            Ok(Err(mut e)) if is_abort(&e) => {
+                error!("Aborted handling:\n{:?}", req);
+                e.response.headers.set(headers::Connection::close());
+                return e.response
+            },
+            Ok(Err(e)) => {
+                error!("Error handling:\n{:?}\nError was: {:?}", req, e.error);
+                let failed = e.response.status.map_or(true, |status| status.is_server_error());
+                match self.internal_error {
+                    Some(ref handler) if failed && e.response.body.is_none() => {
+                        fallback(&**handler, req)
+                    },
+                    _ => e.response
+                }
+            },
+            Err(_) => {
+                error!("Handler panicked:\n{:?}", req);
+                PanicFlag::raise(req);
+                match self.internal_error {
+                    Some(ref handler) => fallback(&**handler, req),
+                    None => Response::with(status::InternalServerError)
+                }
+            }
+        };
+
+        let missing = res.status.map_or(true, |status| status == status::NotFound);
+        match self.not_found {
+            Some(ref handler) if missing && res.body.is_none() => fallback(&**handler, req),
+            _ => res
+        }
+    }
+
+    // Record the status of a response for the stats, and ask for its
+    // connection to be closed after it if the server is draining.
+    fn finish(&self, in_flight: &mut InFlight, res: &mut Response) {
+        in_flight.status = res.status.unwrap_or(status::NotFound);
+
+        if self.draining.load(Ordering::SeqCst) {
+            res.headers.set(headers::Connection::close());
+        }
+    }
+}
+
+// A request counted as in flight in the stats until this is dropped, when it
+// is recorded as finished with `status`. Dropping it during a panic records
+// the request as an internal server error, so that it is never left in
+// flight.
+struct InFlight<'a> {
+    stats: &'a Stats,
+    status: Status
+}
+
+impl<'a> InFlight<'a> {
+    fn start(stats: &'a Stats) -> InFlight<'a> {
+        stats.request_started();
+        InFlight { stats: stats, status: status::InternalServerError }
+    }
+}
+
+impl<'a> Drop for InFlight<'a> {
+    fn drop(&mut self) {
+        self.stats.request_finished(self.status);
+    }
+}
+
+/// A handle for shutting down a server gracefully, from `Iron::shutdown_handle`.
+///
+/// Once `begin` has been called, every response is sent with
+/// `Connection: close`, so that clients finish their current request and then
+/// disconnect instead of keeping the connection alive, and `wait` waits for
+/// the requests in flight to complete. A typical shutdown is:
+///
+/// ```ignore
+/// let shutdown = iron.shutdown_handle();
+/// let listening = iron.http("localhost:3000").unwrap();
+///
+/// // On SIGTERM:
+/// shutdown.begin();
+/// shutdown.wait(Duration::from_secs(30));
+/// ```
+///
+/// Note that the server keeps accepting connections while draining, since
+/// `Listening::close` cannot currently stop it, and that idle keep-alive
+/// connections are only closed when their keep-alive timeout expires.
+#[derive(Clone)]
+pub struct Shutdown {
+    draining: Arc<AtomicBool>,
+    stats: Stats,
+    schedule: Stop
+}
+
+impl Shutdown {
+    /// Start draining: close each connection after its current request, and
+    /// stop the server's scheduled tasks.
+    pub fn begin(&self) {
+        self.draining.store(true, Ordering::SeqCst);
+        self.schedule.stop();
+    }
+
+    /// Whether draining has begun.
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::SeqCst)
+    }
+
+    /// Wait up to `timeout` for the requests in flight to finish, returning
+    /// whether they did.
+    pub fn wait(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+
+        loop {
+            if self.stats.snapshot().requests_in_flight == 0 { return true }
+            if Instant::now() >= deadline { return false }
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+}
+
+impl fmt::Debug for Shutdown {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Shutdown {{ draining: {} }}", self.is_draining())
+    }
 }
 
-fn bad_request(mut http_res: HttpResponse<Fresh>) {
-    *http_res.status_mut() = status::BadRequest;
+fn fallback(handler: &Handler, req: &mut Request) -> Response {
+    handler.handle(req).unwrap_or_else(|e| e.response)
+}
+
+fn reject(mut http_res: HttpResponse<Fresh>, status: Status) {
+    *http_res.status_mut() = status;
+
+    // The rest of the request may not have been read, so the connection
+    // cannot be reused.
+    http_res.headers_mut().set(headers::Connection::close());
 
     // Consume and flush the response.
     // We would like this to work, but can't do anything if it doesn't.
@@ -218,3 +682,200 @@ fn bad_request(mut http_res: HttpResponse<Fresh>) {
     }
 }
 
+
+#[cfg(test)]
+mod test {
+    use std::fmt;
+    use std::io::{Read, Write};
+    use std::net::{SocketAddr, TcpStream};
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+    use std::time::Duration;
+
+    use method::Method;
+    use middleware::Abort;
+    use pool::{self, Connection, Pool};
+    use response::ResponseBody;
+    use test::StubRequest;
+    use {headers, status, Chain, Iron, IronError, IronResult, Request, Response};
+
+    use super::{InFlight, ServerConfig, Timeouts};
+
+    fn vars(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs.iter().map(|&(name, value)| (name.to_owned(), value.to_owned())).collect()
+    }
+
+    #[test]
+    fn test_draining() {
+        let iron = Iron::new(|_: &mut Request| Ok(Response::with(status::Ok)));
+        let shutdown = iron.shutdown_handle();
+
+        let mut in_flight = InFlight::start(&iron.stats);
+        let mut res = Response::with(status::Ok);
+        iron.finish(&mut in_flight, &mut res);
+        assert!(!res.headers.has::<headers::Connection>());
+        drop(in_flight);
+        assert!(shutdown.wait(Duration::from_secs(0)));
+
+        let mut in_flight = InFlight::start(&iron.stats);
+        shutdown.begin();
+        assert!(!shutdown.wait(Duration::from_millis(20)));
+
+        let mut res = Response::with(status::Ok);
+        iron.finish(&mut in_flight, &mut res);
+        assert_eq!(res.headers.get(), Some(&headers::Connection::close()));
+        drop(in_flight);
+        assert!(shutdown.wait(Duration::from_secs(0)));
+    }
+
+    #[test]
+    fn test_panicking_handler_drains() {
+        let iron = Iron::new(|_: &mut Request| -> IronResult<Response> { panic!("boom") });
+        let shutdown = iron.shutdown_handle();
+        let stats = iron.stats();
+        let mut listening = iron.http("127.0.0.1:0").unwrap();
+        let addr = listening.socket;
+        listening.close().unwrap();
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        let _ = stream.read_to_end(&mut vec![]);
+
+        shutdown.begin();
+        assert!(shutdown.wait(Duration::from_secs(5)));
+        assert_eq!(stats.snapshot().requests_in_flight, 0);
+    }
+
+    #[test]
+    fn test_shutdown_stops_scheduled_tasks() {
+        let runs = Arc::new(AtomicUsize::new(0));
+        let counter = runs.clone();
+
+        let mut iron = Iron::new(|_: &mut Request| Ok(Response::with(status::Ok)));
+        iron.schedule(Duration::from_millis(5), move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+        });
+        let shutdown = iron.shutdown_handle();
+        let mut listening = iron.http("127.0.0.1:0").unwrap();
+        listening.close().unwrap();
+
+        thread::sleep(Duration::from_millis(50));
+        shutdown.begin();
+        thread::sleep(Duration::from_millis(20));
+
+        let after_stop = runs.load(Ordering::SeqCst);
+        assert!(after_stop > 0);
+        thread::sleep(Duration::from_millis(30));
+        assert_eq!(runs.load(Ordering::SeqCst), after_stop);
+    }
+
+    #[test]
+    fn test_fallbacks() {
+        let mut iron = Iron::new(|req: &mut Request| -> IronResult<Response> {
+            match &*req.url.path()[0] {
+                "missing" => Ok(Response::new()),
+                "gone" => Ok(Response::with((status::NotFound, "gone"))),
+                "failed" => Err(IronError::new(fmt::Error, status::ServiceUnavailable)),
+                "refused" => Err(IronError::new(fmt::Error, status::Forbidden)),
+                _ => panic!("boom")
+            }
+        });
+        iron.not_found(|_: &mut Request| Ok(Response::with((status::NotFound, "not found"))));
+        iron.internal_error(|_: &mut Request| Ok(Response::with((status::InternalServerError,
+                                                                 "oops"))));
+
+        let respond = |path: &str| {
+            let mut req = StubRequest::new(Method::Get, &format!("http://localhost/{}", path));
+            let res = iron.respond(&mut req.build());
+            let mut body = vec![];
+            if let Some(mut b) = res.body {
+                b.write_body(&mut ResponseBody::new(&mut body)).unwrap();
+            }
+            (res.status, String::from_utf8(body).unwrap())
+        };
+
+        assert_eq!(respond("missing"), (Some(status::NotFound), "not found".into()));
+        assert_eq!(respond("gone"), (Some(status::NotFound), "gone".into()));
+        assert_eq!(respond("failed"), (Some(status::InternalServerError), "oops".into()));
+        assert_eq!(respond("refused"), (Some(status::Forbidden), "".into()));
+        assert_eq!(respond("panic"), (Some(status::InternalServerError), "oops".into()));
+    }
+
+    #[test]
+    fn test_caught_panics_close_pooled_connections() {
+        struct Conn;
+        impl Connection for Conn {}
+
+        let pool = Arc::new(Pool::new(|| Ok(Conn)));
+        let mut chain = Chain::new(|req: &mut Request| -> IronResult<Response> {
+            assert!(pool::connection::<Conn>(req).is_some());
+            match &*req.url.path()[0] {
+                "panic" => panic!("boom"),
+                _ => Ok(Response::with(status::Ok))
+            }
+        });
+        chain.link_before(pool.clone());
+        chain.link_after(pool.clone());
+        let mut iron = Iron::new(chain);
+        iron.internal_error(|_: &mut Request| Ok(Response::with(status::InternalServerError)));
+
+        let respond = |path: &str| {
+            let url = format!("http://localhost/{}", path);
+            iron.respond(&mut StubRequest::new(Method::Get, &url).build()).status
+        };
+        assert_eq!(respond("fine"), Some(status::Ok));
+        assert_eq!((pool.open(), pool.idle()), (1, 1));
+        assert_eq!(respond("panic"), Some(status::InternalServerError));
+        assert_eq!((pool.open(), pool.idle()), (0, 0));
+    }
+
+    #[test]
+    fn test_abort() {
+        let mut iron = Iron::new(|_: &mut Request| -> IronResult<Response> {
+            Err(Abort::with((status::BadRequest, "bad chunk")))
+        });
+        iron.internal_error(|_: &mut Request| Ok(Response::with(status::InternalServerError)));
+
+        let res = iron.respond(&mut StubRequest::new(Method::Post, "http://localhost/").build());
+        assert_eq!(res.status, Some(status::BadRequest));
+        assert_eq!(res.headers.get::<headers::Connection>(), Some(&headers::Connection::close()));
+    }
+
+    #[test]
+    fn test_defaults() {
+        let config = ServerConfig::from_vars(vars(&[("PATH", "/bin")])).unwrap();
+        assert_eq!(config, ServerConfig::default());
+    }
+
+    #[test]
+    fn test_from_vars() {
+        let config = ServerConfig::from_vars(vars(&[
+            ("IRON_ADDR", "127.0.0.1:4000"),
+            ("IRON_PORT", "8080"),
+            ("IRON_THREADS", "3"),
+            ("IRON_KEEP_ALIVE", "0"),
+            ("IRON_READ_TIMEOUT", "10")
+        ])).unwrap();
+
+        assert_eq!(config.addr, "127.0.0.1:8080".parse::<SocketAddr>().unwrap());
+        assert_eq!(config.threads, 3);
+        assert_eq!(config.timeouts, Timeouts {
+            keep_alive: None,
+            read: Some(Duration::from_secs(10)),
+            ..Timeouts::default()
+        });
+
+        let config = ServerConfig::from_vars(vars(&[("IRON_ADDR", "127.0.0.1")])).unwrap();
+        assert_eq!(config.addr, "127.0.0.1:3000".parse::<SocketAddr>().unwrap());
+    }
+
+    #[test]
+    fn test_invalid_vars() {
+        let err = ServerConfig::from_vars(vars(&[("IRON_THREADS", "0")])).unwrap_err();
+        assert_eq!(err.var, "IRON_THREADS");
+
+        assert!(ServerConfig::from_vars(vars(&[("IRON_PORT", "http")])).is_err());
+        assert!(ServerConfig::from_vars(vars(&[("IRON_READ_TIMEOUT", "-1")])).is_err());
+    }
+}
@@ -0,0 +1,437 @@
+//! Background jobs, run by a pool of worker threads after the response has
+//! been sent.
+//!
+//! A `Jobs` pool owns the worker threads. Its `Queue` handle is cheap to
+//! clone and, as `BeforeMiddleware`, makes itself available to handlers
+//! through `Queue::of`:
+//!
+//! ```ignore
+//! let jobs = Jobs::new(4);
+//!
+//! let mut chain = Chain::new(handler);
+//! chain.link_before(jobs.queue());
+//! let listening = Iron::new(chain).http("localhost:3000").unwrap();
+//!
+//! // In a handler:
+//! let queue = Queue::of(req).unwrap();
+//! itry!(queue.enqueue(SendWelcomeEmail { to: address }));
+//!
+//! // On shutdown, wait for queued jobs to finish:
+//! listening.close().unwrap();
+//! jobs.shutdown();
+//! ```
+
+use std::cmp::{self, Ordering};
+use std::collections::{BinaryHeap, VecDeque};
+use std::error::Error;
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Mutex, MutexGuard, Condvar};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use typemap::Key;
+
+use {BeforeMiddleware, Request, IronResult};
+
+/// A unit of background work.
+///
+/// Implemented for closures returning a `Result`.
+pub trait Job: Send + 'static {
+    /// Do the work. Returning an error, or panicking, fails this attempt.
+    fn run(&mut self) -> Result<(), Box<Error + Send + Sync>>;
+
+    /// How to retry the job if an attempt fails. Defaults to never retrying.
+    fn retry(&self) -> Retry { Retry::never() }
+}
+
+impl<F> Job for F where F: FnMut() -> Result<(), Box<Error + Send + Sync>> + Send + 'static {
+    fn run(&mut self) -> Result<(), Box<Error + Send + Sync>> { self() }
+}
+
+/// A retry policy: how many times to attempt a job, and how long to wait
+/// between attempts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Retry {
+    attempts: u32,
+    backoff: Duration
+}
+
+impl Retry {
+    /// Attempt the job once.
+    pub fn never() -> Retry {
+        Retry { attempts: 1, backoff: Duration::from_secs(0) }
+    }
+
+    /// Attempt the job up to `attempts` times in all, waiting `backoff`
+    /// after the first failure and doubling the wait after each further one.
+    pub fn up_to(attempts: u32, backoff: Duration) -> Retry {
+        Retry { attempts: cmp::max(attempts, 1), backoff: backoff }
+    }
+
+    /// The total number of attempts.
+    pub fn attempts(&self) -> u32 {
+        self.attempts
+    }
+
+    // The wait before attempt number `attempt`, counting from 1.
+    fn backoff(&self, attempt: u32) -> Duration {
+        self.backoff * 2u32.saturating_pow(attempt.saturating_sub(2))
+    }
+}
+
+struct Shared {
+    state: Mutex<State>,
+    available: Condvar
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+struct State {
+    queue: VecDeque<Attempt>,
+    // Failed jobs waiting out their backoff before being retried.
+    delayed: BinaryHeap<Delayed>,
+    closed: bool
+}
+
+impl State {
+    // The next job to run, if one is ready, taking retries which are due
+    // before newly queued jobs.
+    fn next(&mut self, now: Instant) -> Option<Attempt> {
+        if self.delayed.peek().map_or(false, |delayed| delayed.at <= now) {
+            return self.delayed.pop().map(|delayed| delayed.attempt);
+        }
+        self.queue.pop_front()
+    }
+}
+
+// A job together with the number of the attempt to be made at it.
+struct Attempt {
+    job: Box<Job>,
+    retry: Retry,
+    number: u32
+}
+
+// An attempt which is not to be made before `at`.
+struct Delayed {
+    at: Instant,
+    attempt: Attempt
+}
+
+// Ordered so that the earliest is the greatest, at the top of the heap.
+impl Ord for Delayed {
+    fn cmp(&self, other: &Delayed) -> Ordering {
+        other.at.cmp(&self.at)
+    }
+}
+
+impl PartialOrd for Delayed {
+    fn partial_cmp(&self, other: &Delayed) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Delayed {
+    fn eq(&self, other: &Delayed) -> bool {
+        self.at == other.at
+    }
+}
+
+impl Eq for Delayed {}
+
+/// A pool of worker threads running queued jobs.
+///
+/// Dropping the pool, or calling `shutdown`, stops new jobs from being
+/// queued and waits for those already queued to finish, including any
+/// retries of them.
+pub struct Jobs {
+    shared: Arc<Shared>,
+    workers: Vec<JoinHandle<()>>
+}
+
+impl Jobs {
+    /// Start `threads` worker threads.
+    ///
+    /// ## Panics
+    ///
+    /// Panics if `threads` is zero.
+    pub fn new(threads: usize) -> Jobs {
+        assert!(threads > 0, "Jobs needs at least one thread");
+
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State {
+                queue: VecDeque::new(),
+                delayed: BinaryHeap::new(),
+                closed: false
+            }),
+            available: Condvar::new()
+        });
+
+        let workers = (0..threads).map(|i| {
+            let shared = shared.clone();
+            thread::Builder::new()
+                .name(format!("iron-jobs-{}", i))
+                .spawn(move || work(&shared))
+                .unwrap()
+        }).collect();
+
+        Jobs { shared: shared, workers: workers }
+    }
+
+    /// A handle for queueing jobs.
+    pub fn queue(&self) -> Queue {
+        Queue { shared: self.shared.clone() }
+    }
+
+    /// Stop accepting jobs, and wait for the queued ones to finish.
+    pub fn shutdown(self) {}
+}
+
+impl Drop for Jobs {
+    fn drop(&mut self) {
+        self.shared.lock().closed = true;
+        self.shared.available.notify_all();
+
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+impl fmt::Debug for Jobs {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Jobs {{ threads: {} }}", self.workers.len())
+    }
+}
+
+fn work(shared: &Shared) {
+    loop {
+        let attempt = {
+            let mut state = shared.lock();
+            loop {
+                let now = Instant::now();
+                if let Some(attempt) = state.next(now) { break attempt }
+                if state.closed && state.delayed.is_empty() { return }
+
+                let wait = state.delayed.peek().map(|delayed| delayed.at - now);
+                state = match wait {
+                    Some(wait) => shared.available.wait_timeout(state, wait)
+                        .unwrap_or_else(|e| e.into_inner()).0,
+                    None => shared.available.wait(state).unwrap_or_else(|e| e.into_inner())
+                };
+            }
+        };
+
+        if let Some(retry) = run(attempt) {
+            let mut state = shared.lock();
+            state.delayed.push(retry);
+            // Wake a worker which may be waiting for a later retry, or
+            // none at all.
+            shared.available.notify_one();
+        }
+    }
+}
+
+// Make an attempt at a job, returning the next attempt if it failed and its
+// policy allows another one. Retries are delayed rather than waited for, so
+// that the worker is free to run other jobs in the meantime.
+fn run(mut attempt: Attempt) -> Option<Delayed> {
+    let (number, attempts) = (attempt.number, attempt.retry.attempts);
+
+    match panic::catch_unwind(AssertUnwindSafe(|| attempt.job.run())) {
+        Ok(Ok(())) => return None,
+        Ok(Err(e)) => warn!("Job failed on attempt {} of {}: {}", number, attempts, e),
+        Err(_) => warn!("Job panicked on attempt {} of {}", number, attempts)
+    }
+
+    if number >= attempts {
+        error!("Job failed after {} attempts, giving up", attempts);
+        return None;
+    }
+
+    attempt.number += 1;
+    Some(Delayed { at: Instant::now() + attempt.retry.backoff(attempt.number), attempt: attempt })
+}
+
+/// A handle for queueing jobs on a `Jobs` pool.
+#[derive(Clone)]
+pub struct Queue {
+    shared: Arc<Shared>
+}
+
+impl Key for Queue { type Value = Queue; }
+
+impl Queue {
+    /// The queue made available to `req` by a `Queue` linked as
+    /// `BeforeMiddleware`, if any.
+    pub fn of(req: &Request) -> Option<Queue> {
+        req.extensions.get::<Queue>().cloned()
+    }
+
+    /// Queue `job` to be run by the next free worker.
+    ///
+    /// Fails if the pool is shutting down.
+    pub fn enqueue<J: Job>(&self, job: J) -> Result<(), QueueClosed> {
+        let mut state = self.shared.lock();
+        if state.closed { return Err(QueueClosed) }
+
+        let retry = job.retry();
+        state.queue.push_back(Attempt { job: Box::new(job), retry: retry, number: 1 });
+        self.shared.available.notify_one();
+        Ok(())
+    }
+
+    /// The number of jobs waiting for a worker, including those waiting to
+    /// be retried.
+    pub fn pending(&self) -> usize {
+        let state = self.shared.lock();
+        state.queue.len() + state.delayed.len()
+    }
+}
+
+impl fmt::Debug for Queue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Queue {{ pending: {} }}", self.pending())
+    }
+}
+
+impl BeforeMiddleware for Queue {
+    fn before(&self, req: &mut Request) -> IronResult<()> {
+        req.extensions.insert::<Queue>(self.clone());
+        Ok(())
+    }
+}
+
+/// The error returned when queueing a job on a pool which is shutting down.
+#[derive(Debug)]
+pub struct QueueClosed;
+
+impl fmt::Display for QueueClosed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.description())
+    }
+}
+
+impl Error for QueueClosed {
+    fn description(&self) -> &str {
+        "Job queue is shut down"
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::error::Error;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    use super::{Job, Jobs, Retry};
+
+    struct Flaky {
+        attempts: Arc<AtomicUsize>,
+        failures: usize
+    }
+
+    impl Job for Flaky {
+        fn run(&mut self) -> Result<(), Box<Error + Send + Sync>> {
+            if self.attempts.fetch_add(1, Ordering::SeqCst) < self.failures {
+                Err("not yet".into())
+            } else {
+                Ok(())
+            }
+        }
+
+        fn retry(&self) -> Retry { Retry::up_to(3, Duration::from_millis(1)) }
+    }
+
+    #[test]
+    fn test_shutdown_drains_queue() {
+        let jobs = Jobs::new(2);
+        let queue = jobs.queue();
+        let done = Arc::new(AtomicUsize::new(0));
+
+        for _ in 0..20 {
+            let done = done.clone();
+            queue.enqueue(move || {
+                done.fetch_add(1, Ordering::SeqCst);
+                Ok(())
+            }).unwrap();
+        }
+
+        jobs.shutdown();
+        assert_eq!(done.load(Ordering::SeqCst), 20);
+        assert!(queue.enqueue(|| Ok(())).is_err());
+    }
+
+    #[test]
+    fn test_retries() {
+        let jobs = Jobs::new(1);
+        let succeeds = Arc::new(AtomicUsize::new(0));
+        let gives_up = Arc::new(AtomicUsize::new(0));
+
+        jobs.queue().enqueue(Flaky { attempts: succeeds.clone(), failures: 2 }).unwrap();
+        jobs.queue().enqueue(Flaky { attempts: gives_up.clone(), failures: 5 }).unwrap();
+        jobs.shutdown();
+
+        assert_eq!(succeeds.load(Ordering::SeqCst), 3);
+        assert_eq!(gives_up.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn test_panicking_jobs_do_not_kill_workers() {
+        let jobs = Jobs::new(1);
+        let done = Arc::new(AtomicUsize::new(0));
+
+        jobs.queue().enqueue(|| -> Result<(), Box<Error + Send + Sync>> { panic!("oops") })
+            .unwrap();
+        let counter = done.clone();
+        jobs.queue().enqueue(move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        }).unwrap();
+
+        jobs.shutdown();
+        assert_eq!(done.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_retries_do_not_block_workers() {
+        let jobs = Jobs::new(1);
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let seen = Arc::new(AtomicUsize::new(0));
+
+        struct Slow(Arc<AtomicUsize>);
+
+        impl Job for Slow {
+            fn run(&mut self) -> Result<(), Box<Error + Send + Sync>> {
+                self.0.fetch_add(1, Ordering::SeqCst);
+                Err("not yet".into())
+            }
+
+            fn retry(&self) -> Retry { Retry::up_to(2, Duration::from_millis(200)) }
+        }
+
+        jobs.queue().enqueue(Slow(attempts.clone())).unwrap();
+        let (attempts_then, seen_then) = (attempts.clone(), seen.clone());
+        jobs.queue().enqueue(move || {
+            seen_then.store(attempts_then.load(Ordering::SeqCst), Ordering::SeqCst);
+            Ok(())
+        }).unwrap();
+
+        jobs.shutdown();
+        assert_eq!(seen.load(Ordering::SeqCst), 1);
+        assert_eq!(attempts.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_backoff() {
+        let retry = Retry::up_to(4, Duration::from_millis(10));
+        assert_eq!(retry.backoff(2), Duration::from_millis(10));
+        assert_eq!(retry.backoff(3), Duration::from_millis(20));
+        assert_eq!(retry.backoff(4), Duration::from_millis(40));
+    }
+}
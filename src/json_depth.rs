@@ -0,0 +1,128 @@
+//! Parsing JSON whose nesting is bounded.
+//!
+//! `rustc_serialize` parses and drops JSON recursively, so a body such as a
+//! hundred thousand `[`s can overflow the stack of the thread handling it.
+//! `parse` first scans the text for how deeply its arrays and objects are
+//! nested, skipping over strings, and only parses it if that is within the
+//! limit:
+//!
+//! ```ignore
+//! let body = try!(req.body_string(limit).map_err(IronError::from));
+//! let value = itry!(json_depth::parse(&body, json_depth::DEFAULT_MAX_DEPTH),
+//!                   status::BadRequest);
+//! ```
+
+use std::error::Error;
+use std::fmt;
+
+use rustc_serialize::json::{Json, ParserError};
+
+/// How deeply arrays and objects may be nested, unless set otherwise.
+pub const DEFAULT_MAX_DEPTH: usize = 128;
+
+/// Parse `text` as JSON, failing without parsing it if its arrays and
+/// objects are nested more than `max_depth` deep.
+pub fn parse(text: &str, max_depth: usize) -> Result<Json, JsonError> {
+    if depth(text) > max_depth { return Err(JsonError::TooDeep(max_depth)) }
+    Json::from_str(text).map_err(JsonError::Syntax)
+}
+
+/// How deeply the arrays and objects of `text` are nested, not counting
+/// brackets inside strings. Unbalanced brackets are not an error here; they
+/// are left for the parser to reject.
+pub fn depth(text: &str) -> usize {
+    let mut depth = 0usize;
+    let mut deepest = 0;
+    let mut in_string = false;
+    let mut escaped = false;
+
+    for b in text.bytes() {
+        if in_string {
+            match b {
+                _ if escaped => escaped = false,
+                b'\\' => escaped = true,
+                b'"' => in_string = false,
+                _ => {}
+            }
+            continue
+        }
+
+        match b {
+            b'"' => in_string = true,
+            b'[' | b'{' => {
+                depth += 1;
+                deepest = deepest.max(depth);
+            },
+            b']' | b'}' => depth = depth.saturating_sub(1),
+            _ => {}
+        }
+    }
+
+    deepest
+}
+
+/// An error parsing JSON of bounded depth.
+#[derive(Debug)]
+pub enum JsonError {
+    /// The text is not valid JSON.
+    Syntax(ParserError),
+
+    /// Arrays and objects are nested more deeply than this limit.
+    TooDeep(usize)
+}
+
+impl fmt::Display for JsonError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            JsonError::Syntax(ref e) => fmt::Display::fmt(e, f),
+            JsonError::TooDeep(limit) => write!(f, "JSON nested more than {} deep", limit)
+        }
+    }
+}
+
+impl Error for JsonError {
+    fn description(&self) -> &str {
+        match *self {
+            JsonError::Syntax(_) => "Invalid JSON",
+            JsonError::TooDeep(_) => "JSON nested too deeply"
+        }
+    }
+
+    fn cause(&self) -> Option<&Error> {
+        match *self {
+            JsonError::Syntax(ref e) => Some(e),
+            JsonError::TooDeep(_) => None
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rustc_serialize::json::Json;
+
+    use super::{depth, parse, JsonError};
+
+    #[test]
+    fn test_depth() {
+        assert_eq!(depth("1"), 0);
+        assert_eq!(depth(r#"{"a": [1, {"b": []}]}"#), 4);
+        assert_eq!(depth(r#"["[[[", "\"{{{"]"#), 1);
+        assert_eq!(depth("]]]["), 1);
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(parse("[[1]]", 2).unwrap(), Json::from_str("[[1]]").unwrap());
+        match parse("[[1]]", 1) {
+            Err(JsonError::TooDeep(1)) => {},
+            other => panic!("Expected too deep, got {:?}", other)
+        }
+        match parse("[1", 2) {
+            Err(JsonError::Syntax(_)) => {},
+            other => panic!("Expected a syntax error, got {:?}", other)
+        }
+
+        let deep = "[".repeat(1_000_000);
+        assert!(parse(&deep, 128).is_err());
+    }
+}
@@ -0,0 +1,342 @@
+//! JSON-RPC 2.0 endpoints.
+//!
+//! `JsonRpc` is an `AroundMiddleware` which serves a set of named methods
+//! at a single path, following the [JSON-RPC 2.0
+//! specification](https://www.jsonrpc.org/specification): it unwraps call
+//! envelopes, decodes each call's params for the method, runs batches and
+//! notifications, and answers with the standard error codes. Other requests
+//! are passed on to the wrapped handler.
+//!
+//! ```ignore
+//! let mut rpc = JsonRpc::new("/rpc");
+//! rpc.method("subtract", |(a, b): (i64, i64), _: &mut Request| Ok(a - b));
+//! rpc.method("whoami", |_: (), req: &mut Request| {
+//!     req.extensions.get::<CurrentUser>().map(|user| user.name.clone())
+//!         .ok_or_else(|| RpcError::new(1, "Not logged in"))
+//! });
+//! chain.link_around(rpc);
+//! ```
+
+use std::collections::HashMap;
+
+use rustc_serialize::Decodable;
+use rustc_serialize::json::{self, Json, Object, ToJson};
+
+use {headers, status, AroundMiddleware, Handler, Request, Response, IronResult, IronError};
+use json_depth::{self, DEFAULT_MAX_DEPTH};
+use method::Method;
+
+/// The code for a body which is not valid JSON.
+pub const PARSE_ERROR: i64 = -32700;
+
+/// The code for a call which is not a valid JSON-RPC request object.
+pub const INVALID_REQUEST: i64 = -32600;
+
+/// The code for a call to a method which does not exist.
+pub const METHOD_NOT_FOUND: i64 = -32601;
+
+/// The code for params which the method cannot decode.
+pub const INVALID_PARAMS: i64 = -32602;
+
+/// The code for an internal error in the server.
+pub const INTERNAL_ERROR: i64 = -32603;
+
+/// An error returned from a call, sent to the client as its `error` member.
+///
+/// The codes from -32768 to -32000 are reserved by the specification;
+/// applications should choose others for their own errors.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RpcError {
+    /// The error code.
+    pub code: i64,
+
+    /// A short description of the error.
+    pub message: String,
+
+    /// Further information about the error, if any.
+    pub data: Option<Json>
+}
+
+impl RpcError {
+    /// Create an error with a code and message, and no data.
+    pub fn new<S: Into<String>>(code: i64, message: S) -> RpcError {
+        RpcError { code: code, message: message.into(), data: None }
+    }
+
+    /// Attach further information to the error.
+    pub fn data(mut self, data: Json) -> RpcError {
+        self.data = Some(data);
+        self
+    }
+}
+
+impl ToJson for RpcError {
+    fn to_json(&self) -> Json {
+        let mut error = Object::new();
+        error.insert("code".into(), self.code.to_json());
+        error.insert("message".into(), self.message.to_json());
+        if let Some(ref data) = self.data { error.insert("data".into(), data.clone()); }
+        Json::Object(error)
+    }
+}
+
+// A registered method, taking its params still encoded.
+type RpcMethod = Box<Fn(Json, &mut Request) -> Result<Json, RpcError> + Send + Sync>;
+
+/// Middleware serving JSON-RPC methods at one path.
+///
+/// Calls must be `POST`ed to the path; other methods are answered with `405
+/// Method Not Allowed`. Responses are sent with `200 OK`, including those
+/// carrying errors, except that requests made up only of notifications get
+/// `204 No Content`. Bodies nested more deeply than the maximum depth are
+/// answered with a parse error, without being parsed.
+pub struct JsonRpc {
+    path: Vec<String>,
+    methods: HashMap<String, RpcMethod>,
+    limit: usize,
+    max_depth: usize
+}
+
+impl JsonRpc {
+    /// Serve methods at `path`, with a body limit of 1 MiB and a maximum
+    /// depth of `json_depth::DEFAULT_MAX_DEPTH`.
+    pub fn new(path: &str) -> JsonRpc {
+        JsonRpc {
+            path: path.split('/').filter(|s| !s.is_empty()).map(String::from).collect(),
+            methods: HashMap::new(),
+            limit: 1024 * 1024,
+            max_depth: DEFAULT_MAX_DEPTH
+        }
+    }
+
+    /// Register a method called `name`.
+    ///
+    /// The call's params are decoded into a `P`: positional params into a
+    /// tuple or `Vec`, and named params into a struct or map. Calls without
+    /// params are decoded from `null`, so `()` or an `Option` accepts them.
+    /// Params which cannot be decoded produce an invalid params error
+    /// without calling `method`. The method also receives the `Request`,
+    /// with the extensions added by earlier middleware.
+    pub fn method<P, R, F>(&mut self, name: &str, method: F) -> &mut JsonRpc
+    where P: Decodable, R: ToJson,
+          F: Fn(P, &mut Request) -> Result<R, RpcError> + Send + Sync + 'static {
+        self.methods.insert(name.to_owned(), Box::new(move |params, req| {
+            let params = try!(P::decode(&mut json::Decoder::new(params)).map_err(|e| {
+                RpcError::new(INVALID_PARAMS, "Invalid params").data(e.to_string().to_json())
+            }));
+            method(params, req).map(|result| result.to_json())
+        }));
+        self
+    }
+
+    /// Reject request bodies longer than `limit` bytes.
+    pub fn limit(&mut self, limit: usize) -> &mut JsonRpc {
+        self.limit = limit;
+        self
+    }
+
+    /// Reject request bodies whose arrays and objects are nested more than
+    /// `depth` deep.
+    pub fn max_depth(&mut self, depth: usize) -> &mut JsonRpc {
+        self.max_depth = depth;
+        self
+    }
+
+    fn serve(&self, req: &mut Request) -> IronResult<Response> {
+        if req.method != Method::Post {
+            return Ok(Response::with(status::MethodNotAllowed)
+                .header(headers::Allow(vec![Method::Post])))
+        }
+
+        let body = try!(req.body_string(self.limit).map_err(IronError::from));
+
+        let reply = match json_depth::parse(&body, self.max_depth) {
+            Err(_) => Some(reply(Json::Null, Err(RpcError::new(PARSE_ERROR, "Parse error")))),
+            Ok(Json::Array(ref calls)) if calls.is_empty() => Some(invalid_request(Json::Null)),
+            Ok(Json::Array(calls)) => {
+                let replies = calls.into_iter().filter_map(|call| self.call(call, req))
+                    .collect::<Vec<_>>();
+                if replies.is_empty() { None } else { Some(Json::Array(replies)) }
+            },
+            Ok(call) => self.call(call, req)
+        };
+
+        match reply {
+            Some(reply) => {
+                Ok(Response::with((status::Ok, reply.to_string()))
+                    .content_type("application/json".parse().unwrap()))
+            },
+            None => Ok(Response::with(status::NoContent))
+        }
+    }
+
+    // Make one call, returning its reply, or `None` for a notification.
+    fn call(&self, call: Json, req: &mut Request) -> Option<Json> {
+        let mut call = match call {
+            Json::Object(call) => call,
+            _ => return Some(invalid_request(Json::Null))
+        };
+
+        let id = call.remove("id");
+        let valid_id = match id {
+            None | Some(Json::Null) | Some(Json::String(_)) | Some(Json::I64(_)) |
+            Some(Json::U64(_)) | Some(Json::F64(_)) => true,
+            _ => false
+        };
+        if !valid_id { return Some(invalid_request(Json::Null)) }
+
+        let version = call.get("jsonrpc").and_then(Json::as_string) == Some("2.0");
+        let name = match call.remove("method") {
+            Some(Json::String(ref name)) if version => name.clone(),
+            _ => return Some(invalid_request(id.unwrap_or(Json::Null)))
+        };
+        let params = match call.remove("params") {
+            None => Json::Null,
+            Some(params @ Json::Array(_)) | Some(params @ Json::Object(_)) => params,
+            Some(_) => return Some(invalid_request(id.unwrap_or(Json::Null)))
+        };
+
+        let result = match self.methods.get(&name) {
+            Some(method) => method(params, req),
+            None => Err(RpcError::new(METHOD_NOT_FOUND, "Method not found"))
+        };
+
+        id.map(|id| reply(id, result))
+    }
+}
+
+fn reply(id: Json, result: Result<Json, RpcError>) -> Json {
+    let mut reply = Object::new();
+    reply.insert("jsonrpc".into(), "2.0".to_json());
+    match result {
+        Ok(result) => reply.insert("result".into(), result),
+        Err(error) => reply.insert("error".into(), error.to_json())
+    };
+    reply.insert("id".into(), id);
+    Json::Object(reply)
+}
+
+fn invalid_request(id: Json) -> Json {
+    reply(id, Err(RpcError::new(INVALID_REQUEST, "Invalid Request")))
+}
+
+impl AroundMiddleware for JsonRpc {
+    fn around(self, handler: Box<Handler>) -> Box<Handler> {
+        Box::new(Endpoint { rpc: self, handler: handler })
+    }
+}
+
+struct Endpoint {
+    rpc: JsonRpc,
+    handler: Box<Handler>
+}
+
+impl Handler for Endpoint {
+    fn handle(&self, req: &mut Request) -> IronResult<Response> {
+        if req.url.path().iter().filter(|s| !s.is_empty()).eq(self.rpc.path.iter()) {
+            self.rpc.serve(req)
+        } else {
+            self.handler.handle(req)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+
+    use rustc_serialize::json::Json;
+
+    use method::Method;
+    use status;
+    use test::{MiddlewareHarness, StubRequest};
+    use {Chain, Request, Response};
+    use super::{JsonRpc, RpcError};
+
+    fn harness() -> MiddlewareHarness<Chain> {
+        let mut rpc = JsonRpc::new("/rpc");
+        rpc.method("subtract", |(a, b): (i64, i64), _: &mut Request| Ok(a - b));
+        rpc.method("sum", |terms: BTreeMap<String, i64>, _: &mut Request| {
+            Ok(terms.values().sum::<i64>())
+        });
+        rpc.method("fail", |_: (), _: &mut Request| -> Result<(), _> {
+            Err(RpcError::new(7, "Failed").data(Json::Boolean(true)))
+        });
+
+        let mut chain = Chain::new(|_: &mut Request| Ok(Response::with(status::NotFound)));
+        chain.link_around(rpc);
+        MiddlewareHarness::new(chain)
+    }
+
+    // Post `body` and return the status and parsed reply.
+    fn post(body: &str) -> (Option<status::Status>, Option<Json>) {
+        let req = StubRequest::new(Method::Post, "http://localhost/rpc").body(body);
+        let mut run = harness().handle(req);
+        let reply = String::from_utf8(run.take_body()).unwrap();
+        (run.status(), Json::from_str(&reply).ok())
+    }
+
+    fn json(text: &str) -> Option<Json> {
+        Some(Json::from_str(text).unwrap())
+    }
+
+    #[test]
+    fn test_calls() {
+        assert_eq!(post(r#"{"jsonrpc":"2.0","method":"subtract","params":[42,23],"id":1}"#),
+                   (Some(status::Ok), json(r#"{"jsonrpc":"2.0","result":19,"id":1}"#)));
+        assert_eq!(post(r#"{"jsonrpc":"2.0","method":"sum","params":{"a":1,"b":2},"id":"x"}"#),
+                   (Some(status::Ok), json(r#"{"jsonrpc":"2.0","result":3,"id":"x"}"#)));
+        assert_eq!(post(r#"{"jsonrpc":"2.0","method":"fail","id":2}"#).1,
+                   json(r#"{"jsonrpc":"2.0","error":{"code":7,"message":"Failed","data":true},
+                            "id":2}"#));
+        assert_eq!(post(r#"{"jsonrpc":"2.0","method":"subtract","params":[42,23]}"#),
+                   (Some(status::NoContent), None));
+    }
+
+    #[test]
+    fn test_errors() {
+        let code = |body: &str| {
+            post(body).1.and_then(|reply| reply.find_path(&["error", "code"]).cloned())
+        };
+
+        assert_eq!(code(r#"{"jsonrpc":"2.0","method""#), Some(Json::I64(-32700)));
+        assert_eq!(code(&"[".repeat(100_000)), Some(Json::I64(-32700)));
+        assert_eq!(code(&format!("{}{}", "[".repeat(200), "]".repeat(200))),
+                   Some(Json::I64(-32700)));
+        assert_eq!(code(r#"[]"#), Some(Json::I64(-32600)));
+        assert_eq!(code(r#"{"jsonrpc":"1.0","method":"sum","id":1}"#), Some(Json::I64(-32600)));
+        assert_eq!(code(r#"{"jsonrpc":"2.0","method":1,"params":"bar"}"#),
+                   Some(Json::I64(-32600)));
+        assert_eq!(code(r#"{"jsonrpc":"2.0","method":"nope","id":1}"#), Some(Json::I64(-32601)));
+        assert_eq!(code(r#"{"jsonrpc":"2.0","method":"subtract","params":["a"],"id":1}"#),
+                   Some(Json::I64(-32602)));
+    }
+
+    #[test]
+    fn test_batch() {
+        let (status, reply) = post(r#"[
+            {"jsonrpc":"2.0","method":"subtract","params":[1,1],"id":1},
+            {"jsonrpc":"2.0","method":"subtract","params":[1,1]},
+            1,
+            {"jsonrpc":"2.0","method":"nope","id":"2"}
+        ]"#);
+        assert_eq!(status, Some(status::Ok));
+
+        let ids = reply.unwrap().as_array().unwrap().iter()
+            .map(|reply| reply.find("id").unwrap().clone())
+            .collect::<Vec<_>>();
+        assert_eq!(ids, vec![Json::U64(1), Json::Null, Json::String("2".into())]);
+
+        assert_eq!(post(r#"[{"jsonrpc":"2.0","method":"sum","params":{}}]"#),
+                   (Some(status::NoContent), None));
+    }
+
+    #[test]
+    fn test_other_requests() {
+        let run = harness().handle(StubRequest::new(Method::Get, "http://localhost/rpc"));
+        assert_eq!(run.status(), Some(status::MethodNotAllowed));
+
+        let run = harness().handle(StubRequest::new(Method::Post, "http://localhost/other"));
+        assert_eq!(run.status(), Some(status::NotFound));
+    }
+}
@@ -67,9 +67,18 @@ extern crate hyper;
 extern crate typemap as tmap;
 extern crate plugin;
 extern crate error as err;
-extern crate url;
+extern crate url as rust_url;
 extern crate num_cpus;
 extern crate conduit_mime_types as mime_types;
+extern crate rustc_serialize;
+extern crate regex;
+extern crate flate2;
+extern crate unicase;
+extern crate md5;
+extern crate sha2;
+extern crate toml;
+#[cfg(feature = "brotli")]
+extern crate brotli;
 #[macro_use]
 extern crate lazy_static;
 
@@ -162,7 +171,134 @@ pub mod request;
 // Request and Response Modifiers
 pub mod modifiers;
 
+// Validation of request parameters
+pub mod validate;
+
+// Pagination of list responses
+pub mod pagination;
+
+// Content negotiation
+pub mod negotiation;
+
+// Temporary files created while handling requests
+pub mod uploads;
+
+// Response body compression
+pub mod compression;
+
+// Conditional requests
+pub mod conditional;
+
+// Validating content types
+pub mod content_type;
+
+// Body checksums
+pub mod checksum;
+
+// Shared key-value caches
+pub mod cache;
+
+// Whole-response caching
+pub mod http_cache;
+
+// External cache backends
+pub mod store;
+
+// Connection pooling
+pub mod pool;
+
+// Background jobs
+pub mod jobs;
+
+// Configuration files
+pub mod config;
+
+// Replaceable time sources
+pub mod clock;
+
+// Testing middleware without a server
+pub mod test;
+
+// Per-request tracing spans
+pub mod tracing;
+
+// Server statistics
+pub mod stats;
+
+// Sampling request profiler
+pub mod profiler;
+
+// Counting allocations per request
+#[cfg(feature = "count-allocations")]
+pub mod allocations;
+
+// Percent-decoding and splitting of URLs
+pub mod url;
+
+// Parsing requests without a connection
+pub mod parse;
+
+// XML request bodies
+pub mod xml;
+
+// Parsing JSON of bounded depth
+pub mod json_depth;
+
+// JSON-RPC endpoints
+pub mod jsonrpc;
+
+// Single JSON endpoints for query languages
+pub mod endpoint;
+
+// Favicon and robots.txt shortcuts
+pub mod shortcuts;
+
+// Handlers taking typed arguments
+pub mod extract;
+
+// Outbound HTTP requests
+pub mod client;
+
+// Signed webhook deliveries
+pub mod webhooks;
+
+// OAuth 2 bearer token validation
+pub mod oauth2;
+
+// Verifying signed requests
+pub mod signing;
+
+// Signed and encrypted cookies
+pub mod cookies;
+
+// Limits on simultaneous requests
+pub mod concurrency;
+
+// Circuit breakers for downstream calls
+pub mod breaker;
+
+// Budgets for downstream calls
+pub mod budget;
+
+// Resolving tenants
+pub mod tenancy;
+
+// Feature flags
+pub mod flags;
+
+// A/B experiments
+pub mod experiments;
+
+// Describing a server's setup
+pub mod cli;
+
 // Helper macros for error handling
 mod macros;
 
+// Temporary files for request bodies
+mod temp;
+
+// Periodic tasks run by the server
+mod schedule;
+
 mod iron;
@@ -0,0 +1,455 @@
+//! Combinators for gluing middleware together without defining new types.
+//!
+//! Each combinator is itself a middleware and can be linked into a `Chain`
+//! like any other. They are usually created through the provided methods on
+//! `BeforeMiddleware` and `AfterMiddleware`, but the structs can also be
+//! constructed directly, which is necessary when a type implements both
+//! traits and the method call would be ambiguous.
+
+use std::marker::PhantomData;
+use std::mem;
+use std::sync::Arc;
+
+use typemap::Key;
+
+use {Request, Response, IronResult, IronError, Url};
+use method::Method;
+use super::{BeforeMiddleware, AfterMiddleware, AroundMiddleware, Handler, skips_error_flow};
+
+/// Runs the first middleware and then the second, exactly as if they had
+/// been linked into a `Chain` one after another.
+///
+/// An error raised by the first middleware is passed to the `catch` method
+/// of the second, and a recovery by the first resumes the normal flow at the
+/// second. An `Abort` or `Redispatch` is returned unchanged, since it skips
+/// the error flow of a `Chain`.
+pub struct AndThen<A, B>(pub A, pub B);
+
+/// Runs the first middleware, using the `catch` method of the second to
+/// recover from any error the first raises, other than an `Abort` or a
+/// `Redispatch`.
+///
+/// The second middleware is never invoked in the normal flow.
+pub struct OrElse<A, B>(pub A, pub B);
+
+/// Runs an `AfterMiddleware` and then applies a function to the `Response`
+/// it produced.
+///
+/// The function is also applied when the middleware recovers from an error.
+pub struct MapResponse<A, F>(pub A, pub F);
+
+/// Runs a middleware only for requests with one of the listed methods.
+///
+/// For other requests the middleware is skipped entirely: a
+/// `BeforeMiddleware` passes the request and any error through untouched,
+/// an `AfterMiddleware` passes the response or error through, and an
+/// `AroundMiddleware` sends the request straight to the handler it wraps.
+///
+/// ```ignore
+/// chain.link_before(OnMethod(vec![Method::Post, Method::Put], BodyParser));
+/// chain.link_around(OnMethod(vec![Method::Get, Method::Head], HttpCache::new(cache)));
+/// ```
+pub struct OnMethod<M>(pub Vec<Method>, pub M);
+
+impl<M> OnMethod<M> {
+    fn applies(&self, req: &Request) -> bool {
+        self.0.contains(&req.method)
+    }
+}
+
+/// Runs a middleware only for requests whose extensions hold a value for
+/// the key `K`, typically inserted by an earlier middleware.
+///
+/// Other requests skip the middleware in the same way as with `OnMethod`.
+/// The key is checked each time the middleware would be called, so a
+/// wrapped `AfterMiddleware` also runs for values inserted by the handler.
+///
+/// ```ignore
+/// chain.link_before(Authenticate);
+/// chain.link_before(WhenPresent::<AuthenticatedUser, _>::new(Authorize));
+/// ```
+pub struct WhenPresent<K, M> {
+    middleware: M,
+    key: PhantomData<fn() -> K>
+}
+
+impl<K: Key, M> WhenPresent<K, M> {
+    /// Run `middleware` for requests with a value for `K`.
+    pub fn new(middleware: M) -> WhenPresent<K, M> {
+        WhenPresent { middleware: middleware, key: PhantomData }
+    }
+}
+
+/// Runs a middleware only for requests whose path starts with a prefix.
+///
+/// The prefix is matched a whole path segment at a time, so `/api` matches
+/// `/api` and `/api/users` but not `/apis`. Other requests skip the
+/// middleware in the same way as with `OnMethod`.
+///
+/// With `strip_prefix`, the wrapped middleware sees the request URL with
+/// the prefix removed, and the original URL is put back once it returns.
+/// A wrapped `AroundMiddleware` sees the stripped URL, but the handler it
+/// wraps sees the original one.
+///
+/// ```ignore
+/// chain.link_before(OnPrefix::new("/api", RequireToken));
+/// chain.link_around(OnPrefix::new("/static", StaticFiles::new("public")).strip_prefix());
+/// ```
+pub struct OnPrefix<M> {
+    prefix: Vec<String>,
+    strip: bool,
+    middleware: M
+}
+
+impl<M> OnPrefix<M> {
+    /// Run `middleware` for requests under `prefix`.
+    pub fn new(prefix: &str, middleware: M) -> OnPrefix<M> {
+        OnPrefix {
+            prefix: prefix.split('/').filter(|s| !s.is_empty()).map(String::from).collect(),
+            strip: false,
+            middleware: middleware
+        }
+    }
+
+    /// Remove the prefix from the URL seen by the middleware.
+    pub fn strip_prefix(mut self) -> OnPrefix<M> {
+        self.strip = true;
+        self
+    }
+}
+
+// Whether `prefix` applies to `req`, and if so the URL the wrapped
+// middleware should see, when it differs from the request's.
+fn match_prefix(prefix: &[String], strip: bool, req: &Request) -> Option<Option<Url>> {
+    let path = req.url.path();
+    if path.len() < prefix.len() || prefix.iter().zip(&path).any(|(p, s)| p != s) {
+        return None
+    }
+    if !strip { return Some(None) }
+
+    let mut url = req.url.clone().into_generic_url();
+    url.set_path(&format!("/{}", path[prefix.len()..].join("/")));
+    Some(Url::from_generic_url(url).ok())
+}
+
+// Call `f` with the request URL replaced by `url`, if any, restoring the
+// original URL afterwards.
+fn with_url<T, F>(req: &mut Request, url: Option<Url>, f: F) -> T
+where F: FnOnce(&mut Request) -> T {
+    match url {
+        Some(url) => {
+            let original = mem::replace(&mut req.url, url);
+            let result = f(req);
+            req.url = original;
+            result
+        },
+        None => f(req)
+    }
+}
+
+impl<A, B> BeforeMiddleware for AndThen<A, B>
+where A: BeforeMiddleware, B: BeforeMiddleware {
+    fn before(&self, req: &mut Request) -> IronResult<()> {
+        match self.0.before(req) {
+            Ok(()) => self.1.before(req),
+            Err(err) if skips_error_flow(&err) => Err(err),
+            Err(err) => self.1.catch(req, err)
+        }
+    }
+
+    fn catch(&self, req: &mut Request, err: IronError) -> IronResult<()> {
+        match self.0.catch(req, err) {
+            Ok(()) => self.1.before(req),
+            Err(err) if skips_error_flow(&err) => Err(err),
+            Err(err) => self.1.catch(req, err)
+        }
+    }
+}
+
+impl<A, B> AfterMiddleware for AndThen<A, B>
+where A: AfterMiddleware, B: AfterMiddleware {
+    fn after(&self, req: &mut Request, res: Response) -> IronResult<Response> {
+        match self.0.after(req, res) {
+            Ok(res) => self.1.after(req, res),
+            Err(err) if skips_error_flow(&err) => Err(err),
+            Err(err) => self.1.catch(req, err)
+        }
+    }
+
+    fn catch(&self, req: &mut Request, err: IronError) -> IronResult<Response> {
+        match self.0.catch(req, err) {
+            Ok(res) => self.1.after(req, res),
+            Err(err) if skips_error_flow(&err) => Err(err),
+            Err(err) => self.1.catch(req, err)
+        }
+    }
+}
+
+impl<A, B> BeforeMiddleware for OrElse<A, B>
+where A: BeforeMiddleware, B: BeforeMiddleware {
+    fn before(&self, req: &mut Request) -> IronResult<()> {
+        match self.0.before(req) {
+            Ok(()) => Ok(()),
+            Err(err) if skips_error_flow(&err) => Err(err),
+            Err(err) => self.1.catch(req, err)
+        }
+    }
+
+    fn catch(&self, req: &mut Request, err: IronError) -> IronResult<()> {
+        match self.0.catch(req, err) {
+            Ok(()) => Ok(()),
+            Err(err) if skips_error_flow(&err) => Err(err),
+            Err(err) => self.1.catch(req, err)
+        }
+    }
+}
+
+impl<A, B> AfterMiddleware for OrElse<A, B>
+where A: AfterMiddleware, B: AfterMiddleware {
+    fn after(&self, req: &mut Request, res: Response) -> IronResult<Response> {
+        match self.0.after(req, res) {
+            Ok(res) => Ok(res),
+            Err(err) if skips_error_flow(&err) => Err(err),
+            Err(err) => self.1.catch(req, err)
+        }
+    }
+
+    fn catch(&self, req: &mut Request, err: IronError) -> IronResult<Response> {
+        match self.0.catch(req, err) {
+            Ok(res) => Ok(res),
+            Err(err) if skips_error_flow(&err) => Err(err),
+            Err(err) => self.1.catch(req, err)
+        }
+    }
+}
+
+impl<A, F> AfterMiddleware for MapResponse<A, F>
+where A: AfterMiddleware, F: Send + Sync + 'static + Fn(Response) -> Response {
+    fn after(&self, req: &mut Request, res: Response) -> IronResult<Response> {
+        self.0.after(req, res).map(&self.1)
+    }
+
+    fn catch(&self, req: &mut Request, err: IronError) -> IronResult<Response> {
+        self.0.catch(req, err).map(&self.1)
+    }
+}
+
+impl<M> BeforeMiddleware for OnMethod<M> where M: BeforeMiddleware {
+    fn before(&self, req: &mut Request) -> IronResult<()> {
+        if self.applies(req) { self.1.before(req) } else { Ok(()) }
+    }
+
+    fn catch(&self, req: &mut Request, err: IronError) -> IronResult<()> {
+        if self.applies(req) && !skips_error_flow(&err) {
+            self.1.catch(req, err)
+        } else {
+            Err(err)
+        }
+    }
+}
+
+impl<M> AfterMiddleware for OnMethod<M> where M: AfterMiddleware {
+    fn after(&self, req: &mut Request, res: Response) -> IronResult<Response> {
+        if self.applies(req) { self.1.after(req, res) } else { Ok(res) }
+    }
+
+    fn catch(&self, req: &mut Request, err: IronError) -> IronResult<Response> {
+        if self.applies(req) && !skips_error_flow(&err) {
+            self.1.catch(req, err)
+        } else {
+            Err(err)
+        }
+    }
+}
+
+impl<M> AroundMiddleware for OnMethod<M> where M: AroundMiddleware {
+    fn around(self, handler: Box<Handler>) -> Box<Handler> {
+        let handler = Shared(Arc::new(handler));
+        let wrapped = self.1.around(Box::new(handler.clone()));
+
+        Box::new(Choose { methods: self.0, wrapped: wrapped, plain: handler })
+    }
+}
+
+// A handler shared between the two branches of an `OnMethod` around.
+#[derive(Clone)]
+struct Shared(Arc<Box<Handler>>);
+
+impl Handler for Shared {
+    fn handle(&self, req: &mut Request) -> IronResult<Response> {
+        self.0.handle(req)
+    }
+}
+
+struct Choose {
+    methods: Vec<Method>,
+    wrapped: Box<Handler>,
+    plain: Shared
+}
+
+impl Handler for Choose {
+    fn handle(&self, req: &mut Request) -> IronResult<Response> {
+        if self.methods.contains(&req.method) {
+            self.wrapped.handle(req)
+        } else {
+            self.plain.handle(req)
+        }
+    }
+}
+
+impl<M> BeforeMiddleware for OnPrefix<M> where M: BeforeMiddleware {
+    fn before(&self, req: &mut Request) -> IronResult<()> {
+        match match_prefix(&self.prefix, self.strip, req) {
+            Some(url) => with_url(req, url, |req| self.middleware.before(req)),
+            None => Ok(())
+        }
+    }
+
+    fn catch(&self, req: &mut Request, err: IronError) -> IronResult<()> {
+        match match_prefix(&self.prefix, self.strip, req) {
+            Some(url) if !skips_error_flow(&err) => {
+                with_url(req, url, |req| self.middleware.catch(req, err))
+            },
+            _ => Err(err)
+        }
+    }
+}
+
+impl<M> AfterMiddleware for OnPrefix<M> where M: AfterMiddleware {
+    fn after(&self, req: &mut Request, res: Response) -> IronResult<Response> {
+        match match_prefix(&self.prefix, self.strip, req) {
+            Some(url) => with_url(req, url, |req| self.middleware.after(req, res)),
+            None => Ok(res)
+        }
+    }
+
+    fn catch(&self, req: &mut Request, err: IronError) -> IronResult<Response> {
+        match match_prefix(&self.prefix, self.strip, req) {
+            Some(url) if !skips_error_flow(&err) => {
+                with_url(req, url, |req| self.middleware.catch(req, err))
+            },
+            _ => Err(err)
+        }
+    }
+}
+
+impl<M> AroundMiddleware for OnPrefix<M> where M: AroundMiddleware {
+    fn around(self, handler: Box<Handler>) -> Box<Handler> {
+        let handler = Shared(Arc::new(handler));
+        let inner: Box<Handler> = if self.strip {
+            Box::new(Unstrip(handler.clone()))
+        } else {
+            Box::new(handler.clone())
+        };
+        let wrapped = self.middleware.around(inner);
+
+        Box::new(ChoosePrefix {
+            prefix: self.prefix,
+            strip: self.strip,
+            wrapped: wrapped,
+            plain: handler
+        })
+    }
+}
+
+struct ChoosePrefix {
+    prefix: Vec<String>,
+    strip: bool,
+    wrapped: Box<Handler>,
+    plain: Shared
+}
+
+// The URLs replaced by enclosing `OnPrefix` arounds, innermost last.
+struct Stripped;
+
+impl Key for Stripped { type Value = Vec<Url>; }
+
+impl Handler for ChoosePrefix {
+    fn handle(&self, req: &mut Request) -> IronResult<Response> {
+        match match_prefix(&self.prefix, self.strip, req) {
+            Some(Some(url)) => {
+                let original = mem::replace(&mut req.url, url);
+                req.extensions.entry::<Stripped>().or_insert_with(Vec::new).push(original);
+                let result = self.wrapped.handle(req);
+                let original = req.extensions.get_mut::<Stripped>().and_then(|s| s.pop());
+                if let Some(original) = original { req.url = original }
+                result
+            },
+            Some(None) => self.wrapped.handle(req),
+            None => self.plain.handle(req)
+        }
+    }
+}
+
+// Gives the handler wrapped by a stripping `OnPrefix` around the original
+// URL back.
+struct Unstrip(Shared);
+
+impl Handler for Unstrip {
+    fn handle(&self, req: &mut Request) -> IronResult<Response> {
+        let original = req.extensions.get_mut::<Stripped>().and_then(|s| s.pop());
+        match original {
+            Some(original) => {
+                let stripped = mem::replace(&mut req.url, original);
+                let result = self.0.handle(req);
+                let original = mem::replace(&mut req.url, stripped);
+                req.extensions.entry::<Stripped>().or_insert_with(Vec::new).push(original);
+                result
+            },
+            None => self.0.handle(req)
+        }
+    }
+}
+
+impl<K: Key, M> BeforeMiddleware for WhenPresent<K, M> where M: BeforeMiddleware {
+    fn before(&self, req: &mut Request) -> IronResult<()> {
+        if req.extensions.contains::<K>() { self.middleware.before(req) } else { Ok(()) }
+    }
+
+    fn catch(&self, req: &mut Request, err: IronError) -> IronResult<()> {
+        if req.extensions.contains::<K>() && !skips_error_flow(&err) {
+            self.middleware.catch(req, err)
+        } else {
+            Err(err)
+        }
+    }
+}
+
+impl<K: Key, M> AfterMiddleware for WhenPresent<K, M> where M: AfterMiddleware {
+    fn after(&self, req: &mut Request, res: Response) -> IronResult<Response> {
+        if req.extensions.contains::<K>() { self.middleware.after(req, res) } else { Ok(res) }
+    }
+
+    fn catch(&self, req: &mut Request, err: IronError) -> IronResult<Response> {
+        if req.extensions.contains::<K>() && !skips_error_flow(&err) {
+            self.middleware.catch(req, err)
+        } else {
+            Err(err)
+        }
+    }
+}
+
+impl<K: Key, M> AroundMiddleware for WhenPresent<K, M> where M: AroundMiddleware {
+    fn around(self, handler: Box<Handler>) -> Box<Handler> {
+        let handler = Shared(Arc::new(handler));
+        let wrapped = self.middleware.around(Box::new(handler.clone()));
+
+        Box::new(ChoosePresent::<K> { wrapped: wrapped, plain: handler, key: PhantomData })
+    }
+}
+
+struct ChoosePresent<K> {
+    wrapped: Box<Handler>,
+    plain: Shared,
+    key: PhantomData<fn() -> K>
+}
+
+impl<K: Key> Handler for ChoosePresent<K> {
+    fn handle(&self, req: &mut Request) -> IronResult<Response> {
+        if req.extensions.contains::<K>() {
+            self.wrapped.handle(req)
+        } else {
+            self.plain.handle(req)
+        }
+    }
+}
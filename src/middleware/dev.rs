@@ -0,0 +1,183 @@
+//! Diagnostic pages for panics, for use during development.
+
+use std::error::Error;
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use typemap::Key;
+
+use {Request, Response, IronResult, IronError, Set, status};
+use mime::Mime;
+use super::{AroundMiddleware, Handler};
+use super::trace::Trace;
+
+/// `AroundMiddleware` which catches panics in the handler it wraps and
+/// answers with a `500 Internal Server Error` page showing the panic
+/// message, the `Trace` recorded so far and the details of the request.
+///
+/// Errors with a server error status and no body get the same page, showing
+/// the error and the contexts added to it with `IronError::context`.
+///
+/// The page reveals the internals of the application, so it is only shown
+/// when enabled, typically from a debug flag; otherwise the middleware does
+/// nothing and panics propagate as usual:
+///
+/// ```ignore
+/// let mut chain = Chain::new(handler);
+/// chain.trace(false);
+/// chain.link_around(DevErrorPage::new(cfg!(debug_assertions)));
+/// ```
+///
+/// The error passed on to `AfterMiddleware::catch` for a panic is a
+/// `Panicked`.
+pub struct DevErrorPage {
+    enabled: bool
+}
+
+impl DevErrorPage {
+    /// Create the middleware, showing diagnostic pages if `enabled`.
+    pub fn new(enabled: bool) -> DevErrorPage {
+        DevErrorPage { enabled: enabled }
+    }
+}
+
+impl AroundMiddleware for DevErrorPage {
+    fn around(self, handler: Box<Handler>) -> Box<Handler> {
+        if !self.enabled { return handler }
+        Box::new(CatchPanics(handler))
+    }
+}
+
+/// The error for a request whose handler panicked, holding the panic's
+/// message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Panicked(pub String);
+
+impl fmt::Display for Panicked {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Handler panicked: {}", self.0)
+    }
+}
+
+impl Error for Panicked {
+    fn description(&self) -> &str { "Handler panicked" }
+}
+
+/// A flag in the extensions of a request, raised when handling the request
+/// panicked and the panic was caught, by `DevErrorPage` or by the server's
+/// `internal_error` handler.
+///
+/// Resources checked out for a request, such as a `Pool`'s connections, hold
+/// on to the flag so that they can tell, once the request is gone, that
+/// they may have been left in an inconsistent state.
+#[derive(Debug, Clone, Default)]
+pub struct PanicFlag(Arc<AtomicBool>);
+
+impl Key for PanicFlag { type Value = PanicFlag; }
+
+impl PanicFlag {
+    /// The flag of `req`, added to its extensions if it has none yet.
+    pub fn of(req: &mut Request) -> PanicFlag {
+        req.extensions.entry::<PanicFlag>().or_insert_with(PanicFlag::default).clone()
+    }
+
+    /// Record that handling `req` panicked.
+    pub fn raise(req: &mut Request) {
+        PanicFlag::of(req).0.store(true, Ordering::SeqCst)
+    }
+
+    /// Whether handling the request panicked.
+    pub fn is_raised(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+struct CatchPanics(Box<Handler>);
+
+impl Handler for CatchPanics {
+    fn handle(&self, req: &mut Request) -> IronResult<Response> {
+        let payload = match panic::catch_unwind(AssertUnwindSafe(|| self.0.handle(req))) {
+            Ok(Err(mut err)) => {
+                if err.status().is_server_error() && err.response.body.is_none() {
+                    let page = render("Handler failed", &err.error.to_string(), &err.contexts(),
+                                      req);
+                    err.response.set_mut(html()).set_mut(page);
+                }
+                return Err(err)
+            },
+            Ok(result) => return result,
+            Err(payload) => payload
+        };
+
+        PanicFlag::raise(req);
+        let message = payload.downcast_ref::<&str>().map(|s| s.to_string())
+            .or_else(|| payload.downcast_ref::<String>().cloned())
+            .unwrap_or_else(|| "(no message)".to_owned());
+
+        let page = render("Handler panicked", &message, &[], req);
+        Err(IronError::new(Panicked(message), (status::InternalServerError, html(), page)))
+    }
+}
+
+fn html() -> Mime {
+    "text/html; charset=utf-8".parse().unwrap()
+}
+
+fn render(heading: &str, message: &str, contexts: &[&str], req: &Request) -> String {
+    let mut page = String::new();
+    page.push_str("<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\">");
+    page.push_str(&format!("<title>{}: {}</title></head><body>\n", heading, escape(message)));
+    page.push_str(&format!("<h1>{}</h1>\n<pre>{}</pre>\n", heading, escape(message)));
+
+    if !contexts.is_empty() {
+        page.push_str("<h2>Context</h2>\n<ol>\n");
+        for context in contexts {
+            page.push_str(&format!("<li>{}</li>\n", escape(context)));
+        }
+        page.push_str("</ol>\n");
+    }
+
+    page.push_str("<h2>Middleware trace</h2>\n");
+    let steps = Trace::of(req);
+    if steps.is_empty() {
+        page.push_str("<p>No steps recorded; enable tracing with <code>Chain::trace</code>.");
+        page.push_str("</p>\n");
+    } else {
+        page.push_str("<ol>\n");
+        for step in steps {
+            page.push_str(&format!("<li><code>{}</code></li>\n", escape(&step.to_string())));
+        }
+        page.push_str("</ol>\n");
+    }
+
+    page.push_str("<h2>Request</h2>\n<table>\n");
+    let mut row = |name: &str, value: &str| {
+        page.push_str(&format!("<tr><th>{}</th><td><code>{}</code></td></tr>\n",
+                               escape(name), escape(value)));
+    };
+    row("Method", &req.method.to_string());
+    row("URL", &req.url.to_string());
+    row("Remote address", &req.remote_addr.to_string());
+    for header in req.headers.iter() {
+        row(header.name(), &header.value_string());
+    }
+    page.push_str("</table>\n</body></html>\n");
+    page
+}
+
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c)
+        }
+    }
+    escaped
+}
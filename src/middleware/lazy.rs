@@ -0,0 +1,83 @@
+//! Deferred construction of expensive middleware.
+
+use std::sync::{Arc, Mutex, RwLock};
+
+use {Request, Response, IronResult, IronError};
+use super::{BeforeMiddleware, AfterMiddleware, Handler};
+
+/// A middleware or `Handler` which is only constructed when the first
+/// request reaches it.
+///
+/// Useful for middleware with an expensive setup step, such as compiling
+/// templates or building a large lookup table, in programs which may build
+/// a `Chain` without ever serving a request through it.
+///
+/// Construction happens exactly once, even if several threads reach the
+/// wrapper at the same time; the other threads block until it finishes.
+///
+/// ## Panics
+///
+/// If the constructor panics, every later request reaching this wrapper
+/// panics as well.
+pub struct Lazy<M> {
+    init: Mutex<Option<Box<FnOnce() -> M + Send>>>,
+    value: RwLock<Option<Arc<M>>>
+}
+
+impl<M> Lazy<M> {
+    /// Create a new `Lazy` which calls `init` to build the wrapped value
+    /// on first use.
+    pub fn new<F>(init: F) -> Lazy<M> where F: FnOnce() -> M + Send + 'static {
+        Lazy {
+            init: Mutex::new(Some(Box::new(init))),
+            value: RwLock::new(None)
+        }
+    }
+
+    /// Whether the wrapped value has been constructed yet.
+    pub fn is_initialized(&self) -> bool {
+        self.value.read().unwrap().is_some()
+    }
+
+    fn get(&self) -> Arc<M> {
+        if let Some(ref value) = *self.value.read().unwrap() {
+            return value.clone();
+        }
+
+        let mut value = self.value.write().unwrap();
+
+        // Another thread may have won the race for the write lock.
+        if value.is_none() {
+            let init = self.init.lock().unwrap().take().unwrap();
+            *value = Some(Arc::new(init()));
+        }
+
+        value.as_ref().unwrap().clone()
+    }
+}
+
+impl<M> BeforeMiddleware for Lazy<M> where M: BeforeMiddleware {
+    fn before(&self, req: &mut Request) -> IronResult<()> {
+        self.get().before(req)
+    }
+
+    fn catch(&self, req: &mut Request, err: IronError) -> IronResult<()> {
+        self.get().catch(req, err)
+    }
+}
+
+impl<M> AfterMiddleware for Lazy<M> where M: AfterMiddleware {
+    fn after(&self, req: &mut Request, res: Response) -> IronResult<Response> {
+        self.get().after(req, res)
+    }
+
+    fn catch(&self, req: &mut Request, err: IronError) -> IronResult<Response> {
+        self.get().catch(req, err)
+    }
+}
+
+impl<M> Handler for Lazy<M> where M: Handler {
+    fn handle(&self, req: &mut Request) -> IronResult<Response> {
+        self.get().handle(req)
+    }
+}
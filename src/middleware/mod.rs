@@ -50,9 +50,36 @@
 //! be run during both the normal and error flow by implementing the `catch` method to
 //! also do the necessary action.
 //!
+//! There are two exceptions to the error flow. A `Chain` which sees a `Redispatch`
+//! skips the remaining middleware entirely and starts over from its first
+//! `BeforeMiddleware` with the rewritten URL, and one which sees an `Abort` skips the
+//! remaining middleware and returns the error at once.
+//!
+//! To find out which middleware handled or raised an error, enable tracing on
+//! a `Chain` with `Chain::trace`. Every middleware call is then recorded in
+//! the request's `Trace`, along with its result and how long it took.
+//!
 
+use std::error::Error as StdError;
+use std::fmt;
 use std::sync::Arc;
-use {Request, Response, IronResult, IronError};
+use std::any::type_name;
+use std::time::Instant;
+use {Request, Response, IronResult, IronError, Url};
+use modifier::Modifier;
+use status;
+
+pub use self::combinators::{AndThen, OrElse, MapResponse, OnMethod, OnPrefix, WhenPresent};
+pub use self::dev::{DevErrorPage, PanicFlag, Panicked};
+pub use self::lazy::Lazy;
+pub use self::trace::{Trace, Step, Phase};
+
+use self::trace::Outcome;
+
+mod combinators;
+mod dev;
+mod lazy;
+mod trace;
 
 /// `Handler`s are responsible for handling requests by creating Responses from Requests.
 pub trait Handler: Send + Sync + 'static {
@@ -80,6 +107,20 @@ pub trait BeforeMiddleware: Send + Sync + 'static {
     /// next `BeforeMiddleware`, or if this was the last `BeforeMiddleware`,
     /// at the `Handler`.
     fn catch(&self, _: &mut Request, err: IronError) -> IronResult<()> { Err(err) }
+
+    /// Run this middleware and then `other`, as if both had been linked into
+    /// a `Chain` in that order.
+    fn and_then<B>(self, other: B) -> AndThen<Self, B>
+    where Self: Sized, B: BeforeMiddleware {
+        AndThen(self, other)
+    }
+
+    /// Run this middleware, letting the `catch` method of `other` recover from
+    /// any error it raises.
+    fn or_else<B>(self, other: B) -> OrElse<Self, B>
+    where Self: Sized, B: BeforeMiddleware {
+        OrElse(self, other)
+    }
 }
 
 /// `AfterMiddleware` are fired after a `Handler` is called inside of a Chain.
@@ -105,6 +146,26 @@ pub trait AfterMiddleware: Send + Sync + 'static {
     fn catch(&self, _: &mut Request, err: IronError) -> IronResult<Response> {
         Err(err)
     }
+
+    /// Run this middleware and then `other`, as if both had been linked into
+    /// a `Chain` in that order.
+    fn and_then<A>(self, other: A) -> AndThen<Self, A>
+    where Self: Sized, A: AfterMiddleware {
+        AndThen(self, other)
+    }
+
+    /// Run this middleware, letting the `catch` method of `other` recover from
+    /// any error it raises.
+    fn or_else<A>(self, other: A) -> OrElse<Self, A>
+    where Self: Sized, A: AfterMiddleware {
+        OrElse(self, other)
+    }
+
+    /// Apply `f` to every `Response` this middleware produces.
+    fn map_response<F>(self, f: F) -> MapResponse<Self, F>
+    where Self: Sized, F: Send + Sync + 'static + Fn(Response) -> Response {
+        MapResponse(self, f)
+    }
 }
 
 /// AroundMiddleware are used to wrap and replace the `Handler` in a `Chain`.
@@ -133,19 +194,45 @@ pub struct Chain {
     afters: Vec<Box<AfterMiddleware>>,
 
     // Internal invariant: this is always Some
-    handler: Option<Box<Handler>>
+    handler: Option<Box<Handler>>,
+
+    // Type names for traces, parallel to the middleware above.
+    before_names: Vec<&'static str>,
+    after_names: Vec<&'static str>,
+    handler_name: &'static str,
+    around_names: Vec<&'static str>,
+
+    tracing: Option<TraceMode>
 }
 
+#[derive(Clone, Copy, PartialEq)]
+enum TraceMode { Record, Log }
+
 impl Chain {
     /// Construct a new ChainBuilder from a `Handler`.
     pub fn new<H: Handler>(handler: H) -> Chain {
         Chain {
             befores: vec![],
             afters: vec![],
-            handler: Some(Box::new(handler) as Box<Handler>)
+            handler: Some(Box::new(handler) as Box<Handler>),
+            before_names: vec![],
+            after_names: vec![],
+            handler_name: type_name::<H>(),
+            around_names: vec![],
+            tracing: None
         }
     }
 
+    /// Record every middleware call made by this `Chain` in the request's
+    /// `Trace`.
+    ///
+    /// If `log_server_errors` is set, the trace is also logged whenever the
+    /// `Chain` produces a response or error with a 5XX status.
+    pub fn trace(&mut self, log_server_errors: bool) -> &mut Chain {
+        self.tracing = Some(if log_server_errors { TraceMode::Log } else { TraceMode::Record });
+        self
+    }
+
     /// Link both a before and after middleware to the chain at once.
     ///
     /// Middleware that have a Before and After piece should have a constructor
@@ -153,9 +240,7 @@ impl Chain {
     pub fn link<B, A>(&mut self, link: (B, A)) -> &mut Chain
     where A: AfterMiddleware, B: BeforeMiddleware {
         let (before, after) = link;
-        self.befores.push(Box::new(before) as Box<BeforeMiddleware>);
-        self.afters.push(Box::new(after) as Box<AfterMiddleware>);
-        self
+        self.link_before(before).link_after(after)
     }
 
     /// Link a `BeforeMiddleware` to the `Chain`, after all previously linked
@@ -163,6 +248,7 @@ impl Chain {
     pub fn link_before<B>(&mut self, before: B) -> &mut Chain
     where B: BeforeMiddleware {
         self.befores.push(Box::new(before) as Box<BeforeMiddleware>);
+        self.before_names.push(type_name::<B>());
         self
     }
 
@@ -171,6 +257,7 @@ impl Chain {
     pub fn link_after<A>(&mut self, after: A) -> &mut Chain
     where A: AfterMiddleware {
         self.afters.push(Box::new(after) as Box<AfterMiddleware>);
+        self.after_names.push(type_name::<A>());
         self
     }
 
@@ -189,21 +276,182 @@ impl Chain {
         let mut handler = self.handler.take().unwrap();
         handler = around.around(handler);
         self.handler = Some(handler);
+        self.around_names.push(type_name::<A>());
         self
     }
+
+    /// The type names of the `BeforeMiddleware` in this `Chain`, in order.
+    pub fn before_names(&self) -> &[&'static str] {
+        &self.before_names
+    }
+
+    /// The type names of the `AfterMiddleware` in this `Chain`, in order.
+    pub fn after_names(&self) -> &[&'static str] {
+        &self.after_names
+    }
+
+    /// The type name of the `Handler` this `Chain` was created with.
+    pub fn handler_name(&self) -> &'static str {
+        self.handler_name
+    }
+
+    /// The type names of the `AroundMiddleware` applied to the `Handler`,
+    /// innermost first.
+    pub fn around_names(&self) -> &[&'static str] {
+        &self.around_names
+    }
 }
 
 impl Handler for Chain {
     fn handle(&self, req: &mut Request) -> IronResult<Response> {
-        // Kick off at befores, which will continue into handler
-        // then afters.
-        self.continue_from_before(req, 0)
+        let result = self.dispatch(req);
+
+        if self.tracing == Some(TraceMode::Log) {
+            if let Some(status) = result.status() {
+                if status.is_server_error() {
+                    warn!("{} for {} {}, trace:\n{}", status, req.method, req.url,
+                          trace::format(Trace::of(req)));
+                }
+            }
+        }
+
+        result
     }
 }
 
+impl Chain {
+    fn dispatch(&self, req: &mut Request) -> IronResult<Response> {
+        let mut redispatches = 0;
+
+        loop {
+            // Kick off at befores, which will continue into handler
+            // then afters.
+            match self.continue_from_before(req, 0) {
+                Err(ref err) if is_redispatch(err) && redispatches < MAX_REDISPATCHES => {
+                    redispatches += 1;
+                    debug!("Redispatching request to {}", req.url);
+                },
+                Err(err) => {
+                    if is_redispatch(&err) {
+                        error!("Gave up after {} redispatches of {}", redispatches, req.url);
+                    }
+                    return Err(err)
+                },
+                res => return res
+            }
+        }
+    }
+}
+
+/// The number of times a single `Chain` will restart a request because of a
+/// `Redispatch` before giving up.
+pub const MAX_REDISPATCHES: usize = 10;
+
+/// An error which asks the enclosing `Chain` to start handling the request
+/// again from its first `BeforeMiddleware`.
+///
+/// This is an internal redirect: the client never sees it. It is usually
+/// produced with `Redispatch::to`, after which the `Chain` skips the rest
+/// of the current pass, including the error flow, and starts over with the
+/// rewritten URL. A pass can be restarted at most `MAX_REDISPATCHES` times,
+/// after which the error is returned from the `Chain` and the client
+/// receives a 500.
+///
+/// A typical use is serving a single page app, where requests for files
+/// which do not exist fall back to `index.html`.
+#[derive(Debug)]
+pub struct Redispatch;
+
+impl Redispatch {
+    /// Rewrite the URL of `req` and produce the error which restarts the
+    /// `Chain`.
+    pub fn to(req: &mut Request, url: Url) -> IronError {
+        req.url = url;
+        IronError::new(Redispatch, status::InternalServerError)
+    }
+}
+
+impl fmt::Display for Redispatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.description())
+    }
+}
+
+impl StdError for Redispatch {
+    fn description(&self) -> &str { "Request redispatched" }
+}
+
+fn is_redispatch(err: &IronError) -> bool {
+    err.error.is::<Redispatch>()
+}
+
+/// An error which abandons a request that cannot be recovered from, such
+/// as one found to violate the protocol part way through its body.
+///
+/// A `Chain` which sees an `Abort` skips the rest of its middleware,
+/// including every `catch` method, and returns the error. The server then
+/// sends the error's response, without running the `not_found` or
+/// `internal_error` handlers, and closes the connection, since it cannot
+/// know where the next request would start.
+///
+/// ```ignore
+/// return Err(Abort::with((status::BadRequest, "Malformed chunk")));
+/// ```
+#[derive(Debug)]
+pub struct Abort;
+
+impl Abort {
+    /// Produce the error which aborts the request, answering it with a
+    /// response built from `modifier`.
+    pub fn with<M: Modifier<Response>>(modifier: M) -> IronError {
+        IronError::new(Abort, modifier)
+    }
+}
+
+impl fmt::Display for Abort {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.description())
+    }
+}
+
+impl StdError for Abort {
+    fn description(&self) -> &str { "Request aborted" }
+}
+
+#[doc(hidden)]
+pub fn is_abort(err: &IronError) -> bool {
+    err.error.is::<Abort>()
+}
+
+// Whether `err` bypasses the error flow of a `Chain`.
+fn skips_error_flow(err: &IronError) -> bool {
+    is_redispatch(err) || is_abort(err)
+}
+
 impl Chain {
     ///////////////// Implementation Helpers /////////////////
 
+    // Call a middleware, recording the call if tracing is enabled.
+    fn step<T, F>(&self, req: &mut Request, name: &'static str, phase: Phase,
+                  call: F) -> IronResult<T>
+    where F: FnOnce(&mut Request) -> IronResult<T>, IronResult<T>: Outcome {
+        if self.tracing.is_none() { return call(req) }
+
+        let start = Instant::now();
+        let result = call(req);
+        let step = Step {
+            name: name,
+            phase: phase,
+            ok: result.is_ok(),
+            status: result.status(),
+            start: start,
+            duration: start.elapsed()
+        };
+
+        req.extensions.entry::<Trace>().or_insert_with(Vec::new).push(step);
+        result
+    }
+
     // Enter the error flow from a before middleware, starting
     // at the passed index.
     //
@@ -217,7 +465,9 @@ impl Chain {
         }
 
         for (i, before) in self.befores[index..].iter().enumerate() {
-            err = match before.catch(req, err) {
+            let name = self.before_names[index + i];
+            err = match self.step(req, name, Phase::BeforeCatch, |req| before.catch(req, err)) {
+                Err(err) if skips_error_flow(&err) => return Err(err),
                 Err(err) => err,
                 Ok(()) => return self.continue_from_before(req, index + i + 1)
             };
@@ -246,7 +496,9 @@ impl Chain {
         if index == self.afters.len() { return Err(err) }
 
         for (i, after) in self.afters[index..].iter().enumerate() {
-            err = match after.catch(req, err) {
+            let name = self.after_names[index + i];
+            err = match self.step(req, name, Phase::AfterCatch, |req| after.catch(req, err)) {
+                Err(err) if skips_error_flow(&err) => return Err(err),
                 Err(err) => err,
                 Ok(res) => return self.continue_from_after(req, index + i + 1, res)
             }
@@ -266,8 +518,10 @@ impl Chain {
         }
 
         for (i, before) in self.befores[index..].iter().enumerate() {
-            match before.before(req) {
+            let name = self.before_names[index + i];
+            match self.step(req, name, Phase::Before, |req| before.before(req)) {
                 Ok(()) => {},
+                Err(err) if skips_error_flow(&err) => return Err(err),
                 Err(err) => return self.fail_from_before(req, index + i + 1, err)
             }
         }
@@ -279,8 +533,11 @@ impl Chain {
     // Enter the normal flow at the handler.
     fn continue_from_handler(&self, req: &mut Request) -> IronResult<Response> {
         // unwrap is safe because it's always Some
-        match self.handler.as_ref().unwrap().handle(req) {
+        let handler = self.handler.as_ref().unwrap();
+        let name = self.around_names.last().cloned().unwrap_or(self.handler_name);
+        match self.step(req, name, Phase::Handler, |req| handler.handle(req)) {
             Ok(res) => self.continue_from_after(req, 0, res),
+            Err(err) if skips_error_flow(&err) => Err(err),
             Err(err) => self.fail_from_handler(req, err)
         }
     }
@@ -295,8 +552,10 @@ impl Chain {
         }
 
         for (i, after) in self.afters[index..].iter().enumerate() {
-            res = match after.after(req, res) {
+            let name = self.after_names[index + i];
+            res = match self.step(req, name, Phase::After, |req| after.after(req, res)) {
                 Ok(r) => r,
+                Err(err) if skips_error_flow(&err) => return Err(err),
                 Err(err) => return self.fail_from_after(req, index + i + 1, err)
             }
         }
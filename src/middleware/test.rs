@@ -1,13 +1,19 @@
-use std::net::ToSocketAddrs;
-use std::sync::atomic::AtomicBool;
+use std::sync::atomic::{AtomicBool, AtomicUsize};
 use std::sync::atomic::Ordering::Relaxed;
-use std::sync::Arc;
+use std::sync::{Arc, Barrier};
+use std::thread;
+use std::time::Duration;
+
+use typemap::Key;
 
 use self::Kind::{Fine, Prob};
 
 use prelude::*;
-use {method, headers};
-use {AfterMiddleware, BeforeMiddleware, Handler, TypeMap, Url};
+use {method, status};
+use test::{MiddlewareHarness, StubRequest};
+use {AfterMiddleware, BeforeMiddleware, Handler, Url};
+use super::{is_redispatch, Abort, AndThen, DevErrorPage, Lazy, MapResponse, OnMethod, OnPrefix,
+            OrElse, Phase, Redispatch, Trace, WhenPresent, MAX_REDISPATCHES};
 
 #[test] fn test_chain_normal() {
     test_chain(
@@ -64,6 +70,385 @@ use {AfterMiddleware, BeforeMiddleware, Handler, TypeMap, Url};
     );
 }
 
+#[test] fn test_chain_trace() {
+    let chain = (vec![Prob, Fine], Fine, vec![Fine]);
+    let mut chain = to_chain(&counters(&chain), chain);
+    chain.trace(false);
+
+    let mut req = request();
+    let _ = chain.handle(&mut req);
+
+    let steps = Trace::of(&req).iter()
+        .map(|step| (step.phase, step.ok))
+        .collect::<Vec<_>>();
+    assert_eq!(steps, vec![(Phase::Before, false), (Phase::BeforeCatch, true),
+                           (Phase::Handler, true), (Phase::After, true)]);
+    assert!(Trace::of(&req)[0].name.ends_with("Middleware"));
+}
+
+#[test] fn test_and_then() {
+    let mark = |label: &'static str| move |req: &mut Request| -> IronResult<()> {
+        req.extensions.entry::<Seen>().or_insert_with(String::new).push_str(label);
+        Ok(())
+    };
+    let fail = |_: &mut Request| -> IronResult<()> { Err(error()) };
+    let seen = |req: &Request| req.extensions.get::<Seen>().cloned();
+
+    let mut req = request();
+    mark("a").and_then(mark("b")).before(&mut req).unwrap();
+    assert_eq!(seen(&req), Some("ab".into()));
+
+    // An error goes to the second's catch, and a recovery by the first
+    // resumes at the second's before.
+    let caught = sharedbool(false);
+    let mut req = request();
+    AndThen(fail, Catcher(caught.clone())).before(&mut req).unwrap();
+    assert!(caught.load(Relaxed));
+    let mut req = request();
+    BeforeMiddleware::catch(&AndThen(Catcher(sharedbool(false)), mark("b")), &mut req, error())
+        .unwrap();
+    assert_eq!(seen(&req), Some("b".into()));
+
+    let append = |label: &'static str| move |_: &mut Request, mut res: Response| {
+        let body = res.headers.get_raw("X-Seen").map_or(String::new(), |values| {
+            String::from_utf8(values[0].clone()).unwrap()
+        });
+        res.headers.set_raw("X-Seen", vec![(body + label).into_bytes()]);
+        Ok(res)
+    };
+    let res = append("a").and_then(append("b")).after(&mut request(), response()).unwrap();
+    assert_eq!(res.headers.get_raw("X-Seen"), Some(&[b"ab".to_vec()][..]));
+}
+
+#[test] fn test_or_else() {
+    let fine = |_: &mut Request| -> IronResult<()> { Ok(()) };
+    let fail = |_: &mut Request| -> IronResult<()> { Err(error()) };
+
+    let caught = sharedbool(false);
+    OrElse(fine, Catcher(caught.clone())).before(&mut request()).unwrap();
+    assert!(!caught.load(Relaxed));
+    OrElse(fail, Catcher(caught.clone())).before(&mut request()).unwrap();
+    assert!(caught.load(Relaxed));
+
+    let failing = |_: &mut Request, _: Response| -> IronResult<Response> { Err(error()) };
+    let caught = sharedbool(false);
+    let res = OrElse(failing, Catcher(caught.clone())).after(&mut request(), response());
+    assert_eq!(res.unwrap().status, Some(status::Ok));
+    assert!(caught.load(Relaxed));
+}
+
+#[test] fn test_map_response() {
+    let pass = |_: &mut Request, res: Response| -> IronResult<Response> { Ok(res) };
+    let accepted = pass.map_response(|res: Response| res.set(status::Accepted));
+    let res = accepted.after(&mut request(), response()).unwrap();
+    assert_eq!(res.status, Some(status::Accepted));
+    assert!(AfterMiddleware::catch(&accepted, &mut request(), error()).is_err());
+
+    // Recoveries are mapped too.
+    let recovering = MapResponse(Catcher(sharedbool(false)), |res: Response| {
+        res.set(status::Accepted)
+    });
+    let res = AfterMiddleware::catch(&recovering, &mut request(), error()).unwrap();
+    assert_eq!(res.status, Some(status::Accepted));
+}
+
+#[test] fn test_on_method() {
+    let mark = |req: &mut Request| -> IronResult<()> {
+        req.extensions.insert::<Marked>(());
+        Ok(())
+    };
+    let harness = MiddlewareHarness::new(OnMethod(vec![method::Post], mark));
+
+    assert!(harness.before(StubRequest::new(method::Post, "http://localhost/"))
+        .extension::<Marked>().is_some());
+    assert!(harness.before(StubRequest::new(method::Get, "http://localhost/"))
+        .extension::<Marked>().is_none());
+}
+
+#[test] fn test_on_method_around() {
+    let mut chain = Chain::new(|_: &mut Request| Ok(Response::with(status::Ok)));
+    chain.link_around(OnMethod(vec![method::Get], |handler: Box<Handler>| -> Box<Handler> {
+        Box::new(move |req: &mut Request| {
+            handler.handle(req).map(|res| res.set(status::NotModified))
+        })
+    }));
+    let harness = MiddlewareHarness::new(chain);
+
+    assert_eq!(harness.handle(StubRequest::new(method::Get, "http://localhost/")).status(),
+               Some(status::NotModified));
+    assert_eq!(harness.handle(StubRequest::new(method::Put, "http://localhost/")).status(),
+               Some(status::Ok));
+}
+
+#[test] fn test_when_present() {
+    let see = |req: &mut Request| -> IronResult<()> {
+        req.extensions.insert::<Seen>("seen".into());
+        Ok(())
+    };
+    let middleware = WhenPresent::<Marked, _>::new(see);
+
+    let mut req = StubRequest::new(method::Get, "http://localhost/").build();
+    middleware.before(&mut req).unwrap();
+    assert!(req.extensions.get::<Seen>().is_none());
+
+    req.extensions.insert::<Marked>(());
+    middleware.before(&mut req).unwrap();
+    assert!(req.extensions.get::<Seen>().is_some());
+}
+
+#[test] fn test_when_present_around() {
+    let mut chain = Chain::new(|_: &mut Request| Ok(Response::with(status::Ok)));
+    chain.link_around(WhenPresent::<Marked, _>::new(|handler: Box<Handler>| -> Box<Handler> {
+        Box::new(move |req: &mut Request| {
+            handler.handle(req).map(|res| res.set(status::NotModified))
+        })
+    }));
+    chain.link_before(|req: &mut Request| -> IronResult<()> {
+        if req.url.path() == vec!["marked"] { req.extensions.insert::<Marked>(()); }
+        Ok(())
+    });
+    let harness = MiddlewareHarness::new(chain);
+
+    assert_eq!(harness.handle(StubRequest::new(method::Get, "http://localhost/marked")).status(),
+               Some(status::NotModified));
+    assert_eq!(harness.handle(StubRequest::new(method::Get, "http://localhost/")).status(),
+               Some(status::Ok));
+}
+
+#[test] fn test_abort() {
+    let recovered = Arc::new(AtomicBool::new(false));
+    let flag = recovered.clone();
+
+    let mut chain = Chain::new(|_: &mut Request| -> IronResult<Response> {
+        Err(Abort::with(status::BadRequest))
+    });
+    chain.link_after(Catcher(flag));
+    let harness = MiddlewareHarness::new(chain);
+
+    let run = harness.handle(StubRequest::new(method::Post, "http://localhost/"));
+    assert!(run.error().map_or(false, |err| err.error.is::<Abort>()));
+    assert_eq!(run.status(), Some(status::BadRequest));
+    assert!(!recovered.load(Relaxed));
+}
+
+#[test] fn test_combinators_skip_error_flow() {
+    let recovered = Arc::new(AtomicBool::new(false));
+    let abort = |_: &mut Request| -> IronResult<()> { Err(Abort::with(status::BadRequest)) };
+    let redispatch = |req: &mut Request| -> IronResult<()> {
+        let url = Url::parse("http://localhost/index.html").unwrap();
+        Err(Redispatch::to(req, url))
+    };
+    let abort_after = |_: &mut Request, _: Response| -> IronResult<Response> {
+        Err(Abort::with(status::BadRequest))
+    };
+
+    let err = OrElse(abort, Catcher(recovered.clone())).before(&mut request()).unwrap_err();
+    assert!(err.error.is::<Abort>());
+    let err = AndThen(redispatch, Catcher(recovered.clone())).before(&mut request())
+        .unwrap_err();
+    assert!(is_redispatch(&err));
+    let err = OrElse(abort_after, Catcher(recovered.clone())).after(&mut request(), response())
+        .unwrap_err();
+    assert!(err.error.is::<Abort>());
+
+    let on_get = OnMethod(vec![method::Get], Catcher(recovered.clone()));
+    let err = BeforeMiddleware::catch(&on_get, &mut request(), Abort::with(status::BadRequest))
+        .unwrap_err();
+    assert!(err.error.is::<Abort>());
+    let prefixed = OnPrefix::new("/", Catcher(recovered.clone()));
+    let err = AfterMiddleware::catch(&prefixed, &mut request(), Abort::with(status::BadRequest))
+        .unwrap_err();
+    assert!(err.error.is::<Abort>());
+
+    assert!(!recovered.load(Relaxed));
+}
+
+#[test] fn test_redispatch() {
+    let befores = Arc::new(AtomicUsize::new(0));
+    let counter = befores.clone();
+
+    let mut chain = Chain::new(|req: &mut Request| -> IronResult<Response> {
+        let path = req.url.path().join("/");
+        match &*path {
+            "index.html" => Ok(Response::with((status::Ok, path))),
+            "loop" => {
+                let url = req.url.clone();
+                Err(Redispatch::to(req, url))
+            },
+            _ => {
+                let url = Url::parse("http://localhost/index.html").unwrap();
+                Err(Redispatch::to(req, url))
+            }
+        }
+    });
+    chain.link_before(move |_: &mut Request| -> IronResult<()> {
+        counter.fetch_add(1, Relaxed);
+        Ok(())
+    });
+    let harness = MiddlewareHarness::new(chain);
+
+    // The before phase runs again with the rewritten URL.
+    let mut run = harness.handle(StubRequest::new(method::Get, "http://localhost/app/users"));
+    assert_eq!(run.status(), Some(status::Ok));
+    assert_eq!(run.take_body(), b"index.html".to_vec());
+    assert_eq!(befores.swap(0, Relaxed), 2);
+
+    // Redispatching for ever gives up with a 500.
+    let run = harness.handle(StubRequest::new(method::Get, "http://localhost/loop"));
+    assert!(run.error().map_or(false, is_redispatch));
+    assert_eq!(run.status(), Some(status::InternalServerError));
+    assert_eq!(befores.load(Relaxed), MAX_REDISPATCHES + 1);
+}
+
+#[test] fn test_lazy() {
+    let built = Arc::new(AtomicUsize::new(0));
+    let counter = built.clone();
+    let lazy = Arc::new(Lazy::new(move || {
+        counter.fetch_add(1, Relaxed);
+        thread::sleep(Duration::from_millis(20));
+        |req: &mut Request| -> IronResult<()> {
+            req.extensions.insert::<Marked>(());
+            Ok(())
+        }
+    }));
+    assert!(!lazy.is_initialized());
+    assert_eq!(built.load(Relaxed), 0);
+
+    // Concurrent first requests build the middleware once.
+    let barrier = Arc::new(Barrier::new(8));
+    let threads: Vec<_> = (0..8).map(|_| {
+        let (lazy, barrier) = (lazy.clone(), barrier.clone());
+        thread::spawn(move || {
+            barrier.wait();
+            let mut req = request();
+            lazy.before(&mut req).unwrap();
+            req.extensions.contains::<Marked>()
+        })
+    }).collect();
+    for thread in threads { assert!(thread.join().unwrap()) }
+    assert!(lazy.is_initialized());
+    assert_eq!(built.load(Relaxed), 1);
+}
+
+#[test] fn test_lazy_delegation() {
+    let caught = sharedbool(false);
+    let flag = caught.clone();
+    let before = Lazy::new(move || Catcher(flag));
+    BeforeMiddleware::catch(&before, &mut request(), error()).unwrap();
+    assert!(caught.load(Relaxed));
+
+    let after = Lazy::new(|| |_: &mut Request, res: Response| -> IronResult<Response> {
+        Ok(res.set(status::Accepted))
+    });
+    assert_eq!(after.after(&mut request(), response()).unwrap().status, Some(status::Accepted));
+    assert!(AfterMiddleware::catch(&after, &mut request(), error()).is_err());
+
+    let handler = Lazy::new(|| |_: &mut Request| Ok(Response::with(status::Ok)));
+    assert_eq!(handler.handle(&mut request()).unwrap().status, Some(status::Ok));
+}
+
+struct Catcher(Arc<AtomicBool>);
+
+impl BeforeMiddleware for Catcher {
+    fn catch(&self, _: &mut Request, _: IronError) -> IronResult<()> {
+        self.0.store(true, Relaxed);
+        Ok(())
+    }
+}
+
+impl AfterMiddleware for Catcher {
+    fn catch(&self, _: &mut Request, _: IronError) -> IronResult<Response> {
+        self.0.store(true, Relaxed);
+        Ok(Response::with(status::Ok))
+    }
+}
+
+#[test] fn test_on_prefix() {
+    let path = |req: &mut Request| -> IronResult<()> {
+        req.extensions.insert::<Seen>(req.url.path().join("/"));
+        Ok(())
+    };
+    let plain = MiddlewareHarness::new(OnPrefix::new("/api", path));
+    let stripping = MiddlewareHarness::new(OnPrefix::new("/api/", path).strip_prefix());
+
+    let run = plain.before(StubRequest::new(method::Get, "http://localhost/api/users"));
+    assert_eq!(run.extension::<Seen>().map(|s| &**s), Some("api/users"));
+
+    let run = stripping.before(StubRequest::new(method::Get, "http://localhost/api/users"));
+    assert_eq!(run.extension::<Seen>().map(|s| &**s), Some("users"));
+    assert_eq!(run.request.url.path(), vec!["api", "users"]);
+
+    let run = stripping.before(StubRequest::new(method::Get, "http://localhost/api"));
+    assert_eq!(run.extension::<Seen>().map(|s| &**s), Some(""));
+
+    let run = plain.before(StubRequest::new(method::Get, "http://localhost/apis"));
+    assert!(run.extension::<Seen>().is_none());
+}
+
+#[test] fn test_on_prefix_around() {
+    let mut chain = Chain::new(|req: &mut Request| {
+        Ok(Response::with((status::Ok, req.url.path().join("/"))))
+    });
+    chain.link_around(OnPrefix::new("/static", |handler: Box<Handler>| -> Box<Handler> {
+        Box::new(move |req: &mut Request| {
+            let seen = req.url.path().join("/");
+            handler.handle(req).map(|mut res| {
+                res.headers.set_raw("X-Seen-Path", vec![seen.into_bytes()]);
+                res
+            })
+        })
+    }).strip_prefix());
+    let harness = MiddlewareHarness::new(chain);
+
+    let mut run = harness.handle(StubRequest::new(method::Get, "http://localhost/static/a.css"));
+    assert_eq!(run.take_body(), b"static/a.css".to_vec());
+    assert_eq!(run.response().headers.get_raw("X-Seen-Path"), Some(&[b"a.css".to_vec()][..]));
+    assert_eq!(run.request.url.path(), vec!["static", "a.css"]);
+}
+
+#[test] fn test_dev_error_page() {
+    let chain = |enabled| {
+        let mut chain = Chain::new(|req: &mut Request| -> IronResult<Response> {
+            panic!("no user <{}>", req.url.path().join("/"))
+        });
+        chain.link_before(|_: &mut Request| Ok(()));
+        chain.trace(false);
+        chain.link_around(DevErrorPage::new(enabled));
+        MiddlewareHarness::new(chain)
+    };
+
+    let mut run = chain(true).handle(StubRequest::new(method::Get, "http://localhost/users/7")
+                                         .raw_header("X-Request-Id", "abc"));
+    assert_eq!(run.status(), Some(status::InternalServerError));
+    let page = String::from_utf8(run.take_body()).unwrap();
+    assert!(page.contains("no user &lt;users/7&gt;"), "{}", page);
+    assert!(page.contains("Before ok"), "{}", page);
+    assert!(page.contains("X-Request-Id"), "{}", page);
+
+    let mut failing = Chain::new(|_: &mut Request| -> IronResult<Response> {
+        let err = IronError::new(::std::fmt::Error, status::BadGateway);
+        Err(err.context("fetching profile").context("rendering page"))
+    });
+    failing.link_around(DevErrorPage::new(true));
+    let mut run = MiddlewareHarness::new(failing)
+        .handle(StubRequest::new(method::Get, "http://localhost/"));
+    assert_eq!(run.status(), Some(status::BadGateway));
+    let page = String::from_utf8(run.take_body()).unwrap();
+    assert!(page.contains("<li>rendering page</li>\n<li>fetching profile</li>"), "{}", page);
+
+    let disabled = chain(false);
+    let panicked = ::std::panic::catch_unwind(::std::panic::AssertUnwindSafe(|| {
+        disabled.handle(StubRequest::new(method::Get, "http://localhost/"))
+    }));
+    assert!(panicked.is_err());
+}
+
+struct Seen;
+impl Key for Seen { type Value = String; }
+
+struct Marked;
+impl Key for Marked { type Value = (); }
+
 // Used to indicate the action taken by a middleware or handler.
 #[derive(Debug, PartialEq)]
 enum Kind {
@@ -135,15 +520,7 @@ impl AfterMiddleware for Middleware {
 
 // Stub request
 fn request<'a, 'b>() -> Request<'a, 'b> {
-    Request {
-        url: Url::parse("http://www.rust-lang.org").unwrap(),
-        remote_addr: "localhost:3000".to_socket_addrs().unwrap().next().unwrap(),
-        local_addr: "localhost:3000".to_socket_addrs().unwrap().next().unwrap(),
-        headers: headers::Headers::new(),
-        body: unsafe { ::std::mem::uninitialized() }, // FIXME(reem): Ugh
-        method: method::Get,
-        extensions: TypeMap::new()
-    }
+    StubRequest::new(method::Get, "http://www.rust-lang.org").build()
 }
 
 // Stub response
@@ -201,23 +578,17 @@ fn to_chain(counters: &ChainLike<Twice<Arc<AtomicBool>>>,
     let (befores, handler, afters) = chain;
     let (ref beforec, ref handlerc, ref afterc) = *counters;
 
-    let befores = befores.into_iter().zip(beforec.iter())
-        .map(into_middleware)
-        .map(|m| Box::new(m) as Box<BeforeMiddleware>)
-        .collect::<Vec<_>>();
-
-    let handler = into_middleware((handler, handlerc));
+    let mut chain = Chain::new(into_middleware((handler, handlerc)));
 
-    let afters = afters.into_iter().zip(afterc.iter())
-        .map(into_middleware)
-        .map(|m| Box::new(m) as Box<AfterMiddleware>)
-        .collect::<Vec<_>>();
+    for before in befores.into_iter().zip(beforec.iter()) {
+        chain.link_before(into_middleware(before));
+    }
 
-    Chain {
-        befores: befores,
-        handler: Some(Box::new(handler) as Box<Handler>),
-        afters: afters
+    for after in afters.into_iter().zip(afterc.iter()) {
+        chain.link_after(into_middleware(after));
     }
+
+    chain
 }
 
 fn into_middleware(input: (Kind, &Twice<Arc<AtomicBool>>)) -> Middleware {
@@ -0,0 +1,104 @@
+//! Recording the path a request takes through a `Chain`.
+
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use typemap::Key;
+
+use {Request, Response, IronResult};
+use status::Status;
+
+/// The part of a middleware which was called.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    /// `BeforeMiddleware::before`.
+    Before,
+
+    /// `BeforeMiddleware::catch`.
+    BeforeCatch,
+
+    /// The `Chain`'s `Handler`, including any `AroundMiddleware`.
+    Handler,
+
+    /// `AfterMiddleware::after`.
+    After,
+
+    /// `AfterMiddleware::catch`.
+    AfterCatch
+}
+
+/// A single middleware call recorded in a `Trace`.
+#[derive(Debug, Clone)]
+pub struct Step {
+    /// The type name of the middleware or handler.
+    pub name: &'static str,
+
+    /// Which of its methods was called.
+    pub phase: Phase,
+
+    /// Whether it returned `Ok`.
+    pub ok: bool,
+
+    /// The status of the response or error it returned, if any.
+    pub status: Option<Status>,
+
+    /// When the call started.
+    pub start: Instant,
+
+    /// How long the call took.
+    pub duration: Duration
+}
+
+impl fmt::Display for Step {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(write!(f, "{} {:?} {}", self.name, self.phase, if self.ok { "ok" } else { "err" }));
+        if let Some(status) = self.status { try!(write!(f, " {}", status.to_u16())) }
+        let micros = self.duration.as_secs() * 1_000_000
+            + self.duration.subsec_nanos() as u64 / 1_000;
+        write!(f, " ({}us)", micros)
+    }
+}
+
+/// The steps taken by a request through every traced `Chain` it passed
+/// through, in order.
+///
+/// Recorded in the request's extensions by chains with tracing enabled
+/// through `Chain::trace`.
+pub struct Trace;
+
+impl Key for Trace { type Value = Vec<Step>; }
+
+impl Trace {
+    /// The steps recorded for `req` so far.
+    pub fn of<'a>(req: &'a Request) -> &'a [Step] {
+        req.extensions.get::<Trace>().map(|steps| &steps[..]).unwrap_or(&[])
+    }
+}
+
+// The response status of a middleware result, if any.
+pub trait Outcome {
+    fn status(&self) -> Option<Status>;
+}
+
+impl Outcome for IronResult<()> {
+    fn status(&self) -> Option<Status> {
+        self.as_ref().err().and_then(|err| err.response.status)
+    }
+}
+
+impl Outcome for IronResult<Response> {
+    fn status(&self) -> Option<Status> {
+        match *self {
+            Ok(ref res) => res.status,
+            Err(ref err) => err.response.status
+        }
+    }
+}
+
+// Format `steps` one per line, for logging.
+pub fn format(steps: &[Step]) -> String {
+    steps.iter().enumerate()
+        .map(|(i, step)| format!("  {}. {}", i + 1, step))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
@@ -57,7 +57,8 @@ use hyper::mime::Mime;
 use {status, headers, Request, Response, Set, Url};
 
 use mime_types;
-use response::{WriteBody, BodyReader};
+use response::{WriteBody, BodyReader, JsonStream};
+use rustc_serialize::Encodable;
 
 lazy_static! {
     static ref MIME_TYPES: mime_types::Types = mime_types::Types::new().unwrap();
@@ -84,6 +85,14 @@ impl <R: io::Read + Send + 'static> Modifier<Response> for BodyReader<R> {
     }
 }
 
+impl<I, T> Modifier<Response> for JsonStream<I>
+where I: Iterator<Item=T> + Send + 'static, T: Encodable {
+    fn modify(self, res: &mut Response) {
+        res.headers.set(headers::ContentType("application/x-ndjson".parse().unwrap()));
+        res.body = Some(Box::new(self));
+    }
+}
+
 impl Modifier<Response> for String {
     #[inline]
     fn modify(self, res: &mut Response) {
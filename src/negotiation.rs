@@ -0,0 +1,211 @@
+//! Content negotiation utilities.
+
+use headers::{Accept, AcceptEncoding, Encoding, QualityItem};
+use mime::Mime;
+use Request;
+
+/// Choose which of the `available` content codings to use for the response
+/// to `req`, according to its `Accept-Encoding` header.
+///
+/// `available` should be in order of the server's preference, which breaks
+/// ties between codings the client values equally. Quality values of zero,
+/// including `identity;q=0` and `*;q=0`, exclude a coding. Returns `None`
+/// if none of the `available` codings is acceptable, in which case the
+/// usual answer is a 406.
+///
+/// A request without an `Accept-Encoding` header gets `identity` if it is
+/// available, since old clients that omit the header may not cope with
+/// compressed responses, and the first available coding otherwise.
+///
+/// ```
+/// # use iron::headers::{Header, AcceptEncoding, Encoding};
+/// # use iron::negotiation::negotiate_from_header;
+/// let header = AcceptEncoding::parse_header(&[b"gzip;q=0.5, identity;q=0".to_vec()]).ok();
+/// let choice = negotiate_from_header(header.as_ref(), &[Encoding::Gzip, Encoding::Identity]);
+/// assert_eq!(choice, Some(Encoding::Gzip));
+/// ```
+pub fn negotiate_encoding(req: &Request, available: &[Encoding]) -> Option<Encoding> {
+    negotiate_from_header(req.headers.get::<AcceptEncoding>(), available)
+}
+
+/// Choose a content coding given the request's `Accept-Encoding` header,
+/// as `negotiate_encoding`.
+pub fn negotiate_from_header(header: Option<&AcceptEncoding>, available: &[Encoding])
+                             -> Option<Encoding> {
+    let accepted = match header {
+        Some(&AcceptEncoding(ref accepted)) => accepted,
+        None => {
+            return available.iter()
+                .find(|encoding| **encoding == Encoding::Identity)
+                .or_else(|| available.first())
+                .cloned()
+        }
+    };
+
+    let mut best: Option<(&Encoding, u16)> = None;
+
+    for encoding in available {
+        let quality = quality_of(accepted, encoding);
+
+        if quality == 0 { continue }
+
+        match best {
+            Some((_, best_quality)) if best_quality >= quality => {},
+            _ => best = Some((encoding, quality))
+        }
+    }
+
+    best.map(|(encoding, _)| encoding.clone())
+}
+
+// The quality the client gave `encoding`, from 0 to 1000.
+fn quality_of(accepted: &[QualityItem<Encoding>], encoding: &Encoding) -> u16 {
+    let name = encoding.to_string().to_ascii_lowercase();
+    let find = |name: &str| {
+        accepted.iter()
+            .find(|item| item.item.to_string().to_ascii_lowercase() == name)
+            .map(|item| item.quality.0)
+    };
+
+    find(&name)
+        .or_else(|| find("*"))
+        // Identity is acceptable unless explicitly excluded.
+        .unwrap_or(if *encoding == Encoding::Identity { 1000 } else { 0 })
+}
+
+/// Choose which of the `available` media types to send in response to
+/// `req`, according to its `Accept` header.
+///
+/// Each type takes the quality of the most specific range matching it, so
+/// `application/json;q=0, */*` excludes JSON. Parameters are ignored. As
+/// with `negotiate_encoding`, `available` should be in order of preference,
+/// a quality of zero excludes a type, and `None` means that none of them is
+/// acceptable. A request without an `Accept` header gets the first type.
+///
+/// ```
+/// # use iron::headers::{Header, Accept};
+/// # use iron::negotiation::negotiate_media_type_from_header;
+/// let header = Accept::parse_header(&[b"text/*;q=0.5, application/json".to_vec()]).ok();
+/// let available = ["text/html".parse().unwrap(), "application/json".parse().unwrap()];
+/// let choice = negotiate_media_type_from_header(header.as_ref(), &available);
+/// assert_eq!(choice, Some("application/json".parse().unwrap()));
+/// ```
+pub fn negotiate_media_type(req: &Request, available: &[Mime]) -> Option<Mime> {
+    negotiate_media_type_from_header(req.headers.get::<Accept>(), available)
+}
+
+/// Choose a media type given the request's `Accept` header, as
+/// `negotiate_media_type`.
+pub fn negotiate_media_type_from_header(header: Option<&Accept>, available: &[Mime])
+                                        -> Option<Mime> {
+    let accepted = match header {
+        Some(&Accept(ref accepted)) => accepted,
+        None => return available.first().cloned()
+    };
+
+    let mut best: Option<(&Mime, u16)> = None;
+
+    for mime in available {
+        let quality = media_quality_of(accepted, mime);
+
+        if quality == 0 { continue }
+
+        match best {
+            Some((_, best_quality)) if best_quality >= quality => {},
+            _ => best = Some((mime, quality))
+        }
+    }
+
+    best.map(|(mime, _)| mime.clone())
+}
+
+// The quality of the most specific range in `accepted` matching `mime`,
+// from 0 to 1000.
+fn media_quality_of(accepted: &[QualityItem<Mime>], mime: &Mime) -> u16 {
+    let Mime(ref top, ref sub, _) = *mime;
+    let mut best: Option<(u8, u16)> = None;
+
+    for item in accepted {
+        let Mime(ref range_top, ref range_sub, _) = item.item;
+        let same_top = range_top.as_str().eq_ignore_ascii_case(top.as_str());
+        let specificity = match (range_top.as_str(), range_sub.as_str()) {
+            ("*", "*") => 0,
+            (_, "*") if same_top => 1,
+            (_, range_sub) if same_top && range_sub.eq_ignore_ascii_case(sub.as_str()) => 2,
+            _ => continue
+        };
+
+        if best.map_or(true, |(best_specificity, _)| specificity > best_specificity) {
+            best = Some((specificity, item.quality.0));
+        }
+    }
+
+    best.map_or(0, |(_, quality)| quality)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{negotiate_from_header, negotiate_media_type_from_header};
+    use headers::{Header, Accept, AcceptEncoding};
+    use mime::Mime;
+    use headers::Encoding::{Gzip, Deflate, Identity, EncodingExt};
+
+    fn header(value: &str) -> AcceptEncoding {
+        AcceptEncoding::parse_header(&[value.as_bytes().to_vec()]).unwrap()
+    }
+
+    #[test]
+    fn test_no_header() {
+        assert_eq!(negotiate_from_header(None, &[Gzip, Identity]), Some(Identity));
+        assert_eq!(negotiate_from_header(None, &[Gzip]), Some(Gzip));
+    }
+
+    #[test]
+    fn test_quality() {
+        let header = header("gzip;q=0.5, deflate");
+        assert_eq!(negotiate_from_header(Some(&header), &[Gzip, Deflate]), Some(Deflate));
+        assert_eq!(negotiate_from_header(Some(&header), &[Gzip, Identity]), Some(Identity));
+    }
+
+    #[test]
+    fn test_server_preference_breaks_ties() {
+        let header = header("deflate, gzip");
+        assert_eq!(negotiate_from_header(Some(&header), &[Gzip, Deflate]), Some(Gzip));
+    }
+
+    #[test]
+    fn test_wildcard() {
+        let header = header("*;q=0.1, gzip;q=0");
+        let br = EncodingExt("br".to_owned());
+        assert_eq!(negotiate_from_header(Some(&header), &[Gzip, br.clone()]), Some(br));
+    }
+
+    #[test]
+    fn test_identity_excluded() {
+        assert_eq!(negotiate_from_header(Some(&header("identity;q=0")), &[Identity]), None);
+        assert_eq!(negotiate_from_header(Some(&header("*;q=0")), &[Identity]), None);
+        assert_eq!(negotiate_from_header(Some(&header("gzip")), &[Deflate]), None);
+    }
+
+    fn media(types: &[&str]) -> Vec<Mime> {
+        types.iter().map(|mime| mime.parse().unwrap()).collect()
+    }
+
+    fn choose(accept: Option<&str>, available: &[&str]) -> Option<Mime> {
+        let header = accept.map(|accept| Accept::parse_header(&[accept.into()]).unwrap());
+        negotiate_media_type_from_header(header.as_ref(), &media(available))
+    }
+
+    #[test]
+    fn test_media_types() {
+        let json = media(&["application/json"]).pop();
+        assert_eq!(choose(None, &["application/json", "text/html"]), json);
+        assert_eq!(choose(Some("text/html, application/*;q=0.5"), &["application/json"]), json);
+        assert_eq!(choose(Some("text/html;q=0.5, application/json"),
+                          &["text/html", "application/json"]), json);
+        assert_eq!(choose(Some("application/json;q=0, */*"), &["application/json"]), None);
+        assert_eq!(choose(Some("text/html"), &["application/json"]), None);
+        assert_eq!(choose(Some("*/*"), &["text/html", "application/json"]),
+                   media(&["text/html"]).pop());
+    }
+}
@@ -0,0 +1,651 @@
+//! Protecting resources with OAuth 2 bearer tokens.
+//!
+//! `OAuth2Resource` is `BeforeMiddleware` which requires each request to
+//! carry an access token in an `Authorization: Bearer` header, validates
+//! it, and stores what it grants as an `AccessToken` in the request
+//! extensions. Tokens are validated either locally, as JSON Web Tokens
+//! signed with a secret shared with the authorization server (`HS256`), or
+//! by asking the server's introspection endpoint (RFC 7662):
+//!
+//! ```ignore
+//! let mut oauth = OAuth2Resource::introspection("https://auth.example.com/introspect",
+//!                                               "api", "client-secret");
+//! oauth.require_scopes(&["read"]).cache(SharedCache::new(MemoryCache::new(10_000)));
+//! chain.link_before(oauth);
+//!
+//! // For routes needing more, in their own chains:
+//! admin.link_before(RequireScopes::new(&["admin"]));
+//!
+//! // In a handler:
+//! let user = AccessToken::of(req).unwrap().subject.clone();
+//! ```
+//!
+//! Failures are answered as RFC 6750 describes: `401 Unauthorized` for a
+//! missing or invalid token, and `403 Forbidden` for a token lacking a
+//! required scope, each with a `WWW-Authenticate` header.
+
+use std::error::Error;
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+use rustc_serialize::base64::FromBase64;
+use rustc_serialize::hex::ToHex;
+use rustc_serialize::json::{Json, Object, ToJson};
+use sha2::{Sha256, Digest};
+use typemap::Key;
+
+use cache::Cache;
+use checksum::{constant_time_eq, hmac_sha256};
+use client::Client;
+use clock::{Clock, SystemClock, unix_seconds};
+use method::Method;
+use url::percent_encode;
+use {headers, status, BeforeMiddleware, Headers, Request, IronResult, IronError};
+
+/// What a validated access token grants, stored in the request extensions
+/// by `OAuth2Resource`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AccessToken {
+    /// The user or other resource owner the token was issued for.
+    pub subject: Option<String>,
+
+    /// The client the token was issued to.
+    pub client_id: Option<String>,
+
+    /// The scopes granted.
+    pub scopes: Vec<String>,
+
+    /// When the token expires, in seconds since the Unix epoch.
+    pub expires: Option<u64>
+}
+
+impl Key for AccessToken { type Value = AccessToken; }
+
+impl AccessToken {
+    /// The token validated for `req`, if any.
+    pub fn of<'a>(req: &'a Request) -> Option<&'a AccessToken> {
+        req.extensions.get::<AccessToken>()
+    }
+
+    /// Whether `scope` has been granted.
+    pub fn has_scope(&self, scope: &str) -> bool {
+        self.scopes.iter().any(|s| s == scope)
+    }
+
+    // Read the standard claims, shared by JSON Web Tokens and introspection
+    // responses. Scopes are a space-separated `scope`, or an `scp` array.
+    fn from_claims(claims: &Object) -> AccessToken {
+        let string = |name: &str| claims.get(name).and_then(Json::as_string).map(String::from);
+        let scopes = match (claims.get("scope"), claims.get("scp")) {
+            (Some(&Json::String(ref scope)), _) => {
+                scope.split(' ').filter(|s| !s.is_empty()).map(String::from).collect()
+            },
+            (_, Some(&Json::Array(ref scopes))) => {
+                scopes.iter().filter_map(Json::as_string).map(String::from).collect()
+            },
+            _ => Vec::new()
+        };
+
+        AccessToken {
+            subject: string("sub"),
+            client_id: string("client_id"),
+            scopes: scopes,
+            expires: claims.get("exp").and_then(Json::as_u64)
+        }
+    }
+}
+
+impl ToJson for AccessToken {
+    fn to_json(&self) -> Json {
+        let mut object = Object::new();
+        if let Some(ref subject) = self.subject {
+            object.insert("sub".into(), subject.to_json());
+        }
+        if let Some(ref client_id) = self.client_id {
+            object.insert("client_id".into(), client_id.to_json());
+        }
+        object.insert("scope".into(), self.scopes.join(" ").to_json());
+        if let Some(expires) = self.expires {
+            object.insert("exp".into(), expires.to_json());
+        }
+        Json::Object(object)
+    }
+}
+
+/// Middleware validating OAuth 2 bearer tokens.
+pub struct OAuth2Resource {
+    validator: Validator,
+    scopes: Vec<String>,
+    realm: Option<String>,
+    cache: Option<Arc<Cache>>,
+    cache_ttl: Duration,
+    clock: Arc<Clock>
+}
+
+enum Validator {
+    Jwt {
+        secret: Vec<u8>,
+        issuer: Option<String>,
+        audience: Option<String>
+    },
+    Introspection {
+        url: String,
+        client_id: String,
+        client_secret: String,
+        client: Client
+    }
+}
+
+impl Validator {
+    // A digest of everything deciding which tokens are accepted, so that
+    // resources sharing a cache never trust each other's cached tokens.
+    fn fingerprint(&self) -> String {
+        let fields: Vec<Option<&[u8]>> = match *self {
+            Validator::Jwt { ref secret, ref issuer, ref audience } => vec![
+                Some(b"jwt"),
+                Some(secret),
+                issuer.as_ref().map(|issuer| issuer.as_bytes()),
+                audience.as_ref().map(|audience| audience.as_bytes())
+            ],
+            Validator::Introspection { ref url, ref client_id, ref client_secret, .. } => vec![
+                Some(b"introspection"),
+                Some(url.as_bytes()),
+                Some(client_id.as_bytes()),
+                Some(client_secret.as_bytes())
+            ]
+        };
+
+        let mut config = Vec::new();
+        for field in fields {
+            match field {
+                Some(bytes) => {
+                    config.extend_from_slice(format!("{}:", bytes.len()).as_bytes());
+                    config.extend_from_slice(bytes);
+                },
+                None => config.push(b'-')
+            }
+        }
+        Sha256::digest(&config)[..16].to_hex()
+    }
+}
+
+impl OAuth2Resource {
+    /// Validate tokens as JSON Web Tokens signed with HMAC-SHA256 under
+    /// `secret`.
+    ///
+    /// Tokens must carry an `exp` claim and be unexpired, and must not be
+    /// used before their `nbf` claim, if any.
+    pub fn jwt(secret: &[u8]) -> OAuth2Resource {
+        OAuth2Resource::with_validator(Validator::Jwt {
+            secret: secret.to_vec(),
+            issuer: None,
+            audience: None
+        })
+    }
+
+    /// Validate tokens by posting them to the introspection endpoint at
+    /// `url`, authenticating as `client_id` with `client_secret`.
+    pub fn introspection(url: &str, client_id: &str, client_secret: &str) -> OAuth2Resource {
+        let mut client = Client::new();
+        client.timeout(Some(Duration::from_secs(5))).redirects(0).limit(64 * 1024);
+
+        OAuth2Resource::with_validator(Validator::Introspection {
+            url: url.into(),
+            client_id: client_id.into(),
+            client_secret: client_secret.into(),
+            client: client
+        })
+    }
+
+    fn with_validator(validator: Validator) -> OAuth2Resource {
+        OAuth2Resource {
+            validator: validator,
+            scopes: Vec::new(),
+            realm: None,
+            cache: None,
+            cache_ttl: Duration::from_secs(60),
+            clock: Arc::new(SystemClock)
+        }
+    }
+
+    /// Reject tokens lacking any of `scopes`.
+    pub fn require_scopes(&mut self, scopes: &[&str]) -> &mut OAuth2Resource {
+        self.scopes = scopes.iter().map(|&scope| scope.into()).collect();
+        self
+    }
+
+    /// Name `realm` in `WWW-Authenticate` headers.
+    pub fn realm(&mut self, realm: &str) -> &mut OAuth2Resource {
+        self.realm = Some(realm.into());
+        self
+    }
+
+    /// Require JSON Web Tokens to have been issued by `issuer`, in their
+    /// `iss` claim. Has no effect with introspection.
+    pub fn issuer(&mut self, issuer: &str) -> &mut OAuth2Resource {
+        if let Validator::Jwt { issuer: ref mut expected, .. } = self.validator {
+            *expected = Some(issuer.into());
+        }
+        self
+    }
+
+    /// Require JSON Web Tokens to be intended for `audience`, in their
+    /// `aud` claim. Has no effect with introspection.
+    pub fn audience(&mut self, audience: &str) -> &mut OAuth2Resource {
+        if let Validator::Jwt { audience: ref mut expected, .. } = self.validator {
+            *expected = Some(audience.into());
+        }
+        self
+    }
+
+    /// Remember validated tokens in `cache`, for up to a minute unless
+    /// changed with `cache_ttl`, and never past their expiry.
+    ///
+    /// Entries are keyed by the validator's configuration as well as the
+    /// token, so a cache may be shared by differently configured resources.
+    pub fn cache<C: Cache>(&mut self, cache: C) -> &mut OAuth2Resource {
+        self.cache = Some(Arc::new(cache));
+        self
+    }
+
+    /// Remember validated tokens for at most `ttl`.
+    pub fn cache_ttl(&mut self, ttl: Duration) -> &mut OAuth2Resource {
+        self.cache_ttl = ttl;
+        self
+    }
+
+    /// Use `clock` to check expiry, instead of the system clock.
+    pub fn clock<C: Clock>(&mut self, clock: C) -> &mut OAuth2Resource {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// Validate `token`, consulting the cache first if there is one.
+    pub fn validate(&self, token: &str) -> Result<AccessToken, OAuth2Error> {
+        let now = unix_seconds(&*self.clock);
+        let key = self.cache_key(token);
+
+        let cached = self.cache.as_ref()
+            .and_then(|cache| cache.get(&key))
+            .and_then(|bytes| String::from_utf8(bytes).ok())
+            .and_then(|json| Json::from_str(&json).ok());
+        if let Some(Json::Object(claims)) = cached {
+            let token = AccessToken::from_claims(&claims);
+            if token.expires.map_or(true, |expires| expires > now) { return Ok(token) }
+        }
+
+        let token = try!(match self.validator {
+            Validator::Jwt { ref secret, ref issuer, ref audience } => {
+                validate_jwt(token, secret, issuer.as_ref(), audience.as_ref(), now)
+            },
+            Validator::Introspection { ref url, ref client_id, ref client_secret, ref client } => {
+                introspect(token, url, client_id, client_secret, client, now)
+            }
+        });
+
+        if let Some(ref cache) = self.cache {
+            let remaining = token.expires.map(|expires| Duration::from_secs(expires - now));
+            let ttl = remaining.map_or(self.cache_ttl, |remaining| remaining.min(self.cache_ttl));
+            cache.set(&key, token.to_json().to_string().into_bytes(), Some(ttl));
+        }
+        Ok(token)
+    }
+
+    fn cache_key(&self, token: &str) -> String {
+        format!("oauth2:{}:{}", self.validator.fingerprint(),
+                Sha256::digest(token.as_bytes()).to_hex())
+    }
+}
+
+// Check the signature and claims of a JSON Web Token.
+fn validate_jwt(token: &str, secret: &[u8], issuer: Option<&String>,
+                audience: Option<&String>, now: u64) -> Result<AccessToken, OAuth2Error> {
+    let invalid = |message: &str| OAuth2Error::InvalidToken(message.into());
+
+    let parts: Vec<&str> = token.split('.').collect();
+    if parts.len() != 3 { return Err(invalid("Malformed token")) }
+
+    let decode = |part: &str| match part.from_base64().ok()
+        .and_then(|bytes| String::from_utf8(bytes).ok())
+        .and_then(|json| Json::from_str(&json).ok()) {
+        Some(Json::Object(object)) => Ok(object),
+        _ => Err(invalid("Malformed token"))
+    };
+    let header = try!(decode(parts[0]));
+    if header.get("alg").and_then(Json::as_string) != Some("HS256") {
+        return Err(invalid("Unsupported signing algorithm"))
+    }
+
+    let signature = try!(parts[2].from_base64().map_err(|_| invalid("Malformed token")));
+    let signed = &token[..parts[0].len() + 1 + parts[1].len()];
+    if !constant_time_eq(&hmac_sha256(secret, signed.as_bytes()), &signature) {
+        return Err(invalid("Invalid signature"))
+    }
+
+    let claims = try!(decode(parts[1]));
+    if claims.get("nbf").and_then(Json::as_u64).map_or(false, |nbf| nbf > now) {
+        return Err(invalid("Token not yet valid"))
+    }
+    if let Some(issuer) = issuer {
+        if claims.get("iss").and_then(Json::as_string) != Some(&**issuer) {
+            return Err(invalid("Wrong issuer"))
+        }
+    }
+    if let Some(audience) = audience {
+        let intended = match claims.get("aud") {
+            Some(&Json::String(ref aud)) => aud == audience,
+            Some(&Json::Array(ref auds)) => {
+                auds.iter().any(|aud| aud.as_string() == Some(audience))
+            },
+            _ => false
+        };
+        if !intended { return Err(invalid("Wrong audience")) }
+    }
+
+    let token = AccessToken::from_claims(&claims);
+    match token.expires {
+        Some(expires) if expires > now => Ok(token),
+        Some(_) => Err(invalid("Token expired")),
+        None => Err(invalid("Token has no expiry"))
+    }
+}
+
+// Ask an introspection endpoint whether a token is active.
+fn introspect(token: &str, url: &str, client_id: &str, client_secret: &str, client: &Client,
+              now: u64) -> Result<AccessToken, OAuth2Error> {
+    let unavailable = |e: &fmt::Display| OAuth2Error::Unavailable(e.to_string());
+
+    let mut headers = Headers::new();
+    headers.set(headers::Authorization(headers::Basic {
+        username: client_id.into(),
+        password: Some(client_secret.into())
+    }));
+    headers.set_raw("Content-Type", vec![b"application/x-www-form-urlencoded".to_vec()]);
+    headers.set_raw("Accept", vec![b"application/json".to_vec()]);
+    let body = format!("token={}&token_type_hint=access_token", percent_encode(token));
+
+    let res = try!(client.send(Method::Post, url, headers, body.as_bytes())
+                   .map_err(|e| unavailable(&e)));
+    if !res.status.is_success() {
+        return Err(unavailable(&format!("Introspection answered {}", res.status)))
+    }
+
+    let claims = match String::from_utf8(res.body).ok().and_then(|s| Json::from_str(&s).ok()) {
+        Some(Json::Object(claims)) => claims,
+        _ => return Err(unavailable(&"Malformed introspection response"))
+    };
+    if claims.get("active").and_then(Json::as_boolean) != Some(true) {
+        return Err(OAuth2Error::InvalidToken("Token is not active".into()))
+    }
+
+    let token = AccessToken::from_claims(&claims);
+    match token.expires {
+        Some(expires) if expires <= now => Err(OAuth2Error::InvalidToken("Token expired".into())),
+        _ => Ok(token)
+    }
+}
+
+// The `WWW-Authenticate` challenge for `err`.
+fn challenge(realm: Option<&String>, err: &OAuth2Error, scopes: &[String]) -> String {
+    let mut params = Vec::new();
+    if let Some(realm) = realm { params.push(format!("realm=\"{}\"", realm)) }
+    match *err {
+        OAuth2Error::InvalidToken(ref message) => {
+            params.push("error=\"invalid_token\"".into());
+            params.push(format!("error_description=\"{}\"", message));
+        },
+        OAuth2Error::InsufficientScope => {
+            params.push("error=\"insufficient_scope\"".into());
+            params.push(format!("scope=\"{}\"", scopes.join(" ")));
+        },
+        OAuth2Error::MissingToken | OAuth2Error::Unavailable(_) => ()
+    }
+
+    if params.is_empty() { "Bearer".into() } else { format!("Bearer {}", params.join(", ")) }
+}
+
+// Fail a request with `err`.
+fn reject(realm: Option<&String>, err: OAuth2Error, scopes: &[String]) -> IronError {
+    let status = match err {
+        OAuth2Error::MissingToken | OAuth2Error::InvalidToken(_) => status::Unauthorized,
+        OAuth2Error::InsufficientScope => status::Forbidden,
+        OAuth2Error::Unavailable(_) => status::ServiceUnavailable
+    };
+    let challenge = challenge(realm, &err, scopes);
+    let mut error = IronError::new(err, status);
+    if status != status::ServiceUnavailable {
+        error.response.headers.set_raw("WWW-Authenticate", vec![challenge.into_bytes()]);
+    }
+    error
+}
+
+fn check_scopes(token: &AccessToken, scopes: &[String]) -> Result<(), OAuth2Error> {
+    if scopes.iter().all(|scope| token.has_scope(scope)) {
+        Ok(())
+    } else {
+        Err(OAuth2Error::InsufficientScope)
+    }
+}
+
+impl BeforeMiddleware for OAuth2Resource {
+    fn before(&self, req: &mut Request) -> IronResult<()> {
+        let token = {
+            let bearer = req.headers.get::<headers::Authorization<headers::Bearer>>();
+            match bearer {
+                Some(bearer) => self.validate(&bearer.token),
+                None => Err(OAuth2Error::MissingToken)
+            }
+        };
+
+        let token = try!(token.and_then(|token| check_scopes(&token, &self.scopes).map(|_| token))
+                         .map_err(|e| reject(self.realm.as_ref(), e, &self.scopes)));
+        req.extensions.insert::<AccessToken>(token);
+        Ok(())
+    }
+}
+
+/// Middleware rejecting requests whose `AccessToken` lacks any of a set of
+/// scopes, for routes needing more than an `OAuth2Resource` linked before
+/// it requires.
+pub struct RequireScopes {
+    scopes: Vec<String>
+}
+
+impl RequireScopes {
+    /// Require all of `scopes`.
+    pub fn new(scopes: &[&str]) -> RequireScopes {
+        RequireScopes { scopes: scopes.iter().map(|&scope| scope.into()).collect() }
+    }
+}
+
+impl BeforeMiddleware for RequireScopes {
+    fn before(&self, req: &mut Request) -> IronResult<()> {
+        let result = match AccessToken::of(req) {
+            Some(token) => check_scopes(token, &self.scopes),
+            None => Err(OAuth2Error::MissingToken)
+        };
+        result.map_err(|e| reject(None, e, &self.scopes))
+    }
+}
+
+/// The reasons a request can be refused by `OAuth2Resource`.
+#[derive(Debug)]
+pub enum OAuth2Error {
+    /// The request has no bearer token.
+    MissingToken,
+
+    /// The token is malformed, forged, expired or revoked.
+    InvalidToken(String),
+
+    /// The token lacks a required scope.
+    InsufficientScope,
+
+    /// The introspection endpoint could not be asked.
+    Unavailable(String)
+}
+
+impl fmt::Display for OAuth2Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            OAuth2Error::InvalidToken(ref message) |
+            OAuth2Error::Unavailable(ref message) => {
+                write!(f, "{}: {}", self.description(), message)
+            },
+            _ => f.write_str(self.description())
+        }
+    }
+}
+
+impl Error for OAuth2Error {
+    fn description(&self) -> &str {
+        match *self {
+            OAuth2Error::MissingToken => "Missing bearer token",
+            OAuth2Error::InvalidToken(_) => "Invalid bearer token",
+            OAuth2Error::InsufficientScope => "Insufficient scope",
+            OAuth2Error::Unavailable(_) => "Token introspection failed"
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use rustc_serialize::base64::{ToBase64, URL_SAFE};
+
+    use cache::{Cache, MemoryCache, SharedCache};
+    use checksum::hmac_sha256;
+    use clock::{ManualClock, unix_seconds};
+    use headers::{Authorization, Basic};
+    use method::Method;
+    use status;
+    use test::{MiddlewareHarness, StubRequest};
+    use {Chain, Iron, IronError, Request, Response};
+    use super::{AccessToken, OAuth2Resource, RequireScopes};
+
+    fn jwt(secret: &[u8], claims: &str) -> String {
+        let signed = format!("{}.{}", br#"{"alg":"HS256","typ":"JWT"}"#.to_base64(URL_SAFE),
+                             claims.as_bytes().to_base64(URL_SAFE));
+        format!("{}.{}", signed, hmac_sha256(secret, signed.as_bytes()).to_base64(URL_SAFE))
+    }
+
+    fn harness(oauth: OAuth2Resource, admin: bool) -> MiddlewareHarness<Chain> {
+        let mut chain = Chain::new(|req: &mut Request| {
+            let subject = AccessToken::of(req).unwrap().subject.clone().unwrap();
+            Ok(Response::with((status::Ok, subject)))
+        });
+        chain.link_before(oauth);
+        if admin { chain.link_before(RequireScopes::new(&["admin"])); }
+        MiddlewareHarness::new(chain)
+    }
+
+    fn get(harness: &MiddlewareHarness<Chain>, token: Option<&str>)
+           -> (Option<status::Status>, Option<String>) {
+        let mut req = StubRequest::new(Method::Get, "http://localhost/");
+        if let Some(token) = token {
+            req = req.raw_header("Authorization", &format!("Bearer {}", token));
+        }
+        let run = harness.handle(req);
+        let challenge = run.response().headers.get_raw("WWW-Authenticate")
+            .map(|values| String::from_utf8(values[0].clone()).unwrap());
+        (run.status(), challenge)
+    }
+
+    #[test]
+    fn test_jwt() {
+        let clock = ManualClock::new();
+        let now = unix_seconds(&clock);
+        let mut oauth = OAuth2Resource::jwt(b"secret");
+        oauth.require_scopes(&["read"]).audience("api").clock(clock.clone());
+        let harness = harness(oauth, false);
+
+        let claims = format!(r#"{{"sub":"ann","aud":["api"],"scope":"read write","exp":{}}}"#,
+                             now + 60);
+        let token = jwt(b"secret", &claims);
+        assert_eq!(get(&harness, Some(&token)), (Some(status::Ok), None));
+
+        assert_eq!(get(&harness, None), (Some(status::Unauthorized), Some("Bearer".into())));
+        assert_eq!(get(&harness, Some(&jwt(b"other", &claims))).0, Some(status::Unauthorized));
+
+        let claims = format!(r#"{{"sub":"ann","aud":"api","scope":"write","exp":{}}}"#, now + 60);
+        assert_eq!(get(&harness, Some(&jwt(b"secret", &claims))),
+                   (Some(status::Forbidden),
+                    Some(r#"Bearer error="insufficient_scope", scope="read""#.into())));
+
+        clock.advance(Duration::from_secs(60));
+        assert_eq!(get(&harness, Some(&token)),
+                   (Some(status::Unauthorized),
+                    Some(r#"Bearer error="invalid_token", error_description="Token expired""#
+                         .into())));
+    }
+
+    #[test]
+    fn test_introspection() {
+        let mut listening = Iron::new(|req: &mut Request| {
+            let body = try!(req.body_string(1024).map_err(IronError::from));
+            let authorized = req.headers.get::<Authorization<Basic>>() == Some(&Authorization(
+                Basic { username: "api".into(), password: Some("pw".into()) }));
+            let answer = match &*body {
+                _ if !authorized => r#"{"active":false}"#,
+                "token=good&token_type_hint=access_token" => {
+                    r#"{"active":true,"sub":"bob","scope":"read"}"#
+                },
+                _ => r#"{"active":false}"#
+            };
+            Ok(Response::with((status::Ok, answer)))
+        }).http("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/introspect", listening.socket);
+        listening.close().unwrap();
+
+        let cache = MemoryCache::new(10);
+        let mut oauth = OAuth2Resource::introspection(&url, "api", "pw");
+        oauth.cache(cache);
+        let token = oauth.validate("good").unwrap();
+        assert_eq!(token.subject, Some("bob".into()));
+        assert!(oauth.validate("bad").is_err());
+
+        let harness = harness(oauth, true);
+        assert_eq!(get(&harness, Some("good")).0, Some(status::Forbidden));
+    }
+
+    #[test]
+    fn test_cache() {
+        let clock = ManualClock::new();
+        let expires = unix_seconds(&clock) + 30;
+        let cache = SharedCache::new(MemoryCache::with_clock(10, clock.clone()));
+        let mut oauth = OAuth2Resource::jwt(b"secret");
+        oauth.clock(clock.clone()).cache(cache.clone());
+
+        let token = jwt(b"secret", &format!(r#"{{"sub":"ann","exp":{}}}"#, expires));
+        assert!(oauth.validate(&token).is_ok());
+
+        // A cached token is not validated again, but still expires.
+        let claims = format!(r#"{{"sub":"bob","exp":{}}}"#, expires);
+        cache.set(&oauth.cache_key(&token), claims.into_bytes(), None);
+        assert_eq!(oauth.validate(&token).unwrap().subject, Some("bob".into()));
+        clock.advance(Duration::from_secs(30));
+        assert!(oauth.validate(&token).is_err());
+    }
+
+    #[test]
+    fn test_shared_cache() {
+        let clock = ManualClock::new();
+        let now = unix_seconds(&clock);
+        let cache = SharedCache::new(MemoryCache::with_clock(10, clock.clone()));
+        let resource = |secret: &[u8], audience: &str| {
+            let mut oauth = OAuth2Resource::jwt(secret);
+            oauth.audience(audience).clock(clock.clone()).cache(cache.clone());
+            oauth
+        };
+        let a = resource(b"secret", "a");
+        let b = resource(b"secret", "b");
+        let c = resource(b"other", "a");
+
+        let token = jwt(b"secret", &format!(r#"{{"sub":"ann","aud":"a","exp":{}}}"#, now + 60));
+        assert!(a.validate(&token).is_ok());
+        assert!(a.validate(&token).is_ok());
+        assert!(b.validate(&token).is_err());
+        assert!(c.validate(&token).is_err());
+    }
+}
@@ -0,0 +1,167 @@
+//! Consistent pagination for list endpoints.
+//!
+//! `Pagination` reads the `page` and `per_page` query parameters of a
+//! request, and produces a modifier which describes the surrounding pages
+//! with `Link` and `X-Total-Count` headers once the total is known:
+//!
+//! ```ignore
+//! let pagination = Pagination::from_request(req, 30, 100);
+//! let (items, total) = load_items(pagination.offset(), pagination.limit());
+//! Ok(Response::with((status::Ok, render(items), pagination.headers(&req.url, total))))
+//! ```
+
+use modifier::Modifier;
+use url;
+
+use {Request, Response, Url};
+
+/// The page of results requested by a client.
+///
+/// Pages are numbered from 1.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Pagination {
+    /// The requested page, at least 1.
+    pub page: u64,
+
+    /// The number of items on each page, at least 1.
+    pub per_page: u64
+}
+
+impl Pagination {
+    /// Read `page` and `per_page` from the query string of `req`.
+    ///
+    /// Missing or invalid values fall back to the first page and to
+    /// `default_per_page` items per page, and `per_page` is capped at
+    /// `max_per_page`.
+    pub fn from_request(req: &Request, default_per_page: u64, max_per_page: u64) -> Pagination {
+        Pagination::from_query(req.url.query().unwrap_or(""), default_per_page, max_per_page)
+    }
+
+    /// Read `page` and `per_page` from a raw query string, as `from_request`.
+    pub fn from_query(query: &str, default_per_page: u64, max_per_page: u64) -> Pagination {
+        let mut page = None;
+        let mut per_page = None;
+
+        for (key, value) in url::query_pairs(query).filter_map(Result::ok) {
+            match &*key {
+                "page" => page = value.parse::<u64>().ok(),
+                "per_page" => per_page = value.parse::<u64>().ok(),
+                _ => {}
+            }
+        }
+
+        Pagination {
+            page: page.unwrap_or(1).max(1),
+            per_page: per_page.unwrap_or(default_per_page).max(1).min(max_per_page.max(1))
+        }
+    }
+
+    /// The number of items before the requested page.
+    pub fn offset(&self) -> u64 {
+        (self.page - 1).saturating_mul(self.per_page)
+    }
+
+    /// The maximum number of items on the requested page.
+    pub fn limit(&self) -> u64 {
+        self.per_page
+    }
+
+    /// A modifier which sets the `X-Total-Count` header to `total`, and adds a
+    /// `Link` header pointing to the previous and next pages of `url`, when
+    /// there are any.
+    pub fn headers(&self, url: &Url, total: u64) -> PaginationHeaders {
+        let mut links = vec![];
+
+        if self.page > 1 {
+            links.push((self.page_url(url, self.page - 1), "prev"));
+        }
+
+        if self.page.saturating_mul(self.per_page) < total {
+            links.push((self.page_url(url, self.page + 1), "next"));
+        }
+
+        PaginationHeaders {
+            total: total,
+            links: links.into_iter()
+                .map(|(url, rel)| format!("<{}>; rel=\"{}\"", url, rel))
+                .collect()
+        }
+    }
+
+    // `url` with its `page` and `per_page` parameters replaced.
+    fn page_url(&self, url: &Url, page: u64) -> String {
+        let mut url = url.clone().into_generic_url();
+
+        let pairs = url.query_pairs()
+            .filter(|&(ref key, _)| key != "page" && key != "per_page")
+            .map(|(key, value)| (key.into_owned(), value.into_owned()))
+            .collect::<Vec<_>>();
+
+        url.query_pairs_mut()
+            .clear()
+            .extend_pairs(pairs)
+            .append_pair("page", &page.to_string())
+            .append_pair("per_page", &self.per_page.to_string());
+
+        url.to_string()
+    }
+}
+
+/// Sets the `Link` and `X-Total-Count` headers of a paginated response.
+///
+/// Created by `Pagination::headers`.
+#[derive(Debug, Clone)]
+pub struct PaginationHeaders {
+    total: u64,
+    links: Vec<String>
+}
+
+impl Modifier<Response> for PaginationHeaders {
+    fn modify(self, res: &mut Response) {
+        res.headers.set_raw("X-Total-Count", vec![self.total.to_string().into_bytes()]);
+
+        if !self.links.is_empty() {
+            res.headers.set_raw("Link", vec![self.links.join(", ").into_bytes()]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Pagination;
+    use {Response, Set, Url};
+
+    #[test]
+    fn test_from_query() {
+        assert_eq!(Pagination::from_query("page=3&per_page=20", 30, 100),
+                   Pagination { page: 3, per_page: 20 });
+        assert_eq!(Pagination::from_query("page=0&per_page=500", 30, 100),
+                   Pagination { page: 1, per_page: 100 });
+        assert_eq!(Pagination::from_query("page=x", 30, 100),
+                   Pagination { page: 1, per_page: 30 });
+    }
+
+    #[test]
+    fn test_offset() {
+        assert_eq!(Pagination { page: 3, per_page: 20 }.offset(), 40);
+    }
+
+    #[test]
+    fn test_headers() {
+        let url = Url::parse("http://example.com/items?q=x&page=2&per_page=10").unwrap();
+        let pagination = Pagination { page: 2, per_page: 10 };
+        let res = Response::new().set(pagination.headers(&url, 25));
+
+        assert_eq!(res.headers.get_raw("X-Total-Count").unwrap(), &[b"25".to_vec()]);
+        assert_eq!(String::from_utf8(res.headers.get_raw("Link").unwrap()[0].clone()).unwrap(),
+                   "<http://example.com/items?q=x&page=1&per_page=10>; rel=\"prev\", \
+                    <http://example.com/items?q=x&page=3&per_page=10>; rel=\"next\"");
+    }
+
+    #[test]
+    fn test_last_page_has_no_next() {
+        let url = Url::parse("http://example.com/items").unwrap();
+        let res = Response::new().set(Pagination { page: 1, per_page: 10 }.headers(&url, 10));
+        assert!(res.headers.get_raw("Link").is_none());
+    }
+}
@@ -0,0 +1,299 @@
+//! Parsing requests from raw bytes, without a connection.
+//!
+//! `request_from_bytes` runs a complete HTTP/1 request through the same
+//! parsing and checks the server applies to requests arriving on a socket,
+//! including the `Limits` on URIs and headers, framing checks and `Host`
+//! validation, and returns the `Request` a handler would see. Being a pure
+//! function of its input, it is suitable as a fuzzing entry point:
+//!
+//! ```
+//! use iron::parse::request_from_bytes;
+//! use iron::status;
+//!
+//! let req = request_from_bytes(b"GET /a?b=c HTTP/1.1\r\nHost: example.com\r\n\r\n").unwrap();
+//! assert_eq!(req.url.path(), vec!["a"]);
+//!
+//! let err = request_from_bytes(b"GET / HTTP/1.1\r\n\r\n").unwrap_err();
+//! assert_eq!(err.status, status::BadRequest);
+//! ```
+
+use std::error::Error;
+use std::fmt;
+use std::io::Read;
+use std::net::SocketAddr;
+
+use hyper::Error as HttpError;
+use hyper::buffer::BufReader;
+use hyper::http::h1::{self, HttpReader};
+use hyper::uri::RequestUri;
+
+use request::Body;
+use status::{self, Status};
+use {headers, Headers, Limits, Protocol, Request};
+
+/// Why a request could not be parsed, and the status the server answers it
+/// with.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseError {
+    /// The status of the response the server would send.
+    pub status: Status,
+
+    /// A description of the problem.
+    pub reason: String
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} ({})", self.reason, self.status)
+    }
+}
+
+impl Error for ParseError {
+    fn description(&self) -> &str {
+        &self.reason
+    }
+}
+
+impl From<Rejection> for ParseError {
+    fn from((status, reason): Rejection) -> ParseError {
+        ParseError { status: status, reason: reason.into() }
+    }
+}
+
+fn bad_request<S: Into<String>>(reason: S) -> ParseError {
+    ParseError { status: status::BadRequest, reason: reason.into() }
+}
+
+/// Parse a request, including its body, from `bytes`, using the default
+/// `Limits`.
+///
+/// The request appears to come from `127.0.0.1:50000` to a plain HTTP
+/// server on `127.0.0.1:80`, and its body is held in memory, so it can be
+/// rewound. Bytes after the end of the request are ignored.
+pub fn request_from_bytes(bytes: &[u8]) -> Result<Request<'static, 'static>, ParseError> {
+    request_from_bytes_with(bytes, &Limits::default())
+}
+
+/// Parse a request, including its body, from `bytes`, checking it against
+/// `limits`.
+pub fn request_from_bytes_with(bytes: &[u8], limits: &Limits)
+                               -> Result<Request<'static, 'static>, ParseError> {
+    let mut input = BufReader::new(bytes);
+
+    // hyper reports running out of input part way through the head as the
+    // head being too large, as it would be if its buffer were full.
+    let head = try!(h1::parse_request(&mut input).map_err(|e| match e {
+        HttpError::TooLarge if bytes.len() < MAX_HEAD && !has_end_of_head(bytes) => {
+            bad_request("Incomplete request head")
+        },
+        HttpError::TooLarge => ParseError {
+            status: status::RequestHeaderFieldsTooLarge,
+            reason: "Request head too large".into()
+        },
+        e => bad_request(format!("Invalid request: {}", e))
+    }));
+
+    try!(check_uri(&head.subject.1, limits));
+    try!(check_headers(&head.headers, limits));
+
+    // `check_headers` has made sure that any transfer coding ends in chunked.
+    let mut reader = match head.headers.get::<headers::TransferEncoding>() {
+        Some(_) => HttpReader::ChunkedReader(&mut input, None),
+        None => match head.headers.get::<headers::ContentLength>() {
+            Some(&headers::ContentLength(length)) => HttpReader::SizedReader(&mut input, length),
+            None => HttpReader::EmptyReader(&mut input)
+        }
+    };
+
+    let mut body = vec![];
+    try!(reader.read_to_end(&mut body).map_err(|e| bad_request(format!("Invalid body: {}", e))));
+
+    let remote_addr = SocketAddr::from(([127, 0, 0, 1], 50000));
+    let local_addr = SocketAddr::from(([127, 0, 0, 1], 80));
+    let req = try!(Request::from_parts(head, Body::from_bytes(body), remote_addr, local_addr,
+                                       &Protocol::Http).map_err(bad_request));
+    Ok(req)
+}
+
+// The size of hyper's buffer for request heads.
+const MAX_HEAD: usize = 8192 + 4096 * 100;
+
+fn has_end_of_head(bytes: &[u8]) -> bool {
+    bytes.windows(4).any(|w| w == b"\r\n\r\n") || bytes.windows(2).any(|w| w == b"\n\n")
+}
+
+// Checks shared with the server, which applies them before handing a request
+// to its handler.
+
+#[doc(hidden)]
+pub type Rejection = (Status, &'static str);
+
+// Check the length of a request's target against `limits`.
+#[doc(hidden)]
+pub fn check_uri(uri: &RequestUri, limits: &Limits) -> Result<(), Rejection> {
+    let length = match *uri {
+        RequestUri::AbsolutePath(ref path) => path.len(),
+        ref uri => uri.to_string().len()
+    };
+
+    if length > limits.max_uri_length { return Err((status::UriTooLong, "URI too long")) }
+    Ok(())
+}
+
+// Check the headers of a request against `limits`, and for framing which
+// servers and proxies could disagree about, letting a request be smuggled
+// inside another.
+#[doc(hidden)]
+pub fn check_headers(headers: &Headers, limits: &Limits) -> Result<(), Rejection> {
+    let too_large = |reason| Err((status::RequestHeaderFieldsTooLarge, reason));
+    let invalid = |reason| Err((status::BadRequest, reason));
+
+    let mut count = 0;
+    let mut bytes = 0;
+
+    for header in headers.iter() {
+        let values = headers.get_raw(header.name()).unwrap_or(&[]);
+        count += values.len();
+
+        for value in values {
+            let size = header.name().len() + value.len();
+            if size > limits.max_header_size { return too_large("Header too large") }
+            bytes += size;
+
+            if value.iter().any(|&b| b == b'\r' || b == b'\n' || b == 0) {
+                return invalid("Invalid character in header value");
+            }
+        }
+    }
+
+    if count > limits.max_headers { return too_large("Too many headers") }
+    if bytes > limits.max_header_bytes { return too_large("Headers too large") }
+
+    if let Some(lengths) = headers.get_raw("Content-Length") {
+        if headers.has::<headers::TransferEncoding>() {
+            return invalid("Both Content-Length and Transfer-Encoding given");
+        }
+        if lengths.len() > 1 || lengths[0].contains(&b',') {
+            return invalid("Multiple Content-Length values");
+        }
+        if headers.get::<headers::ContentLength>().is_none() {
+            return invalid("Invalid Content-Length");
+        }
+    }
+
+    // Without chunked as the final coding, the end of the body could only be
+    // found by closing the connection, which a request cannot do.
+    if headers.get_raw("Transfer-Encoding").is_some() {
+        match headers.get::<headers::TransferEncoding>() {
+            Some(codings) if codings.last() == Some(&headers::Encoding::Chunked) => {},
+            Some(_) => return invalid("Request body is not chunked"),
+            None => return invalid("Invalid Transfer-Encoding")
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Read;
+
+    use hyper::uri::RequestUri;
+
+    use method::Method;
+    use request::HttpVersion;
+    use {status, Headers, Limits};
+    use super::{check_headers, check_uri, request_from_bytes};
+
+    #[test]
+    fn test_request_from_bytes() {
+        let mut req = request_from_bytes(b"POST /items HTTP/1.1\r\nHost: example.com:8080\r\n\
+                                           Content-Length: 5\r\n\r\nhello, and more").unwrap();
+        assert_eq!(req.method, Method::Post);
+        assert_eq!(req.version, HttpVersion::Http11);
+        assert!(!req.is_secure());
+        assert_eq!(req.url.to_string(), "http://example.com:8080/items");
+
+        let mut body = String::new();
+        req.body.read_to_string(&mut body).unwrap();
+        assert_eq!(body, "hello");
+
+        let req = request_from_bytes(b"GET / HTTP/1.0\r\n\r\n").unwrap();
+        assert_eq!(req.version, HttpVersion::Http10);
+
+        let mut req = request_from_bytes(b"GET https://example.com/ HTTP/1.1\r\n\r\n").unwrap();
+        assert!(!req.is_secure());
+        req.url = ::Url::parse("https://example.com/").unwrap();
+        assert!(!req.is_secure());
+    }
+
+    #[test]
+    fn test_chunked() {
+        let mut req = request_from_bytes(b"PUT / HTTP/1.1\r\nHost: a\r\n\
+                                           Transfer-Encoding: chunked\r\n\r\n\
+                                           3\r\nabc\r\n2\r\nde\r\n0\r\n\r\n").unwrap();
+        let mut body = String::new();
+        req.body.read_to_string(&mut body).unwrap();
+        assert_eq!(body, "abcde");
+    }
+
+    #[test]
+    fn test_errors() {
+        let status = |bytes: &[u8]| request_from_bytes(bytes).err().map(|e| e.status);
+
+        assert_eq!(status(b""), Some(status::BadRequest));
+        assert_eq!(status(b"GET / HTTP/1.1\r\nHost: a\r\n"), Some(status::BadRequest));
+        assert_eq!(status(b"GET / HTTP/1.1\r\nHost: a\r\nContent-Length: 5\r\n\r\nabc"),
+                   Some(status::BadRequest));
+        assert_eq!(status(b"GET / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: gzip\r\n\r\n"),
+                   Some(status::BadRequest));
+
+        let long = format!("GET /{} HTTP/1.1\r\nHost: a\r\n\r\n", "a".repeat(9000));
+        assert_eq!(status(long.as_bytes()), Some(status::UriTooLong));
+
+        let many = format!("GET / HTTP/1.1\r\n{}\r\n", "X-A: 1\r\n".repeat(150));
+        assert_eq!(status(many.as_bytes()), Some(status::RequestHeaderFieldsTooLarge));
+    }
+
+    fn headers(lines: &[(&str, &str)]) -> Headers {
+        let mut headers = Headers::new();
+        for &(name, value) in lines {
+            let mut values = headers.get_raw(name).map(|v| v.to_vec()).unwrap_or_default();
+            values.push(value.as_bytes().to_vec());
+            headers.set_raw(name.to_owned(), values);
+        }
+        headers
+    }
+
+    #[test]
+    fn test_check_headers() {
+        let limits = Limits::default();
+        let check = |lines: &[(&str, &str)]| check_headers(&headers(lines), &limits);
+
+        assert!(check(&[("Host", "example.com"), ("Content-Length", "5")]).is_ok());
+        assert!(check(&[("Content-Length", "5"), ("Transfer-Encoding", "chunked")]).is_err());
+        assert!(check(&[("Content-Length", "5"), ("Content-Length", "5")]).is_err());
+        assert!(check(&[("Content-Length", "5, 6")]).is_err());
+        assert!(check(&[("Content-Length", "five")]).is_err());
+        assert!(check(&[("X-Evil", "a\rb")]).is_err());
+        assert!(check(&[("Transfer-Encoding", "gzip, chunked")]).is_ok());
+        assert!(check(&[("Transfer-Encoding", "chunked, gzip")]).is_err());
+        assert!(check(&[("Transfer-Encoding", "gzip")]).is_err());
+
+        let small = Limits { max_uri_length: 5, max_headers: 2, max_header_size: 6,
+                             max_header_bytes: 10 };
+        let status = |lines: &[(&str, &str)]| {
+            check_headers(&headers(lines), &small).err().map(|(status, _)| status)
+        };
+        assert_eq!(status(&[("A", "1"), ("B", "2")]), None);
+        assert_eq!(status(&[("A", "1"), ("B", "2"), ("C", "3")]),
+                   Some(status::RequestHeaderFieldsTooLarge));
+        assert_eq!(status(&[("A", "123456")]), Some(status::RequestHeaderFieldsTooLarge));
+        assert_eq!(status(&[("A", "12345"), ("B", "12345")]),
+                   Some(status::RequestHeaderFieldsTooLarge));
+
+        assert!(check_uri(&RequestUri::AbsolutePath("/abcd".into()), &small).is_ok());
+        assert_eq!(check_uri(&RequestUri::AbsolutePath("/abcde".into()), &small).unwrap_err().0,
+                   status::UriTooLong);
+    }
+}
@@ -0,0 +1,412 @@
+//! A generic connection pool, for database drivers and other clients.
+//!
+//! Drivers implement `Connection` for their connection type, and a `Pool`
+//! opens connections with a given function, up to a maximum number. As
+//! middleware, the pool checks a connection out for each request before the
+//! handler runs, and checks it back in once the response has been produced:
+//!
+//! ```ignore
+//! let mut pool = Pool::new(|| PgConnection::connect(DATABASE_URL).map_err(Box::from));
+//! pool.max_size(16).timeout(Duration::from_secs(5));
+//!
+//! let pool = Arc::new(pool);
+//! chain.link_before(pool.clone());
+//! chain.link_after(pool);
+//!
+//! // In a handler:
+//! let conn = pool::connection::<PgConnection>(req).unwrap();
+//! ```
+//!
+//! Connections are returned to the pool when their `Pooled` guard is dropped,
+//! so they are not leaked even if a handler panics. Connections in use
+//! during a panic are closed rather than reused, since they may have been
+//! left in an inconsistent state. This includes panics caught by
+//! `DevErrorPage` or the server's `internal_error` handler, which raise the
+//! request's `PanicFlag`.
+
+use std::error::Error;
+use std::fmt;
+use std::marker::PhantomData;
+use std::mem;
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Mutex, MutexGuard, Condvar};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use typemap::Key;
+
+use middleware::PanicFlag;
+use {status, BeforeMiddleware, AfterMiddleware, Request, Response, IronResult, IronError};
+
+/// A connection which can be pooled.
+pub trait Connection: Send + 'static {
+    /// Whether the connection can still be used.
+    ///
+    /// Called on idle connections before they are checked out; unhealthy
+    /// connections are closed. The default assumes connections stay healthy.
+    fn is_healthy(&mut self) -> bool { true }
+}
+
+/// A pool of connections of type `C`.
+pub struct Pool<C: Connection> {
+    shared: Arc<Shared<C>>,
+    timeout: Duration
+}
+
+struct Shared<C> {
+    connect: Box<Fn() -> Result<C, Box<Error + Send + Sync>> + Send + Sync>,
+    state: Mutex<State<C>>,
+    returned: Condvar
+}
+
+struct State<C> {
+    idle: Vec<C>,
+    // The number of connections open, idle or checked out.
+    open: usize,
+    max_size: usize
+}
+
+impl<C: Connection> Pool<C> {
+    /// Create a pool which opens connections with `connect`.
+    ///
+    /// Defaults to at most ten connections, and to waiting up to thirty
+    /// seconds for one to become available.
+    pub fn new<F>(connect: F) -> Pool<C>
+    where F: Fn() -> Result<C, Box<Error + Send + Sync>> + Send + Sync + 'static {
+        Pool {
+            shared: Arc::new(Shared {
+                connect: Box::new(connect),
+                state: Mutex::new(State { idle: vec![], open: 0, max_size: 10 }),
+                returned: Condvar::new()
+            }),
+            timeout: Duration::from_secs(30)
+        }
+    }
+
+    /// Set the maximum number of open connections.
+    ///
+    /// ## Panics
+    ///
+    /// Panics if `max_size` is zero.
+    pub fn max_size(&mut self, max_size: usize) -> &mut Pool<C> {
+        assert!(max_size > 0, "Pool max_size must be positive");
+        self.shared.lock().max_size = max_size;
+        self
+    }
+
+    /// Set how long `get` waits for a connection when all are in use.
+    pub fn timeout(&mut self, timeout: Duration) -> &mut Pool<C> {
+        self.timeout = timeout;
+        self
+    }
+
+    /// The number of connections currently open, including those in use.
+    pub fn open(&self) -> usize {
+        self.shared.lock().open
+    }
+
+    /// The number of open connections not currently in use.
+    pub fn idle(&self) -> usize {
+        self.shared.lock().idle.len()
+    }
+
+    /// Check out a connection, reusing a healthy idle one if there is one and
+    /// opening a new one otherwise.
+    ///
+    /// If `max_size` connections are already in use, waits for one to be
+    /// returned, failing with `PoolError::Timeout` after the pool's timeout.
+    pub fn get(&self) -> Result<Pooled<C>, PoolError> {
+        let deadline = Instant::now() + self.timeout;
+        let mut state = self.shared.lock();
+
+        loop {
+            if let Some(mut conn) = state.idle.pop() {
+                // Health checks may be slow, so are made without the lock.
+                drop(state);
+                let slot = Slot(&self.shared);
+                if conn.is_healthy() {
+                    mem::forget(slot);
+                    return Ok(self.pooled(conn));
+                }
+                drop(slot);
+                state = self.shared.lock();
+                continue;
+            }
+
+            if state.open < state.max_size {
+                state.open += 1;
+                drop(state);
+
+                let slot = Slot(&self.shared);
+                return match (self.shared.connect)() {
+                    Ok(conn) => {
+                        mem::forget(slot);
+                        Ok(self.pooled(conn))
+                    },
+                    Err(e) => Err(PoolError::Connect(e))
+                };
+            }
+
+            let now = Instant::now();
+            if now >= deadline { return Err(PoolError::Timeout) }
+
+            state = self.shared.returned.wait_timeout(state, deadline - now)
+                .unwrap_or_else(|e| e.into_inner()).0;
+        }
+    }
+
+    fn pooled(&self, conn: C) -> Pooled<C> {
+        Pooled { conn: Some(conn), shared: self.shared.clone(), panicked: None }
+    }
+}
+
+impl<C: Connection> fmt::Debug for Pool<C> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let state = self.shared.lock();
+        write!(f, "Pool {{ open: {}, idle: {}, max_size: {} }}",
+               state.open, state.idle.len(), state.max_size)
+    }
+}
+
+impl<C> Shared<C> {
+    fn lock(&self) -> MutexGuard<State<C>> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+// A place counted in `open` for a connection being opened or checked, given
+// up unless forgotten once the connection is handed out, including when
+// opening or checking it fails or panics.
+struct Slot<'a, C: 'a>(&'a Shared<C>);
+
+impl<'a, C> Drop for Slot<'a, C> {
+    fn drop(&mut self) {
+        self.0.lock().open -= 1;
+        self.0.returned.notify_one();
+    }
+}
+
+/// A connection checked out of a `Pool`, returned to it when dropped.
+pub struct Pooled<C: Connection> {
+    conn: Option<C>,
+    shared: Arc<Shared<C>>,
+    // The flag of the request the connection was checked out for.
+    panicked: Option<PanicFlag>
+}
+
+impl<C: Connection> Deref for Pooled<C> {
+    type Target = C;
+    fn deref(&self) -> &C { self.conn.as_ref().unwrap() }
+}
+
+impl<C: Connection> DerefMut for Pooled<C> {
+    fn deref_mut(&mut self) -> &mut C { self.conn.as_mut().unwrap() }
+}
+
+impl<C: Connection> Drop for Pooled<C> {
+    fn drop(&mut self) {
+        let conn = self.conn.take().unwrap();
+        let mut state = self.shared.lock();
+
+        if thread::panicking() || self.panicked.as_ref().map_or(false, PanicFlag::is_raised) {
+            state.open -= 1;
+        } else {
+            state.idle.push(conn);
+        }
+
+        self.shared.returned.notify_one();
+    }
+}
+
+// The key under which a request's connection is stored.
+struct Checkout<C>(PhantomData<C>);
+
+impl<C: Connection> Key for Checkout<C> { type Value = Pooled<C>; }
+
+/// The connection checked out for `req` by a `Pool<C>`, if there is one.
+pub fn connection<'a, C: Connection>(req: &'a mut Request) -> Option<&'a mut Pooled<C>> {
+    req.extensions.get_mut::<Checkout<C>>()
+}
+
+impl<C: Connection> BeforeMiddleware for Pool<C> {
+    fn before(&self, req: &mut Request) -> IronResult<()> {
+        match self.get() {
+            Ok(mut conn) => {
+                conn.panicked = Some(PanicFlag::of(req));
+                req.extensions.insert::<Checkout<C>>(conn);
+                Ok(())
+            },
+            Err(e @ PoolError::Timeout) => Err(IronError::new(e, status::ServiceUnavailable)),
+            Err(e) => Err(IronError::new(e, status::InternalServerError))
+        }
+    }
+}
+
+impl<C: Connection> AfterMiddleware for Pool<C> {
+    fn after(&self, req: &mut Request, res: Response) -> IronResult<Response> {
+        req.extensions.remove::<Checkout<C>>();
+        Ok(res)
+    }
+
+    fn catch(&self, req: &mut Request, err: IronError) -> IronResult<Response> {
+        req.extensions.remove::<Checkout<C>>();
+        Err(err)
+    }
+}
+
+/// An error checking a connection out of a `Pool`.
+#[derive(Debug)]
+pub enum PoolError {
+    /// No connection became available within the pool's timeout.
+    Timeout,
+
+    /// Opening a new connection failed.
+    Connect(Box<Error + Send + Sync>)
+}
+
+impl fmt::Display for PoolError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PoolError::Timeout => f.write_str("Timed out waiting for a pooled connection"),
+            PoolError::Connect(ref e) => write!(f, "Could not open a pooled connection: {}", e)
+        }
+    }
+}
+
+impl Error for PoolError {
+    fn description(&self) -> &str {
+        match *self {
+            PoolError::Timeout => "Timed out waiting for a pooled connection",
+            PoolError::Connect(_) => "Could not open a pooled connection"
+        }
+    }
+
+    fn cause(&self) -> Option<&Error> {
+        match *self {
+            PoolError::Connect(ref e) => Some(&**e),
+            PoolError::Timeout => None
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::panic::{self, AssertUnwindSafe};
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::thread;
+    use std::time::Duration;
+
+    use method::Method;
+    use middleware::DevErrorPage;
+    use status;
+    use test::{MiddlewareHarness, StubRequest};
+    use {Chain, Request, Response};
+    use super::{connection, Connection, Pool, PoolError};
+
+    struct Conn {
+        healthy: Arc<AtomicBool>
+    }
+
+    impl Connection for Conn {
+        fn is_healthy(&mut self) -> bool { self.healthy.load(Ordering::SeqCst) }
+    }
+
+    fn pool(opened: Arc<AtomicUsize>, healthy: Arc<AtomicBool>) -> Pool<Conn> {
+        let mut pool = Pool::new(move || {
+            opened.fetch_add(1, Ordering::SeqCst);
+            Ok(Conn { healthy: healthy.clone() })
+        });
+        pool.max_size(2).timeout(Duration::from_millis(10));
+        pool
+    }
+
+    #[test]
+    fn test_reuses_connections() {
+        let opened = Arc::new(AtomicUsize::new(0));
+        let pool = pool(opened.clone(), Arc::new(AtomicBool::new(true)));
+
+        drop(pool.get().unwrap());
+        drop(pool.get().unwrap());
+
+        assert_eq!(opened.load(Ordering::SeqCst), 1);
+        assert_eq!(pool.idle(), 1);
+    }
+
+    #[test]
+    fn test_max_size_and_timeout() {
+        let pool = pool(Arc::new(AtomicUsize::new(0)), Arc::new(AtomicBool::new(true)));
+
+        let first = pool.get().unwrap();
+        let _second = pool.get().unwrap();
+
+        match pool.get() {
+            Err(PoolError::Timeout) => {},
+            _ => panic!("expected a timeout")
+        }
+
+        drop(first);
+        assert!(pool.get().is_ok());
+    }
+
+    #[test]
+    fn test_unhealthy_connections_are_replaced() {
+        let opened = Arc::new(AtomicUsize::new(0));
+        let healthy = Arc::new(AtomicBool::new(true));
+        let pool = pool(opened.clone(), healthy.clone());
+
+        drop(pool.get().unwrap());
+        healthy.store(false, Ordering::SeqCst);
+        let _conn = pool.get().unwrap();
+
+        assert_eq!(opened.load(Ordering::SeqCst), 2);
+        assert_eq!(pool.open(), 1);
+    }
+
+    #[test]
+    fn test_connections_in_panicking_threads_are_closed() {
+        let pool = Arc::new(pool(Arc::new(AtomicUsize::new(0)), Arc::new(AtomicBool::new(true))));
+
+        let thread_pool = pool.clone();
+        let result = thread::spawn(move || {
+            let _conn = thread_pool.get().unwrap();
+            panic!("handler failed");
+        }).join();
+
+        assert!(result.is_err());
+        assert_eq!(pool.open(), 0);
+        assert_eq!(pool.idle(), 0);
+    }
+
+    #[test]
+    fn test_connections_in_caught_panics_are_closed() {
+        let pool = Arc::new(pool(Arc::new(AtomicUsize::new(0)), Arc::new(AtomicBool::new(true))));
+        let mut chain = Chain::new(|req: &mut Request| {
+            assert!(connection::<Conn>(req).is_some());
+            if req.url.path() == vec!["panic"] { panic!("handler failed") }
+            Ok(Response::with(status::Ok))
+        });
+        chain.link_around(DevErrorPage::new(true));
+        chain.link_before(pool.clone());
+        chain.link_after(pool.clone());
+        let harness = MiddlewareHarness::new(chain);
+
+        let run = harness.handle(StubRequest::new(Method::Get, "http://localhost/"));
+        assert_eq!(run.status(), Some(status::Ok));
+        assert_eq!(pool.idle(), 1);
+
+        let run = harness.handle(StubRequest::new(Method::Get, "http://localhost/panic"));
+        assert_eq!(run.status(), Some(status::InternalServerError));
+        assert_eq!(pool.open(), 0);
+    }
+
+    #[test]
+    fn test_panicking_connect_gives_up_its_place() {
+        let mut pool = Pool::new(|| -> Result<Conn, _> { panic!("driver bug") });
+        pool.max_size(1).timeout(Duration::from_millis(10));
+
+        assert!(panic::catch_unwind(AssertUnwindSafe(|| pool.get())).is_err());
+        assert_eq!(pool.open(), 0);
+        assert!(panic::catch_unwind(AssertUnwindSafe(|| pool.get())).is_err());
+    }
+}
@@ -0,0 +1,214 @@
+//! Sampling profiler for finding the causes of slow requests.
+//!
+//! A `Profiler`, linked as both `BeforeMiddleware` and `AfterMiddleware`,
+//! records timings for a sample of requests: the whole request, each
+//! middleware call (when tracing is enabled on the `Chain` with
+//! `Chain::trace`) and writing the response. Each sampled request is written
+//! to a sink as one line of JSON in the Trace Event Format, which flame
+//! chart viewers such as `chrome://tracing` and speedscope can load:
+//!
+//! ```ignore
+//! let sink = File::create("profile.json").unwrap();
+//! let profiler = Arc::new(Profiler::new(sink).sample_rate(0.01));
+//! chain.trace(false);
+//! chain.link_before(profiler.clone());
+//! chain.link_after(profiler);
+//! ```
+
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use rustc_serialize::json::{Json, Object, ToJson};
+use typemap::Key;
+
+use middleware::Trace;
+use {BeforeMiddleware, AfterMiddleware, Request, Response, IronResult, IronError};
+
+/// Middleware which profiles a sample of requests and writes the results to
+/// a sink.
+///
+/// Link the same `Profiler` as both `BeforeMiddleware` and
+/// `AfterMiddleware`, usually first and last, through an `Arc`.
+pub struct Profiler {
+    sink: Arc<Mutex<Box<Write + Send>>>,
+    every: usize,
+    seen: AtomicUsize
+}
+
+impl Profiler {
+    /// Write profiles to `sink`, profiling every request.
+    pub fn new<W: Write + Send + 'static>(sink: W) -> Profiler {
+        Profiler {
+            sink: Arc::new(Mutex::new(Box::new(sink))),
+            every: 1,
+            seen: AtomicUsize::new(0)
+        }
+    }
+
+    /// Profile only a fraction `rate` of requests, between `0.0` and `1.0`.
+    ///
+    /// Requests are sampled at even intervals, so a rate of `0.01` profiles
+    /// every hundredth request. A rate of `0.0` profiles none.
+    pub fn sample_rate(mut self, rate: f64) -> Profiler {
+        self.every = if rate > 0.0 { (1.0 / rate.min(1.0)).round() as usize } else { 0 };
+        self
+    }
+
+    fn finish(&self, req: &mut Request, res: &mut Response) {
+        let profile = match req.extensions.remove::<Profiling>() {
+            Some(profile) => profile,
+            None => return
+        };
+
+        let mut events = vec![event(&profile.name, "request", profile.at(profile.start),
+                                    Duration::from_secs(0))];
+        for step in Trace::of(req).iter().filter(|step| step.start >= profile.start) {
+            events.push(event(step.name, &format!("{:?}", step.phase), profile.at(step.start),
+                              step.duration));
+        }
+
+        // The response is written after every middleware has run, so the
+        // profile is completed from its callbacks.
+        let writing = Arc::new(Mutex::new(None));
+        let started = writing.clone();
+        res.on_before_headers(move |_| {
+            *started.lock().unwrap_or_else(|e| e.into_inner()) = Some(Instant::now());
+        });
+
+        let sink = self.sink.clone();
+        res.on_after_body(move |_| {
+            let end = Instant::now();
+            let writing = writing.lock().unwrap_or_else(|e| e.into_inner()).unwrap_or(end);
+            events.push(event("write", "write", profile.at(writing), end - writing));
+            if let Json::Object(ref mut root) = events[0] {
+                root.insert("dur".into(), micros(end - profile.start).to_json());
+            }
+
+            let mut object = Object::new();
+            object.insert("traceEvents".into(), Json::Array(events));
+            object.insert("displayTimeUnit".into(), "ms".to_json());
+            let line = format!("{}\n", Json::Object(object));
+
+            let mut sink = sink.lock().unwrap_or_else(|e| e.into_inner());
+            if let Err(e) = sink.write_all(line.as_bytes()).and_then(|_| sink.flush()) {
+                error!("Error writing profile: {}", e);
+            }
+        });
+    }
+}
+
+impl BeforeMiddleware for Profiler {
+    fn before(&self, req: &mut Request) -> IronResult<()> {
+        if self.every == 0 || self.seen.fetch_add(1, Ordering::Relaxed) % self.every != 0 {
+            return Ok(())
+        }
+
+        req.extensions.insert::<Profiling>(Profile {
+            name: format!("{} /{}", req.method, req.url.path().join("/")),
+            start: Instant::now(),
+            epoch: SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default()
+        });
+        Ok(())
+    }
+}
+
+impl AfterMiddleware for Profiler {
+    fn after(&self, req: &mut Request, mut res: Response) -> IronResult<Response> {
+        self.finish(req, &mut res);
+        Ok(res)
+    }
+
+    fn catch(&self, req: &mut Request, mut err: IronError) -> IronResult<Response> {
+        self.finish(req, &mut err.response);
+        Err(err)
+    }
+}
+
+// The profile being recorded for a request.
+struct Profiling;
+
+impl Key for Profiling { type Value = Profile; }
+
+struct Profile {
+    name: String,
+    start: Instant,
+    // The time since the Unix epoch at `start`.
+    epoch: Duration
+}
+
+impl Profile {
+    // The time since the Unix epoch at `instant`, so that profiles from
+    // different requests line up.
+    fn at(&self, instant: Instant) -> Duration {
+        self.epoch + instant.duration_since(self.start)
+    }
+}
+
+// A complete event, starting `at` since the Unix epoch.
+fn event(name: &str, category: &str, at: Duration, duration: Duration) -> Json {
+    let mut object = Object::new();
+    object.insert("name".into(), name.to_json());
+    object.insert("cat".into(), category.to_json());
+    object.insert("ph".into(), "X".to_json());
+    object.insert("ts".into(), micros(at).to_json());
+    object.insert("dur".into(), micros(duration).to_json());
+    object.insert("pid".into(), 1.to_json());
+    object.insert("tid".into(), 1.to_json());
+    Json::Object(object)
+}
+
+fn micros(duration: Duration) -> u64 {
+    duration.as_secs() * 1_000_000 + duration.subsec_nanos() as u64 / 1_000
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{self, Write};
+    use std::sync::{Arc, Mutex};
+
+    use rustc_serialize::json::Json;
+
+    use method::Method;
+    use response::HttpResponse;
+    use test::StubRequest;
+    use {status, BeforeMiddleware, AfterMiddleware, Headers, Response};
+    use super::Profiler;
+
+    #[derive(Clone)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> { Ok(()) }
+    }
+
+    #[test]
+    fn test_sampling() {
+        let out = Shared(Arc::new(Mutex::new(vec![])));
+        let profiler = Profiler::new(out.clone()).sample_rate(0.5);
+
+        for _ in 0..4 {
+            let mut req = StubRequest::new(Method::Get, "http://localhost/a").build();
+            profiler.before(&mut req).unwrap();
+            let res = profiler.after(&mut req, Response::with((status::Ok, "hi"))).unwrap();
+
+            let (mut out, mut headers) = (vec![], Headers::new());
+            res.write_back(HttpResponse::new(&mut out, &mut headers), &Method::Get);
+        }
+
+        let output = String::from_utf8(out.0.lock().unwrap().clone()).unwrap();
+        let lines = output.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 2);
+
+        let profile = Json::from_str(lines[0]).unwrap();
+        let events = profile.find("traceEvents").unwrap().as_array().unwrap();
+        let names = events.iter().map(|e| e.find("name").unwrap().as_string().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(names, vec!["GET /a", "write"]);
+    }
+}
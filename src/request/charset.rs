@@ -0,0 +1,139 @@
+//! Decoding of request bodies according to their declared charset.
+
+use std::error::Error as StdError;
+use std::fmt;
+use std::io;
+
+/// An error produced while reading a request body as text.
+#[derive(Debug)]
+pub enum BodyError {
+    /// The body could not be read.
+    Io(io::Error),
+
+    /// The body declared a charset which Iron cannot decode.
+    UnsupportedCharset(String),
+
+    /// The body contained bytes which are not valid in its charset.
+    InvalidEncoding(&'static str)
+}
+
+impl fmt::Display for BodyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            BodyError::Io(ref err) => write!(f, "Error reading request body: {}", err),
+            BodyError::UnsupportedCharset(ref charset) => write!(f, "Unsupported charset: {}", charset),
+            BodyError::InvalidEncoding(charset) => write!(f, "Request body is not valid {}", charset)
+        }
+    }
+}
+
+impl StdError for BodyError {
+    fn description(&self) -> &str {
+        match *self {
+            BodyError::Io(ref err) => err.description(),
+            BodyError::UnsupportedCharset(_) => "Unsupported charset",
+            BodyError::InvalidEncoding(_) => "Invalid encoding"
+        }
+    }
+
+    fn cause(&self) -> Option<&StdError> {
+        match *self {
+            BodyError::Io(ref err) => Some(err),
+            _ => None
+        }
+    }
+}
+
+impl From<io::Error> for BodyError {
+    fn from(err: io::Error) -> BodyError {
+        BodyError::Io(err)
+    }
+}
+
+/// Decode `bytes` in the named charset.
+///
+/// Supports UTF-8, US-ASCII, ISO-8859-1 and UTF-16 (with or without a byte
+/// order mark, big endian if there is none).
+pub fn decode(bytes: Vec<u8>, charset: &str) -> Result<String, BodyError> {
+    match &*charset.to_ascii_lowercase() {
+        "utf-8" | "utf8" => {
+            String::from_utf8(bytes).map_err(|_| BodyError::InvalidEncoding("UTF-8"))
+        },
+        "us-ascii" | "ascii" => {
+            if bytes.iter().any(|&b| b >= 0x80) {
+                return Err(BodyError::InvalidEncoding("US-ASCII"));
+            }
+            Ok(bytes.into_iter().map(|b| b as char).collect())
+        },
+        "iso-8859-1" | "iso_8859-1" | "latin1" | "l1" => {
+            // The first 256 code points of Unicode are exactly ISO-8859-1.
+            Ok(bytes.into_iter().map(|b| b as char).collect())
+        },
+        "utf-16" => {
+            if bytes.starts_with(&[0xFF, 0xFE]) {
+                decode_utf16(&bytes[2..], false)
+            } else if bytes.starts_with(&[0xFE, 0xFF]) {
+                decode_utf16(&bytes[2..], true)
+            } else {
+                decode_utf16(&bytes, true)
+            }
+        },
+        "utf-16be" => decode_utf16(&bytes, true),
+        "utf-16le" => decode_utf16(&bytes, false),
+        _ => Err(BodyError::UnsupportedCharset(charset.to_owned()))
+    }
+}
+
+fn decode_utf16(bytes: &[u8], big_endian: bool) -> Result<String, BodyError> {
+    if bytes.len() % 2 != 0 {
+        return Err(BodyError::InvalidEncoding("UTF-16"));
+    }
+
+    let units = bytes.chunks(2).map(|pair| {
+        if big_endian {
+            (pair[0] as u16) << 8 | pair[1] as u16
+        } else {
+            (pair[1] as u16) << 8 | pair[0] as u16
+        }
+    }).collect::<Vec<_>>();
+
+    String::from_utf16(&units).map_err(|_| BodyError::InvalidEncoding("UTF-16"))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{decode, BodyError};
+
+    #[test]
+    fn test_utf8() {
+        assert_eq!(decode("héllo".as_bytes().to_vec(), "UTF-8").unwrap(), "héllo");
+        assert!(decode(vec![0xC3], "utf-8").is_err());
+    }
+
+    #[test]
+    fn test_latin1() {
+        assert_eq!(decode(vec![0x68, 0xE9], "ISO-8859-1").unwrap(), "hé");
+    }
+
+    #[test]
+    fn test_ascii() {
+        assert_eq!(decode(b"plain".to_vec(), "us-ascii").unwrap(), "plain");
+        assert!(decode(vec![0xE9], "us-ascii").is_err());
+    }
+
+    #[test]
+    fn test_utf16() {
+        assert_eq!(decode(vec![0x00, 0x68, 0x00, 0xE9], "utf-16").unwrap(), "hé");
+        assert_eq!(decode(vec![0xFF, 0xFE, 0x68, 0x00], "utf-16").unwrap(), "h");
+        assert_eq!(decode(vec![0x68, 0x00], "utf-16le").unwrap(), "h");
+        assert!(decode(vec![0x00], "utf-16be").is_err());
+    }
+
+    #[test]
+    fn test_unsupported() {
+        match decode(vec![], "koi8-r") {
+            Err(BodyError::UnsupportedCharset(ref charset)) => assert_eq!(charset, "koi8-r"),
+            other => panic!("Unexpected result: {:?}", other)
+        }
+    }
+}
@@ -1,12 +1,14 @@
 //! Iron's HTTP Request representation and associated methods.
 
-use std::io::{self, Read};
+use std::io::{self, Read, Write, Seek, SeekFrom};
 use std::net::SocketAddr;
 use std::fmt::{self, Debug};
+use std::str;
 
-use hyper::uri::RequestUri::{AbsoluteUri, AbsolutePath};
+use hyper::uri::RequestUri::{self, AbsoluteUri, AbsolutePath};
+pub use hyper::version::HttpVersion;
 use hyper::net::NetworkStream;
-use hyper::http::h1::HttpReader;
+use hyper::http::h1::{HttpReader, Incoming};
 
 use typemap::TypeMap;
 use plugin::Extensible;
@@ -16,10 +18,22 @@ pub use hyper::server::request::Request as HttpRequest;
 use hyper::buffer;
 
 pub use self::url::Url;
+pub use self::charset::BodyError;
+pub use self::query::QueryError;
 
 use {Protocol, Plugin, Headers, Set, headers};
+use temp::TempFile;
+use mime::Attr;
+use rustc_serialize::Decodable;
 
 mod url;
+mod charset;
+mod query;
+
+/// The most `raw_body_bytes` allocates for a body before reading it, however
+/// long the `Content-Length` says it is. Longer bodies grow the buffer as
+/// they arrive.
+pub const MAX_PREALLOCATION: u64 = 64 * 1024;
 
 /// The `Request` given to all `Middleware`.
 ///
@@ -27,6 +41,13 @@ mod url;
 /// an `TypeMap` for data communication between middleware.
 pub struct Request<'a, 'b: 'a> {
     /// The requested URL.
+    ///
+    /// For requests whose target is a path, as most are, the host and port
+    /// come from the `Host` header, with the port defaulting to that of the
+    /// scheme. For absolute-form targets, as sent to proxies, the `Host`
+    /// header is ignored. Either way the scheme is that of the server's
+    /// protocol, so `url.scheme()`, `url.host()` and `url.port()` are the
+    /// origin the client asked for.
     pub url: Url,
 
     /// The originating address of the request.
@@ -44,8 +65,16 @@ pub struct Request<'a, 'b: 'a> {
     /// The request method.
     pub method: Method,
 
+    /// The HTTP version of the request.
+    pub version: HttpVersion,
+
     /// Extensible storage for data passed between middleware.
-    pub extensions: TypeMap
+    pub extensions: TypeMap,
+
+    // Whether the server received the request over TLS. Kept apart from the
+    // URL, whose scheme may have been rewritten by middleware.
+    #[doc(hidden)]
+    pub secure: bool
 }
 
 impl<'a, 'b> Debug for Request<'a, 'b> {
@@ -54,6 +83,7 @@ impl<'a, 'b> Debug for Request<'a, 'b> {
 
         try!(writeln!(f, "    url: {:?}", self.url));
         try!(writeln!(f, "    method: {:?}", self.method));
+        try!(writeln!(f, "    version: {}", self.version));
         try!(writeln!(f, "    remote_addr: {:?}", self.remote_addr));
         try!(writeln!(f, "    local_addr: {:?}", self.local_addr));
 
@@ -68,59 +98,295 @@ impl<'a, 'b> Request<'a, 'b> {
     /// This constructor consumes the HttpRequest.
     pub fn from_http(req: HttpRequest<'a, 'b>, local_addr: SocketAddr, protocol: &Protocol)
                      -> Result<Request<'a, 'b>, String> {
-        let (addr, method, headers, uri, _, reader) = req.deconstruct();
-
-        let url = match uri {
-            AbsoluteUri(ref url) => {
-                match Url::from_generic_url(url.clone()) {
-                    Ok(url) => url,
-                    Err(e) => return Err(e)
-                }
-            },
+        let (addr, method, headers, uri, version, reader) = req.deconstruct();
+        let head = Incoming { version: version, subject: (method, uri), headers: headers };
+        Request::from_parts(head, Body::new(reader), addr, local_addr, protocol)
+    }
 
-            AbsolutePath(ref path) => {
-                // Attempt to prepend the Host header (mandatory in HTTP/1.1)
-                let url_string = match headers.get::<headers::Host>() {
-                    Some(ref host) => {
-                        format!("{}://{}:{}{}", protocol.name(), host.hostname, local_addr.port(),
-                                path)
-                    },
-                    None => return Err("No host specified in request".into())
-                };
-
-                match Url::parse(&url_string) {
-                    Ok(url) => url,
-                    Err(e) => return Err(format!("Couldn't parse requested URL: {}", e))
-                }
-            },
-            _ => return Err("Unsupported request URI".into())
-        };
+    // Create a request from a parsed request line and headers, and its body.
+    #[doc(hidden)]
+    pub fn from_parts(head: Incoming<(Method, RequestUri)>, body: Body<'a, 'b>,
+                      remote_addr: SocketAddr, local_addr: SocketAddr, protocol: &Protocol)
+                      -> Result<Request<'a, 'b>, String> {
+        let Incoming { version, subject: (method, uri), headers } = head;
+        let url = try!(request_url(&uri, version, &headers, local_addr, protocol));
 
         Ok(Request {
             url: url,
-            remote_addr: addr,
+            remote_addr: remote_addr,
             local_addr: local_addr,
             headers: headers,
-            body: Body::new(reader),
+            body: body,
             method: method,
-            extensions: TypeMap::new()
+            version: version,
+            extensions: TypeMap::new(),
+            secure: protocol.name() == "https"
         })
     }
+
+    /// Whether the request was received over HTTPS.
+    ///
+    /// This is decided by the protocol the server is listening with, not by
+    /// the URL, so it cannot be changed by a client or by middleware which
+    /// rewrites `url`.
+    pub fn is_secure(&self) -> bool {
+        self.secure
+    }
+
+    /// Read the body as text, decoding it according to the charset given in
+    /// the `Content-Type` header, or as UTF-8 if there is none.
+    ///
+    /// The body is buffered first, so fails if it is longer than `limit`
+    /// bytes, and can be rewound afterwards. Bytes which are invalid in the
+    /// charset produce an error rather than replacement characters.
+    pub fn body_string(&mut self, limit: usize) -> Result<String, BodyError> {
+        let charset = self.headers.get::<headers::ContentType>()
+            .and_then(|content_type| content_type.get_param(Attr::Charset))
+            .map_or_else(|| "utf-8".to_owned(), |charset| charset.as_str().to_owned());
+
+        try!(self.body.buffer(limit));
+
+        let mut bytes = Vec::new();
+        try!(self.body.read_to_end(&mut bytes));
+        charset::decode(bytes, &charset)
+    }
+
+    /// Read the rest of the body as bytes, for binary payloads such as
+    /// protobuf or msgpack messages.
+    ///
+    /// Unlike `body_string`, the bytes are neither decoded nor validated, and
+    /// the body is read straight into the returned buffer, sized from the
+    /// `Content-Length` if there is one, up to `MAX_PREALLOCATION` bytes,
+    /// rather than buffered in the `Body` first. Fails with
+    /// `ErrorKind::InvalidData` if the rest of the body is longer than
+    /// `limit` bytes. The body cannot be read again afterwards, unless it
+    /// had been buffered.
+    pub fn raw_body_bytes(&mut self, limit: usize) -> io::Result<Vec<u8>> {
+        let expected = self.headers.get::<headers::ContentLength>().map_or(0, |length| length.0);
+        let capacity = expected.min(limit as u64).min(MAX_PREALLOCATION);
+        let mut bytes = Vec::with_capacity(capacity as usize);
+
+        try!(self.body.by_ref().take((limit as u64).saturating_add(1)).read_to_end(&mut bytes));
+        if bytes.len() > limit { return Err(too_large(limit as u64)) }
+        Ok(bytes)
+    }
+
+    /// Decode the query string into a `T`.
+    ///
+    /// `T` is typically a struct whose fields correspond to the query
+    /// parameters; see `QueryError` for what can go wrong. A request without
+    /// a query string is decoded as if its query were empty.
+    ///
+    /// ```ignore
+    /// let listing = itry!(req.query_as::<Listing>(), status::BadRequest);
+    /// ```
+    pub fn query_as<T: Decodable>(&self) -> Result<T, QueryError> {
+        query::decode(self.url.query().unwrap_or(""))
+    }
+}
+
+// Work out the URL a request is for from its target and `Host` header,
+// which must be given once, unless the target is in absolute form or the
+// request is HTTP/1.0, and be a valid host with an optional port.
+fn request_url(uri: &RequestUri, version: HttpVersion, headers: &Headers,
+               local_addr: SocketAddr, protocol: &Protocol) -> Result<Url, String> {
+    let path = match *uri {
+        AbsoluteUri(ref url) => {
+            // The scheme is the server's, whatever the client claims.
+            let mut url = url.clone();
+            try!(url.set_scheme(protocol.name())
+                .map_err(|_| format!("Unsupported request URI: {}", url)));
+            return Url::from_generic_url(url)
+        },
+        AbsolutePath(ref path) => path,
+        _ => return Err("Unsupported request URI".into())
+    };
+
+    let authority = match headers.get_raw("Host") {
+        Some(values) if values.len() == 1 => {
+            let value = try!(str::from_utf8(&values[0])
+                .map_err(|_| "Host header is not valid UTF-8".to_owned()));
+            let (host, port) = try!(parse_host(value)
+                .ok_or_else(|| format!("Invalid Host header: {:?}", value)));
+            let port = port.unwrap_or(if protocol.name() == "https" { 443 } else { 80 });
+            format!("{}:{}", host, port)
+        },
+        Some(_) => return Err("Multiple Host headers in request".into()),
+        None if version == HttpVersion::Http10 => local_addr.to_string(),
+        None => return Err("No host specified in request".into())
+    };
+
+    Url::parse(&format!("{}://{}{}", protocol.name(), authority, path))
+        .map_err(|e| format!("Couldn't parse requested URL: {}", e))
+}
+
+// Split a `Host` header into its host, a registered name or a bracketed IP
+// literal, and its port, if any.
+fn parse_host(value: &str) -> Option<(&str, Option<u16>)> {
+    let (host, port) = if value.starts_with('[') {
+        let end = match value.find(']') { Some(end) => end + 1, None => return None };
+        let literal = &value[1..end - 1];
+        if literal.is_empty() || !literal.chars().all(|c| c.is_digit(16) || c == ':' || c == '.') {
+            return None
+        }
+        match &value[end..] {
+            "" => (&value[..end], None),
+            rest if rest.starts_with(':') => (&value[..end], Some(&rest[1..])),
+            _ => return None
+        }
+    } else {
+        let mut parts = value.splitn(2, ':');
+        let host = parts.next().unwrap_or("");
+        let valid = !host.is_empty() &&
+            host.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.' || c == '_');
+        if !valid { return None }
+        (host, parts.next())
+    };
+
+    match port {
+        None => Some((host, None)),
+        Some(port) if !port.is_empty() && port.chars().all(|c| c.is_ascii_digit()) => {
+            port.parse().ok().map(|port| (host, Some(port)))
+        },
+        Some(_) => None
+    }
 }
 
 /// The body of an Iron request,
-pub struct Body<'a, 'b: 'a>(HttpReader<&'a mut buffer::BufReader<&'b mut NetworkStream>>);
+///
+/// Reads are bounded by the request's `Content-Length` (or its chunked
+/// framing), so reading past the end of the body is not possible.
+///
+/// By default the body is read directly from the connection and can only be
+/// read once. Calling `buffer` reads the rest of it into memory, after which
+/// it can be read again from the start with `rewind`, so that several
+/// middleware can each inspect it. Large bodies can instead be buffered in a
+/// temporary file with `buffer_spilling`; the file is deleted along with the
+/// `Request`.
+pub struct Body<'a, 'b: 'a> {
+    // `None` for bodies created by `from_bytes`, which are always buffered.
+    reader: Option<HttpReader<&'a mut buffer::BufReader<&'b mut NetworkStream>>>,
+    buffered: Option<Buffer>
+}
+
+enum Buffer {
+    Memory(io::Cursor<Vec<u8>>),
+    Disk(TempFile)
+}
 
 impl<'a, 'b> Body<'a, 'b> {
     /// Create a new reader for use in an Iron request from a hyper HttpReader.
     pub fn new(reader: HttpReader<&'a mut buffer::BufReader<&'b mut NetworkStream>>) -> Body<'a, 'b> {
-        Body(reader)
+        Body { reader: Some(reader), buffered: None }
+    }
+
+    /// Create a body which reads `bytes` rather than a connection, for
+    /// instance to build requests in tests.
+    ///
+    /// The body is already buffered, so it can be rewound.
+    pub fn from_bytes<B: Into<Vec<u8>>>(bytes: B) -> Body<'a, 'b> {
+        Body { reader: None, buffered: Some(Buffer::Memory(io::Cursor::new(bytes.into()))) }
+    }
+
+    /// Read the rest of the body into memory, so that it can be rewound.
+    ///
+    /// Fails with `ErrorKind::InvalidData` if the remainder of the body is
+    /// longer than `limit` bytes, in which case the body is left unusable.
+    /// Does nothing if the body has already been buffered.
+    pub fn buffer(&mut self, limit: usize) -> io::Result<()> {
+        self.buffer_spilling(limit, limit as u64)
+    }
+
+    /// Buffer the rest of the body, so that it can be rewound, keeping it in
+    /// memory if it is at most `in_memory` bytes long and writing it to a
+    /// temporary file otherwise.
+    ///
+    /// Fails with `ErrorKind::InvalidData` if the remainder of the body is
+    /// longer than `limit` bytes, in which case the body is left unusable.
+    /// Does nothing if the body has already been buffered.
+    pub fn buffer_spilling(&mut self, in_memory: usize, limit: u64) -> io::Result<()> {
+        if self.buffered.is_some() { return Ok(()) }
+
+        let in_memory = in_memory as u64;
+
+        let mut empty = io::empty();
+        let reader: &mut Read = match self.reader {
+            Some(ref mut reader) => reader,
+            None => &mut empty
+        };
+
+        let mut bytes = Vec::new();
+        try!(reader.take(in_memory.saturating_add(1)).read_to_end(&mut bytes));
+
+        let read = bytes.len() as u64;
+        if read > limit { return Err(too_large(limit)) }
+
+        if read <= in_memory {
+            self.buffered = Some(Buffer::Memory(io::Cursor::new(bytes)));
+            return Ok(())
+        }
+
+        let mut file = try!(TempFile::new());
+        try!(file.write_all(&bytes));
+
+        let remaining = limit - read;
+        let copied = try!(io::copy(&mut reader.take(remaining.saturating_add(1)), &mut file));
+        if copied > remaining { return Err(too_large(limit)) }
+
+        try!(file.seek(SeekFrom::Start(0)));
+        self.buffered = Some(Buffer::Disk(file));
+        Ok(())
     }
+
+    /// Whether the body has been buffered by `buffer` or `buffer_spilling`.
+    pub fn is_buffered(&self) -> bool {
+        self.buffered.is_some()
+    }
+
+    /// Start reading the body again from the point at which it was buffered.
+    ///
+    /// Fails with `ErrorKind::Other` if the body has not been buffered.
+    pub fn rewind(&mut self) -> io::Result<()> {
+        match self.buffered {
+            Some(Buffer::Memory(ref mut cursor)) => {
+                cursor.set_position(0);
+                Ok(())
+            },
+            Some(Buffer::Disk(ref mut file)) => file.seek(SeekFrom::Start(0)).map(|_| ()),
+            None => Err(io::Error::new(io::ErrorKind::Other,
+                                       "Cannot rewind a request body which was not buffered"))
+        }
+    }
+
+    /// Buffer the body and read it into a `String`, provided it is valid
+    /// UTF-8 and no longer than `limit` bytes.
+    ///
+    /// Reading starts at the current position, and the body can be rewound
+    /// afterwards.
+    pub fn read_to_string_limited(&mut self, limit: usize) -> io::Result<String> {
+        try!(self.buffer(limit));
+
+        let mut string = String::new();
+        try!(self.read_to_string(&mut string));
+        Ok(string)
+    }
+}
+
+fn too_large(limit: u64) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData,
+                   format!("Request body exceeds limit of {} bytes", limit))
 }
 
 impl<'a, 'b> Read for Body<'a, 'b> {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        self.0.read(buf)
+        match self.buffered {
+            Some(Buffer::Memory(ref mut cursor)) => cursor.read(buf),
+            Some(Buffer::Disk(ref mut file)) => file.read(buf),
+            None => match self.reader {
+                Some(ref mut reader) => reader.read(buf),
+                None => Ok(0)
+            }
+        }
     }
 }
 
@@ -137,3 +403,100 @@ impl<'a, 'b> Extensible for Request<'a, 'b> {
 
 impl<'a, 'b> Plugin for Request<'a, 'b> {}
 impl<'a, 'b> Set for Request<'a, 'b> {}
+
+#[cfg(test)]
+mod test {
+    use std::net::SocketAddr;
+
+    use hyper::uri::RequestUri::{AbsoluteUri, AbsolutePath};
+    use hyper::version::HttpVersion::{Http10, Http11};
+
+    use std::io;
+
+    use method::Method;
+    use test::StubRequest;
+    use {Headers, Protocol};
+    use super::{parse_host, request_url};
+
+    fn url_for(target: &str, hosts: &[&str]) -> Result<String, String> {
+        let mut headers = Headers::new();
+        if !hosts.is_empty() {
+            headers.set_raw("Host", hosts.iter().map(|h| h.as_bytes().to_vec()).collect());
+        }
+        let uri = if target.starts_with('/') {
+            AbsolutePath(target.into())
+        } else {
+            AbsoluteUri(target.parse().unwrap())
+        };
+        let local_addr = SocketAddr::from(([10, 0, 0, 1], 3000));
+        request_url(&uri, Http11, &headers, local_addr, &Protocol::Http).map(|url| url.to_string())
+    }
+
+    #[test]
+    fn test_host_header() {
+        assert_eq!(url_for("/a?b", &["example.com"]).unwrap(), "http://example.com/a?b");
+        assert_eq!(url_for("/", &["example.com:8080"]).unwrap(), "http://example.com:8080/");
+        assert_eq!(url_for("/", &["[::1]:8080"]).unwrap(), "http://[::1]:8080/");
+
+        assert!(url_for("/", &[]).is_err());
+        assert!(url_for("/", &["a.com", "b.com"]).is_err());
+        assert!(url_for("/", &["evil.com@example.com"]).is_err());
+        assert!(url_for("/", &["example.com:http"]).is_err());
+    }
+
+    #[test]
+    fn test_absolute_form() {
+        assert_eq!(url_for("http://example.com:81/x", &["other.com"]).unwrap(),
+                   "http://example.com:81/x");
+        assert_eq!(url_for("https://example.com/", &[]).unwrap(), "http://example.com/");
+        assert_eq!(url_for("https://example.com:8443/", &[]).unwrap(),
+                   "http://example.com:8443/");
+    }
+
+    #[test]
+    fn test_http10_without_host() {
+        let local_addr = SocketAddr::from(([10, 0, 0, 1], 3000));
+        let url = request_url(&AbsolutePath("/".into()), Http10, &Headers::new(), local_addr,
+                              &Protocol::Http).unwrap();
+        assert_eq!(url.to_string(), "http://10.0.0.1:3000/");
+    }
+
+    #[test]
+    fn test_raw_body_bytes() {
+        let bytes = vec![0, 159, 146, 150, 255];
+        let mut req = StubRequest::new(Method::Post, "http://localhost/").body(bytes.clone())
+            .build();
+        assert_eq!(req.raw_body_bytes(5).unwrap(), bytes);
+
+        let mut req = StubRequest::new(Method::Post, "http://localhost/").body(bytes.clone())
+            .build();
+        assert_eq!(req.raw_body_bytes(4).unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+        // The largest limit means no limit.
+        let mut req = StubRequest::new(Method::Post, "http://localhost/").body(bytes.clone())
+            .build();
+        assert_eq!(req.raw_body_bytes(usize::MAX).unwrap(), bytes);
+        let mut req = StubRequest::new(Method::Post, "http://localhost/").body(bytes.clone())
+            .build();
+        req.body.buffer_spilling(usize::MAX, u64::MAX).unwrap();
+        assert_eq!(req.raw_body_bytes(5).unwrap(), bytes);
+
+        let mut req = StubRequest::new(Method::Post, "http://localhost/")
+            .raw_header("Content-Length", "1000000000")
+            .body(vec![1])
+            .build();
+        let bytes = req.raw_body_bytes(2_000_000_000).unwrap();
+        assert_eq!(bytes, vec![1]);
+        assert!(bytes.capacity() as u64 <= super::MAX_PREALLOCATION);
+    }
+
+    #[test]
+    fn test_parse_host() {
+        assert_eq!(parse_host("a.b-c_d"), Some(("a.b-c_d", None)));
+        assert_eq!(parse_host("[fe80::1]"), Some(("[fe80::1]", None)));
+        assert_eq!(parse_host("host:99999"), None);
+        assert_eq!(parse_host("host:"), None);
+        assert_eq!(parse_host("[::1"), None);
+        assert_eq!(parse_host("a b"), None);
+    }
+}
@@ -0,0 +1,380 @@
+//! Decoding of query strings into user-defined types.
+
+use std::collections::HashMap;
+use std::error::Error as StdError;
+use std::fmt;
+use std::str::FromStr;
+
+use rustc_serialize::{Decodable, Decoder};
+use url::{self, DecodeError};
+
+/// An error produced while decoding a query string with `Request::query_as`.
+///
+/// Any of these means the client sent a query the handler cannot accept, so
+/// they are usually answered with a 400.
+#[derive(Debug, Clone, PartialEq)]
+pub enum QueryError {
+    /// A required parameter was not present.
+    Missing(String),
+
+    /// A parameter could not be parsed as the expected type.
+    Invalid {
+        /// The name of the parameter.
+        field: String,
+        /// The value the client sent.
+        value: String,
+        /// A description of the expected type.
+        expected: &'static str
+    },
+
+    /// The target type cannot be represented by a query string.
+    Unsupported(String),
+
+    /// The query string itself is malformed, for instance with an invalid
+    /// percent-escape.
+    Malformed(DecodeError)
+}
+
+impl fmt::Display for QueryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            QueryError::Missing(ref field) => write!(f, "Missing query parameter `{}`", field),
+            QueryError::Invalid { ref field, ref value, expected } =>
+                write!(f, "Query parameter `{}` must be {}, got `{}`", field, expected, value),
+            QueryError::Unsupported(ref reason) => write!(f, "Unsupported query type: {}", reason),
+            QueryError::Malformed(ref err) => write!(f, "Malformed query string: {}", err)
+        }
+    }
+}
+
+impl StdError for QueryError {
+    fn description(&self) -> &str {
+        match *self {
+            QueryError::Missing(_) => "Missing query parameter",
+            QueryError::Invalid { .. } => "Invalid query parameter",
+            QueryError::Unsupported(_) => "Unsupported query type",
+            QueryError::Malformed(_) => "Malformed query string"
+        }
+    }
+}
+
+/// Decode a raw, percent-encoded query string into `T`.
+///
+/// `T` must be a struct (or a map) whose fields are strings, numbers, bools,
+/// chars, C-like enums, or `Option`s or `Vec`s of those. Repeated parameters
+/// such as `tags=a&tags=b` decode into `Vec` fields, absent or empty
+/// parameters decode into `None`.
+pub fn decode<T: Decodable>(query: &str) -> Result<T, QueryError> {
+    let mut params = HashMap::new();
+    let mut keys = Vec::new();
+
+    for pair in url::query_pairs(query) {
+        let (key, value) = try!(pair.map_err(QueryError::Malformed));
+        if !params.contains_key(&key) { keys.push(key.clone()) }
+        params.entry(key).or_insert_with(Vec::new).push(value);
+    }
+
+    T::decode(&mut QueryDecoder {
+        params: params,
+        keys: keys,
+        field: None,
+        index: 0,
+        in_seq: false,
+        reading_key: false
+    })
+}
+
+struct QueryDecoder {
+    params: HashMap<String, Vec<String>>,
+
+    // Parameter names in the order they first appear.
+    keys: Vec<String>,
+
+    // The parameter currently being decoded, and which of its values.
+    field: Option<String>,
+    index: usize,
+
+    in_seq: bool,
+    reading_key: bool
+}
+
+impl QueryDecoder {
+    fn value(&self) -> Result<&str, QueryError> {
+        let field = match self.field {
+            Some(ref field) => field,
+            None => return Err(unsupported("only structs and maps can be decoded from a query"))
+        };
+
+        if self.reading_key { return Ok(field) }
+
+        self.params.get(field)
+            .and_then(|values| values.get(self.index))
+            .map(|value| &**value)
+            .ok_or_else(|| QueryError::Missing(field.clone()))
+    }
+
+    fn parse<T: FromStr>(&self, expected: &'static str) -> Result<T, QueryError> {
+        let value = try!(self.value());
+        value.parse().map_err(|_| self.invalid(value, expected))
+    }
+
+    fn invalid(&self, value: &str, expected: &'static str) -> QueryError {
+        QueryError::Invalid {
+            field: self.field.clone().unwrap_or_default(),
+            value: value.to_owned(),
+            expected: expected
+        }
+    }
+
+    fn with_field<T, F>(&mut self, field: String, f: F) -> Result<T, QueryError>
+    where F: FnOnce(&mut QueryDecoder) -> Result<T, QueryError> {
+        if self.field.is_some() {
+            return Err(unsupported("nested structs and maps cannot be decoded from a query"));
+        }
+
+        self.field = Some(field);
+        self.index = 0;
+        let result = f(self);
+        self.field = None;
+        result
+    }
+}
+
+fn unsupported(reason: &str) -> QueryError {
+    QueryError::Unsupported(reason.to_owned())
+}
+
+impl Decoder for QueryDecoder {
+    type Error = QueryError;
+
+    fn read_nil(&mut self) -> Result<(), QueryError> { Ok(()) }
+    fn read_usize(&mut self) -> Result<usize, QueryError> { self.parse("an unsigned integer") }
+    fn read_u64(&mut self) -> Result<u64, QueryError> { self.parse("an unsigned integer") }
+    fn read_u32(&mut self) -> Result<u32, QueryError> { self.parse("an unsigned integer") }
+    fn read_u16(&mut self) -> Result<u16, QueryError> { self.parse("an unsigned integer") }
+    fn read_u8(&mut self) -> Result<u8, QueryError> { self.parse("an unsigned integer") }
+    fn read_isize(&mut self) -> Result<isize, QueryError> { self.parse("an integer") }
+    fn read_i64(&mut self) -> Result<i64, QueryError> { self.parse("an integer") }
+    fn read_i32(&mut self) -> Result<i32, QueryError> { self.parse("an integer") }
+    fn read_i16(&mut self) -> Result<i16, QueryError> { self.parse("an integer") }
+    fn read_i8(&mut self) -> Result<i8, QueryError> { self.parse("an integer") }
+    fn read_f64(&mut self) -> Result<f64, QueryError> { self.parse("a number") }
+    fn read_f32(&mut self) -> Result<f32, QueryError> { self.parse("a number") }
+
+    fn read_bool(&mut self) -> Result<bool, QueryError> {
+        let value = try!(self.value());
+        match value {
+            "true" | "1" | "on" | "yes" => Ok(true),
+            "false" | "0" | "off" | "no" => Ok(false),
+            _ => Err(self.invalid(value, "a boolean"))
+        }
+    }
+
+    fn read_char(&mut self) -> Result<char, QueryError> {
+        let value = try!(self.value());
+        let mut chars = value.chars();
+        match (chars.next(), chars.next()) {
+            (Some(c), None) => Ok(c),
+            _ => Err(self.invalid(value, "a single character"))
+        }
+    }
+
+    fn read_str(&mut self) -> Result<String, QueryError> {
+        self.value().map(|value| value.to_owned())
+    }
+
+    fn read_enum<T, F>(&mut self, _: &str, f: F) -> Result<T, QueryError>
+    where F: FnOnce(&mut Self) -> Result<T, QueryError> {
+        f(self)
+    }
+
+    fn read_enum_variant<T, F>(&mut self, names: &[&str], mut f: F) -> Result<T, QueryError>
+    where F: FnMut(&mut Self, usize) -> Result<T, QueryError> {
+        let index = {
+            let value = try!(self.value());
+            match names.iter().position(|name| *name == value) {
+                Some(index) => index,
+                None => return Err(self.invalid(value, "a known variant"))
+            }
+        };
+        f(self, index)
+    }
+
+    fn read_enum_variant_arg<T, F>(&mut self, _: usize, _: F) -> Result<T, QueryError>
+    where F: FnOnce(&mut Self) -> Result<T, QueryError> {
+        Err(unsupported("enum variants with data cannot be decoded from a query"))
+    }
+
+    fn read_enum_struct_variant<T, F>(&mut self, _: &[&str], _: F) -> Result<T, QueryError>
+    where F: FnMut(&mut Self, usize) -> Result<T, QueryError> {
+        Err(unsupported("enum variants with data cannot be decoded from a query"))
+    }
+
+    fn read_enum_struct_variant_field<T, F>(&mut self, _: &str, _: usize, _: F)
+                                            -> Result<T, QueryError>
+    where F: FnOnce(&mut Self) -> Result<T, QueryError> {
+        Err(unsupported("enum variants with data cannot be decoded from a query"))
+    }
+
+    fn read_struct<T, F>(&mut self, _: &str, _: usize, f: F) -> Result<T, QueryError>
+    where F: FnOnce(&mut Self) -> Result<T, QueryError> {
+        if self.field.is_some() {
+            return Err(unsupported("nested structs and maps cannot be decoded from a query"));
+        }
+        f(self)
+    }
+
+    fn read_struct_field<T, F>(&mut self, name: &str, _: usize, f: F) -> Result<T, QueryError>
+    where F: FnOnce(&mut Self) -> Result<T, QueryError> {
+        self.with_field(name.to_owned(), f)
+    }
+
+    fn read_tuple<T, F>(&mut self, _: usize, _: F) -> Result<T, QueryError>
+    where F: FnOnce(&mut Self) -> Result<T, QueryError> {
+        Err(unsupported("tuples cannot be decoded from a query"))
+    }
+
+    fn read_tuple_arg<T, F>(&mut self, _: usize, _: F) -> Result<T, QueryError>
+    where F: FnOnce(&mut Self) -> Result<T, QueryError> {
+        Err(unsupported("tuples cannot be decoded from a query"))
+    }
+
+    fn read_tuple_struct<T, F>(&mut self, _: &str, _: usize, _: F) -> Result<T, QueryError>
+    where F: FnOnce(&mut Self) -> Result<T, QueryError> {
+        Err(unsupported("tuple structs cannot be decoded from a query"))
+    }
+
+    fn read_tuple_struct_arg<T, F>(&mut self, _: usize, _: F) -> Result<T, QueryError>
+    where F: FnOnce(&mut Self) -> Result<T, QueryError> {
+        Err(unsupported("tuple structs cannot be decoded from a query"))
+    }
+
+    fn read_option<T, F>(&mut self, mut f: F) -> Result<T, QueryError>
+    where F: FnMut(&mut Self, bool) -> Result<T, QueryError> {
+        let present = match self.value() {
+            Ok(value) => !value.is_empty(),
+            Err(QueryError::Missing(_)) => false,
+            Err(err) => return Err(err)
+        };
+        f(self, present)
+    }
+
+    fn read_seq<T, F>(&mut self, f: F) -> Result<T, QueryError>
+    where F: FnOnce(&mut Self, usize) -> Result<T, QueryError> {
+        if self.in_seq || self.reading_key {
+            return Err(unsupported("nested sequences cannot be decoded from a query"));
+        }
+
+        let len = match self.field {
+            Some(ref field) => self.params.get(field).map_or(0, |values| values.len()),
+            None => return Err(unsupported("only structs and maps can be decoded from a query"))
+        };
+
+        self.in_seq = true;
+        let result = f(self, len);
+        self.in_seq = false;
+        result
+    }
+
+    fn read_seq_elt<T, F>(&mut self, idx: usize, f: F) -> Result<T, QueryError>
+    where F: FnOnce(&mut Self) -> Result<T, QueryError> {
+        self.index = idx;
+        let result = f(self);
+        self.index = 0;
+        result
+    }
+
+    fn read_map<T, F>(&mut self, f: F) -> Result<T, QueryError>
+    where F: FnOnce(&mut Self, usize) -> Result<T, QueryError> {
+        if self.field.is_some() {
+            return Err(unsupported("nested structs and maps cannot be decoded from a query"));
+        }
+
+        let len = self.keys.len();
+        f(self, len)
+    }
+
+    fn read_map_elt_key<T, F>(&mut self, idx: usize, f: F) -> Result<T, QueryError>
+    where F: FnOnce(&mut Self) -> Result<T, QueryError> {
+        let key = self.keys[idx].clone();
+        self.reading_key = true;
+        let result = self.with_field(key, f);
+        self.reading_key = false;
+        result
+    }
+
+    fn read_map_elt_val<T, F>(&mut self, idx: usize, f: F) -> Result<T, QueryError>
+    where F: FnOnce(&mut Self) -> Result<T, QueryError> {
+        let key = self.keys[idx].clone();
+        self.with_field(key, f)
+    }
+
+    fn error(&mut self, err: &str) -> QueryError {
+        unsupported(err)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use rustc_serialize::{Decodable, Decoder};
+
+    use super::{decode, QueryError};
+
+    #[derive(Debug, PartialEq)]
+    struct Listing {
+        page: u32,
+        per_page: Option<u32>,
+        desc: bool,
+        tags: Vec<String>
+    }
+
+    impl Decodable for Listing {
+        fn decode<D: Decoder>(d: &mut D) -> Result<Listing, D::Error> {
+            d.read_struct("Listing", 4, |d| {
+                Ok(Listing {
+                    page: try!(d.read_struct_field("page", 0, Decodable::decode)),
+                    per_page: try!(d.read_struct_field("per_page", 1, Decodable::decode)),
+                    desc: try!(d.read_struct_field("desc", 2, Decodable::decode)),
+                    tags: try!(d.read_struct_field("tags", 3, Decodable::decode))
+                })
+            })
+        }
+    }
+
+    #[test]
+    fn test_decode_struct() {
+        let listing: Listing = decode("page=2&per_page=50&desc=true&tags=a&tags=b%20c").unwrap();
+        assert_eq!(listing, Listing {
+            page: 2,
+            per_page: Some(50),
+            desc: true,
+            tags: vec!["a".to_owned(), "b c".to_owned()]
+        });
+    }
+
+    #[test]
+    fn test_optional_and_empty_fields() {
+        let listing: Listing = decode("page=1&per_page=&desc=0").unwrap();
+        assert_eq!(listing.per_page, None);
+        assert!(listing.tags.is_empty());
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(decode::<Listing>("desc=true"), Err(QueryError::Missing("page".to_owned())));
+        assert_eq!(decode::<Listing>("page=two&desc=true"), Err(QueryError::Invalid {
+            field: "page".to_owned(),
+            value: "two".to_owned(),
+            expected: "an unsigned integer"
+        }));
+    }
+
+    #[test]
+    fn test_decode_map() {
+        let map: HashMap<String, Vec<String>> = decode("a=1&b=2&a=3").unwrap();
+        assert_eq!(map["a"], vec!["1".to_owned(), "3".to_owned()]);
+        assert_eq!(map["b"], vec!["2".to_owned()]);
+    }
+}
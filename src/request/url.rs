@@ -1,6 +1,6 @@
 //! HTTP/HTTPS URL type for Iron.
 
-use url::{self, Host};
+use rust_url::{self as url, Host};
 use std::fmt;
 
 /// HTTP/HTTPS URL type for Iron.
@@ -182,7 +182,7 @@ mod test {
 
         // Convert to a generic URL and check fidelity.
         let raw_url = url.clone().into_generic_url();
-        assert_eq!(::url::Url::parse(url_str).unwrap(), raw_url);
+        assert_eq!(::rust_url::Url::parse(url_str).unwrap(), raw_url);
 
         // Convert back to an Iron URL and check fidelity.
         let new_url = Url::from_generic_url(raw_url).unwrap();
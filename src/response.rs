@@ -1,15 +1,23 @@
 //! Iron's HTTP Response representation and associated methods.
 
-use std::io::{self, Write};
+use std::io::{self, Seek, SeekFrom, Write};
 use std::fmt::{self, Debug};
 use std::fs::File;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
 
-use typemap::TypeMap;
+use rustc_serialize::Encodable;
+use rustc_serialize::json;
+
+use typemap::{TypeMap, Key};
 use plugin::Extensible;
 use modifier::{Set, Modifier};
 use hyper::header::Headers;
 
-use status::{self, Status};
+use status::{self, Status, StatusClass};
+use method::Method;
+use mime::Mime;
 use {Plugin, headers};
 
 pub use hyper::server::response::Response as HttpResponse;
@@ -38,40 +46,145 @@ impl<'a> Write for ResponseBody<'a> {
 /// Wrapper type to set `Read`ers as response bodies
 pub struct BodyReader<R: Send>(pub R);
 
+/// A response body which writes each item of an iterator as a line of JSON
+/// (newline-delimited JSON, or NDJSON) as it is produced.
+///
+/// Items are written through a buffer, which is flushed to the client
+/// whenever `flush_interval` has passed since the last flush, and once all
+/// items have been written. With a zero interval every line is flushed as
+/// soon as it is written.
+///
+/// The iterator runs on a thread of its own, so that lines are flushed on
+/// time even while a slow iterator, such as a database cursor, is producing
+/// the next item.
+///
+/// As a modifier, it sets the body and a `Content-Type` of
+/// `application/x-ndjson`.
+pub struct JsonStream<I> {
+    items: Option<I>,
+    flush_interval: Duration
+}
+
+impl<I, T> JsonStream<I> where I: Iterator<Item=T> + Send + 'static, T: Encodable {
+    /// Stream `items`, flushing at most every 100 milliseconds.
+    pub fn new(items: I) -> JsonStream<I> {
+        JsonStream { items: Some(items), flush_interval: Duration::from_millis(100) }
+    }
+
+    /// Set how often buffered lines are flushed to the client.
+    pub fn flush_interval(mut self, interval: Duration) -> JsonStream<I> {
+        self.flush_interval = interval;
+        self
+    }
+}
+
+impl<I, T> WriteBody for JsonStream<I>
+where I: Iterator<Item=T> + Send + 'static, T: Encodable {
+    fn write_body(&mut self, res: &mut ResponseBody) -> io::Result<()> {
+        let items = match self.items.take() {
+            Some(items) => items,
+            None => return Ok(())
+        };
+
+        // Encoded lines, or the error encoding one. The producer stops once
+        // the receiver is gone, as when the client disconnects.
+        let (lines, received) = mpsc::sync_channel::<Result<String, String>>(64);
+        thread::spawn(move || {
+            for item in items {
+                let line = json::encode(&item).map_err(|e| e.to_string());
+                let failed = line.is_err();
+                if lines.send(line).is_err() || failed { break }
+            }
+        });
+
+        let mut out = io::BufWriter::new(res);
+        let mut flushed = Instant::now();
+
+        loop {
+            let due = self.flush_interval.checked_sub(flushed.elapsed())
+                .unwrap_or_else(|| Duration::from_secs(0));
+            let line = match received.recv_timeout(due) {
+                Ok(line) => line,
+                Err(RecvTimeoutError::Timeout) => {
+                    try!(out.flush());
+                    flushed = Instant::now();
+                    match received.recv() {
+                        Ok(line) => line,
+                        Err(_) => break
+                    }
+                },
+                Err(RecvTimeoutError::Disconnected) => break
+            };
+
+            let line = try!(line.map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)));
+            try!(out.write_all(line.as_bytes()));
+            try!(out.write_all(b"\n"));
+
+            if flushed.elapsed() >= self.flush_interval {
+                try!(out.flush());
+                flushed = Instant::now();
+            }
+        }
+
+        out.flush()
+    }
+}
+
 /// A trait which writes the body of an HTTP response.
 pub trait WriteBody: Send {
     /// Writes the body to the provided `ResponseBody`.
     fn write_body(&mut self, res: &mut ResponseBody) -> io::Result<()>;
+
+    /// The number of bytes `write_body` would write, if it is known without
+    /// writing them. Used to answer HEAD requests; defaults to `None`.
+    fn size(&self) -> Option<u64> { None }
 }
 
 impl WriteBody for String {
     fn write_body(&mut self, res: &mut ResponseBody) -> io::Result<()> {
         self.as_bytes().write_body(res)
     }
+
+    fn size(&self) -> Option<u64> { Some(self.len() as u64) }
 }
 
 impl<'a> WriteBody for &'a str {
     fn write_body(&mut self, res: &mut ResponseBody) -> io::Result<()> {
         self.as_bytes().write_body(res)
     }
+
+    fn size(&self) -> Option<u64> { Some(self.len() as u64) }
 }
 
 impl WriteBody for Vec<u8> {
     fn write_body(&mut self, res: &mut ResponseBody) -> io::Result<()> {
         res.write_all(self)
     }
+
+    fn size(&self) -> Option<u64> { Some(self.len() as u64) }
 }
 
 impl<'a> WriteBody for &'a [u8] {
     fn write_body(&mut self, res: &mut ResponseBody) -> io::Result<()> {
         res.write_all(self)
     }
+
+    fn size(&self) -> Option<u64> { Some(self.len() as u64) }
 }
 
 impl WriteBody for File {
     fn write_body(&mut self, res: &mut ResponseBody) -> io::Result<()> {
         io::copy(self, res).map(|_| ())
     }
+
+    fn size(&self) -> Option<u64> {
+        // The file is copied from its current position.
+        let position = (&*self).seek(SeekFrom::Current(0));
+        match (self.metadata(), position) {
+            (Ok(metadata), Ok(position)) => Some(metadata.len().saturating_sub(position)),
+            _ => None
+        }
+    }
 }
 
 impl WriteBody for Box<io::Read + Send> {
@@ -122,34 +235,188 @@ impl Response {
     }
 
     /// Construct a Response with the specified modifier pre-applied.
+    ///
+    /// Several modifiers can be applied at once as a tuple, typically a
+    /// status and a body, and the builder methods below add headers, so a
+    /// complete response can be built in one expression:
+    ///
+    /// ```ignore
+    /// Response::with((status::Ok, body))
+    ///     .content_type(mime!(Application/Json))
+    ///     .header(headers::CacheControl(vec![headers::CacheDirective::NoCache]))
+    ///     .cookie("session=abc; HttpOnly")
+    /// ```
     pub fn with<M: Modifier<Response>>(m: M) -> Response {
         Response::new().set(m)
     }
 
+    /// Set a header, replacing any previous value.
+    pub fn header<H: headers::Header + headers::HeaderFormat>(mut self, header: H) -> Response {
+        self.headers.set(header);
+        self
+    }
+
+    /// Set the `Content-Type` header.
+    pub fn content_type(self, mime: Mime) -> Response {
+        self.header(headers::ContentType(mime))
+    }
+
+    /// Add a `Set-Cookie` header, keeping any cookies already set.
+    ///
+    /// `cookie` is the full header value, such as `id=a3fWa; Max-Age=2592000`.
+    pub fn cookie<C: Into<String>>(mut self, cookie: C) -> Response {
+        let mut cookies = self.headers.get_raw("Set-Cookie").map(|v| v.to_vec())
+            .unwrap_or_default();
+        cookies.push(cookie.into().into_bytes());
+        self.headers.set_raw("Set-Cookie", cookies);
+        self
+    }
+
+    /// Construct a `200 OK` Response with a binary body of type
+    /// `content_type`, such as a protobuf or msgpack message.
+    ///
+    /// The bytes are sent as they are, without being copied.
+    pub fn binary(content_type: Mime, bytes: Vec<u8>) -> Response {
+        Response::with((status::Ok, bytes)).content_type(content_type)
+    }
+
+    /// Construct a `200 OK` Response streaming `items` as newline-delimited
+    /// JSON.
+    ///
+    /// See `JsonStream` for details.
+    pub fn json_stream<I, T>(items: I) -> Response
+    where I: Iterator<Item=T> + Send + 'static, T: Encodable {
+        Response::with((status::Ok, JsonStream::new(items)))
+    }
+
+    /// Register a callback to run just before the headers of this Response
+    /// are written to the client.
+    ///
+    /// Callbacks run in the order they were registered, after every
+    /// middleware has finished, so they see the final status, headers and
+    /// body regardless of where in a `Chain` they were registered. A callback
+    /// may itself register further callbacks, which run afterwards.
+    pub fn on_before_headers<F>(&mut self, f: F)
+    where F: FnOnce(&mut Response) + Send + 'static {
+        self.extensions.entry::<BeforeHeaders>()
+            .or_insert_with(Vec::new)
+            .push(Box::new(f));
+    }
+
+    /// Register a callback to run once the body of this Response has been
+    /// written to the client, or writing it has failed.
+    ///
+    /// Callbacks run in the order they were registered and receive the
+    /// result of writing the response.
+    pub fn on_after_body<F>(&mut self, f: F)
+    where F: FnOnce(&io::Result<()>) + Send + 'static {
+        self.extensions.entry::<AfterBody>()
+            .or_insert_with(Vec::new)
+            .push(Box::new(f));
+    }
+
     // `write_back` is used to put all the data added to `self`
     // back onto an `HttpResponse` so that it is sent back to the
     // client.
     //
     // `write_back` consumes the `Response`.
     #[doc(hidden)]
-    pub fn write_back(self, mut http_res: HttpResponse<Fresh>) {
-        *http_res.headers_mut() = self.headers;
+    pub fn write_back(mut self, mut http_res: HttpResponse<Fresh>, method: &Method) {
+        while let Some(hooks) = self.extensions.remove::<BeforeHeaders>() {
+            for hook in hooks {
+                hook(&mut self);
+            }
+        }
+
+        let after_body = self.extensions.remove::<AfterBody>().unwrap_or_default();
 
         // Default to a 404 if no response code was set
-        *http_res.status_mut() = self.status.clone().unwrap_or(status::NotFound);
+        let status = self.status.unwrap_or(status::NotFound);
+        self.finalize(status, method);
 
+        *http_res.headers_mut() = self.headers;
+        *http_res.status_mut() = status;
+
+        let unsized_head = *method == Method::Head &&
+            !http_res.headers().has::<headers::ContentLength>();
         let out = match self.body {
             Some(body) => write_with_body(http_res, body),
-            None => {
-                http_res.headers_mut().set(headers::ContentLength(0));
-                http_res.start().and_then(|res| res.end())
-            }
+            None if unsized_head => write_head_only(http_res),
+            None => http_res.start().and_then(|res| res.end())
         };
 
+        for hook in after_body {
+            hook(&out);
+        }
+
         if let Err(e) = out {
             error!("Error writing response: {}", e);
         }
     }
+
+    // Reconcile the body and framing headers left behind by middleware so
+    // that the response we write is valid HTTP.
+    //
+    // * 1xx, 204 and 304 responses never carry a body, and only a 304 may
+    //   keep the `Content-Length` of the representation it stands in for.
+    // * `Transfer-Encoding` takes precedence over `Content-Length`.
+    // * Responses to HEAD requests carry the headers of the equivalent GET,
+    //   including its `Content-Length` when the size of the body is known
+    //   without writing it, but no body.
+    fn finalize(&mut self, status: Status, method: &Method) {
+        if status == status::NoContent || status == status::NotModified ||
+           status.class() == StatusClass::Informational {
+            self.body = None;
+            self.headers.remove::<headers::TransferEncoding>();
+            if status != status::NotModified {
+                self.headers.remove::<headers::ContentLength>();
+            }
+            return;
+        }
+
+        if self.headers.has::<headers::TransferEncoding>() {
+            self.headers.remove::<headers::ContentLength>();
+        }
+
+        if *method == Method::Head {
+            let body = self.body.take();
+
+            // Streamed bodies, and bodies with side effects, are not
+            // rendered just to measure them; without a size the headers
+            // are sent without a length.
+            if !self.headers.has::<headers::ContentLength>() {
+                let size = match body {
+                    Some(ref body) => body.size(),
+                    None => Some(0)
+                };
+                if let Some(size) = size {
+                    self.headers.remove::<headers::TransferEncoding>();
+                    self.headers.set(headers::ContentLength(size));
+                }
+            }
+        } else if self.body.is_none() {
+            self.headers.set(headers::ContentLength(0));
+        }
+    }
+}
+
+// Callbacks registered with `Response::on_before_headers`.
+struct BeforeHeaders;
+
+impl Key for BeforeHeaders { type Value = Vec<Box<FnOnce(&mut Response) + Send>>; }
+
+// Callbacks registered with `Response::on_after_body`.
+struct AfterBody;
+
+impl Key for AfterBody { type Value = Vec<Box<FnOnce(&io::Result<()>) + Send>>; }
+
+// Write only the head of a response to a HEAD request whose length is not
+// known. hyper frames such a response as chunked, and ending it would write a
+// last-chunk, which must not follow the head of a HEAD response, so the body
+// writer is discarded instead.
+fn write_head_only(res: HttpResponse<Fresh>) -> io::Result<()> {
+    let (_, body, _, _) = try!(res.start()).deconstruct();
+    body.into_inner().flush()
 }
 
 fn write_with_body(mut res: HttpResponse<Fresh>, mut body: Box<WriteBody>)
@@ -193,3 +460,132 @@ impl Extensible for Response {
 
 impl Plugin for Response {}
 impl Set for Response {}
+
+#[cfg(test)]
+mod test {
+    use std::io::{self, Read, Write};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use super::{BodyReader, JsonStream, Response, ResponseBody, WriteBody};
+    use {headers, method, status};
+
+    #[test]
+    fn test_builder() {
+        let res = Response::with((status::Created, "{}"))
+            .content_type("application/json".parse().unwrap())
+            .header(headers::Location("/items/1".into()))
+            .cookie("a=1")
+            .cookie("b=2; HttpOnly");
+
+        assert_eq!(res.status, Some(status::Created));
+        assert_eq!(res.headers.get::<headers::ContentType>().unwrap().to_string(),
+                   "application/json");
+        assert_eq!(res.headers.get(), Some(&headers::Location("/items/1".into())));
+        assert_eq!(res.headers.get_raw("Set-Cookie"),
+                   Some(&[b"a=1".to_vec(), b"b=2; HttpOnly".to_vec()][..]));
+    }
+
+    #[test]
+    fn test_binary() {
+        let res = Response::binary("application/x-protobuf".parse().unwrap(), vec![8, 150, 1]);
+        assert_eq!(res.status, Some(status::Ok));
+        assert_eq!(res.headers.get::<headers::ContentType>().unwrap().to_string(),
+                   "application/x-protobuf");
+        assert_eq!(res.headers.get(), Some(&headers::ContentLength(3)));
+    }
+
+    #[test]
+    fn test_no_body_for_no_content() {
+        let mut res = Response::with((status::NoContent, "body"));
+        res.finalize(status::NoContent, &method::Get);
+        assert!(res.body.is_none());
+        assert!(!res.headers.has::<headers::ContentLength>());
+    }
+
+    #[test]
+    fn test_not_modified_keeps_length() {
+        let mut res = Response::with((status::NotModified, "body"));
+        res.finalize(status::NotModified, &method::Get);
+        assert!(res.body.is_none());
+        assert_eq!(res.headers.get(), Some(&headers::ContentLength(4)));
+    }
+
+    #[test]
+    fn test_transfer_encoding_wins() {
+        let mut res = Response::with((status::Ok, "body"));
+        res.headers.set(headers::TransferEncoding(vec![headers::Encoding::Chunked]));
+        res.finalize(status::Ok, &method::Get);
+        assert!(!res.headers.has::<headers::ContentLength>());
+    }
+
+    #[test]
+    fn test_head_measures_body() {
+        let mut res = Response::with(status::Ok);
+        res.body = Some(Box::new("hello"));
+        res.finalize(status::Ok, &method::Head);
+        assert!(res.body.is_none());
+        assert_eq!(res.headers.get(), Some(&headers::ContentLength(5)));
+
+        // A streamed body is not rendered to measure it.
+        let mut res = Response::with(status::Ok);
+        res.body = Some(Box::new(BodyReader(Panics)));
+        res.finalize(status::Ok, &method::Head);
+        assert!(res.body.is_none());
+        assert!(!res.headers.has::<headers::ContentLength>());
+    }
+
+    struct Panics;
+
+    impl Read for Panics {
+        fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+            panic!("HEAD response body was read")
+        }
+    }
+
+    #[test]
+    fn test_json_stream() {
+        let mut res = Response::json_stream(vec![(1, "a"), (2, "b")].into_iter());
+        assert_eq!(res.headers.get::<headers::ContentType>().unwrap().to_string(),
+                   "application/x-ndjson");
+
+        let mut body = vec![];
+        res.body.take().unwrap().write_body(&mut ResponseBody::new(&mut body)).unwrap();
+        assert_eq!(body, b"[1,\"a\"]\n[2,\"b\"]\n".to_vec());
+    }
+
+    #[test]
+    fn test_json_stream_flushes_while_waiting() {
+        struct Recorder(Instant, Vec<u8>, Option<Duration>);
+
+        impl Write for Recorder {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                if self.2.is_none() { self.2 = Some(self.0.elapsed()) }
+                self.1.extend_from_slice(buf);
+                Ok(buf.len())
+            }
+
+            fn flush(&mut self) -> io::Result<()> { Ok(()) }
+        }
+
+        // The first line is written well within the interval, and the second
+        // item takes much longer to produce.
+        let items = (0..2).map(|i| {
+            if i == 1 { thread::sleep(Duration::from_millis(300)) }
+            i
+        });
+        let mut stream = JsonStream::new(items).flush_interval(Duration::from_millis(50));
+        let mut recorder = Recorder(Instant::now(), vec![], None);
+        stream.write_body(&mut ResponseBody::new(&mut recorder)).unwrap();
+
+        assert_eq!(recorder.1, b"0\n1\n".to_vec());
+        assert!(recorder.2.unwrap() < Duration::from_millis(200), "{:?}", recorder.2);
+    }
+
+    #[test]
+    fn test_empty_body_length() {
+        let mut res = Response::with(status::Ok);
+        res.finalize(status::Ok, &method::Get);
+        assert_eq!(res.headers.get(), Some(&headers::ContentLength(0)));
+    }
+}
@@ -0,0 +1,117 @@
+//! Periodic tasks which run for as long as a server does.
+
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Mutex, Condvar};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// Tasks to run at fixed intervals, each on its own thread.
+///
+/// Tasks are started by `start` and stopped through a `Stop` handle or when
+/// the `Schedule` is dropped.
+#[derive(Default)]
+pub struct Schedule {
+    // Behind a mutex only so that a server holding a `Schedule` is `Sync`.
+    pending: Mutex<Vec<(Duration, Box<FnMut() + Send>)>>,
+    stopped: Stop,
+    threads: Vec<JoinHandle<()>>
+}
+
+/// A handle for stopping the tasks of a `Schedule` without waiting for them.
+///
+/// A task already running finishes its current run.
+#[derive(Clone, Default)]
+pub struct Stop(Arc<(Mutex<bool>, Condvar)>);
+
+impl Stop {
+    /// Stop the tasks.
+    pub fn stop(&self) {
+        let (ref lock, ref cvar) = *self.0;
+        *lock.lock().unwrap_or_else(|e| e.into_inner()) = true;
+        cvar.notify_all();
+    }
+}
+
+impl Schedule {
+    /// Add a task to run every `interval`, once started.
+    pub fn add(&mut self, interval: Duration, task: Box<FnMut() + Send>) {
+        self.pending.get_mut().unwrap().push((interval, task));
+    }
+
+    /// A handle for stopping the tasks.
+    pub fn stop_handle(&self) -> Stop {
+        self.stopped.clone()
+    }
+
+    /// Start running the tasks added so far.
+    pub fn start(&mut self) {
+        for (interval, mut task) in self.pending.get_mut().unwrap().drain(..) {
+            let stopped = self.stopped.0.clone();
+
+            self.threads.push(thread::spawn(move || {
+                let (ref lock, ref cvar) = *stopped;
+                let mut next = Instant::now() + interval;
+
+                loop {
+                    let mut stop = lock.lock().unwrap_or_else(|e| e.into_inner());
+                    while !*stop && Instant::now() < next {
+                        let wait = next.saturating_duration_since(Instant::now());
+                        stop = cvar.wait_timeout(stop, wait).unwrap_or_else(|e| e.into_inner()).0;
+                    }
+                    if *stop { return }
+                    drop(stop);
+
+                    if panic::catch_unwind(AssertUnwindSafe(|| task())).is_err() {
+                        error!("Scheduled task panicked");
+                    }
+
+                    next += interval;
+                }
+            }));
+        }
+    }
+}
+
+impl Drop for Schedule {
+    fn drop(&mut self) {
+        self.stopped.stop();
+
+        for thread in self.threads.drain(..) {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+    use std::time::Duration;
+
+    use super::Schedule;
+
+    #[test]
+    fn test_runs_until_dropped() {
+        let runs = Arc::new(AtomicUsize::new(0));
+        let counter = runs.clone();
+
+        let mut schedule = Schedule::default();
+        schedule.add(Duration::from_millis(5), Box::new(move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+        }));
+
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(runs.load(Ordering::SeqCst), 0);
+
+        schedule.start();
+        thread::sleep(Duration::from_millis(50));
+        drop(schedule);
+
+        let after_stop = runs.load(Ordering::SeqCst);
+        assert!(after_stop > 0);
+
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(runs.load(Ordering::SeqCst), after_stop);
+    }
+}
@@ -0,0 +1,178 @@
+//! Answering requests for `/favicon.ico` and `/robots.txt` from memory.
+//!
+//! Browsers and crawlers request these two paths constantly. `Favicon` and
+//! `RobotsTxt` are `AroundMiddleware` which answer `GET` and `HEAD` requests
+//! for them directly, with long-lived caching headers, without calling the
+//! handler they wrap. Wrapping a whole `Chain` keeps these requests away
+//! from its middleware, such as routers and access logs:
+//!
+//! ```ignore
+//! let chain = Chain::new(router);
+//! let handler = Favicon::from_file("public/favicon.ico").unwrap().around(Box::new(chain));
+//! let handler = RobotsTxt::new("User-agent: *\nDisallow: /admin\n").around(handler);
+//! Iron::new(handler).http("localhost:3000").unwrap();
+//! ```
+
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+
+use mime::Mime;
+
+use {headers, status, AroundMiddleware, Handler, Request, Response, IronResult};
+use method::Method;
+
+/// Middleware answering requests for `/favicon.ico` with an icon held in
+/// memory.
+///
+/// The icon is sent as `image/png` if it is a PNG, and `image/x-icon`
+/// otherwise, and may be cached for a day unless changed with `max_age`.
+pub struct Favicon(Asset);
+
+impl Favicon {
+    /// Serve the icon `bytes`.
+    pub fn new<B: Into<Vec<u8>>>(bytes: B) -> Favicon {
+        let bytes = bytes.into();
+        let png = bytes.starts_with(b"\x89PNG");
+        Favicon(Asset::new("favicon.ico", if png { "image/png" } else { "image/x-icon" }, bytes))
+    }
+
+    /// Serve the icon read from the file at `path`, which is read once,
+    /// now.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> io::Result<Favicon> {
+        let mut bytes = Vec::new();
+        try!(try!(File::open(path)).read_to_end(&mut bytes));
+        Ok(Favicon::new(bytes))
+    }
+
+    /// Let clients cache the icon for `seconds`.
+    pub fn max_age(mut self, seconds: u32) -> Favicon {
+        self.0.max_age = seconds;
+        self
+    }
+
+    /// Log each request answered, at the `info` level, since they never
+    /// reach any logging middleware in the wrapped handler.
+    pub fn logged(mut self, logged: bool) -> Favicon {
+        self.0.logged = logged;
+        self
+    }
+}
+
+/// Middleware answering requests for `/robots.txt` with the given rules.
+///
+/// The rules may be cached for a day unless changed with `max_age`.
+pub struct RobotsTxt(Asset);
+
+impl RobotsTxt {
+    /// Serve `rules` as the contents of `robots.txt`.
+    pub fn new<S: Into<String>>(rules: S) -> RobotsTxt {
+        RobotsTxt(Asset::new("robots.txt", "text/plain; charset=utf-8", rules.into().into_bytes()))
+    }
+
+    /// Let clients cache the rules for `seconds`.
+    pub fn max_age(mut self, seconds: u32) -> RobotsTxt {
+        self.0.max_age = seconds;
+        self
+    }
+
+    /// Log each request answered, at the `info` level, since they never
+    /// reach any logging middleware in the wrapped handler.
+    pub fn logged(mut self, logged: bool) -> RobotsTxt {
+        self.0.logged = logged;
+        self
+    }
+}
+
+struct Asset {
+    path: &'static str,
+    content_type: Mime,
+    body: Vec<u8>,
+    max_age: u32,
+    logged: bool
+}
+
+impl Asset {
+    fn new(path: &'static str, content_type: &str, body: Vec<u8>) -> Asset {
+        Asset {
+            path: path,
+            content_type: content_type.parse().unwrap(),
+            body: body,
+            max_age: 24 * 60 * 60,
+            logged: false
+        }
+    }
+}
+
+impl AroundMiddleware for Favicon {
+    fn around(self, handler: Box<Handler>) -> Box<Handler> {
+        Box::new(Shortcut { asset: self.0, handler: handler })
+    }
+}
+
+impl AroundMiddleware for RobotsTxt {
+    fn around(self, handler: Box<Handler>) -> Box<Handler> {
+        Box::new(Shortcut { asset: self.0, handler: handler })
+    }
+}
+
+struct Shortcut {
+    asset: Asset,
+    handler: Box<Handler>
+}
+
+impl Handler for Shortcut {
+    fn handle(&self, req: &mut Request) -> IronResult<Response> {
+        let asset = &self.asset;
+        let matches = (req.method == Method::Get || req.method == Method::Head) &&
+            req.url.path() == vec![asset.path];
+        if !matches { return self.handler.handle(req) }
+
+        if asset.logged { info!("{} {} {}", req.method, req.url, status::Ok) }
+
+        Ok(Response::with((status::Ok, asset.body.clone(), asset.content_type.clone()))
+            .header(headers::CacheControl(vec![headers::CacheDirective::Public,
+                                               headers::CacheDirective::MaxAge(asset.max_age)])))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use headers::{CacheControl, CacheDirective, ContentType};
+    use method::Method;
+    use status;
+    use test::{MiddlewareHarness, StubRequest};
+    use {AroundMiddleware, Handler, Request, Response};
+    use super::{Favicon, RobotsTxt};
+
+    #[test]
+    fn test_shortcuts() {
+        let handler: Box<Handler> = Box::new(|_: &mut Request| {
+            Ok(Response::with((status::Ok, "handler")))
+        });
+        let handler = Favicon::new(&b"\x89PNG..."[..]).max_age(60).around(handler);
+        let handler = RobotsTxt::new("User-agent: *\n").around(handler);
+        let harness = MiddlewareHarness::new(handler);
+
+        let get = |method, path: &str| {
+            let url = format!("http://localhost{}", path);
+            let mut run = harness.handle(StubRequest::new(method, &url));
+            let content_type = run.response().headers.get::<ContentType>().map(|t| t.to_string());
+            let cache = run.response().headers.get::<CacheControl>().cloned();
+            (run.take_body(), content_type, cache)
+        };
+
+        let (body, content_type, cache) = get(Method::Get, "/favicon.ico");
+        assert_eq!(body, b"\x89PNG...".to_vec());
+        assert_eq!(content_type, Some("image/png".into()));
+        assert_eq!(cache, Some(CacheControl(vec![CacheDirective::Public,
+                                                 CacheDirective::MaxAge(60)])));
+
+        let (body, content_type, _) = get(Method::Head, "/robots.txt");
+        assert_eq!(body, b"User-agent: *\n".to_vec());
+        assert_eq!(content_type, Some("text/plain; charset=utf-8".into()));
+
+        assert_eq!(get(Method::Post, "/robots.txt").0, b"handler".to_vec());
+        assert_eq!(get(Method::Get, "/favicon.ico/x").0, b"handler".to_vec());
+    }
+}
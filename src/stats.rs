@@ -0,0 +1,259 @@
+//! Counters describing what a running server is doing.
+//!
+//! Every `Iron` keeps `Stats`, which can be read through a handle taken with
+//! `Iron::stats` before the server starts listening, or served as JSON by
+//! linking a `StatsEndpoint`:
+//!
+//! ```ignore
+//! let mut iron = Iron::new(Chain::new(handler));
+//! let stats = iron.stats();
+//! iron.handler.link_around(StatsEndpoint::new(stats.clone()));
+//! iron.http("localhost:3000").unwrap();
+//! ```
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use rustc_serialize::json::{Json, Object, ToJson};
+
+use {status, headers, AroundMiddleware, Handler, Request, Response, IronResult};
+use status::Status;
+
+/// A handle to a server's counters.
+///
+/// Clones share the same counters.
+#[derive(Clone)]
+pub struct Stats {
+    inner: Arc<Inner>
+}
+
+struct Inner {
+    started: Instant,
+    threads: AtomicUsize,
+    connections: AtomicUsize,
+    in_flight: AtomicUsize,
+    served: AtomicUsize,
+    statuses: Mutex<BTreeMap<u16, u64>>,
+    sections: Mutex<BTreeMap<String, Box<Fn() -> Json + Send + Sync>>>
+}
+
+impl Stats {
+    /// Create a new set of counters, all zero.
+    pub fn new() -> Stats {
+        Stats {
+            inner: Arc::new(Inner {
+                started: Instant::now(),
+                threads: AtomicUsize::new(0),
+                connections: AtomicUsize::new(0),
+                in_flight: AtomicUsize::new(0),
+                served: AtomicUsize::new(0),
+                statuses: Mutex::new(BTreeMap::new()),
+                sections: Mutex::new(BTreeMap::new())
+            })
+        }
+    }
+
+    /// Read the current values of the counters.
+    pub fn snapshot(&self) -> Snapshot {
+        let inner = &self.inner;
+        Snapshot {
+            uptime: inner.started.elapsed(),
+            threads: inner.threads.load(Ordering::Relaxed),
+            active_connections: inner.connections.load(Ordering::Relaxed),
+            requests_in_flight: inner.in_flight.load(Ordering::Relaxed),
+            requests_served: inner.served.load(Ordering::Relaxed) as u64,
+            statuses: inner.statuses.lock().unwrap_or_else(|e| e.into_inner()).clone(),
+            sections: inner.sections.lock().unwrap_or_else(|e| e.into_inner()).iter()
+                .map(|(name, section)| (name.clone(), section()))
+                .collect()
+        }
+    }
+
+    /// Include the value returned by `section` in each snapshot, under
+    /// `name`, so that other parts of an application can report their own
+    /// status alongside the server's. A section added under the same name
+    /// replaces the earlier one.
+    pub fn add_section<F>(&self, name: &str, section: F)
+    where F: Fn() -> Json + Send + Sync + 'static {
+        self.inner.sections.lock().unwrap_or_else(|e| e.into_inner())
+            .insert(name.into(), Box::new(section));
+    }
+
+    // Recording, done by `Iron` while it serves requests.
+
+    #[doc(hidden)]
+    pub fn set_threads(&self, threads: usize) {
+        self.inner.threads.store(threads, Ordering::Relaxed);
+    }
+
+    #[doc(hidden)]
+    pub fn connection_started(&self) {
+        self.inner.connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[doc(hidden)]
+    pub fn connection_ended(&self) {
+        self.inner.connections.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    #[doc(hidden)]
+    pub fn request_started(&self) {
+        self.inner.in_flight.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[doc(hidden)]
+    pub fn request_finished(&self, status: Status) {
+        self.inner.in_flight.fetch_sub(1, Ordering::Relaxed);
+        self.inner.served.fetch_add(1, Ordering::Relaxed);
+        *self.inner.statuses.lock().unwrap_or_else(|e| e.into_inner())
+            .entry(status.to_u16()).or_insert(0) += 1;
+    }
+}
+
+impl Default for Stats {
+    fn default() -> Stats { Stats::new() }
+}
+
+/// The values of a server's counters at one point in time.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Snapshot {
+    /// How long since the server was created.
+    pub uptime: Duration,
+
+    /// The number of threads handling requests, or zero if the server is not
+    /// listening.
+    pub threads: usize,
+
+    /// The number of open client connections.
+    pub active_connections: usize,
+
+    /// The number of requests being handled.
+    pub requests_in_flight: usize,
+
+    /// The number of requests which have been answered.
+    pub requests_served: u64,
+
+    /// The number of responses sent with each status code.
+    pub statuses: BTreeMap<u16, u64>,
+
+    /// The sections added with `Stats::add_section`, by name.
+    pub sections: BTreeMap<String, Json>
+}
+
+impl ToJson for Snapshot {
+    fn to_json(&self) -> Json {
+        let mut object = Object::new();
+        object.insert("uptime_secs".into(), self.uptime.as_secs().to_json());
+        object.insert("threads".into(), self.threads.to_json());
+        object.insert("active_connections".into(), self.active_connections.to_json());
+        object.insert("requests_in_flight".into(), self.requests_in_flight.to_json());
+        object.insert("requests_served".into(), self.requests_served.to_json());
+        object.insert("statuses".into(), Json::Object(self.statuses.iter()
+            .map(|(status, count)| (status.to_string(), count.to_json()))
+            .collect()));
+        for (name, section) in &self.sections {
+            object.insert(name.clone(), section.clone());
+        }
+        Json::Object(object)
+    }
+}
+
+/// `AroundMiddleware` which answers GET requests for a fixed path with a JSON
+/// snapshot of `Stats`, passing all other requests to the handler it wraps.
+///
+/// The path defaults to `/__iron/stats`. The endpoint has no access control
+/// of its own, so it should not be exposed to untrusted clients.
+pub struct StatsEndpoint {
+    stats: Stats,
+    path: Vec<String>
+}
+
+impl StatsEndpoint {
+    /// Serve `stats` at `/__iron/stats`.
+    pub fn new(stats: Stats) -> StatsEndpoint {
+        StatsEndpoint { stats: stats, path: vec!["__iron".into(), "stats".into()] }
+    }
+
+    /// Serve the stats at `path` instead.
+    pub fn path(mut self, path: &str) -> StatsEndpoint {
+        self.path = path.split('/').filter(|s| !s.is_empty()).map(String::from).collect();
+        self
+    }
+}
+
+impl AroundMiddleware for StatsEndpoint {
+    fn around(self, handler: Box<Handler>) -> Box<Handler> {
+        Box::new(Endpoint { stats: self.stats, path: self.path, handler: handler })
+    }
+}
+
+struct Endpoint {
+    stats: Stats,
+    path: Vec<String>,
+    handler: Box<Handler>
+}
+
+impl Handler for Endpoint {
+    fn handle(&self, req: &mut Request) -> IronResult<Response> {
+        let matches = req.method == ::method::Get &&
+            req.url.path().iter().filter(|s| !s.is_empty()).eq(self.path.iter());
+        if !matches { return self.handler.handle(req) }
+
+        let mut res = Response::with((status::Ok, self.stats.snapshot().to_json().to_string()));
+        res.headers.set(headers::ContentType("application/json".parse().unwrap()));
+        res.headers.set(headers::CacheControl(vec![headers::CacheDirective::NoStore]));
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rustc_serialize::json::ToJson;
+
+    use method::Method;
+    use status;
+    use test::{MiddlewareHarness, StubRequest};
+    use {Chain, Request, Response};
+    use super::{Stats, StatsEndpoint};
+
+    #[test]
+    fn test_counters() {
+        let stats = Stats::new();
+        stats.connection_started();
+        stats.request_started();
+        stats.request_finished(status::Ok);
+        stats.request_started();
+        stats.request_finished(status::NotFound);
+        stats.request_started();
+
+        let snapshot = stats.clone().snapshot();
+        assert_eq!(snapshot.active_connections, 1);
+        assert_eq!(snapshot.requests_in_flight, 1);
+        assert_eq!(snapshot.requests_served, 2);
+        assert_eq!(snapshot.statuses.get(&404), Some(&1));
+
+        stats.add_section("queue", || 3.to_json());
+        assert_eq!(stats.snapshot().sections.get("queue"), Some(&3.to_json()));
+    }
+
+    #[test]
+    fn test_endpoint() {
+        let stats = Stats::new();
+        stats.request_started();
+        stats.request_finished(status::Ok);
+
+        let mut chain = Chain::new(|_: &mut Request| Ok(Response::with(status::Ok)));
+        chain.link_around(StatsEndpoint::new(stats));
+        let harness = MiddlewareHarness::new(chain);
+
+        let req = StubRequest::new(Method::Get, "http://localhost/__iron/stats");
+        let mut run = harness.handle(req);
+        let body = String::from_utf8(run.take_body()).unwrap();
+        assert!(body.contains(r#""statuses":{"200":1}"#), "{}", body);
+
+        let mut run = harness.handle(StubRequest::new(Method::Get, "http://localhost/other"));
+        assert!(run.take_body().is_empty());
+    }
+}
@@ -0,0 +1,60 @@
+//! A small pool of TCP connections to one server.
+
+use std::io::{self, BufReader};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Connections to `addr`, opened as needed, of which up to `max_idle` are
+/// kept open between uses.
+///
+/// Connections should only be given back when the last exchange on them
+/// completed, so that no unread data remains.
+pub struct Connections {
+    pub addr: SocketAddr,
+    pub timeout: Option<Duration>,
+    pub max_idle: usize,
+    idle: Mutex<Vec<BufReader<TcpStream>>>
+}
+
+impl Connections {
+    pub fn new(addr: SocketAddr) -> Connections {
+        Connections {
+            addr: addr,
+            timeout: Some(Duration::from_secs(5)),
+            max_idle: 4,
+            idle: Mutex::new(vec![])
+        }
+    }
+
+    /// An idle connection, or a new one if there are none.
+    pub fn take(&self) -> io::Result<BufReader<TcpStream>> {
+        if let Some(conn) = self.idle.lock().unwrap().pop() {
+            return Ok(conn);
+        }
+
+        let stream = try!(match self.timeout {
+            Some(timeout) => TcpStream::connect_timeout(&self.addr, timeout),
+            None => TcpStream::connect(&self.addr)
+        });
+
+        try!(stream.set_read_timeout(self.timeout));
+        try!(stream.set_write_timeout(self.timeout));
+        try!(stream.set_nodelay(true));
+        Ok(BufReader::new(stream))
+    }
+
+    /// Return a connection for reuse.
+    pub fn give(&self, conn: BufReader<TcpStream>) {
+        let mut idle = self.idle.lock().unwrap();
+        if idle.len() < self.max_idle { idle.push(conn) }
+    }
+}
+
+/// The first address `addr` resolves to.
+pub fn resolve<A: ToSocketAddrs>(addr: A) -> io::Result<SocketAddr> {
+    match try!(addr.to_socket_addrs()).next() {
+        Some(addr) => Ok(addr),
+        None => Err(io::Error::new(io::ErrorKind::InvalidInput, "Address resolved to nothing"))
+    }
+}
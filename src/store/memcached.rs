@@ -0,0 +1,298 @@
+//! A minimal client for memcached's text protocol.
+//!
+//! `Memcached` spreads keys over several servers with consistent hashing, so
+//! that adding or removing a server only moves a small share of the keys,
+//! and implements `Cache`:
+//!
+//! ```ignore
+//! let memcached = try!(Memcached::new(&["10.0.0.1:11211", "10.0.0.2:11211"]));
+//! let cache = SharedCache::new(memcached);
+//! ```
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::{self, BufRead, Write};
+use std::net::ToSocketAddrs;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use md5;
+
+use cache::Cache;
+use super::connections::{Connections, resolve};
+
+// The number of points each server has on the hash ring.
+const POINTS_PER_SERVER: usize = 160;
+
+// Expiry times longer than this are sent as absolute Unix times, as
+// memcached treats them as such.
+const MAX_RELATIVE_EXPIRY: u64 = 30 * 24 * 60 * 60;
+
+/// A client for a group of memcached servers.
+///
+/// Each server has its own pool of connections, as for `Redis`.
+pub struct Memcached {
+    servers: Vec<Connections>,
+    // Points on the hash ring, mapped to the index of their server.
+    ring: BTreeMap<u32, usize>
+}
+
+impl Memcached {
+    /// Create a client for the servers at `addrs`.
+    ///
+    /// Fails with `ErrorKind::InvalidInput` if `addrs` is empty. No
+    /// connection is made until the first command.
+    pub fn new<A: ToSocketAddrs>(addrs: &[A]) -> io::Result<Memcached> {
+        if addrs.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "No memcached servers given"));
+        }
+
+        let mut servers = vec![];
+        for addr in addrs {
+            servers.push(Connections::new(try!(resolve(addr))));
+        }
+
+        let ring = build_ring(&servers.iter().map(|s| s.addr.to_string()).collect::<Vec<_>>());
+        Ok(Memcached { servers: servers, ring: ring })
+    }
+
+    /// Set the timeout for connecting, reading and writing. Defaults to five
+    /// seconds.
+    pub fn timeout(&mut self, timeout: Option<Duration>) -> &mut Memcached {
+        for server in &mut self.servers { server.timeout = timeout }
+        self
+    }
+
+    /// Set how many idle connections to keep open to each server. Defaults
+    /// to four.
+    pub fn max_idle(&mut self, max_idle: usize) -> &mut Memcached {
+        for server in &mut self.servers { server.max_idle = max_idle }
+        self
+    }
+
+    fn server(&self, key: &[u8]) -> &Connections {
+        &self.servers[locate(&self.ring, key)]
+    }
+
+    fn try_get(&self, key: &[u8]) -> io::Result<Option<Vec<u8>>> {
+        let server = self.server(key);
+        let mut conn = try!(server.take());
+
+        try!(conn.get_mut().write_all(&[b"get ", key, b"\r\n"].concat()));
+        let value = try!(read_value(&mut conn));
+
+        server.give(conn);
+        Ok(value)
+    }
+
+    fn try_set(&self, key: &[u8], value: &[u8], ttl: Option<Duration>) -> io::Result<()> {
+        let server = self.server(key);
+        let mut conn = try!(server.take());
+
+        let header = format!(" 0 {} {}\r\n", expiry(ttl), value.len());
+        let command = [b"set ", key, header.as_bytes(), value, b"\r\n"].concat();
+        try!(conn.get_mut().write_all(&command));
+        let reply = try!(read_line(&mut conn));
+
+        server.give(conn);
+        match &reply[..] {
+            b"STORED" => Ok(()),
+            reply => Err(unexpected(reply))
+        }
+    }
+
+    fn try_delete(&self, key: &[u8]) -> io::Result<bool> {
+        let server = self.server(key);
+        let mut conn = try!(server.take());
+
+        try!(conn.get_mut().write_all(&[b"delete ", key, b"\r\n"].concat()));
+        let reply = try!(read_line(&mut conn));
+
+        server.give(conn);
+        match &reply[..] {
+            b"DELETED" => Ok(true),
+            b"NOT_FOUND" => Ok(false),
+            reply => Err(unexpected(reply))
+        }
+    }
+}
+
+impl fmt::Debug for Memcached {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let addrs = self.servers.iter().map(|s| s.addr.to_string()).collect::<Vec<_>>();
+        write!(f, "Memcached {{ servers: [{}] }}", addrs.join(", "))
+    }
+}
+
+impl Cache for Memcached {
+    fn get(&self, key: &str) -> Option<Vec<u8>> {
+        self.try_get(&encode_key(key)).unwrap_or_else(|e| {
+            warn!("memcached get failed: {}", e);
+            None
+        })
+    }
+
+    fn set(&self, key: &str, value: Vec<u8>, ttl: Option<Duration>) {
+        if let Err(e) = self.try_set(&encode_key(key), &value, ttl) {
+            warn!("memcached set failed: {}", e);
+        }
+    }
+
+    fn delete(&self, key: &str) -> bool {
+        self.try_delete(&encode_key(key)).unwrap_or_else(|e| {
+            warn!("memcached delete failed: {}", e);
+            false
+        })
+    }
+}
+
+fn build_ring(names: &[String]) -> BTreeMap<u32, usize> {
+    let mut ring = BTreeMap::new();
+
+    for (index, name) in names.iter().enumerate() {
+        for point in 0..POINTS_PER_SERVER {
+            ring.insert(hash(format!("{}-{}", name, point).as_bytes()), index);
+        }
+    }
+
+    ring
+}
+
+// The index of the server responsible for `key`: that of the first point on
+// the ring at or after the key's hash, wrapping around.
+fn locate(ring: &BTreeMap<u32, usize>, key: &[u8]) -> usize {
+    let hash = hash(key);
+    ring.range(hash..).next().or_else(|| ring.iter().next()).map(|(_, &index)| index).unwrap()
+}
+
+fn hash(bytes: &[u8]) -> u32 {
+    let digest = md5::compute(bytes).0;
+    (digest[0] as u32) << 24 | (digest[1] as u32) << 16 |
+    (digest[2] as u32) << 8 | digest[3] as u32
+}
+
+// Keys may be at most 250 bytes, without whitespace or control characters.
+// Other keys are replaced by their digest.
+fn encode_key(key: &str) -> Vec<u8> {
+    if key.len() <= 250 && !key.is_empty() && key.bytes().all(|b| b > b' ' && b != 0x7f) {
+        key.as_bytes().to_vec()
+    } else {
+        format!("md5:{:x}", md5::compute(key.as_bytes())).into_bytes()
+    }
+}
+
+// The expiry time to send for `ttl`: zero for none, whole seconds rounded up,
+// and absolute times beyond memcached's limit on relative ones.
+fn expiry(ttl: Option<Duration>) -> u64 {
+    let ttl = match ttl {
+        Some(ttl) => ttl,
+        None => return 0
+    };
+
+    let secs = ttl.as_secs() + if ttl.subsec_nanos() > 0 { 1 } else { 0 };
+
+    if secs > MAX_RELATIVE_EXPIRY {
+        SystemTime::now().duration_since(UNIX_EPOCH).map(|now| now.as_secs()).unwrap_or(0) + secs
+    } else {
+        secs.max(1)
+    }
+}
+
+// Read the reply to a `get` of one key.
+fn read_value<R: BufRead>(reader: &mut R) -> io::Result<Option<Vec<u8>>> {
+    let line = try!(read_line(reader));
+    if line == b"END" { return Ok(None) }
+
+    let len = {
+        let parts = line.split(|&b| b == b' ').collect::<Vec<_>>();
+
+        match (parts.get(0), parts.get(3)) {
+            (Some(&b"VALUE"), Some(len)) => {
+                match String::from_utf8_lossy(len).parse::<usize>() {
+                    Ok(len) => len,
+                    Err(_) => return Err(unexpected(&line))
+                }
+            },
+            _ => return Err(unexpected(&line))
+        }
+    };
+
+    let mut value = vec![0; len + 2];
+    try!(reader.read_exact(&mut value));
+    if !value.ends_with(b"\r\n") { return Err(unexpected(&value)) }
+    value.truncate(len);
+
+    match &try!(read_line(reader))[..] {
+        b"END" => Ok(Some(value)),
+        line => Err(unexpected(line))
+    }
+}
+
+// Read a CRLF-terminated line, without the terminator.
+fn read_line<R: BufRead>(reader: &mut R) -> io::Result<Vec<u8>> {
+    let mut line = vec![];
+    try!(reader.read_until(b'\n', &mut line));
+
+    if !line.ends_with(b"\r\n") { return Err(unexpected(&line)) }
+
+    line.truncate(line.len() - 2);
+    Ok(line)
+}
+
+fn unexpected(reply: &[u8]) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData,
+                   format!("Unexpected memcached reply: {:?}", String::from_utf8_lossy(reply)))
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+    use std::time::Duration;
+
+    use super::{build_ring, locate, encode_key, expiry, read_value};
+
+    #[test]
+    fn test_read_value() {
+        let mut reply = Cursor::new(b"VALUE key 0 5\r\nhe\r\no\r\nEND\r\n".to_vec());
+        assert_eq!(read_value(&mut reply).unwrap(), Some(b"he\r\no".to_vec()));
+
+        assert_eq!(read_value(&mut Cursor::new(b"END\r\n".to_vec())).unwrap(), None);
+        assert!(read_value(&mut Cursor::new(b"SERVER_ERROR oops\r\n".to_vec())).is_err());
+        assert!(read_value(&mut Cursor::new(b"VALUE key 0 5\r\nhe".to_vec())).is_err());
+    }
+
+    #[test]
+    fn test_encode_key() {
+        assert_eq!(encode_key("session:abc"), b"session:abc".to_vec());
+        assert!(encode_key("with space").starts_with(b"md5:"));
+        assert!(encode_key("").starts_with(b"md5:"));
+        assert_eq!(encode_key(&"x".repeat(300)).len(), 36);
+    }
+
+    #[test]
+    fn test_expiry() {
+        assert_eq!(expiry(None), 0);
+        assert_eq!(expiry(Some(Duration::from_millis(1500))), 2);
+        assert_eq!(expiry(Some(Duration::from_millis(0))), 1);
+        assert!(expiry(Some(Duration::from_secs(60 * 24 * 60 * 60))) > 1_000_000_000);
+    }
+
+    #[test]
+    fn test_consistent_hashing() {
+        let names = (0..4).map(|i| format!("10.0.0.{}:11211", i)).collect::<Vec<_>>();
+        let before = build_ring(&names[..3]);
+        let after = build_ring(&names);
+
+        let keys = (0..1000).map(|i| format!("key-{}", i)).collect::<Vec<_>>();
+        let moved = keys.iter()
+            .filter(|key| locate(&before, key.as_bytes()) != locate(&after, key.as_bytes()))
+            .count();
+
+        // Roughly a quarter of the keys move to the new server, and only there.
+        assert!(moved > 100 && moved < 400, "{} keys moved", moved);
+        assert!(keys.iter().all(|key| {
+            let old = locate(&before, key.as_bytes());
+            let new = locate(&after, key.as_bytes());
+            old == new || new == 3
+        }));
+    }
+}
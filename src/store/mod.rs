@@ -0,0 +1,7 @@
+//! Clients for external stores which implement the `Cache` trait, for
+//! sharing state between several server processes.
+
+pub mod redis;
+pub mod memcached;
+
+mod connections;
@@ -0,0 +1,249 @@
+//! A minimal client for servers speaking the Redis protocol (RESP).
+//!
+//! `Redis` sends commands over a small pool of connections to one server,
+//! and implements `Cache`, so that several processes can share a cache:
+//!
+//! ```ignore
+//! let redis = try!(Redis::new("127.0.0.1:6379"));
+//! let cache = SharedCache::new(redis);
+//! ```
+
+use std::fmt;
+use std::io::{self, BufRead, Write};
+use std::net::ToSocketAddrs;
+use std::time::Duration;
+
+use cache::Cache;
+use super::connections::{Connections, resolve};
+
+/// A reply from the server.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Reply {
+    /// A simple string, such as `OK`.
+    Status(String),
+
+    /// An integer.
+    Integer(i64),
+
+    /// A binary-safe string, or `None` for the null bulk string.
+    Bulk(Option<Vec<u8>>),
+
+    /// An array of replies, or `None` for the null array.
+    Array(Option<Vec<Reply>>)
+}
+
+/// A client for a Redis server.
+///
+/// Connections are opened as needed and up to `max_idle` of them are kept
+/// open for later commands. A connection on which an error occurs is
+/// discarded.
+pub struct Redis {
+    connections: Connections
+}
+
+impl Redis {
+    /// Create a client for the server at `addr`.
+    ///
+    /// No connection is made until the first command.
+    pub fn new<A: ToSocketAddrs>(addr: A) -> io::Result<Redis> {
+        Ok(Redis { connections: Connections::new(try!(resolve(addr))) })
+    }
+
+    /// Set the timeout for connecting, reading and writing. Defaults to five
+    /// seconds.
+    pub fn timeout(&mut self, timeout: Option<Duration>) -> &mut Redis {
+        self.connections.timeout = timeout;
+        self
+    }
+
+    /// Set how many idle connections to keep open. Defaults to four.
+    pub fn max_idle(&mut self, max_idle: usize) -> &mut Redis {
+        self.connections.max_idle = max_idle;
+        self
+    }
+
+    /// Send a command, given as its name followed by its arguments, and
+    /// read the reply.
+    ///
+    /// Error replies from the server are returned as errors of kind
+    /// `ErrorKind::Other`.
+    pub fn command(&self, args: &[&[u8]]) -> io::Result<Reply> {
+        let mut conn = try!(self.connections.take());
+
+        try!(write_command(conn.get_mut(), args));
+        let reply = try!(read_reply(&mut conn));
+
+        self.connections.give(conn);
+        reply.map_err(|message| io::Error::new(io::ErrorKind::Other, message))
+    }
+}
+
+impl fmt::Debug for Redis {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Redis {{ addr: {} }}", self.connections.addr)
+    }
+}
+
+impl Cache for Redis {
+    fn get(&self, key: &str) -> Option<Vec<u8>> {
+        match self.command(&[b"GET", key.as_bytes()]) {
+            Ok(Reply::Bulk(value)) => value,
+            Ok(reply) => {
+                warn!("Unexpected reply to Redis GET: {:?}", reply);
+                None
+            },
+            Err(e) => {
+                warn!("Redis GET failed: {}", e);
+                None
+            }
+        }
+    }
+
+    fn set(&self, key: &str, value: Vec<u8>, ttl: Option<Duration>) {
+        let result = match ttl {
+            Some(ttl) => {
+                let millis = (ttl.as_secs() * 1000 + ttl.subsec_millis() as u64).max(1);
+                self.command(&[b"SET", key.as_bytes(), &value, b"PX",
+                               millis.to_string().as_bytes()])
+            },
+            None => self.command(&[b"SET", key.as_bytes(), &value])
+        };
+
+        if let Err(e) = result {
+            warn!("Redis SET failed: {}", e);
+        }
+    }
+
+    fn delete(&self, key: &str) -> bool {
+        match self.command(&[b"DEL", key.as_bytes()]) {
+            Ok(Reply::Integer(deleted)) => deleted > 0,
+            Ok(reply) => {
+                warn!("Unexpected reply to Redis DEL: {:?}", reply);
+                false
+            },
+            Err(e) => {
+                warn!("Redis DEL failed: {}", e);
+                false
+            }
+        }
+    }
+}
+
+// Write a command as an array of bulk strings.
+fn write_command<W: Write>(writer: &mut W, args: &[&[u8]]) -> io::Result<()> {
+    let mut buf = format!("*{}\r\n", args.len()).into_bytes();
+
+    for arg in args {
+        buf.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+        buf.extend_from_slice(arg);
+        buf.extend_from_slice(b"\r\n");
+    }
+
+    try!(writer.write_all(&buf));
+    writer.flush()
+}
+
+// Read one reply. The inner `Err` holds an error reply from the server, after
+// which the connection can still be used; the outer one is an I/O or protocol
+// error, after which it cannot.
+fn read_reply<R: BufRead>(reader: &mut R) -> io::Result<Result<Reply, String>> {
+    let line = try!(read_line(reader));
+    let (kind, rest) = match line.split_first() {
+        Some((&kind, rest)) => (kind, String::from_utf8_lossy(rest).into_owned()),
+        None => return Err(invalid("empty reply"))
+    };
+
+    let reply = match kind {
+        b'+' => Reply::Status(rest),
+        b'-' => return Ok(Err(rest)),
+        b':' => Reply::Integer(try!(parse_integer(&rest))),
+        b'$' => {
+            let len = try!(parse_integer(&rest));
+            if len < 0 { return Ok(Ok(Reply::Bulk(None))) }
+
+            let mut value = vec![0; len as usize + 2];
+            try!(reader.read_exact(&mut value));
+            if !value.ends_with(b"\r\n") { return Err(invalid("unterminated bulk string")) }
+
+            value.truncate(len as usize);
+            Reply::Bulk(Some(value))
+        },
+        b'*' => {
+            let len = try!(parse_integer(&rest));
+            if len < 0 { return Ok(Ok(Reply::Array(None))) }
+
+            // Read every item, even after an error, to keep the connection
+            // usable.
+            let mut items = Vec::with_capacity(len as usize);
+            let mut error = None;
+            for _ in 0..len {
+                match try!(read_reply(reader)) {
+                    Ok(item) => items.push(item),
+                    Err(message) => { error.get_or_insert(message); }
+                }
+            }
+
+            if let Some(message) = error { return Ok(Err(message)) }
+            Reply::Array(Some(items))
+        },
+        _ => return Err(invalid("unknown reply type"))
+    };
+
+    Ok(Ok(reply))
+}
+
+// Read a CRLF-terminated line, without the terminator.
+fn read_line<R: BufRead>(reader: &mut R) -> io::Result<Vec<u8>> {
+    let mut line = vec![];
+    try!(reader.read_until(b'\n', &mut line));
+
+    if !line.ends_with(b"\r\n") { return Err(invalid("unterminated line")) }
+
+    line.truncate(line.len() - 2);
+    Ok(line)
+}
+
+fn parse_integer(s: &str) -> io::Result<i64> {
+    s.parse().map_err(|_| invalid("invalid integer"))
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("Invalid Redis reply: {}", message))
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+
+    use super::{write_command, read_reply, Reply};
+
+    fn read(bytes: &[u8]) -> Result<Reply, String> {
+        read_reply(&mut Cursor::new(bytes.to_vec())).unwrap()
+    }
+
+    #[test]
+    fn test_write_command() {
+        let mut buf = vec![];
+        write_command(&mut buf, &[b"SET", b"key", b"va\r\nlue"]).unwrap();
+        assert_eq!(buf, b"*3\r\n$3\r\nSET\r\n$3\r\nkey\r\n$7\r\nva\r\nlue\r\n".to_vec());
+    }
+
+    #[test]
+    fn test_read_reply() {
+        assert_eq!(read(b"+OK\r\n"), Ok(Reply::Status("OK".to_owned())));
+        assert_eq!(read(b"-ERR wrong type\r\n"), Err("ERR wrong type".to_owned()));
+        assert_eq!(read(b":42\r\n"), Ok(Reply::Integer(42)));
+        assert_eq!(read(b"$5\r\nhe\r\no\r\n"), Ok(Reply::Bulk(Some(b"he\r\no".to_vec()))));
+        assert_eq!(read(b"$-1\r\n"), Ok(Reply::Bulk(None)));
+        assert_eq!(read(b"*2\r\n:1\r\n$1\r\na\r\n"),
+                   Ok(Reply::Array(Some(vec![Reply::Integer(1),
+                                             Reply::Bulk(Some(b"a".to_vec()))]))));
+    }
+
+    #[test]
+    fn test_invalid_reply() {
+        assert!(read_reply(&mut Cursor::new(b"$5\r\nab".to_vec())).is_err());
+        assert!(read_reply(&mut Cursor::new(b"?\r\n".to_vec())).is_err());
+        assert!(read_reply(&mut Cursor::new(b"+OK".to_vec())).is_err());
+    }
+}
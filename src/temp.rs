@@ -0,0 +1,148 @@
+//! Self-deleting temporary files.
+
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// A file in the system's temporary directory which is deleted when it is
+/// dropped, including while unwinding from a panic.
+#[derive(Debug)]
+pub struct TempFile {
+    file: File,
+    path: PathBuf,
+    persisted: bool
+}
+
+impl TempFile {
+    /// Create a new, empty temporary file, open for reading and writing.
+    pub fn new() -> io::Result<TempFile> {
+        let dir = env::temp_dir();
+
+        loop {
+            let nanos = SystemTime::now().duration_since(UNIX_EPOCH)
+                .map(|d| d.subsec_nanos()).unwrap_or(0);
+            let path = dir.join(format!("iron-{}-{}-{}", process::id(),
+                                        COUNTER.fetch_add(1, Ordering::Relaxed), nanos));
+
+            let mut options = OpenOptions::new();
+            options.read(true).write(true).create_new(true);
+            private(&mut options);
+
+            match options.open(&path) {
+                Ok(file) => return Ok(TempFile { file: file, path: path, persisted: false }),
+                Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e)
+            }
+        }
+    }
+
+    /// The location of the file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Move the file to `dest` so that it is kept after being dropped.
+    ///
+    /// The file is renamed if possible, and copied otherwise, for instance
+    /// when `dest` is on another filesystem.
+    pub fn persist<P: AsRef<Path>>(mut self, dest: P) -> io::Result<()> {
+        self.move_to(dest.as_ref())
+    }
+
+    // Like `persist`, but leaving the file to the caller, who still owns
+    // it if moving it fails.
+    #[doc(hidden)]
+    pub fn move_to(&mut self, dest: &Path) -> io::Result<()> {
+        try!(self.file.flush());
+
+        match fs::rename(&self.path, dest) {
+            Ok(()) => {
+                self.persisted = true;
+                Ok(())
+            },
+            Err(_) => fs::copy(&self.path, dest).map(|_| ())
+        }
+    }
+}
+
+// Keep the file from being read by other local users, since it may hold a
+// request body.
+#[cfg(unix)]
+fn private(options: &mut OpenOptions) {
+    use std::os::unix::fs::OpenOptionsExt;
+    options.mode(0o600);
+}
+
+#[cfg(not(unix))]
+fn private(_: &mut OpenOptions) {}
+
+impl Read for TempFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.file.read(buf)
+    }
+}
+
+impl Write for TempFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl Seek for TempFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.file.seek(pos)
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        if self.persisted { return }
+
+        if let Err(e) = fs::remove_file(&self.path) {
+            warn!("Could not remove temporary file {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Read, Write, Seek, SeekFrom};
+
+    use super::TempFile;
+
+    #[test]
+    fn test_deleted_on_drop() {
+        let mut file = TempFile::new().unwrap();
+        let path = file.path.clone();
+
+        file.write_all(b"spilled").unwrap();
+        file.seek(SeekFrom::Start(0)).unwrap();
+
+        let mut contents = String::new();
+        file.read_to_string(&mut contents).unwrap();
+        assert_eq!(contents, "spilled");
+
+        drop(file);
+        assert!(!path.exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_private() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let file = TempFile::new().unwrap();
+        let mode = file.path().metadata().unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+}
@@ -0,0 +1,288 @@
+//! Resolving the tenant a request is for, in multi-tenant applications.
+//!
+//! `Tenancy` is `BeforeMiddleware` which works out the tenant from the
+//! request, trying each of its resolvers in turn, and stores it as a
+//! `Tenant` in the request's extensions:
+//!
+//! ```ignore
+//! let mut tenancy = Tenancy::new();
+//! tenancy.subdomain("example.com")      // acme.example.com
+//!        .header("X-Tenant")            // or X-Tenant: acme
+//!        .tenants(&["acme", "globex"])
+//!        .hook(|tenant, req| {
+//!            req.extensions.insert::<TenantDb>(pools.get(&tenant.id));
+//!            Ok(())
+//!        });
+//! chain.link_before(tenancy);
+//!
+//! // Later middleware and handlers:
+//! let tenant = Tenant::of(req).unwrap();
+//! chain.link_around(ConcurrencyLimit::per_key(10, Tenant::key));
+//! ```
+//!
+//! Hooks run once the tenant is known, so per-tenant resources, such as a
+//! database pool, can be attached before anything else needs them.
+
+use std::error::Error;
+use std::fmt;
+
+use rust_url::Host;
+use typemap::Key;
+
+use request::Url;
+use {status, BeforeMiddleware, Request, IronResult, IronError};
+
+/// The tenant a request is for, as resolved by `Tenancy`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Tenant {
+    /// The tenant's identifier, as found in the request.
+    pub id: String
+}
+
+impl Key for Tenant { type Value = Tenant; }
+
+impl Tenant {
+    /// The tenant of `req`, if `Tenancy` has resolved one.
+    pub fn of<'a>(req: &'a Request) -> Option<&'a Tenant> {
+        req.extensions.get::<Tenant>()
+    }
+
+    /// The id of the tenant of `req`, for keying limits, caches or logs by
+    /// tenant, as with `ConcurrencyLimit::per_key`.
+    pub fn key(req: &Request) -> Option<String> {
+        Tenant::of(req).map(|tenant| tenant.id.clone())
+    }
+}
+
+type Hook = Box<Fn(&Tenant, &mut Request) -> IronResult<()> + Send + Sync>;
+
+enum Resolver {
+    Subdomain(String),
+    Header(String),
+    PathPrefix,
+    Custom(Box<Fn(&Request) -> Option<String> + Send + Sync>)
+}
+
+/// Middleware resolving the `Tenant` of each request.
+///
+/// Requests for which no resolver finds a tenant are rejected with
+/// `400 Bad Request`, unless the tenant is `optional`, and requests for a
+/// tenant not in the list given to `tenants`, if any, with `404 Not Found`.
+pub struct Tenancy {
+    resolvers: Vec<Resolver>,
+    tenants: Option<Vec<String>>,
+    required: bool,
+    hooks: Vec<Hook>
+}
+
+impl Tenancy {
+    /// Create the middleware with no resolvers.
+    pub fn new() -> Tenancy {
+        Tenancy {
+            resolvers: Vec::new(),
+            tenants: None,
+            required: true,
+            hooks: Vec::new()
+        }
+    }
+
+    /// Take the tenant from the subdomain of `domain` the request is for,
+    /// so that `acme.example.com` is for the tenant `acme`.
+    ///
+    /// Only a single label is taken; `a.b.example.com` has no tenant.
+    pub fn subdomain(&mut self, domain: &str) -> &mut Tenancy {
+        self.resolvers.push(Resolver::Subdomain(domain.trim_start_matches('.').to_lowercase()));
+        self
+    }
+
+    /// Take the tenant from the header `name`.
+    pub fn header(&mut self, name: &str) -> &mut Tenancy {
+        self.resolvers.push(Resolver::Header(name.into()));
+        self
+    }
+
+    /// Take the tenant from the first segment of the path, which is then
+    /// removed from the URL, so that `/acme/orders` is a request for
+    /// `/orders` for the tenant `acme`.
+    pub fn path_prefix(&mut self) -> &mut Tenancy {
+        self.resolvers.push(Resolver::PathPrefix);
+        self
+    }
+
+    /// Take the tenant from the result of `resolver`, such as a claim of
+    /// the request's access token.
+    pub fn resolver<F>(&mut self, resolver: F) -> &mut Tenancy
+    where F: Fn(&Request) -> Option<String> + Send + Sync + 'static {
+        self.resolvers.push(Resolver::Custom(Box::new(resolver)));
+        self
+    }
+
+    /// Only accept these tenants.
+    pub fn tenants(&mut self, tenants: &[&str]) -> &mut Tenancy {
+        self.tenants = Some(tenants.iter().map(|&tenant| tenant.into()).collect());
+        self
+    }
+
+    /// Pass on requests without a tenant, instead of rejecting them.
+    pub fn optional(&mut self) -> &mut Tenancy {
+        self.required = false;
+        self
+    }
+
+    /// Call `hook` once the tenant of a request is known, before the
+    /// request is passed on. An error from the hook fails the request.
+    pub fn hook<F>(&mut self, hook: F) -> &mut Tenancy
+    where F: Fn(&Tenant, &mut Request) -> IronResult<()> + Send + Sync + 'static {
+        self.hooks.push(Box::new(hook));
+        self
+    }
+
+    // The tenant found by the first resolver to find one, and which
+    // resolver found it.
+    fn resolve(&self, req: &Request) -> Option<(String, &Resolver)> {
+        self.resolvers.iter().filter_map(|resolver| {
+            let id = match *resolver {
+                Resolver::Subdomain(ref domain) => match req.url.host() {
+                    Host::Domain(host) => {
+                        let host = host.to_lowercase();
+                        let suffix = format!(".{}", domain);
+                        if host.ends_with(&suffix) {
+                            Some(host[..host.len() - suffix.len()].to_owned())
+                                .filter(|label| !label.contains('.'))
+                        } else {
+                            None
+                        }
+                    },
+                    _ => None
+                },
+                Resolver::Header(ref name) => req.headers.get_raw(name)
+                    .and_then(|values| values.first())
+                    .map(|value| String::from_utf8_lossy(value).trim().to_owned()),
+                Resolver::PathPrefix => req.url.path().first().map(|&segment| segment.to_owned()),
+                Resolver::Custom(ref resolver) => resolver(req)
+            };
+            id.filter(|id| !id.is_empty()).map(|id| (id, resolver))
+        }).next()
+    }
+}
+
+impl BeforeMiddleware for Tenancy {
+    fn before(&self, req: &mut Request) -> IronResult<()> {
+        let (id, resolver) = match self.resolve(req) {
+            Some(resolved) => resolved,
+            None if self.required => {
+                return Err(IronError::new(TenantError::Missing, status::BadRequest))
+            },
+            None => return Ok(())
+        };
+
+        if let Some(ref tenants) = self.tenants {
+            if !tenants.contains(&id) {
+                return Err(IronError::new(TenantError::Unknown(id), status::NotFound))
+            }
+        }
+
+        if let Resolver::PathPrefix = *resolver {
+            let mut url = req.url.clone().into_generic_url();
+            let rest = req.url.path()[1..].join("/");
+            url.set_path(&format!("/{}", rest));
+            if let Ok(url) = Url::from_generic_url(url) { req.url = url }
+        }
+
+        let tenant = Tenant { id: id };
+        for hook in &self.hooks {
+            try!(hook(&tenant, req));
+        }
+        req.extensions.insert::<Tenant>(tenant);
+        Ok(())
+    }
+}
+
+/// The error for a request rejected by `Tenancy`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TenantError {
+    /// No resolver found a tenant.
+    Missing,
+
+    /// The tenant found is not one of those accepted.
+    Unknown(String)
+}
+
+impl fmt::Display for TenantError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TenantError::Missing => f.write_str(self.description()),
+            TenantError::Unknown(ref id) => write!(f, "Unknown tenant: {}", id)
+        }
+    }
+}
+
+impl Error for TenantError {
+    fn description(&self) -> &str {
+        match *self {
+            TenantError::Missing => "No tenant given",
+            TenantError::Unknown(_) => "Unknown tenant"
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use method::Method;
+    use status;
+    use test::{MiddlewareHarness, StubRequest};
+    use {IronError, Request};
+    use super::{Tenancy, Tenant, TenantError};
+
+    fn tenant(harness: &MiddlewareHarness<Tenancy>, req: StubRequest)
+              -> Result<(String, String), Option<status::Status>> {
+        let run = harness.before(req);
+        match run.extension::<Tenant>() {
+            Some(tenant) => Ok((tenant.id.clone(), run.request.url.path().join("/"))),
+            None => Err(run.status())
+        }
+    }
+
+    #[test]
+    fn test_resolvers() {
+        let mut tenancy = Tenancy::new();
+        tenancy.subdomain("example.com").header("X-Tenant").path_prefix();
+        let harness = MiddlewareHarness::new(tenancy);
+        let get = |url: &str| StubRequest::new(Method::Get, url);
+
+        assert_eq!(tenant(&harness, get("http://Acme.example.com/orders")),
+                   Ok(("acme".into(), "orders".into())));
+        assert_eq!(tenant(&harness, get("http://example.com/orders").raw_header("X-Tenant", "b")),
+                   Ok(("b".into(), "orders".into())));
+        assert_eq!(tenant(&harness, get("http://example.com/c/orders/1")),
+                   Ok(("c".into(), "orders/1".into())));
+        assert_eq!(tenant(&harness, get("http://example.com/")), Err(Some(status::BadRequest)));
+    }
+
+    #[test]
+    fn test_tenants_and_hooks() {
+        let mut tenancy = Tenancy::new();
+        tenancy.header("X-Tenant").tenants(&["acme", "globex"]).hook(|tenant, req: &mut Request| {
+            if tenant.id == "globex" && req.method != Method::Get {
+                return Err(IronError::new(TenantError::Unknown(tenant.id.clone()),
+                                          status::Forbidden))
+            }
+            Ok(())
+        });
+        let harness = MiddlewareHarness::new(tenancy);
+        let req = |method, id| {
+            StubRequest::new(method, "http://localhost/").raw_header("X-Tenant", id)
+        };
+
+        assert!(tenant(&harness, req(Method::Post, "acme")).is_ok());
+        assert_eq!(tenant(&harness, req(Method::Get, "initech")), Err(Some(status::NotFound)));
+        assert!(tenant(&harness, req(Method::Get, "globex")).is_ok());
+        assert_eq!(tenant(&harness, req(Method::Post, "globex")), Err(Some(status::Forbidden)));
+
+        let mut optional = Tenancy::new();
+        optional.header("X-Tenant").optional();
+        let harness = MiddlewareHarness::new(optional);
+        assert_eq!(tenant(&harness, StubRequest::new(Method::Get, "http://localhost/")),
+                   Err(None));
+    }
+}
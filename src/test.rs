@@ -0,0 +1,299 @@
+//! Utilities for testing middleware and handlers without a server.
+//!
+//! `StubRequest` builds requests whose body is read from memory, and
+//! `MiddlewareHarness` runs a single middleware or handler against them,
+//! capturing the request afterwards so that changes to its extensions can be
+//! inspected along with the result:
+//!
+//! ```
+//! # use iron::prelude::*;
+//! # use iron::{status, BeforeMiddleware};
+//! # use iron::method::Method;
+//! # use iron::test::{MiddlewareHarness, StubRequest};
+//! struct RequireJson;
+//!
+//! impl BeforeMiddleware for RequireJson {
+//!     fn before(&self, req: &mut Request) -> IronResult<()> {
+//!         if req.headers.get_raw("Content-Type").is_some() { return Ok(()) }
+//!         Err(IronError::new(::std::fmt::Error, status::UnsupportedMediaType))
+//!     }
+//! }
+//!
+//! let harness = MiddlewareHarness::new(RequireJson);
+//! let run = harness.before(StubRequest::new(Method::Post, "http://localhost/items"));
+//! assert_eq!(run.status(), Some(status::UnsupportedMediaType));
+//! ```
+
+use std::net::SocketAddr;
+
+use typemap::Key;
+
+use headers::{Header, HeaderFormat, Headers};
+use method::Method;
+use request::{Body, HttpVersion};
+use response::ResponseBody;
+use status::Status;
+use {AfterMiddleware, BeforeMiddleware, Handler, Request, Response, IronResult, IronError,
+     TypeMap, Url};
+
+/// A builder for requests which do not come from a connection.
+#[derive(Debug, Clone)]
+pub struct StubRequest {
+    method: Method,
+    url: Url,
+    version: HttpVersion,
+    headers: Headers,
+    body: Vec<u8>,
+    remote_addr: SocketAddr
+}
+
+impl StubRequest {
+    /// Start building a request for `url` with an empty body.
+    ///
+    /// A request for an `https` URL is taken to have been received over
+    /// HTTPS.
+    ///
+    /// ## Panics
+    ///
+    /// Panics if `url` is not a valid absolute URL.
+    pub fn new(method: Method, url: &str) -> StubRequest {
+        StubRequest {
+            method: method,
+            url: Url::parse(url).unwrap_or_else(|e| panic!("Invalid stub URL {}: {}", url, e)),
+            version: HttpVersion::Http11,
+            headers: Headers::new(),
+            body: vec![],
+            remote_addr: SocketAddr::from(([127, 0, 0, 1], 50000))
+        }
+    }
+
+    /// Set the HTTP version. Defaults to HTTP/1.1.
+    pub fn version(mut self, version: HttpVersion) -> StubRequest {
+        self.version = version;
+        self
+    }
+
+    /// Set a header.
+    pub fn header<H: Header + HeaderFormat>(mut self, header: H) -> StubRequest {
+        self.headers.set(header);
+        self
+    }
+
+    /// Set a header from its raw value.
+    pub fn raw_header(mut self, name: &str, value: &str) -> StubRequest {
+        self.headers.set_raw(name.to_owned(), vec![value.as_bytes().to_vec()]);
+        self
+    }
+
+    /// Set the body.
+    pub fn body<B: Into<Vec<u8>>>(mut self, body: B) -> StubRequest {
+        self.body = body.into();
+        self
+    }
+
+    /// Set the address the request comes from. Defaults to `127.0.0.1:50000`.
+    pub fn remote_addr(mut self, addr: SocketAddr) -> StubRequest {
+        self.remote_addr = addr;
+        self
+    }
+
+    /// Build the request.
+    pub fn build<'a, 'b>(self) -> Request<'a, 'b> {
+        let port = self.url.port();
+        let secure = self.url.scheme() == "https";
+
+        Request {
+            url: self.url,
+            remote_addr: self.remote_addr,
+            local_addr: SocketAddr::from(([127, 0, 0, 1], port)),
+            headers: self.headers,
+            body: Body::from_bytes(self.body),
+            method: self.method,
+            version: self.version,
+            extensions: TypeMap::new(),
+            secure: secure
+        }
+    }
+}
+
+/// Runs a single middleware or handler against stub requests.
+pub struct MiddlewareHarness<M> {
+    middleware: M
+}
+
+impl<M> MiddlewareHarness<M> {
+    /// Wrap `middleware`, which may also be a `Handler` such as a `Chain`.
+    pub fn new(middleware: M) -> MiddlewareHarness<M> {
+        MiddlewareHarness { middleware: middleware }
+    }
+
+    /// The wrapped middleware.
+    pub fn middleware(&self) -> &M {
+        &self.middleware
+    }
+}
+
+impl<M: BeforeMiddleware> MiddlewareHarness<M> {
+    /// Run `BeforeMiddleware::before` on a request.
+    pub fn before(&self, req: StubRequest) -> Run<()> {
+        let mut req = req.build();
+        let result = self.middleware.before(&mut req);
+        Run { request: req, result: result }
+    }
+
+    /// Run `BeforeMiddleware::catch` on a request and an error.
+    pub fn catch_before(&self, req: StubRequest, err: IronError) -> Run<()> {
+        let mut req = req.build();
+        let result = self.middleware.catch(&mut req, err);
+        Run { request: req, result: result }
+    }
+}
+
+impl<M: AfterMiddleware> MiddlewareHarness<M> {
+    /// Run `AfterMiddleware::after` on a request and a response.
+    pub fn after(&self, req: StubRequest, res: Response) -> Run<Response> {
+        let mut req = req.build();
+        let result = self.middleware.after(&mut req, res);
+        Run { request: req, result: result }
+    }
+
+    /// Run `AfterMiddleware::catch` on a request and an error.
+    pub fn catch_after(&self, req: StubRequest, err: IronError) -> Run<Response> {
+        let mut req = req.build();
+        let result = self.middleware.catch(&mut req, err);
+        Run { request: req, result: result }
+    }
+}
+
+impl<M: Handler> MiddlewareHarness<M> {
+    /// Run `Handler::handle` on a request.
+    ///
+    /// To test an `AroundMiddleware`, wrap a handler in it with a `Chain`
+    /// and test the chain.
+    pub fn handle(&self, req: StubRequest) -> Run<Response> {
+        let mut req = req.build();
+        let result = self.middleware.handle(&mut req);
+        Run { request: req, result: result }
+    }
+}
+
+/// The outcome of running middleware with a `MiddlewareHarness`: the request
+/// as the middleware left it, and its result.
+pub struct Run<T> {
+    /// The request after the middleware ran.
+    pub request: Request<'static, 'static>,
+
+    /// What the middleware returned.
+    pub result: IronResult<T>
+}
+
+impl<T> Run<T> {
+    /// Whether the middleware succeeded.
+    pub fn is_ok(&self) -> bool {
+        self.result.is_ok()
+    }
+
+    /// The error the middleware returned, if any.
+    pub fn error(&self) -> Option<&IronError> {
+        self.result.as_ref().err()
+    }
+
+    /// The value the middleware stored in the request's extensions under
+    /// `K`, if any.
+    pub fn extension<K: Key>(&self) -> Option<&K::Value> {
+        self.request.extensions.get::<K>()
+    }
+}
+
+impl Run<()> {
+    /// The status of the error response, if the middleware failed.
+    pub fn status(&self) -> Option<Status> {
+        self.error().and_then(|err| err.response.status)
+    }
+}
+
+impl Run<Response> {
+    /// The status of the response, whether the middleware succeeded or not.
+    pub fn status(&self) -> Option<Status> {
+        match self.result {
+            Ok(ref res) => res.status,
+            Err(ref err) => err.response.status
+        }
+    }
+
+    /// The response, whether the middleware succeeded or not.
+    pub fn response(&self) -> &Response {
+        match self.result {
+            Ok(ref res) => res,
+            Err(ref err) => &err.response
+        }
+    }
+
+    /// Write out the body of the response, whether the middleware succeeded
+    /// or not, leaving the response without one.
+    pub fn take_body(&mut self) -> Vec<u8> {
+        let res = match self.result {
+            Ok(ref mut res) => res,
+            Err(ref mut err) => &mut err.response
+        };
+
+        let mut bytes = vec![];
+        if let Some(mut body) = res.body.take() {
+            body.write_body(&mut ResponseBody::new(&mut bytes)).unwrap();
+        }
+        bytes
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Read;
+
+    use typemap::Key;
+
+    use method::Method;
+    use {status, AfterMiddleware, BeforeMiddleware, Request, Response, IronResult};
+    use super::{MiddlewareHarness, StubRequest};
+
+    struct Seen;
+    impl Key for Seen { type Value = String; }
+
+    struct Record;
+
+    impl BeforeMiddleware for Record {
+        fn before(&self, req: &mut Request) -> IronResult<()> {
+            let mut body = String::new();
+            req.body.read_to_string(&mut body).unwrap();
+            req.extensions.insert::<Seen>(body);
+            Ok(())
+        }
+    }
+
+    impl AfterMiddleware for Record {
+        fn after(&self, _: &mut Request, _: Response) -> IronResult<Response> {
+            Ok(Response::with((status::Created, "recorded")))
+        }
+    }
+
+    #[test]
+    fn test_before() {
+        let harness = MiddlewareHarness::new(Record);
+        let req = StubRequest::new(Method::Post, "http://localhost:3000/").body("hello");
+        let run = harness.before(req);
+
+        assert!(run.is_ok());
+        assert_eq!(run.status(), None);
+        assert_eq!(run.extension::<Seen>().map(|s| &**s), Some("hello"));
+        assert_eq!(run.request.local_addr.port(), 3000);
+    }
+
+    #[test]
+    fn test_after() {
+        let harness = MiddlewareHarness::new(Record);
+        let mut run = harness.after(StubRequest::new(Method::Get, "http://localhost/"),
+                                    Response::new());
+
+        assert_eq!(run.status(), Some(status::Created));
+        assert_eq!(run.take_body(), b"recorded".to_vec());
+    }
+}
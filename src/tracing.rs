@@ -0,0 +1,457 @@
+//! Per-request tracing spans, for finding out where the time goes.
+//!
+//! A `Tracer`, linked as both `BeforeMiddleware` and `AfterMiddleware`,
+//! opens a root span for each request and closes it once the response has
+//! been produced. Handlers and middleware open child spans around work they
+//! want to time, such as downstream calls, with `tracing::span`; a span ends
+//! when its guard is dropped:
+//!
+//! ```ignore
+//! let tracer = Arc::new(Tracer::new(LogExporter));
+//! chain.link_before(tracer.clone());
+//! chain.link_after(tracer);
+//!
+//! // In a handler:
+//! let span = tracing::span(req, "load user");
+//! span.tag("user_id", &id);
+//! let user = {
+//!     let _query = span.child("select");
+//!     db.load_user(id)
+//! };
+//! drop(span);
+//! ```
+//!
+//! If tracing is also enabled on the `Chain` with `Chain::trace`, each
+//! middleware call becomes a child span of the root as well.
+//!
+//! Completed traces are handed to an `Exporter`. `LogExporter`,
+//! `JsonFileExporter` and `UdpExporter` are provided; exporters are called on
+//! the thread handling the request, so slow ones should queue their work.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs, UdpSocket};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use rustc_serialize::json::{Json, Object, ToJson};
+use typemap::Key;
+
+use middleware::Trace;
+use {BeforeMiddleware, AfterMiddleware, Request, Response, IronResult, IronError};
+
+/// A timed operation within a trace.
+#[derive(Debug, Clone)]
+pub struct Span {
+    /// The index of this span in its trace.
+    pub id: usize,
+
+    /// The index of the span this one is part of, or `None` for the root.
+    pub parent: Option<usize>,
+
+    /// What the span measures.
+    pub name: String,
+
+    /// When the span started, relative to the start of the trace.
+    pub offset: Duration,
+
+    /// How long the span lasted. Spans still open when the trace completes
+    /// end with it.
+    pub duration: Duration,
+
+    /// Extra information attached to the span.
+    pub tags: Vec<(String, String)>
+}
+
+/// The spans recorded for one request.
+#[derive(Debug, Clone)]
+pub struct CompletedTrace {
+    /// An identifier for the trace, unique within this process.
+    pub id: u64,
+
+    /// When the request started.
+    pub started: SystemTime,
+
+    /// The spans, root first, in the order they were opened.
+    pub spans: Vec<Span>
+}
+
+impl ToJson for CompletedTrace {
+    fn to_json(&self) -> Json {
+        let mut object = Object::new();
+        object.insert("id".into(), format!("{:016x}", self.id).to_json());
+        object.insert("started".into(), micros(
+            self.started.duration_since(UNIX_EPOCH).unwrap_or_default()).to_json());
+        object.insert("spans".into(), Json::Array(self.spans.iter().map(|span| {
+            let mut object = Object::new();
+            object.insert("id".into(), span.id.to_json());
+            object.insert("parent".into(), span.parent.to_json());
+            object.insert("name".into(), span.name.to_json());
+            object.insert("offset_us".into(), micros(span.offset).to_json());
+            object.insert("duration_us".into(), micros(span.duration).to_json());
+            object.insert("tags".into(), Json::Object(span.tags.iter()
+                .map(|&(ref k, ref v)| (k.clone(), v.to_json()))
+                .collect()));
+            Json::Object(object)
+        }).collect()));
+        Json::Object(object)
+    }
+}
+
+fn micros(duration: Duration) -> u64 {
+    duration.as_secs() * 1_000_000 + duration.subsec_nanos() as u64 / 1_000
+}
+
+/// Receives completed traces.
+pub trait Exporter: Send + Sync + 'static {
+    /// Export `trace`.
+    fn export(&self, trace: &CompletedTrace);
+}
+
+/// Logs each trace at `info` level, one span per line.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LogExporter;
+
+impl Exporter for LogExporter {
+    fn export(&self, trace: &CompletedTrace) {
+        let mut lines = vec![format!("Trace {:016x}:", trace.id)];
+        for span in &trace.spans {
+            let mut depth = 0;
+            let mut parent = span.parent;
+            while let Some(id) = parent {
+                depth += 1;
+                parent = trace.spans[id].parent;
+            }
+
+            let tags = span.tags.iter()
+                .map(|&(ref k, ref v)| format!(" {}={}", k, v))
+                .collect::<String>();
+            lines.push(format!("{:width$}{} +{}us {}us{}", "", span.name, micros(span.offset),
+                               micros(span.duration), tags, width = 2 + 2 * depth));
+        }
+        info!("{}", lines.join("\n"));
+    }
+}
+
+/// Appends each trace to a file as a line of JSON.
+#[derive(Debug)]
+pub struct JsonFileExporter {
+    file: Mutex<File>
+}
+
+impl JsonFileExporter {
+    /// Append traces to the file at `path`, creating it if necessary.
+    pub fn new<P: AsRef<Path>>(path: P) -> io::Result<JsonFileExporter> {
+        let file = try!(OpenOptions::new().append(true).create(true).open(path));
+        Ok(JsonFileExporter { file: Mutex::new(file) })
+    }
+}
+
+impl Exporter for JsonFileExporter {
+    fn export(&self, trace: &CompletedTrace) {
+        let line = format!("{}\n", trace.to_json());
+        let mut file = self.file.lock().unwrap_or_else(|e| e.into_inner());
+        if let Err(e) = file.write_all(line.as_bytes()) {
+            error!("Error writing trace: {}", e);
+        }
+    }
+}
+
+/// Sends each trace as a JSON datagram to a collector.
+///
+/// Traces too large for a single datagram are dropped.
+#[derive(Debug)]
+pub struct UdpExporter {
+    socket: UdpSocket
+}
+
+impl UdpExporter {
+    /// Send traces to `addr`, which may be an IPv4 or an IPv6 address.
+    pub fn new<A: ToSocketAddrs>(addr: A) -> io::Result<UdpExporter> {
+        let addr = match try!(addr.to_socket_addrs()).next() {
+            Some(addr) => addr,
+            None => {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                          "No address to send traces to"))
+            }
+        };
+
+        // Bind to any local address of the same family as the collector's.
+        let local = match addr {
+            SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)),
+            SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 0))
+        };
+        let socket = try!(UdpSocket::bind(SocketAddr::new(local, 0)));
+        try!(socket.connect(addr));
+        Ok(UdpExporter { socket: socket })
+    }
+}
+
+impl Exporter for UdpExporter {
+    fn export(&self, trace: &CompletedTrace) {
+        if let Err(e) = self.socket.send(trace.to_json().to_string().as_bytes()) {
+            warn!("Error sending trace: {}", e);
+        }
+    }
+}
+
+/// Middleware which records a trace of each request and exports it once the
+/// response has been produced.
+///
+/// Link the same `Tracer` as both `BeforeMiddleware` and `AfterMiddleware`,
+/// usually first and last, through an `Arc`.
+pub struct Tracer {
+    exporter: Box<Exporter>
+}
+
+impl Tracer {
+    /// Export traces to `exporter`.
+    pub fn new<E: Exporter>(exporter: E) -> Tracer {
+        Tracer { exporter: Box::new(exporter) }
+    }
+
+    fn finish(&self, req: &mut Request) {
+        let state = match req.extensions.remove::<Active>() {
+            Some(state) => state,
+            None => return
+        };
+
+        let mut guard = state.lock().unwrap_or_else(|e| e.into_inner());
+        let state = &mut *guard;
+        let (start, end) = (state.start, Instant::now());
+
+        for step in Trace::of(req).iter().filter(|step| step.start >= start) {
+            let id = state.spans.len();
+            state.spans.push(Span {
+                id: id,
+                parent: Some(0),
+                name: format!("{:?} {}", step.phase, step.name),
+                offset: step.start.duration_since(start),
+                duration: step.duration,
+                tags: step.status.map(|s| vec![("status".into(), s.to_u16().to_string())])
+                    .unwrap_or_default()
+            });
+        }
+
+        for (span, open) in state.spans.iter_mut().zip(&state.open) {
+            if *open { span.duration = end.duration_since(start) - span.offset }
+        }
+
+        self.exporter.export(&CompletedTrace {
+            id: state.id,
+            started: state.started,
+            spans: state.spans.clone()
+        });
+    }
+}
+
+impl BeforeMiddleware for Tracer {
+    fn before(&self, req: &mut Request) -> IronResult<()> {
+        static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+        let started = SystemTime::now();
+        let nanos = started.duration_since(UNIX_EPOCH).unwrap_or_default().subsec_nanos();
+        let id = (NEXT_ID.fetch_add(1, Ordering::Relaxed) as u64) << 32 | nanos as u64;
+
+        let root = Span {
+            id: 0,
+            parent: None,
+            name: format!("{} {}", req.method, req.url.path().join("/")),
+            offset: Duration::from_secs(0),
+            duration: Duration::from_secs(0),
+            tags: vec![]
+        };
+
+        req.extensions.insert::<Active>(Arc::new(Mutex::new(State {
+            id: id,
+            started: started,
+            start: Instant::now(),
+            spans: vec![root],
+            open: vec![true]
+        })));
+        Ok(())
+    }
+}
+
+impl AfterMiddleware for Tracer {
+    fn after(&self, req: &mut Request, res: Response) -> IronResult<Response> {
+        root(req).tag("status", &res.status.map_or(0, |s| s.to_u16()).to_string());
+        self.finish(req);
+        Ok(res)
+    }
+
+    fn catch(&self, req: &mut Request, err: IronError) -> IronResult<Response> {
+        let span = root(req);
+        span.tag("status", &err.response.status.map_or(0, |s| s.to_u16()).to_string());
+        span.tag("error", &err.to_string());
+        drop(span);
+
+        self.finish(req);
+        Err(err)
+    }
+}
+
+// The trace being recorded for a request.
+struct Active;
+
+impl Key for Active { type Value = Arc<Mutex<State>>; }
+
+struct State {
+    id: u64,
+    started: SystemTime,
+    start: Instant,
+    spans: Vec<Span>,
+    // Whether each span is still open.
+    open: Vec<bool>
+}
+
+/// An open span, which ends when dropped.
+///
+/// Guards for requests without a `Tracer` do nothing.
+pub struct SpanGuard {
+    state: Option<Arc<Mutex<State>>>,
+    id: usize,
+    // Whether dropping the guard ends the span.
+    ends: bool
+}
+
+impl SpanGuard {
+    /// Open a span within this one.
+    pub fn child(&self, name: &str) -> SpanGuard {
+        open(self.state.clone(), self.id, name)
+    }
+
+    /// Attach a piece of information to the span.
+    pub fn tag(&self, key: &str, value: &str) {
+        if let Some(ref state) = self.state {
+            let mut state = state.lock().unwrap_or_else(|e| e.into_inner());
+            state.spans[self.id].tags.push((key.into(), value.into()));
+        }
+    }
+}
+
+impl Drop for SpanGuard {
+    fn drop(&mut self) {
+        if !self.ends { return }
+
+        if let Some(ref state) = self.state {
+            let mut state = state.lock().unwrap_or_else(|e| e.into_inner());
+            if state.open[self.id] {
+                let elapsed = state.start.elapsed();
+                let span = &mut state.spans[self.id];
+                span.duration = elapsed - span.offset;
+                state.open[self.id] = false;
+            }
+        }
+    }
+}
+
+/// Open a span named `name` as a child of the request's root span.
+pub fn span(req: &Request, name: &str) -> SpanGuard {
+    open(req.extensions.get::<Active>().cloned(), 0, name)
+}
+
+// A guard for the root span of `req`, which does not end it.
+fn root(req: &Request) -> SpanGuard {
+    SpanGuard { state: req.extensions.get::<Active>().cloned(), id: 0, ends: false }
+}
+
+fn open(state: Option<Arc<Mutex<State>>>, parent: usize, name: &str) -> SpanGuard {
+    let id = match state {
+        Some(ref state) => {
+            let mut state = state.lock().unwrap_or_else(|e| e.into_inner());
+            let id = state.spans.len();
+            let offset = state.start.elapsed();
+            state.spans.push(Span {
+                id: id,
+                parent: Some(parent),
+                name: name.into(),
+                offset: offset,
+                duration: Duration::from_secs(0),
+                tags: vec![]
+            });
+            state.open.push(true);
+            id
+        },
+        None => 0
+    };
+
+    SpanGuard { state: state, id: id, ends: true }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::UdpSocket;
+    use std::str;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use rustc_serialize::json::{Json, ToJson};
+
+    use method::Method;
+    use test::StubRequest;
+    use {status, BeforeMiddleware, AfterMiddleware, Response};
+    use super::{span, CompletedTrace, Exporter, Tracer, UdpExporter};
+
+    struct Collect(Arc<Mutex<Vec<CompletedTrace>>>);
+
+    impl Exporter for Collect {
+        fn export(&self, trace: &CompletedTrace) {
+            self.0.lock().unwrap().push(trace.clone());
+        }
+    }
+
+    #[test]
+    fn test_span_tree() {
+        let traces = Arc::new(Mutex::new(vec![]));
+        let tracer = Tracer::new(Collect(traces.clone()));
+        let mut req = StubRequest::new(Method::Get, "http://localhost/users/1").build();
+
+        tracer.before(&mut req).unwrap();
+        {
+            let outer = span(&req, "load");
+            outer.tag("id", "1");
+            let _inner = outer.child("query");
+        }
+        let _ = span(&req, "render");
+        tracer.after(&mut req, Response::with(status::Ok)).unwrap();
+
+        let traces = traces.lock().unwrap();
+        let spans = &traces[0].spans;
+        let tree = spans.iter().map(|s| (&*s.name, s.parent)).collect::<Vec<_>>();
+        assert_eq!(tree, vec![("GET users/1", None), ("load", Some(0)), ("query", Some(1)),
+                              ("render", Some(0))]);
+        assert_eq!(spans[0].tags, vec![("status".to_owned(), "200".to_owned())]);
+        assert_eq!(spans[1].tags, vec![("id".to_owned(), "1".to_owned())]);
+        assert!(spans[1].duration >= spans[2].duration);
+        assert!(traces[0].to_json().find("spans").unwrap().as_array().unwrap().len() == 4);
+    }
+
+    #[test]
+    fn test_spans_without_tracer() {
+        let req = StubRequest::new(Method::Get, "http://localhost/").build();
+        let span = span(&req, "noop");
+        span.tag("ignored", "yes");
+        drop(span.child("child"));
+    }
+
+    #[test]
+    fn test_udp_exporter() {
+        for collector in &["127.0.0.1:0", "[::1]:0"] {
+            let collector = UdpSocket::bind(collector).unwrap();
+            collector.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+            let tracer = Tracer::new(UdpExporter::new(collector.local_addr().unwrap()).unwrap());
+            let mut req = StubRequest::new(Method::Get, "http://localhost/").build();
+            tracer.before(&mut req).unwrap();
+            tracer.after(&mut req, Response::with(status::Ok)).unwrap();
+
+            let mut buf = [0; 4096];
+            let len = collector.recv(&mut buf).unwrap();
+            let trace = Json::from_str(str::from_utf8(&buf[..len]).unwrap()).unwrap();
+            assert!(trace.find("spans").is_some());
+        }
+    }
+}
@@ -0,0 +1,142 @@
+//! Lifecycle management for temporary files created while handling a request.
+//!
+//! Body parsers which stream uploads to disk, such as multipart parsers,
+//! should create their files through the request's `Uploads` registry. Every
+//! file in the registry is deleted once the request has been handled, even
+//! if a handler panics, unless a handler explicitly keeps it with `persist`:
+//!
+//! ```ignore
+//! // In a body parser:
+//! let file = try!(Uploads::of(req).create());
+//! try!(io::copy(&mut part, file));
+//! let path = file.path().to_owned();
+//!
+//! // Later, in a handler which wants to keep the upload:
+//! itry!(Uploads::of(req).persist(&path, "/srv/avatars/42.png"));
+//! ```
+
+use std::io;
+use std::path::Path;
+
+use typemap::Key;
+
+use Request;
+
+pub use temp::TempFile;
+
+/// The temporary files belonging to a request.
+///
+/// Stored in the request's extensions; see `Uploads::of`.
+#[derive(Debug, Default)]
+pub struct Uploads {
+    files: Vec<TempFile>
+}
+
+impl Key for Uploads { type Value = Uploads; }
+
+impl Uploads {
+    /// The registry of `req`, created empty if it does not exist yet.
+    pub fn of<'r>(req: &'r mut Request) -> &'r mut Uploads {
+        req.extensions.entry::<Uploads>().or_insert_with(Uploads::default)
+    }
+
+    /// Create a new, empty temporary file owned by this registry.
+    pub fn create(&mut self) -> io::Result<&mut TempFile> {
+        self.files.push(try!(TempFile::new()));
+        Ok(self.files.last_mut().unwrap())
+    }
+
+    /// Take ownership of an existing temporary file.
+    pub fn register(&mut self, file: TempFile) {
+        self.files.push(file);
+    }
+
+    /// The temporary file at `path`, if this registry owns it.
+    pub fn get(&mut self, path: &Path) -> Option<&mut TempFile> {
+        self.files.iter_mut().find(|file| file.path() == path)
+    }
+
+    /// The paths of all files in this registry.
+    pub fn paths(&self) -> Vec<&Path> {
+        self.files.iter().map(|file| file.path()).collect()
+    }
+
+    /// Move the temporary file at `path` to `dest`, so that it outlives the
+    /// request.
+    ///
+    /// Fails with `ErrorKind::NotFound` if this registry does not own a file
+    /// at `path`. If moving the file fails, the registry keeps it, so that
+    /// it can be persisted elsewhere.
+    pub fn persist<P: AsRef<Path>, Q: AsRef<Path>>(&mut self, path: P, dest: Q) -> io::Result<()> {
+        let path = path.as_ref();
+
+        match self.files.iter().position(|file| file.path() == path) {
+            Some(index) => {
+                try!(self.files[index].move_to(dest.as_ref()));
+                self.files.remove(index);
+                Ok(())
+            },
+            None => Err(io::Error::new(io::ErrorKind::NotFound,
+                                       format!("No upload at {}", path.display())))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::io::{self, Write};
+
+    use super::{Uploads, TempFile};
+
+    #[test]
+    fn test_files_deleted_with_registry() {
+        let mut uploads = Uploads::default();
+        let path = {
+            let file = uploads.create().unwrap();
+            file.write_all(b"upload").unwrap();
+            file.path().to_owned()
+        };
+
+        assert!(path.exists());
+        drop(uploads);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_persist() {
+        let mut uploads = Uploads::default();
+        let path = uploads.create().unwrap().path().to_owned();
+
+        let dest = TempFile::new().unwrap();
+        let dest_path = dest.path().to_owned();
+        drop(dest);
+
+        uploads.persist(&path, &dest_path).unwrap();
+        drop(uploads);
+
+        assert!(dest_path.exists());
+        fs::remove_file(&dest_path).unwrap();
+    }
+
+    #[test]
+    fn test_failed_persist_keeps_file() {
+        let mut uploads = Uploads::default();
+        let path = uploads.create().unwrap().path().to_owned();
+
+        let dir = TempFile::new().unwrap();
+        let dest = dir.path().join("missing").join("upload");
+        assert!(uploads.persist(&path, &dest).is_err());
+        assert!(path.exists());
+        assert_eq!(uploads.paths(), vec![&*path]);
+
+        drop(uploads);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_persist_unknown_path() {
+        let err = Uploads::default().persist("/nonexistent", "/tmp/x").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+    }
+}
@@ -0,0 +1,193 @@
+//! Strict percent-decoding and encoding, and splitting of paths and query
+//! strings.
+//!
+//! Decoding here rejects malformed escapes, invalid UTF-8 and embedded NUL
+//! characters instead of passing them through or replacing them, so that
+//! everything which interprets a URL sees exactly the same strings. The
+//! query parser used by `Request::query_as` and `Pagination` is built on it.
+//!
+//! ```
+//! use iron::url::{percent_decode, path_segments};
+//!
+//! assert_eq!(percent_decode("caf%C3%A9").unwrap(), "café");
+//! assert!(percent_decode("%FF").is_err());
+//! assert_eq!(path_segments("/files/a%20b/").unwrap(), vec!["files", "a b", ""]);
+//! ```
+
+use std::error::Error;
+use std::fmt;
+
+/// An error decoding part of a URL.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DecodeError {
+    /// A `%` at this byte offset was not followed by two hex digits.
+    InvalidEscape(usize),
+
+    /// The decoded bytes were not valid UTF-8.
+    InvalidUtf8,
+
+    /// The decoded string contained a NUL character.
+    Nul
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DecodeError::InvalidEscape(at) => write!(f, "Invalid percent-escape at byte {}", at),
+            _ => f.write_str(self.description())
+        }
+    }
+}
+
+impl Error for DecodeError {
+    fn description(&self) -> &str {
+        match *self {
+            DecodeError::InvalidEscape(_) => "Invalid percent-escape",
+            DecodeError::InvalidUtf8 => "Percent-decoded text is not valid UTF-8",
+            DecodeError::Nul => "Percent-decoded text contains a NUL character"
+        }
+    }
+}
+
+/// Decode the percent-escapes in `input`.
+pub fn percent_decode(input: &str) -> Result<String, DecodeError> {
+    decode(input, false)
+}
+
+/// Decode a component of an `application/x-www-form-urlencoded` query
+/// string, in which `+` stands for a space.
+pub fn form_decode(input: &str) -> Result<String, DecodeError> {
+    decode(input, true)
+}
+
+fn decode(input: &str, plus_is_space: bool) -> Result<String, DecodeError> {
+    let bytes = input.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' => {
+                let escape = bytes.get(i + 1..i + 3).and_then(|h| match (hex(h[0]), hex(h[1])) {
+                    (Some(high), Some(low)) => Some(high << 4 | low),
+                    _ => None
+                });
+                match escape {
+                    Some(b) => out.push(b),
+                    None => return Err(DecodeError::InvalidEscape(i))
+                }
+                i += 3;
+            },
+            b'+' if plus_is_space => { out.push(b' '); i += 1 },
+            b => { out.push(b); i += 1 }
+        }
+    }
+
+    if out.contains(&0) { return Err(DecodeError::Nul) }
+    String::from_utf8(out).map_err(|_| DecodeError::InvalidUtf8)
+}
+
+fn hex(digit: u8) -> Option<u8> {
+    (digit as char).to_digit(16).map(|d| d as u8)
+}
+
+/// Percent-encode every byte of `input` except the unreserved characters
+/// (ASCII letters and digits, `-`, `.`, `_` and `~`), making it safe to
+/// use as a path segment or query component.
+pub fn percent_encode(input: &str) -> String {
+    let mut out = String::with_capacity(input.len());
+    for &b in input.as_bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                out.push(b as char)
+            },
+            _ => out.push_str(&format!("%{:02X}", b))
+        }
+    }
+    out
+}
+
+/// Split a URL path into its decoded segments.
+///
+/// A leading `/` is ignored, so `/a/b` gives `["a", "b"]` and `/` gives
+/// `[""]`. Segments may contain `/` once decoded from `%2F`, so code mapping
+/// segments to files must check for it.
+pub fn path_segments(path: &str) -> Result<Vec<String>, DecodeError> {
+    let path = if path.starts_with('/') { &path[1..] } else { path };
+    path.split('/').map(percent_decode).collect()
+}
+
+/// Parse a query string into its decoded name/value pairs, in order.
+///
+/// Empty pairs, as in `a=1&&b=2`, are skipped, and a pair without `=`
+/// has an empty value.
+pub fn query_pairs(query: &str) -> QueryPairs {
+    QueryPairs { pairs: query.split('&') }
+}
+
+/// An iterator over the pairs of a query string, from `query_pairs`.
+///
+/// Each pair is decoded separately, so callers can either fail on the first
+/// malformed pair or skip it.
+pub struct QueryPairs<'a> {
+    pairs: ::std::str::Split<'a, char>
+}
+
+impl<'a> Iterator for QueryPairs<'a> {
+    type Item = Result<(String, String), DecodeError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let pair = match self.pairs.by_ref().find(|pair| !pair.is_empty()) {
+            Some(pair) => pair,
+            None => return None
+        };
+
+        let mut parts = pair.splitn(2, '=');
+        let name = parts.next().unwrap_or("");
+        let value = parts.next().unwrap_or("");
+
+        Some(form_decode(name).and_then(|name| form_decode(value).map(|value| (name, value))))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{percent_decode, percent_encode, form_decode, path_segments, query_pairs,
+                DecodeError};
+
+    #[test]
+    fn test_decode() {
+        assert_eq!(percent_decode("a%2Fb+c"), Ok("a/b+c".to_owned()));
+        assert_eq!(form_decode("a%2fb+c"), Ok("a/b c".to_owned()));
+        assert_eq!(percent_decode("100%"), Err(DecodeError::InvalidEscape(3)));
+        assert_eq!(percent_decode("%zz"), Err(DecodeError::InvalidEscape(0)));
+        assert_eq!(percent_decode("%C3"), Err(DecodeError::InvalidUtf8));
+        assert_eq!(percent_decode("a%00b"), Err(DecodeError::Nul));
+    }
+
+    #[test]
+    fn test_encode_round_trip() {
+        let text = "a b/c?d=é&~";
+        assert_eq!(percent_encode(text), "a%20b%2Fc%3Fd%3D%C3%A9%26~");
+        assert_eq!(percent_decode(&percent_encode(text)).unwrap(), text);
+    }
+
+    #[test]
+    fn test_path_segments() {
+        assert_eq!(path_segments("/").unwrap(), vec![""]);
+        assert_eq!(path_segments("/a/b%20c").unwrap(), vec!["a", "b c"]);
+        assert!(path_segments("/a/%00").is_err());
+    }
+
+    #[test]
+    fn test_query_pairs() {
+        let pairs = query_pairs("a=1&&b&c=x+y%21").collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(pairs, vec![("a".to_owned(), "1".to_owned()),
+                               ("b".to_owned(), "".to_owned()),
+                               ("c".to_owned(), "x y!".to_owned())]);
+
+        let mut pairs = query_pairs("a=%&b=2");
+        assert!(pairs.next().unwrap().is_err());
+        assert_eq!(pairs.next().unwrap(), Ok(("b".to_owned(), "2".to_owned())));
+    }
+}
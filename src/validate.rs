@@ -0,0 +1,335 @@
+//! Validation of parsed request parameters.
+//!
+//! A `Validator` is a list of rules for named fields, built once and run
+//! against each request's parameters, whether they come from the query
+//! string, a form or a JSON object:
+//!
+//! ```
+//! # use std::collections::HashMap;
+//! use iron::validate::Validator;
+//!
+//! let mut validator = Validator::new();
+//! validator.required("name")
+//!          .length("name", 1, 50)
+//!          .range("age", 0.0, 150.0)
+//!          .regex("email", r"^[^@\s]+@[^@\s]+$");
+//!
+//! let mut params = HashMap::new();
+//! params.insert("name".to_owned(), vec!["Ferris".to_owned()]);
+//! params.insert("age".to_owned(), vec!["200".to_owned()]);
+//!
+//! let errors = validator.validate(&params).unwrap_err();
+//! assert_eq!(errors.get("age"), &["must be between 0 and 150".to_owned()]);
+//! ```
+//!
+//! The resulting `ValidationErrors` can be used directly as a modifier to
+//! produce a 422 response listing the errors for each field as JSON.
+
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error as StdError;
+use std::fmt;
+
+use modifier::Modifier;
+use regex::Regex;
+use rustc_serialize::json::{Json, ToJson};
+use typemap::Key;
+
+use {headers, status, Request, Response, IronResult, IronError};
+
+/// A source of named, textual field values.
+pub trait Fields {
+    /// All values given for the field `name`, empty if there are none.
+    fn values(&self, name: &str) -> Vec<String>;
+}
+
+impl Fields for HashMap<String, Vec<String>> {
+    fn values(&self, name: &str) -> Vec<String> {
+        self.get(name).cloned().unwrap_or_default()
+    }
+}
+
+impl Fields for HashMap<String, String> {
+    fn values(&self, name: &str) -> Vec<String> {
+        self.get(name).cloned().into_iter().collect()
+    }
+}
+
+impl Fields for BTreeMap<String, Json> {
+    fn values(&self, name: &str) -> Vec<String> {
+        fn to_value(json: &Json) -> Option<String> {
+            match *json {
+                Json::String(ref s) => Some(s.clone()),
+                Json::Null => None,
+                Json::Array(_) | Json::Object(_) => None,
+                ref other => Some(other.to_string())
+            }
+        }
+
+        match self.get(name) {
+            Some(&Json::Array(ref items)) => items.iter().filter_map(to_value).collect(),
+            Some(json) => to_value(json).into_iter().collect(),
+            None => vec![]
+        }
+    }
+}
+
+enum Rule {
+    Required,
+    Length(usize, usize),
+    Regex(Regex),
+    Range(f64, f64),
+    Custom(Box<Fn(&str) -> Result<(), String> + Send + Sync>)
+}
+
+impl Rule {
+    fn check(&self, value: &str) -> Result<(), String> {
+        match *self {
+            Rule::Required => Ok(()),
+            Rule::Length(min, max) => {
+                let len = value.chars().count();
+                if len < min || len > max {
+                    Err(format!("must be between {} and {} characters long", min, max))
+                } else {
+                    Ok(())
+                }
+            },
+            Rule::Regex(ref regex) => {
+                if regex.is_match(value) { Ok(()) } else { Err("has an invalid format".to_owned()) }
+            },
+            Rule::Range(min, max) => match value.trim().parse::<f64>() {
+                Ok(n) if n >= min && n <= max => Ok(()),
+                Ok(_) => Err(format!("must be between {} and {}", min, max)),
+                Err(_) => Err("must be a number".to_owned())
+            },
+            Rule::Custom(ref check) => check(value)
+        }
+    }
+}
+
+/// A set of validation rules for named fields.
+///
+/// Apart from `required`, rules only apply to values which are present and
+/// not empty, and are checked against every value given for a field.
+#[derive(Default)]
+pub struct Validator {
+    rules: Vec<(String, Rule)>
+}
+
+impl Validator {
+    /// Create a `Validator` with no rules.
+    pub fn new() -> Validator {
+        Validator { rules: vec![] }
+    }
+
+    /// Require at least one non-empty value for `field`.
+    pub fn required(&mut self, field: &str) -> &mut Validator {
+        self.rule(field, Rule::Required)
+    }
+
+    /// Require values of `field` to be between `min` and `max` characters long.
+    pub fn length(&mut self, field: &str, min: usize, max: usize) -> &mut Validator {
+        self.rule(field, Rule::Length(min, max))
+    }
+
+    /// Require values of `field` to match the regular expression `pattern`.
+    ///
+    /// ## Panics
+    ///
+    /// Panics if `pattern` is not a valid regular expression.
+    pub fn regex(&mut self, field: &str, pattern: &str) -> &mut Validator {
+        let regex = Regex::new(pattern)
+            .unwrap_or_else(|e| panic!("Invalid pattern for field {}: {}", field, e));
+        self.rule(field, Rule::Regex(regex))
+    }
+
+    /// Require values of `field` to be numbers between `min` and `max` inclusive.
+    pub fn range(&mut self, field: &str, min: f64, max: f64) -> &mut Validator {
+        self.rule(field, Rule::Range(min, max))
+    }
+
+    /// Check values of `field` with a closure, which returns the error
+    /// message for invalid values.
+    pub fn custom<F>(&mut self, field: &str, check: F) -> &mut Validator
+    where F: Fn(&str) -> Result<(), String> + Send + Sync + 'static {
+        self.rule(field, Rule::Custom(Box::new(check)))
+    }
+
+    fn rule(&mut self, field: &str, rule: Rule) -> &mut Validator {
+        self.rules.push((field.to_owned(), rule));
+        self
+    }
+
+    /// Check `fields` against every rule, collecting all failures.
+    pub fn validate<F: Fields>(&self, fields: &F) -> Result<(), ValidationErrors> {
+        let mut errors = ValidationErrors::new();
+
+        for &(ref field, ref rule) in &self.rules {
+            let values = fields.values(field);
+            let values = values.iter().filter(|value| !value.is_empty()).collect::<Vec<_>>();
+
+            if let Rule::Required = *rule {
+                if values.is_empty() { errors.add(field, "is required") }
+                continue;
+            }
+
+            for value in values {
+                if let Err(message) = rule.check(value) {
+                    errors.add(field, &message);
+                    break;
+                }
+            }
+        }
+
+        if errors.is_empty() { Ok(()) } else { Err(errors) }
+    }
+
+    /// Validate `fields` as part of handling `req`.
+    ///
+    /// On failure the errors are stored in the request's extensions, under
+    /// the `ValidationErrors` key, and returned as an `IronError` whose
+    /// response is a 422 listing them.
+    pub fn validate_request<F: Fields>(&self, req: &mut Request, fields: &F) -> IronResult<()> {
+        match self.validate(fields) {
+            Ok(()) => Ok(()),
+            Err(errors) => {
+                req.extensions.insert::<ValidationErrors>(errors.clone());
+                Err(IronError::new(errors.clone(), errors))
+            }
+        }
+    }
+}
+
+/// The messages for each field which failed validation.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct ValidationErrors {
+    fields: BTreeMap<String, Vec<String>>
+}
+
+impl ValidationErrors {
+    /// Create an empty set of errors.
+    pub fn new() -> ValidationErrors {
+        ValidationErrors { fields: BTreeMap::new() }
+    }
+
+    /// Record an error message for `field`.
+    pub fn add(&mut self, field: &str, message: &str) {
+        self.fields.entry(field.to_owned()).or_insert_with(Vec::new).push(message.to_owned());
+    }
+
+    /// The error messages for `field`, empty if it is valid.
+    pub fn get(&self, field: &str) -> &[String] {
+        self.fields.get(field).map_or(&[], |messages| &messages[..])
+    }
+
+    /// Whether there are no errors at all.
+    pub fn is_empty(&self) -> bool {
+        self.fields.is_empty()
+    }
+
+    /// The fields which failed validation and their error messages.
+    pub fn fields(&self) -> &BTreeMap<String, Vec<String>> {
+        &self.fields
+    }
+}
+
+impl Key for ValidationErrors { type Value = ValidationErrors; }
+
+impl fmt::Display for ValidationErrors {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut first = true;
+        for (field, messages) in &self.fields {
+            for message in messages {
+                if !first { try!(f.write_str(", ")) }
+                try!(write!(f, "{} {}", field, message));
+                first = false;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl StdError for ValidationErrors {
+    fn description(&self) -> &str { "Validation failed" }
+}
+
+impl Modifier<Response> for ValidationErrors {
+    /// Respond with a 422 and a JSON object mapping each invalid field to
+    /// its error messages.
+    fn modify(self, res: &mut Response) {
+        let body = self.fields.to_json().to_string();
+        res.headers.set(headers::ContentType("application/json".parse().unwrap()));
+        (status::UnprocessableEntity, body).modify(res);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use rustc_serialize::json::Json;
+
+    use super::Validator;
+    use {status, Response, Set};
+
+    fn params(pairs: &[(&str, &str)]) -> HashMap<String, Vec<String>> {
+        let mut params = HashMap::new();
+        for &(key, value) in pairs {
+            params.entry(key.to_owned()).or_insert_with(Vec::new).push(value.to_owned());
+        }
+        params
+    }
+
+    #[test]
+    fn test_valid() {
+        let mut validator = Validator::new();
+        validator.required("name").length("name", 1, 5);
+        assert!(validator.validate(&params(&[("name", "iron")])).is_ok());
+    }
+
+    #[test]
+    fn test_required_and_optional() {
+        let mut validator = Validator::new();
+        validator.required("name").range("age", 0.0, 10.0);
+
+        let errors = validator.validate(&params(&[("name", "")])).unwrap_err();
+        assert_eq!(errors.get("name"), &["is required".to_owned()]);
+        assert!(errors.get("age").is_empty());
+    }
+
+    #[test]
+    fn test_rules() {
+        let mut validator = Validator::new();
+        validator.regex("code", "^[a-z]+$")
+                 .range("age", 0.0, 10.0)
+                 .custom("tag", |v| if v == "bad" { Err("is bad".to_owned()) } else { Ok(()) });
+
+        let errors = validator.validate(&params(&[
+            ("code", "ABC"), ("age", "x"), ("tag", "ok"), ("tag", "bad")
+        ])).unwrap_err();
+
+        assert_eq!(errors.get("code"), &["has an invalid format".to_owned()]);
+        assert_eq!(errors.get("age"), &["must be a number".to_owned()]);
+        assert_eq!(errors.get("tag"), &["is bad".to_owned()]);
+    }
+
+    #[test]
+    fn test_json_fields() {
+        let mut validator = Validator::new();
+        validator.required("name").range("age", 0.0, 10.0);
+
+        let object = Json::from_str(r#"{"age": 12}"#).unwrap().into_object().unwrap();
+        let errors = validator.validate(&object).unwrap_err();
+        assert_eq!(errors.get("name").len(), 1);
+        assert_eq!(errors.get("age").len(), 1);
+    }
+
+    #[test]
+    fn test_response() {
+        let mut validator = Validator::new();
+        validator.required("name");
+        let errors = validator.validate(&params(&[])).unwrap_err();
+
+        let res = Response::new().set(errors);
+        assert_eq!(res.status, Some(status::UnprocessableEntity));
+    }
+}
@@ -0,0 +1,387 @@
+//! Delivering events to webhooks, with signatures and retries.
+//!
+//! `Webhooks` keeps the URLs subscribed to an application's events. Each
+//! event sent is posted as JSON to every matching subscriber by a job on a
+//! `Jobs` queue, so handlers do not wait for the deliveries:
+//!
+//! ```ignore
+//! let jobs = Jobs::new(2);
+//! let webhooks = Webhooks::new(jobs.queue());
+//! webhooks.subscribe("https://example.com/hooks", "s3cret", &["order.created"]);
+//! webhooks.report_to(&stats);
+//!
+//! // In a handler:
+//! itry!(webhooks.send("order.created", &order.to_json()));
+//! ```
+//!
+//! Each delivery is a `POST` with these headers:
+//!
+//! * `X-Webhook-Event`: the name of the event;
+//! * `X-Webhook-Delivery`: a unique identifier, the same for every attempt,
+//!   which receivers can use to ignore repeated deliveries;
+//! * `X-Webhook-Timestamp`: when the attempt was made, in seconds since the
+//!   Unix epoch;
+//! * `X-Webhook-Signature`: `sha256=` followed by the hex HMAC-SHA256, under
+//!   the subscriber's secret, of the delivery id, timestamp, event and body
+//!   joined by `.`, as computed by `signature`.
+//!
+//! Receivers should check the signature, reject deliveries whose timestamp
+//! is more than five minutes away from their own clock, and remember the
+//! ids of the deliveries they accept for at least as long, so that a
+//! captured delivery cannot be replayed.
+//!
+//! Deliveries answered with anything other than a `2xx` status are retried
+//! with exponential backoff.
+
+use std::error::Error;
+use std::fmt;
+use std::process;
+use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use rustc_serialize::hex::ToHex;
+use rustc_serialize::json::{Json, Object, ToJson};
+
+use checksum::{hmac_sha256, Algorithm};
+use client::Client;
+use jobs::{Job, Queue, QueueClosed, Retry};
+use method::Method;
+use stats::Stats;
+use Headers;
+
+/// A set of webhook subscriptions, and the deliveries to them.
+///
+/// Clones share the same subscriptions and counters.
+#[derive(Clone)]
+pub struct Webhooks {
+    queue: Queue,
+    client: Arc<Client>,
+    retry: Retry,
+    shared: Arc<Shared>
+}
+
+struct Shared {
+    subscriptions: RwLock<Vec<Subscription>>,
+    next_delivery: AtomicUsize,
+    pending: AtomicUsize,
+    delivered: AtomicUsize,
+    retried: AtomicUsize,
+    failed: AtomicUsize
+}
+
+struct Subscription {
+    url: String,
+    secret: String,
+    events: Vec<String>
+}
+
+/// Counts of webhook deliveries.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeliveryStatus {
+    /// Deliveries queued or being attempted.
+    pub pending: usize,
+
+    /// Deliveries which have succeeded.
+    pub delivered: usize,
+
+    /// Attempts which were retries of a failed attempt.
+    pub retried: usize,
+
+    /// Deliveries abandoned after failing every attempt.
+    pub failed: usize
+}
+
+impl Webhooks {
+    /// Deliver events with jobs on `queue`.
+    ///
+    /// Deliveries are attempted up to six times, waiting ten seconds after
+    /// the first failure and doubling the wait after each further one.
+    pub fn new(queue: Queue) -> Webhooks {
+        let mut client = Client::new();
+        client.timeout(Some(Duration::from_secs(10))).limit(64 * 1024);
+
+        Webhooks {
+            queue: queue,
+            client: Arc::new(client),
+            retry: Retry::up_to(6, Duration::from_secs(10)),
+            shared: Arc::new(Shared {
+                subscriptions: RwLock::new(Vec::new()),
+                next_delivery: AtomicUsize::new(1),
+                pending: AtomicUsize::new(0),
+                delivered: AtomicUsize::new(0),
+                retried: AtomicUsize::new(0),
+                failed: AtomicUsize::new(0)
+            })
+        }
+    }
+
+    /// Retry failed deliveries according to `retry`.
+    pub fn retry(mut self, retry: Retry) -> Webhooks {
+        self.retry = retry;
+        self
+    }
+
+    /// Make deliveries with `client`.
+    pub fn client(mut self, client: Client) -> Webhooks {
+        self.client = Arc::new(client);
+        self
+    }
+
+    /// Deliver the events named in `events`, or all events if it is empty,
+    /// to `url`, signed with `secret`.
+    pub fn subscribe(&self, url: &str, secret: &str, events: &[&str]) {
+        self.shared.subscriptions.write().unwrap().push(Subscription {
+            url: url.into(),
+            secret: secret.into(),
+            events: events.iter().map(|&event| event.into()).collect()
+        });
+    }
+
+    /// Remove the subscriptions for `url`, returning whether there were
+    /// any. Deliveries already queued are still made.
+    pub fn unsubscribe(&self, url: &str) -> bool {
+        let mut subscriptions = self.shared.subscriptions.write().unwrap();
+        let before = subscriptions.len();
+        subscriptions.retain(|subscription| subscription.url != url);
+        subscriptions.len() != before
+    }
+
+    /// Queue a delivery of `payload` for `event` to each subscriber,
+    /// returning how many were queued.
+    pub fn send(&self, event: &str, payload: &Json) -> Result<usize, QueueClosed> {
+        let body = payload.to_string();
+        let subscriptions = self.shared.subscriptions.read().unwrap();
+
+        let mut queued = 0;
+        for subscription in subscriptions.iter() {
+            let subscribed = subscription.events.is_empty() ||
+                subscription.events.iter().any(|e| e == event);
+            if !subscribed { continue }
+
+            // Counted before it is queued, so that it cannot finish first.
+            self.shared.pending.fetch_add(1, Ordering::Relaxed);
+            let queued_delivery = self.queue.enqueue(Delivery {
+                id: self.delivery_id(),
+                event: event.into(),
+                url: subscription.url.clone(),
+                secret: subscription.secret.clone(),
+                body: body.clone(),
+                client: self.client.clone(),
+                retry: self.retry,
+                attempt: 0,
+                shared: self.shared.clone()
+            });
+            if let Err(e) = queued_delivery {
+                self.shared.pending.fetch_sub(1, Ordering::Relaxed);
+                return Err(e);
+            }
+            queued += 1;
+        }
+        Ok(queued)
+    }
+
+    // An identifier for a new delivery, unique across processes and hosts
+    // without a source of randomness: a hash of the time, the process and
+    // thread, a counter, and an address, which varies between runs.
+    fn delivery_id(&self) -> String {
+        let count = self.shared.next_delivery.fetch_add(1, Ordering::Relaxed);
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let seed = format!("{}.{}:{}:{:?}:{}:{:p}", now.as_secs(), now.subsec_nanos(),
+                           process::id(), thread::current().id(), count, &count);
+        Algorithm::Sha256.digest(seed.as_bytes())[..16].to_hex()
+    }
+
+    /// Count the deliveries made so far.
+    pub fn status(&self) -> DeliveryStatus {
+        let shared = &self.shared;
+        DeliveryStatus {
+            pending: shared.pending.load(Ordering::Relaxed),
+            delivered: shared.delivered.load(Ordering::Relaxed),
+            retried: shared.retried.load(Ordering::Relaxed),
+            failed: shared.failed.load(Ordering::Relaxed)
+        }
+    }
+
+    /// Include the delivery counts in the snapshots of `stats`, under
+    /// `webhooks`.
+    pub fn report_to(&self, stats: &Stats) {
+        let webhooks = self.clone();
+        stats.add_section("webhooks", move || webhooks.status().to_json());
+    }
+}
+
+impl fmt::Debug for Webhooks {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Webhooks {{ subscriptions: {}, status: {:?} }}",
+               self.shared.subscriptions.read().unwrap().len(), self.status())
+    }
+}
+
+impl ToJson for DeliveryStatus {
+    fn to_json(&self) -> Json {
+        let mut object = Object::new();
+        object.insert("pending".into(), self.pending.to_json());
+        object.insert("delivered".into(), self.delivered.to_json());
+        object.insert("retried".into(), self.retried.to_json());
+        object.insert("failed".into(), self.failed.to_json());
+        Json::Object(object)
+    }
+}
+
+/// The `X-Webhook-Signature` of a delivery: `sha256=` followed by the hex
+/// HMAC-SHA256 under `secret` of `id`, `timestamp`, `event` and `body`,
+/// joined by `.`.
+pub fn signature(secret: &str, id: &str, timestamp: u64, event: &str, body: &str) -> String {
+    let signed = format!("{}.{}.{}.{}", id, timestamp, event, body);
+    format!("sha256={}", hmac_sha256(secret.as_bytes(), signed.as_bytes()).to_hex())
+}
+
+struct Delivery {
+    id: String,
+    event: String,
+    url: String,
+    body: String,
+    secret: String,
+    client: Arc<Client>,
+    retry: Retry,
+    attempt: u32,
+    shared: Arc<Shared>
+}
+
+impl Delivery {
+    fn post(&self) -> Result<(), Box<Error + Send + Sync>> {
+        let mut headers = Headers::new();
+        headers.set_raw("Content-Type", vec![b"application/json".to_vec()]);
+        headers.set_raw("X-Webhook-Event", vec![self.event.clone().into_bytes()]);
+        headers.set_raw("X-Webhook-Delivery", vec![self.id.clone().into_bytes()]);
+
+        // Each attempt is signed afresh, so that retries are not rejected
+        // as stale.
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default()
+            .as_secs();
+        let signature = signature(&self.secret, &self.id, timestamp, &self.event, &self.body);
+        headers.set_raw("X-Webhook-Timestamp", vec![timestamp.to_string().into_bytes()]);
+        headers.set_raw("X-Webhook-Signature", vec![signature.into_bytes()]);
+
+        let res = try!(self.client.send(Method::Post, &self.url, headers, self.body.as_bytes()));
+        if res.status.is_success() {
+            Ok(())
+        } else {
+            Err(format!("Webhook {} answered {}", self.url, res.status).into())
+        }
+    }
+}
+
+impl Job for Delivery {
+    fn run(&mut self) -> Result<(), Box<Error + Send + Sync>> {
+        self.attempt += 1;
+        if self.attempt > 1 { self.shared.retried.fetch_add(1, Ordering::Relaxed); }
+
+        let result = self.post();
+        let finished = match result {
+            Ok(()) => {
+                self.shared.delivered.fetch_add(1, Ordering::Relaxed);
+                true
+            },
+            Err(_) if self.attempt >= self.retry.attempts() => {
+                self.shared.failed.fetch_add(1, Ordering::Relaxed);
+                true
+            },
+            Err(_) => false
+        };
+        if finished { self.shared.pending.fetch_sub(1, Ordering::Relaxed); }
+        result
+    }
+
+    fn retry(&self) -> Retry {
+        self.retry
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use rustc_serialize::hex::ToHex;
+    use rustc_serialize::json::ToJson;
+
+    use checksum::hmac_sha256;
+    use jobs::{Jobs, Retry};
+    use status;
+    use {Iron, IronError, Request, Response};
+    use super::{signature, DeliveryStatus, Webhooks};
+
+    #[test]
+    fn test_signature() {
+        let expected = hmac_sha256(b"secret", b"abc.1700000000.created.[1]").to_hex();
+        let signed = signature("secret", "abc", 1700000000, "created", "[1]");
+        assert_eq!(signed, format!("sha256={}", expected));
+        assert!(signature("secret", "abd", 1700000000, "created", "[1]") != signed);
+        assert!(signature("secret", "abc", 1700000001, "created", "[1]") != signed);
+        assert!(signature("secret", "abc", 1700000000, "deleted", "[1]") != signed);
+    }
+
+    #[test]
+    fn test_deliveries() {
+        // The receiver fails the first attempt at each delivery.
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let log = received.clone();
+        let mut listening = Iron::new(move |req: &mut Request| {
+            let body = try!(req.body_string(1024).map_err(IronError::from));
+            let header = |name| req.headers.get_raw(name)
+                .map(|values| String::from_utf8(values[0].clone()).unwrap());
+            let (id, event) = (header("X-Webhook-Delivery"), header("X-Webhook-Event"));
+            let timestamp = header("X-Webhook-Timestamp").and_then(|t| t.parse().ok());
+            let signed = match (&id, timestamp, &event, header("X-Webhook-Signature")) {
+                (&Some(ref id), Some(timestamp), &Some(ref event), Some(sent)) => {
+                    sent == signature("secret", id, timestamp, event, &body)
+                },
+                _ => false
+            };
+            let delivery = (id, event, signed, body);
+
+            let mut log = log.lock().unwrap();
+            let retried = log.contains(&delivery);
+            log.push(delivery);
+            Ok(Response::with(if retried { status::Ok } else { status::ServiceUnavailable }))
+        }).http("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", listening.socket);
+        listening.close().unwrap();
+
+        let jobs = Jobs::new(1);
+        let webhooks = Webhooks::new(jobs.queue())
+            .retry(Retry::up_to(2, Duration::from_millis(1)));
+        webhooks.subscribe(&url, "secret", &["created"]);
+        webhooks.subscribe("http://127.0.0.1:1/unreachable", "secret", &[]);
+
+        assert_eq!(webhooks.send("created", &vec![1].to_json()).unwrap(), 2);
+        assert_eq!(webhooks.send("deleted", &vec![2].to_json()).unwrap(), 1);
+        assert!(webhooks.unsubscribe("http://127.0.0.1:1/unreachable"));
+        drop(jobs);
+
+        let received = received.lock().unwrap();
+        let id = received[0].0.clone().unwrap();
+        assert_eq!(id.len(), 32);
+        assert!(id.chars().all(|c| c.is_digit(16)));
+
+        let delivery = (Some(id), Some("created".into()), true, "[1]".into());
+        assert_eq!(*received, vec![delivery.clone(), delivery]);
+        assert_eq!(webhooks.status(),
+                   DeliveryStatus { pending: 0, delivered: 1, retried: 3, failed: 2 });
+        assert!(webhooks.delivery_id() != webhooks.delivery_id());
+    }
+
+    #[test]
+    fn test_closed_queue() {
+        let jobs = Jobs::new(1);
+        let webhooks = Webhooks::new(jobs.queue());
+        webhooks.subscribe("http://127.0.0.1:1/unreachable", "secret", &[]);
+        drop(jobs);
+
+        assert!(webhooks.send("created", &vec![1].to_json()).is_err());
+        assert_eq!(webhooks.status().pending, 0);
+    }
+}
@@ -0,0 +1,712 @@
+//! Parsing XML request bodies.
+//!
+//! `Events` is a streaming, SAX-style parser which reads a document from any
+//! `Read`, including a request body, one event at a time, so large documents
+//! need not be held in memory. `Element::parse` builds a simple tree from the
+//! same events, and `XmlBodyParser` is a `BeforeMiddleware` which parses the
+//! bodies of requests with an XML content type and stores the document in
+//! the request's extensions under `XmlBody`:
+//!
+//! ```ignore
+//! chain.link_before(XmlBodyParser::new(1024 * 1024));
+//!
+//! // In the handler:
+//! let envelope = req.extensions.get::<XmlBody>().unwrap();
+//! let body = envelope.child("soap:Body");
+//! ```
+//!
+//! Only the well-formedness of documents is checked. Namespace prefixes are
+//! kept as part of names rather than resolved, and documents with a
+//! `<!DOCTYPE>` are rejected, since their entities could expand without
+//! bound or refer to local files. So are documents with elements nested
+//! more deeply than `DEFAULT_MAX_DEPTH`, or a depth set with `max_depth`.
+
+use std::error::Error;
+use std::fmt;
+use std::io::{self, BufReader, Read};
+use std::mem;
+
+use mime::Mime;
+use typemap::Key;
+
+use {headers, status, BeforeMiddleware, Request, IronResult, IronError};
+
+/// An event produced while parsing an XML document.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    /// The start of an element, with its attributes in order. An empty
+    /// element such as `<a/>` produces a `Start` followed by an `End`.
+    Start(String, Vec<(String, String)>),
+
+    /// The end of the element with this name.
+    End(String),
+
+    /// Character data inside an element, with entities and `CDATA` sections
+    /// decoded. Comments, processing instructions and whitespace outside
+    /// the root element produce no events.
+    Text(String)
+}
+
+/// An error parsing an XML document.
+#[derive(Debug)]
+pub enum XmlError {
+    /// The document could not be read.
+    Io(io::Error),
+
+    /// The document is not well-formed, as described, at this byte offset.
+    Syntax(usize, String)
+}
+
+impl fmt::Display for XmlError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            XmlError::Io(ref e) => write!(f, "Error reading XML: {}", e),
+            XmlError::Syntax(at, ref reason) => write!(f, "Invalid XML at byte {}: {}", at, reason)
+        }
+    }
+}
+
+impl Error for XmlError {
+    fn description(&self) -> &str {
+        match *self {
+            XmlError::Io(_) => "Error reading XML",
+            XmlError::Syntax(..) => "Invalid XML"
+        }
+    }
+}
+
+/// How deeply elements may be nested, unless set otherwise.
+pub const DEFAULT_MAX_DEPTH: usize = 256;
+
+/// A streaming parser, iterating over the `Event`s of a document.
+///
+/// Parsing stops at the first error, which is the last item produced.
+pub struct Events<R> {
+    input: io::Bytes<BufReader<R>>,
+    peeked: Option<u8>,
+    offset: usize,
+    open: Vec<String>,
+    max_depth: usize,
+    pending_end: Option<String>,
+    seen_root: bool,
+    finished: bool
+}
+
+impl<R: Read> Events<R> {
+    /// Parse the document read from `input`.
+    pub fn new(input: R) -> Events<R> {
+        Events {
+            input: BufReader::new(input).bytes(),
+            peeked: None,
+            offset: 0,
+            open: vec![],
+            max_depth: DEFAULT_MAX_DEPTH,
+            pending_end: None,
+            seen_root: false,
+            finished: false
+        }
+    }
+
+    /// Fail with a syntax error on elements nested more than `depth` deep,
+    /// counting the root element as one deep.
+    pub fn max_depth(mut self, depth: usize) -> Events<R> {
+        self.max_depth = depth;
+        self
+    }
+
+    fn advance(&mut self) -> Result<Option<Event>, XmlError> {
+        if let Some(name) = self.pending_end.take() { return Ok(Some(Event::End(name))) }
+
+        loop {
+            match try!(self.peek()) {
+                None => {
+                    if let Some(name) = self.open.last() {
+                        return Err(self.error(format!("Element <{}> is not closed", name)))
+                    }
+                    if !self.seen_root { return Err(self.error("No root element")) }
+                    return Ok(None)
+                },
+                Some(b'<') => {
+                    self.bump();
+                    if let Some(event) = try!(self.markup()) { return Ok(Some(event)) }
+                },
+                Some(_) => {
+                    let text = try!(self.text());
+                    if !self.open.is_empty() { return Ok(Some(Event::Text(text))) }
+                    if !text.chars().all(char::is_whitespace) {
+                        return Err(self.error("Text outside the root element"))
+                    }
+                }
+            }
+        }
+    }
+
+    // Parse the markup following a `<`, returning `None` for markup which
+    // produces no event.
+    fn markup(&mut self) -> Result<Option<Event>, XmlError> {
+        match try!(self.peek()) {
+            Some(b'?') => {
+                try!(self.until(b"?>"));
+                Ok(None)
+            },
+            Some(b'!') => {
+                self.bump();
+                match try!(self.next_byte()) {
+                    Some(b'-') => {
+                        try!(self.expect(b"-"));
+                        try!(self.until(b"-->"));
+                        Ok(None)
+                    },
+                    Some(b'[') => {
+                        if self.open.is_empty() {
+                            return Err(self.error("CDATA section outside the root element"))
+                        }
+                        try!(self.expect(b"CDATA["));
+                        let text = try!(self.until(b"]]>"));
+                        Ok(Some(Event::Text(try!(self.string(text)))))
+                    },
+                    Some(b'D') => Err(self.error("Document type declarations are not supported")),
+                    _ => Err(self.error("Invalid markup"))
+                }
+            },
+            Some(b'/') => {
+                self.bump();
+                let name = try!(self.name());
+                self.skip_whitespace();
+                try!(self.expect(b">"));
+                match self.open.pop() {
+                    Some(ref open) if *open == name => Ok(Some(Event::End(name))),
+                    Some(open) => {
+                        Err(self.error(format!("Expected </{}>, found </{}>", open, name)))
+                    },
+                    None => Err(self.error(format!("Unexpected </{}>", name)))
+                }
+            },
+            _ => {
+                if self.open.is_empty() && self.seen_root {
+                    return Err(self.error("Element after the root element"))
+                }
+                self.start().map(Some)
+            }
+        }
+    }
+
+    fn start(&mut self) -> Result<Event, XmlError> {
+        if self.open.len() >= self.max_depth {
+            return Err(self.error(format!("Elements nested more than {} deep", self.max_depth)))
+        }
+
+        let name = try!(self.name());
+        let mut attributes: Vec<(String, String)> = vec![];
+
+        loop {
+            let spaced = self.skip_whitespace();
+            match try!(self.peek()) {
+                Some(b'>') => {
+                    self.bump();
+                    self.open.push(name.clone());
+                    break
+                },
+                Some(b'/') => {
+                    self.bump();
+                    try!(self.expect(b">"));
+                    self.pending_end = Some(name.clone());
+                    break
+                },
+                Some(_) if spaced => {
+                    let attribute = try!(self.name());
+                    if attributes.iter().any(|&(ref a, _)| *a == attribute) {
+                        return Err(self.error(format!("Duplicate attribute {}", attribute)))
+                    }
+                    self.skip_whitespace();
+                    try!(self.expect(b"="));
+                    self.skip_whitespace();
+                    let value = try!(self.quoted());
+                    attributes.push((attribute, value));
+                },
+                _ => return Err(self.error(format!("Invalid start tag <{}>", name)))
+            }
+        }
+
+        self.seen_root = true;
+        Ok(Event::Start(name, attributes))
+    }
+
+    fn name(&mut self) -> Result<String, XmlError> {
+        let mut name = vec![];
+        while let Some(b) = try!(self.peek()) {
+            match b {
+                b' ' | b'\t' | b'\r' | b'\n' | b'/' | b'>' | b'=' | b'<' | b'"' | b'\'' => break,
+                _ => { self.bump(); name.push(b) }
+            }
+        }
+        if name.is_empty() { return Err(self.error("Expected a name")) }
+        self.string(name)
+    }
+
+    fn quoted(&mut self) -> Result<String, XmlError> {
+        let quote = match try!(self.next_byte()) {
+            Some(quote @ b'"') | Some(quote @ b'\'') => quote,
+            _ => return Err(self.error("Expected a quoted attribute value"))
+        };
+
+        let mut value = vec![];
+        loop {
+            match try!(self.next_byte()) {
+                Some(b) if b == quote => break,
+                Some(b'<') => return Err(self.error("Invalid < in attribute value")),
+                Some(b) => value.push(b),
+                None => return Err(self.error("Unterminated attribute value"))
+            }
+        }
+        let value = try!(self.string(value));
+        self.unescape(&value)
+    }
+
+    fn text(&mut self) -> Result<String, XmlError> {
+        let mut text = vec![];
+        while let Some(b) = try!(self.peek()) {
+            if b == b'<' { break }
+            self.bump();
+            text.push(b);
+        }
+        let text = try!(self.string(text));
+        self.unescape(&text)
+    }
+
+    // Read up to and including `end`, returning what came before it.
+    fn until(&mut self, end: &[u8]) -> Result<Vec<u8>, XmlError> {
+        let mut read = vec![];
+        while !read.ends_with(end) {
+            match try!(self.next_byte()) {
+                Some(b) => read.push(b),
+                None => return Err(self.error("Unexpected end of document"))
+            }
+        }
+        let length = read.len() - end.len();
+        read.truncate(length);
+        Ok(read)
+    }
+
+    fn expect(&mut self, expected: &[u8]) -> Result<(), XmlError> {
+        for &b in expected {
+            if try!(self.next_byte()) != Some(b) {
+                return Err(self.error(format!("Expected {}", String::from_utf8_lossy(expected))))
+            }
+        }
+        Ok(())
+    }
+
+    // Skip whitespace, returning whether there was any.
+    fn skip_whitespace(&mut self) -> bool {
+        let mut skipped = false;
+        while let Ok(Some(b' ')) | Ok(Some(b'\t')) | Ok(Some(b'\r')) | Ok(Some(b'\n')) =
+            self.peek() {
+            self.bump();
+            skipped = true;
+        }
+        skipped
+    }
+
+    fn unescape(&self, text: &str) -> Result<String, XmlError> {
+        let mut unescaped = String::with_capacity(text.len());
+        let mut rest = text;
+
+        while let Some(start) = rest.find('&') {
+            unescaped.push_str(&rest[..start]);
+            let end = match rest[start..].find(';') {
+                Some(end) => start + end,
+                None => return Err(self.error("Unterminated entity reference"))
+            };
+
+            let entity = &rest[start + 1..end];
+            let c = match entity {
+                "lt" => Some('<'),
+                "gt" => Some('>'),
+                "amp" => Some('&'),
+                "quot" => Some('"'),
+                "apos" => Some('\''),
+                _ if entity.starts_with("#x") => {
+                    u32::from_str_radix(&entity[2..], 16).ok().and_then(::std::char::from_u32)
+                },
+                _ if entity.starts_with('#') => {
+                    entity[1..].parse().ok().and_then(::std::char::from_u32)
+                },
+                _ => None
+            };
+            match c {
+                Some(c) => unescaped.push(c),
+                None => return Err(self.error(format!("Unknown entity &{};", entity)))
+            }
+            rest = &rest[end + 1..];
+        }
+
+        unescaped.push_str(rest);
+        Ok(unescaped)
+    }
+
+    fn string(&self, bytes: Vec<u8>) -> Result<String, XmlError> {
+        String::from_utf8(bytes).map_err(|_| self.error("Invalid UTF-8"))
+    }
+
+    fn peek(&mut self) -> Result<Option<u8>, XmlError> {
+        if self.peeked.is_none() {
+            self.peeked = match self.input.next() {
+                Some(Ok(b)) => Some(b),
+                Some(Err(e)) => return Err(XmlError::Io(e)),
+                None => None
+            };
+        }
+        Ok(self.peeked)
+    }
+
+    // Consume a byte which has been peeked.
+    fn bump(&mut self) {
+        if self.peeked.take().is_some() { self.offset += 1 }
+    }
+
+    fn next_byte(&mut self) -> Result<Option<u8>, XmlError> {
+        let b = try!(self.peek());
+        self.bump();
+        Ok(b)
+    }
+
+    fn error<S: Into<String>>(&self, reason: S) -> XmlError {
+        XmlError::Syntax(self.offset, reason.into())
+    }
+}
+
+impl<R: Read> Iterator for Events<R> {
+    type Item = Result<Event, XmlError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.finished { return None }
+
+        match self.advance() {
+            Ok(Some(event)) => Some(Ok(event)),
+            Ok(None) => { self.finished = true; None },
+            Err(e) => { self.finished = true; Some(Err(e)) }
+        }
+    }
+}
+
+/// An element of a parsed document, with everything inside it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Element {
+    /// The element's name, including any namespace prefix.
+    pub name: String,
+
+    /// The element's attributes, in order.
+    pub attributes: Vec<(String, String)>,
+
+    /// The elements and text inside the element, in order.
+    pub children: Vec<Node>
+}
+
+/// A child of an `Element`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Node {
+    /// A nested element.
+    Element(Element),
+
+    /// Character data. Adjacent text and `CDATA` sections are joined.
+    Text(String)
+}
+
+impl Element {
+    /// Parse a whole document read from `input`, returning its root element.
+    pub fn parse<R: Read>(input: R) -> Result<Element, XmlError> {
+        Element::from_events(Events::new(input))
+    }
+
+    /// Build the root element of a document from its `events`, as
+    /// configured.
+    pub fn from_events<R: Read>(events: Events<R>) -> Result<Element, XmlError> {
+        let mut open: Vec<Element> = vec![];
+        let mut root = None;
+
+        for event in events {
+            match try!(event) {
+                Event::Start(name, attributes) => {
+                    open.push(Element { name: name, attributes: attributes, children: vec![] });
+                },
+                Event::Text(text) => {
+                    // Events are only produced inside the root element.
+                    let children = &mut open.last_mut().unwrap().children;
+                    if let Some(&mut Node::Text(ref mut last)) = children.last_mut() {
+                        last.push_str(&text);
+                        continue
+                    }
+                    children.push(Node::Text(text));
+                },
+                Event::End(_) => {
+                    let element = open.pop().unwrap();
+                    match open.last_mut() {
+                        Some(parent) => parent.children.push(Node::Element(element)),
+                        None => root = Some(element)
+                    }
+                }
+            }
+        }
+
+        // A document without a root element produces an error event.
+        Ok(root.unwrap())
+    }
+
+    /// The part of the element's name after any namespace prefix.
+    pub fn local_name(&self) -> &str {
+        self.name.rsplit(':').next().unwrap_or(&self.name)
+    }
+
+    /// The value of the attribute `name`, if the element has it.
+    pub fn attribute(&self, name: &str) -> Option<&str> {
+        self.attributes.iter().find(|&&(ref n, _)| n == name).map(|&(_, ref value)| &**value)
+    }
+
+    /// The first child element called `name`.
+    pub fn child(&self, name: &str) -> Option<&Element> {
+        self.elements().into_iter().find(|element| element.name == name)
+    }
+
+    /// The child elements, in order, leaving out text.
+    pub fn elements(&self) -> Vec<&Element> {
+        self.children.iter().filter_map(|node| match *node {
+            Node::Element(ref element) => Some(element),
+            Node::Text(_) => None
+        }).collect()
+    }
+
+    /// The text directly inside the element, leaving out that of nested
+    /// elements.
+    pub fn text(&self) -> String {
+        self.children.iter().filter_map(|node| match *node {
+            Node::Text(ref text) => Some(&**text),
+            Node::Element(_) => None
+        }).collect()
+    }
+}
+
+// Dropped without recursion, so that a deeply nested tree, however it was
+// built, cannot overflow the stack.
+impl Drop for Element {
+    fn drop(&mut self) {
+        let mut nodes = mem::replace(&mut self.children, vec![]);
+        while let Some(node) = nodes.pop() {
+            if let Node::Element(mut element) = node {
+                nodes.extend(element.children.drain(..));
+            }
+        }
+    }
+}
+
+/// Middleware which parses the bodies of requests with an XML content type.
+///
+/// Bodies of type `text/xml`, `application/xml` or any `+xml` type, such as
+/// `application/soap+xml`, are buffered and parsed, and the document is
+/// stored under `XmlBody`. The body is rewound afterwards, so it can still
+/// be read as is. Bodies longer than the limit are rejected with `413
+/// Payload Too Large` and documents which are not well-formed, or nested
+/// too deeply, with `400 Bad Request`. Other requests are passed on
+/// untouched.
+pub struct XmlBodyParser {
+    limit: usize,
+    max_depth: usize
+}
+
+impl XmlBodyParser {
+    /// Parse XML bodies of up to `limit` bytes, nested up to
+    /// `DEFAULT_MAX_DEPTH` deep.
+    pub fn new(limit: usize) -> XmlBodyParser {
+        XmlBodyParser { limit: limit, max_depth: DEFAULT_MAX_DEPTH }
+    }
+
+    /// Reject documents with elements nested more than `depth` deep.
+    pub fn max_depth(mut self, depth: usize) -> XmlBodyParser {
+        self.max_depth = depth;
+        self
+    }
+}
+
+/// The root element of a request's XML body, as parsed by `XmlBodyParser`.
+pub struct XmlBody;
+
+impl Key for XmlBody { type Value = Element; }
+
+impl BeforeMiddleware for XmlBodyParser {
+    fn before(&self, req: &mut Request) -> IronResult<()> {
+        let xml = match req.headers.get::<headers::ContentType>() {
+            Some(&headers::ContentType(ref mime)) => is_xml(mime),
+            None => false
+        };
+        if !xml { return Ok(()) }
+
+        if let Err(e) = req.body.buffer(self.limit) {
+            return Err(match e.kind() {
+                io::ErrorKind::InvalidData => IronError::new(e, status::PayloadTooLarge),
+                _ => IronError::new(e, status::BadRequest)
+            });
+        }
+
+        // The body may have been buffered earlier with a larger limit.
+        let input = Limited { inner: &mut req.body, remaining: self.limit };
+        let parsed = Element::from_events(Events::new(input).max_depth(self.max_depth));
+        if let Err(e) = req.body.rewind() {
+            return Err(IronError::new(e, status::InternalServerError));
+        }
+
+        match parsed {
+            Ok(document) => {
+                req.extensions.insert::<XmlBody>(document);
+                Ok(())
+            },
+            Err(e) => {
+                let too_large = match e {
+                    XmlError::Io(ref e) => e.kind() == io::ErrorKind::InvalidData,
+                    XmlError::Syntax(..) => false
+                };
+                let status = if too_large { status::PayloadTooLarge } else { status::BadRequest };
+                Err(IronError::new(e, status))
+            }
+        }
+    }
+}
+
+// A reader which fails with `ErrorKind::InvalidData` if `inner` is longer
+// than `remaining` bytes.
+struct Limited<R> {
+    inner: R,
+    remaining: usize
+}
+
+impl<R: Read> Read for Limited<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.remaining == 0 {
+            return match try!(self.inner.read(&mut [0])) {
+                0 => Ok(0),
+                _ => Err(io::Error::new(io::ErrorKind::InvalidData, "Request body too large"))
+            }
+        }
+
+        let length = buf.len().min(self.remaining);
+        let read = try!(self.inner.read(&mut buf[..length]));
+        self.remaining -= read;
+        Ok(read)
+    }
+}
+
+fn is_xml(mime: &Mime) -> bool {
+    let Mime(ref top, ref sub, _) = *mime;
+    match (top.as_str(), sub.as_str()) {
+        ("text", "xml") | ("application", "xml") => true,
+        (_, sub) => sub.ends_with("+xml")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use headers::ContentType;
+    use method::Method;
+    use test::StubRequest;
+    use {status, BeforeMiddleware};
+    use super::{Element, Event, Events, Node, XmlBody, XmlBodyParser, DEFAULT_MAX_DEPTH};
+
+    fn events(document: &str) -> Result<Vec<Event>, String> {
+        Events::new(document.as_bytes()).collect::<Result<Vec<_>, _>>().map_err(|e| e.to_string())
+    }
+
+    #[test]
+    fn test_events() {
+        let document = "<?xml version=\"1.0\"?>\n<!-- greeting -->\n\
+                        <a x='1' y=\"&lt;2&gt;\"><b/>t&amp;t<![CDATA[<raw>]]>&#65;&#x42;</a>\n";
+        assert_eq!(events(document).unwrap(), vec![
+            Event::Start("a".into(), vec![("x".into(), "1".into()), ("y".into(), "<2>".into())]),
+            Event::Start("b".into(), vec![]),
+            Event::End("b".into()),
+            Event::Text("t&t".into()),
+            Event::Text("<raw>".into()),
+            Event::Text("AB".into()),
+            Event::End("a".into())
+        ]);
+    }
+
+    #[test]
+    fn test_errors() {
+        for document in &["", "<a>", "<a></b>", "</a>", "<a/><b/>", "text<a/>", "<a x=1/>",
+                          "<a x='1' x='2'/>", "<a>&bogus;</a>", "<a><!-- </a>",
+                          "<!DOCTYPE a [<!ENTITY b \"c\">]><a/>"] {
+            assert!(events(document).is_err(), "{} parsed", document);
+        }
+    }
+
+    #[test]
+    fn test_max_depth() {
+        let nested = |depth| format!("{}{}", "<a>".repeat(depth), "</a>".repeat(depth));
+        assert!(events(&nested(DEFAULT_MAX_DEPTH)).is_ok());
+        assert!(events(&nested(DEFAULT_MAX_DEPTH + 1)).is_err());
+        assert!(events(&format!("{}<b/>{}", "<a>".repeat(DEFAULT_MAX_DEPTH),
+                                "</a>".repeat(DEFAULT_MAX_DEPTH))).is_err());
+
+        let shallow = |document: &str| {
+            Events::new(document.as_bytes()).max_depth(2).collect::<Result<Vec<_>, _>>()
+        };
+        assert!(shallow("<a><b/></a>").is_ok());
+        assert!(shallow("<a><b><c/></b></a>").is_err());
+    }
+
+    #[test]
+    fn test_drop_deep_element() {
+        let mut root = Element { name: "a".into(), attributes: vec![], children: vec![] };
+        for _ in 0..1_000_000 {
+            let parent = Element { name: "a".into(), attributes: vec![],
+                                   children: vec![Node::Element(root)] };
+            root = parent;
+        }
+        drop(root);
+    }
+
+    #[test]
+    fn test_element() {
+        let document = "<s:Envelope xmlns:s='urn:soap'><s:Body>\
+                        <Add a=\"1\">x<![CDATA[y]]><b/>z</Add></s:Body></s:Envelope>";
+        let envelope = Element::parse(document.as_bytes()).unwrap();
+        assert_eq!(envelope.local_name(), "Envelope");
+        assert_eq!(envelope.attribute("xmlns:s"), Some("urn:soap"));
+
+        let add = envelope.child("s:Body").and_then(|body| body.child("Add")).unwrap();
+        assert_eq!(add.attribute("a"), Some("1"));
+        assert_eq!(add.text(), "xyz");
+        assert_eq!(add.children[0], Node::Text("xy".into()));
+        assert_eq!(add.elements().len(), 1);
+    }
+
+    #[test]
+    fn test_body_parser() {
+        let parser = XmlBodyParser::new(64);
+        let request = |content_type: &str, body: &str| {
+            StubRequest::new(Method::Post, "http://localhost/")
+                .header(ContentType(content_type.parse().unwrap()))
+                .body(body)
+                .build()
+        };
+
+        let mut req = request("application/soap+xml; charset=utf-8", "<a>1</a>");
+        parser.before(&mut req).unwrap();
+        assert_eq!(req.extensions.get::<XmlBody>().map(|a| a.text()), Some("1".into()));
+        assert_eq!(req.body_string(64).unwrap(), "<a>1</a>");
+
+        let mut req = request("text/plain", "<a>");
+        parser.before(&mut req).unwrap();
+        assert!(req.extensions.get::<XmlBody>().is_none());
+
+        let status = |content_type, body| {
+            parser.before(&mut request(content_type, body)).unwrap_err().response.status
+        };
+        assert_eq!(status("text/xml", "<a>"), Some(status::BadRequest));
+        assert_eq!(status("application/xml", &format!("<a>{}</a>", "x".repeat(64))),
+                   Some(status::PayloadTooLarge));
+
+        let shallow = XmlBodyParser::new(64).max_depth(2);
+        let err = shallow.before(&mut request("text/xml", "<a><b><c/></b></a>")).unwrap_err();
+        assert_eq!(err.response.status, Some(status::BadRequest));
+    }
+}